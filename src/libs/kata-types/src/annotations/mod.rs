@@ -158,6 +158,10 @@ pub const KATA_ANNO_CFG_HYPERVISOR_KERNEL_HASH: &str =
 /// A sandbox annotation for passing additional guest kernel parameters.
 pub const KATA_ANNO_CFG_HYPERVISOR_KERNEL_PARAMS: &str =
     "io.katacontainers.config.hypervisor.kernel_params";
+/// A sandbox annotation for appending guest kernel parameters to the configured ones, subject to
+/// the kernel parameter allowlist and denylist of the hypervisor configuration.
+pub const KATA_ANNO_CFG_HYPERVISOR_KERNEL_PARAMS_APPEND: &str =
+    "io.katacontainers.config.hypervisor.kernel_params_append";
 /// A sandbox annotation for passing a container guest image path.
 pub const KATA_ANNO_CFG_HYPERVISOR_IMAGE_PATH: &str = "io.katacontainers.config.hypervisor.image";
 /// A sandbox annotation for passing a container guest image SHA-512 hash value.
@@ -227,6 +231,9 @@ pub const KATA_ANNO_CFG_HYPERVISOR_MEMORY_PREALLOC: &str =
 /// A sandbox annotation to specify if the memory should be pre-allocated from huge pages.
 pub const KATA_ANNO_CFG_HYPERVISOR_HUGE_PAGES: &str =
     "io.katacontainers.config.hypervisor.enable_hugepages";
+/// A sandbox annotation to specify the host NUMA node the VM memory and vCPUs are placed on.
+pub const KATA_ANNO_CFG_HYPERVISOR_HOST_NUMA_NODE: &str =
+    "io.katacontainers.config.hypervisor.host_numa_node";
 /// A sandbox annotation to soecify file based memory backend root directory.
 pub const KATA_ANNO_CFG_HYPERVISOR_FILE_BACKED_MEM_ROOT_DIR: &str =
     "io.katacontainers.config.hypervisor.file_mem_backend";
@@ -258,6 +265,19 @@ pub const KATA_ANNO_CFG_HYPERVISOR_GUEST_HOOK_PATH: &str =
 /// A sandbox annotation to enable rootless hypervisor (only supported in QEMU currently).
 pub const KATA_ANNO_CFG_HYPERVISOR_ENABLE_ROOTLESS_HYPERVISOR: &str =
     "io.katacontainers.config.hypervisor.rootless";
+/// A sandbox annotation to specify the data the host provides to an SEV-SNP guest, bound into
+/// its launch measurement, in hexadecimal or base64.
+pub const KATA_ANNO_CFG_HYPERVISOR_SNP_HOST_DATA: &str =
+    "io.katacontainers.config.hypervisor.snp_host_data";
+/// A sandbox annotation to specify the MRCONFIGID of a TDX guest, in hexadecimal or base64.
+pub const KATA_ANNO_CFG_HYPERVISOR_TDX_MRCONFIGID: &str =
+    "io.katacontainers.config.hypervisor.tdx_mrconfigid";
+/// A sandbox annotation to specify the MROWNER of a TDX guest, in hexadecimal or base64.
+pub const KATA_ANNO_CFG_HYPERVISOR_TDX_MROWNER: &str =
+    "io.katacontainers.config.hypervisor.tdx_mrowner";
+/// A sandbox annotation to specify the MROWNERCONFIG of a TDX guest, in hexadecimal or base64.
+pub const KATA_ANNO_CFG_HYPERVISOR_TDX_MROWNERCONFIG: &str =
+    "io.katacontainers.config.hypervisor.tdx_mrownerconfig";
 
 // Hypervisor Shared File System related annotations
 /// A sandbox annotation to specify the shared file system type, either inline-virtio-fs (default), virtio-9p, virtio-fs or virtio-fs-nydus.
@@ -575,6 +595,9 @@ impl Annotation {
                     KATA_ANNO_CFG_HYPERVISOR_KERNEL_PARAMS => {
                         hv.boot_info.kernel_params = value.to_string();
                     }
+                    KATA_ANNO_CFG_HYPERVISOR_KERNEL_PARAMS_APPEND => {
+                        hv.boot_info.append_annotation_kernel_params(value)?;
+                    }
                     KATA_ANNO_CFG_HYPERVISOR_IMAGE_PATH => {
                         hv.boot_info.validate_boot_path(value)?;
                         hv.boot_info.image = value.to_string();
@@ -733,6 +756,14 @@ impl Annotation {
                             return Err(bool_err);
                         }
                     },
+                    KATA_ANNO_CFG_HYPERVISOR_HOST_NUMA_NODE => match self.get_value::<u32>(key) {
+                        Ok(r) => {
+                            hv.memory_info.host_numa_node = r;
+                        }
+                        Err(_e) => {
+                            return Err(u32_err);
+                        }
+                    },
                     KATA_ANNO_CFG_HYPERVISOR_FILE_BACKED_MEM_ROOT_DIR => {
                         hv.memory_info.validate_memory_backend_path(value)?;
                         hv.memory_info.file_mem_backend = value.to_string();
@@ -807,6 +838,24 @@ impl Annotation {
                             }
                         }
                     }
+                    // The attestation data is checked once set, so that invalid data fails the
+                    // sandbox creation with the name of the field.
+                    KATA_ANNO_CFG_HYPERVISOR_SNP_HOST_DATA => {
+                        hv.security_info.snp_host_data = value.to_string();
+                        hv.security_info.get_launch_data()?;
+                    }
+                    KATA_ANNO_CFG_HYPERVISOR_TDX_MRCONFIGID => {
+                        hv.security_info.tdx_mrconfigid = value.to_string();
+                        hv.security_info.get_launch_data()?;
+                    }
+                    KATA_ANNO_CFG_HYPERVISOR_TDX_MROWNER => {
+                        hv.security_info.tdx_mrowner = value.to_string();
+                        hv.security_info.get_launch_data()?;
+                    }
+                    KATA_ANNO_CFG_HYPERVISOR_TDX_MROWNERCONFIG => {
+                        hv.security_info.tdx_mrownerconfig = value.to_string();
+                        hv.security_info.get_launch_data()?;
+                    }
                     // Hypervisor Shared File System related annotations
                     KATA_ANNO_CFG_HYPERVISOR_SHARED_FS => {
                         hv.shared_fs.shared_fs = self.get(key);
@@ -7,7 +7,7 @@
 use bitmask_enum::bitmask;
 
 /// CapabilityBits
-#[bitmask(u8)]
+#[bitmask(u16)]
 pub enum CapabilityBits {
     /// hypervisor supports use block device
     BlockDeviceSupport,
@@ -17,6 +17,16 @@ pub enum CapabilityBits {
     MultiQueueSupport,
     /// hypervisor supports filesystem share
     FsSharingSupport,
+    /// hypervisor supports filesystem share with virtio-fs
+    VirtioFsSupport,
+    /// hypervisor supports filesystem share with virtio-9p
+    Virtio9pSupport,
+    /// hypervisor supports guest memory hotplug
+    MemoryHotplugSupport,
+    /// hypervisor supports VFIO device hotplug
+    VfioHotplugSupport,
+    /// hypervisor supports hybrid vsock
+    HybridVsockSupport,
 }
 
 /// Capabilities describe a virtcontainers hypervisor capabilities through a bit mask.
@@ -24,6 +34,8 @@ pub enum CapabilityBits {
 pub struct Capabilities {
     /// Capability flags
     flags: CapabilityBits,
+    /// Maximum number of vsock devices, 0 if unlimited
+    max_vsock_devices: u32,
 }
 
 impl Default for Capabilities {
@@ -37,6 +49,7 @@ impl Capabilities {
     pub fn new() -> Self {
         Capabilities {
             flags: CapabilityBits { bits: 0 },
+            max_vsock_devices: 0,
         }
     }
 
@@ -64,6 +77,41 @@ impl Capabilities {
     pub fn is_fs_sharing_supported(&self) -> bool {
         self.flags.and(CapabilityBits::FsSharingSupport) != 0
     }
+
+    /// is_virtio_fs_supported tells if an hypervisor supports filesystem sharing with virtio-fs.
+    pub fn is_virtio_fs_supported(&self) -> bool {
+        self.flags.and(CapabilityBits::VirtioFsSupport) != 0
+    }
+
+    /// is_virtio_9p_supported tells if an hypervisor supports filesystem sharing with virtio-9p.
+    pub fn is_virtio_9p_supported(&self) -> bool {
+        self.flags.and(CapabilityBits::Virtio9pSupport) != 0
+    }
+
+    /// is_memory_hotplug_supported tells if an hypervisor supports guest memory hotplug.
+    pub fn is_memory_hotplug_supported(&self) -> bool {
+        self.flags.and(CapabilityBits::MemoryHotplugSupport) != 0
+    }
+
+    /// is_vfio_hotplug_supported tells if an hypervisor supports VFIO device hotplug.
+    pub fn is_vfio_hotplug_supported(&self) -> bool {
+        self.flags.and(CapabilityBits::VfioHotplugSupport) != 0
+    }
+
+    /// is_hybrid_vsock_supported tells if an hypervisor supports hybrid vsock.
+    pub fn is_hybrid_vsock_supported(&self) -> bool {
+        self.flags.and(CapabilityBits::HybridVsockSupport) != 0
+    }
+
+    /// set the maximum number of vsock devices, 0 if unlimited.
+    pub fn set_max_vsock_devices(&mut self, max: u32) {
+        self.max_vsock_devices = max;
+    }
+
+    /// max_vsock_devices returns the maximum number of vsock devices, 0 if unlimited.
+    pub fn max_vsock_devices(&self) -> u32 {
+        self.max_vsock_devices
+    }
 }
 
 #[cfg(test)]
@@ -102,6 +150,27 @@ mod tests {
                 | CapabilityBits::MultiQueueSupport
                 | CapabilityBits::FsSharingSupport,
         );
-        assert!(cap.is_fs_sharing_supported())
+        assert!(cap.is_fs_sharing_supported());
+        assert!(!cap.is_virtio_fs_supported());
+
+        // test set virtio-fs and hotplug support
+        cap.set(
+            CapabilityBits::FsSharingSupport
+                | CapabilityBits::VirtioFsSupport
+                | CapabilityBits::MemoryHotplugSupport
+                | CapabilityBits::VfioHotplugSupport
+                | CapabilityBits::HybridVsockSupport,
+        );
+        assert!(cap.is_virtio_fs_supported());
+        assert!(!cap.is_virtio_9p_supported());
+        assert!(cap.is_memory_hotplug_supported());
+        assert!(cap.is_vfio_hotplug_supported());
+        assert!(cap.is_hybrid_vsock_supported());
+        assert!(!cap.is_block_device_supported());
+
+        // test set vsock device limit
+        assert_eq!(cap.max_vsock_devices(), 0);
+        cap.set_max_vsock_devices(1);
+        assert_eq!(cap.max_vsock_devices(), 1);
     }
 }
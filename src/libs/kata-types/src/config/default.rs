@@ -82,3 +82,8 @@ pub const DEFAULT_CH_PCI_BRIDGES: u32 = 2;
 pub const MAX_CH_PCI_BRIDGES: u32 = 5;
 pub const MAX_CH_VCPUS: u32 = 256;
 pub const MIN_CH_MEMORY_SIZE_MB: u32 = 64;
+pub const DEFAULT_CH_CONSOLE_LOG_MAX_SIZE_MB: u32 = 10;
+pub const DEFAULT_CH_ACPI_SHUTDOWN_TIMEOUT_SECS: u32 = 10;
+pub const DEFAULT_CH_VMM_SHUTDOWN_TIMEOUT_SECS: u32 = 5;
+pub const DEFAULT_CH_VMM_TERMINATE_TIMEOUT_SECS: u32 = 5;
+pub const DEFAULT_CH_SECCOMP: &str = "false";
@@ -13,13 +13,77 @@ use super::{default, register_hypervisor_plugin};
 use crate::config::default::MAX_CH_VCPUS;
 use crate::config::default::MIN_CH_MEMORY_SIZE_MB;
 
-use crate::config::hypervisor::VIRTIO_BLK_MMIO;
+use crate::config::hypervisor::{
+    BootInfo, MISSING_FIRMWARE_FAIL, MISSING_FIRMWARE_KERNEL, VIRTIO_BLK_MMIO,
+};
 use crate::config::{ConfigPlugin, TomlConfig};
 use crate::{eother, resolve_path, validate_path};
 
 /// Hypervisor name for CH, used to index `TomlConfig::hypervisor`.
 pub const HYPERVISOR_NAME_CH: &str = "cloud-hypervisor";
 
+/// Seccomp modes supported by CH's `--seccomp` option.
+pub const CH_SECCOMP_MODES: [&str; 3] = ["true", "log", "false"];
+
+/// Maximum length of a SMBIOS string passed to CH.
+pub const CH_MAX_SMBIOS_STRING_LEN: usize = 255;
+
+/// Maximum CH log level, each level adding a `-v` option.
+pub const CH_MAX_LOG_LEVEL: u32 = 3;
+
+/// Maximum size of the in-memory buffer retaining the end of the guest console, in KiB.
+pub const CH_MAX_CONSOLE_BUFFER_SIZE_KB: u32 = 16 * 1024;
+
+/// Guest console backed by the legacy serial port.
+pub const CH_CONSOLE_TYPE_SERIAL: &str = "serial";
+/// Guest console backed by a virtio-console device.
+pub const CH_CONSOLE_TYPE_VIRTIO: &str = "virtio";
+/// No guest console.
+pub const CH_CONSOLE_TYPE_OFF: &str = "off";
+
+/// Block device I/O backends supported by CH.
+pub const CH_BLOCK_DEVICE_AIO_IO_URING: &str = "io_uring";
+pub const CH_BLOCK_DEVICE_AIO_AIO: &str = "aio";
+
+/// Host virtualization devices CH can run on.
+pub const CH_HYPERVISOR_DEVICE_KVM: &str = "kvm";
+pub const CH_HYPERVISOR_DEVICE_MSHV: &str = "mshv";
+
+/// Maximum number of PCI segments supported by CH.
+pub const CH_MAX_NUM_PCI_SEGMENTS: u16 = 16;
+
+/// Guest clock sources that can be selected for CH guests.
+pub const CH_GUEST_CLOCK_SOURCES: [&str; 4] = ["kvm-clock", "tsc", "hpet", "acpi_pm"];
+
+/// Maximum guest clock offset, in seconds (roughly 100 years).
+pub const CH_MAX_GUEST_CLOCK_OFFSET_SECS: i64 = 100 * 365 * 24 * 60 * 60;
+
+/// Guest kernel parameters keeping the CPU and the bootloader from seeding the guest random
+/// number generator, set when the guest entropy is deterministic.
+pub const CH_DETERMINISTIC_ENTROPY_PARAMS: [&str; 2] =
+    ["random.trust_cpu=off", "random.trust_bootloader=off"];
+
+/// Types of the devices CH can place behind its virtio-iommu device.
+pub const CH_IOMMU_DEVICE_BLOCK: &str = "block";
+pub const CH_IOMMU_DEVICE_NET: &str = "net";
+pub const CH_IOMMU_DEVICE_PMEM: &str = "pmem";
+pub const CH_IOMMU_DEVICE_VDPA: &str = "vdpa";
+pub const CH_IOMMU_DEVICE_VFIO: &str = "vfio";
+pub const CH_IOMMU_DEVICE_VSOCK: &str = "vsock";
+pub const CH_IOMMU_DEVICE_TYPES: [&str; 6] = [
+    CH_IOMMU_DEVICE_BLOCK,
+    CH_IOMMU_DEVICE_NET,
+    CH_IOMMU_DEVICE_PMEM,
+    CH_IOMMU_DEVICE_VDPA,
+    CH_IOMMU_DEVICE_VFIO,
+    CH_IOMMU_DEVICE_VSOCK,
+];
+
+/// PCI hotplug mechanisms a guest kernel may support. CH only provides ACPI hotplug.
+pub const CH_GUEST_PCI_HOTPLUG_ACPI: &str = "acpi";
+pub const CH_GUEST_PCI_HOTPLUG_NATIVE: &str = "native";
+pub const CH_GUEST_PCI_HOTPLUG_NONE: &str = "none";
+
 /// Configuration information for CH.
 #[derive(Default, Debug)]
 pub struct CloudHypervisorConfig {}
@@ -71,14 +135,24 @@ impl ConfigPlugin for CloudHypervisorConfig {
             if ch.boot_info.firmware.is_empty() {
                 ch.boot_info.firmware = default::DEFAULT_CH_FIRMWARE_PATH.to_string();
             }
+            if ch.boot_info.missing_firmware_policy.is_empty() {
+                ch.boot_info.missing_firmware_policy = MISSING_FIRMWARE_FAIL.to_string();
+            }
 
             if ch.device_info.default_bridges == 0 {
                 ch.device_info.default_bridges = default::DEFAULT_CH_PCI_BRIDGES;
             }
+            if ch.device_info.guest_pci_hotplug.is_empty() {
+                ch.device_info.guest_pci_hotplug = CH_GUEST_PCI_HOTPLUG_ACPI.to_string();
+            }
 
             if ch.machine_info.entropy_source.is_empty() {
                 ch.machine_info.entropy_source = default::DEFAULT_CH_ENTROPY_SOURCE.to_string();
             }
+            resolve_path!(
+                ch.machine_info.guest_entropy_seed_file,
+                "CH guest entropy seed file `{}` is invalid: {}"
+            )?;
 
             if ch.memory_info.default_memory == 0 {
                 ch.memory_info.default_memory = default::DEFAULT_CH_MEMORY_SIZE_MB;
@@ -86,6 +160,34 @@ impl ConfigPlugin for CloudHypervisorConfig {
             if ch.memory_info.memory_slots == 0 {
                 ch.memory_info.memory_slots = default::DEFAULT_CH_MEMORY_SLOTS;
             }
+
+            if ch.debug_info.guest_console_log_max_size_mb == 0 {
+                ch.debug_info.guest_console_log_max_size_mb =
+                    default::DEFAULT_CH_CONSOLE_LOG_MAX_SIZE_MB;
+            }
+
+            if ch.machine_info.acpi_shutdown_timeout_secs == 0 {
+                ch.machine_info.acpi_shutdown_timeout_secs =
+                    default::DEFAULT_CH_ACPI_SHUTDOWN_TIMEOUT_SECS;
+            }
+            if ch.machine_info.vmm_shutdown_timeout_secs == 0 {
+                ch.machine_info.vmm_shutdown_timeout_secs =
+                    default::DEFAULT_CH_VMM_SHUTDOWN_TIMEOUT_SECS;
+            }
+            if ch.machine_info.vmm_terminate_timeout_secs == 0 {
+                ch.machine_info.vmm_terminate_timeout_secs =
+                    default::DEFAULT_CH_VMM_TERMINATE_TIMEOUT_SECS;
+            }
+
+            if ch.debug_info.console_type.is_empty() {
+                ch.debug_info.console_type = CH_CONSOLE_TYPE_SERIAL.to_string();
+            }
+
+            // CH has always been launched without seccomp filtering, which
+            // must be enabled explicitly.
+            if ch.security_info.seccomp.is_empty() {
+                ch.security_info.seccomp = default::DEFAULT_CH_SECCOMP.to_string();
+            }
         }
 
         Ok(())
@@ -115,6 +217,37 @@ impl ConfigPlugin for CloudHypervisorConfig {
             if ch.boot_info.image.is_empty() && ch.boot_info.initrd.is_empty() {
                 return Err(eother!("Both guest boot image and initrd for CH are empty"));
             }
+            match ch.boot_info.missing_firmware_policy.as_str() {
+                MISSING_FIRMWARE_FAIL => (),
+                MISSING_FIRMWARE_KERNEL => {
+                    if ch.security_info.confidential_guest {
+                        return Err(eother!(
+                            "CH cannot fall back to kernel boot for a confidential guest"
+                        ));
+                    }
+                }
+                policy => {
+                    return Err(eother!(
+                        "CH missing firmware policy `{}` is invalid, expected `{}` or `{}`",
+                        policy,
+                        MISSING_FIRMWARE_FAIL,
+                        MISSING_FIRMWARE_KERNEL
+                    ));
+                }
+            }
+
+            let aio = ch.blockdev_info.block_device_aio.as_str();
+            if !aio.is_empty()
+                && aio != CH_BLOCK_DEVICE_AIO_IO_URING
+                && aio != CH_BLOCK_DEVICE_AIO_AIO
+            {
+                return Err(eother!(
+                    "CH block device I/O backend `{}` is invalid, expected `{}` or `{}`",
+                    aio,
+                    CH_BLOCK_DEVICE_AIO_IO_URING,
+                    CH_BLOCK_DEVICE_AIO_AIO
+                ));
+            }
 
             if (ch.cpu_info.default_vcpus > 0
                 && ch.cpu_info.default_vcpus as u32 > default::MAX_CH_VCPUS)
@@ -139,8 +272,354 @@ impl ConfigPlugin for CloudHypervisorConfig {
                     MIN_CH_MEMORY_SIZE_MB
                 ));
             }
+
+            let smbios_strings = std::iter::once(&ch.machine_info.smbios_serial_number)
+                .chain(ch.machine_info.smbios_oem_strings.iter());
+            for s in smbios_strings {
+                if s.len() > CH_MAX_SMBIOS_STRING_LEN {
+                    return Err(eother!(
+                        "CH SMBIOS string `{}` is longer than {} bytes",
+                        s,
+                        CH_MAX_SMBIOS_STRING_LEN
+                    ));
+                }
+            }
+            if !ch.machine_info.smbios_uuid.is_empty()
+                && !is_valid_uuid(&ch.machine_info.smbios_uuid)
+            {
+                return Err(eother!(
+                    "CH SMBIOS UUID `{}` is invalid",
+                    ch.machine_info.smbios_uuid
+                ));
+            }
+
+            if ch.debug_info.ch_log_level > CH_MAX_LOG_LEVEL {
+                return Err(eother!(
+                    "CH log level {} is invalid, maximum is {}",
+                    ch.debug_info.ch_log_level,
+                    CH_MAX_LOG_LEVEL
+                ));
+            }
+
+            let console_types = [
+                CH_CONSOLE_TYPE_SERIAL,
+                CH_CONSOLE_TYPE_VIRTIO,
+                CH_CONSOLE_TYPE_OFF,
+            ];
+            if !console_types.contains(&ch.debug_info.console_type.as_str()) {
+                return Err(eother!(
+                    "CH console type `{}` is invalid, expected one of {:?}",
+                    ch.debug_info.console_type,
+                    console_types
+                ));
+            }
+
+            if ch.debug_info.console_socket && ch.debug_info.console_type != CH_CONSOLE_TYPE_SERIAL
+            {
+                return Err(eother!(
+                    "CH console socket requires console type `{}`, not `{}`",
+                    CH_CONSOLE_TYPE_SERIAL,
+                    ch.debug_info.console_type
+                ));
+            }
+
+            if ch.debug_info.console_buffer_size_kb > CH_MAX_CONSOLE_BUFFER_SIZE_KB {
+                return Err(eother!(
+                    "CH console buffer size {} KiB is invalid, maximum is {} KiB",
+                    ch.debug_info.console_buffer_size_kb,
+                    CH_MAX_CONSOLE_BUFFER_SIZE_KB
+                ));
+            }
+            if ch.debug_info.console_buffer_size_kb != 0 && !ch.debug_info.console_socket {
+                return Err(eother!("CH console buffer requires the console socket"));
+            }
+
+            let device = ch.machine_info.hypervisor_device.as_str();
+            if !device.is_empty()
+                && device != CH_HYPERVISOR_DEVICE_KVM
+                && device != CH_HYPERVISOR_DEVICE_MSHV
+            {
+                return Err(eother!(
+                    "CH hypervisor device `{}` is invalid, expected `{}` or `{}`",
+                    device,
+                    CH_HYPERVISOR_DEVICE_KVM,
+                    CH_HYPERVISOR_DEVICE_MSHV
+                ));
+            }
+
+            if !ch.machine_info.guest_clock_source.is_empty()
+                && !CH_GUEST_CLOCK_SOURCES.contains(&ch.machine_info.guest_clock_source.as_str())
+            {
+                return Err(eother!(
+                    "CH guest clock source `{}` is invalid, expected one of {:?}",
+                    ch.machine_info.guest_clock_source,
+                    CH_GUEST_CLOCK_SOURCES
+                ));
+            }
+            if ch.machine_info.guest_clock_offset.abs() > CH_MAX_GUEST_CLOCK_OFFSET_SECS {
+                return Err(eother!(
+                    "CH guest clock offset {} is out of range",
+                    ch.machine_info.guest_clock_offset
+                ));
+            }
+
+            check_confidential_rootfs(ch.security_info.confidential_guest, &ch.boot_info)?;
+
+            check_deterministic_entropy(
+                ch.machine_info.deterministic_guest_entropy,
+                &ch.machine_info.guest_entropy_seed_file,
+                &ch.machine_info.entropy_source,
+                &ch.boot_info.kernel_params,
+            )?;
+
+            if ch.device_info.num_pci_segments > CH_MAX_NUM_PCI_SEGMENTS {
+                return Err(eother!(
+                    "CH cannot support {} PCI segments, maximum is {}",
+                    ch.device_info.num_pci_segments,
+                    CH_MAX_NUM_PCI_SEGMENTS
+                ));
+            }
+            let num_pci_segments = if ch.device_info.num_pci_segments > 0 {
+                ch.device_info.num_pci_segments
+            } else {
+                CH_MAX_NUM_PCI_SEGMENTS
+            };
+            for segment in &ch.device_info.iommu_segments {
+                if *segment >= num_pci_segments {
+                    return Err(eother!(
+                        "CH IOMMU segment {} is invalid, only {} PCI segments are available",
+                        segment,
+                        num_pci_segments
+                    ));
+                }
+            }
+
+            for device_type in &ch.device_info.iommu_devices {
+                if !CH_IOMMU_DEVICE_TYPES.contains(&device_type.as_str()) {
+                    return Err(eother!(
+                        "CH IOMMU device type `{}` is invalid, expected one of {:?}",
+                        device_type,
+                        CH_IOMMU_DEVICE_TYPES
+                    ));
+                }
+            }
+            if !ch.device_info.iommu_devices.is_empty() && !ch.device_info.enable_iommu {
+                return Err(eother!("CH IOMMU devices require enable_iommu"));
+            }
+
+            match ch.device_info.guest_pci_hotplug.as_str() {
+                CH_GUEST_PCI_HOTPLUG_ACPI | CH_GUEST_PCI_HOTPLUG_NONE => (),
+                CH_GUEST_PCI_HOTPLUG_NATIVE => {
+                    return Err(eother!(
+                        "CH only provides ACPI PCI hotplug, not native PCIe hotplug: use a guest kernel with CONFIG_HOTPLUG_PCI_ACPI and set guest_pci_hotplug to `{}`, or set it to `{}` to cold plug all the devices",
+                        CH_GUEST_PCI_HOTPLUG_ACPI,
+                        CH_GUEST_PCI_HOTPLUG_NONE
+                    ));
+                }
+                hotplug => {
+                    return Err(eother!(
+                        "CH guest PCI hotplug `{}` is invalid, expected `{}`, `{}` or `{}`",
+                        hotplug,
+                        CH_GUEST_PCI_HOTPLUG_ACPI,
+                        CH_GUEST_PCI_HOTPLUG_NATIVE,
+                        CH_GUEST_PCI_HOTPLUG_NONE
+                    ));
+                }
+            }
+
+            if !ch.memory_info.sgx_epc_sections_mb.is_empty() {
+                if !cfg!(target_arch = "x86_64") {
+                    return Err(eother!("CH SGX EPC sections are only supported on x86_64"));
+                }
+                if ch.memory_info.sgx_epc_sections_mb.contains(&0) {
+                    return Err(eother!("CH SGX EPC section size cannot be zero"));
+                }
+            }
+
+            if ch.security_info.vmm_uid.is_some() != ch.security_info.vmm_gid.is_some() {
+                return Err(eother!("CH vmm_uid and vmm_gid must be set together"));
+            }
+
+            if !CH_SECCOMP_MODES.contains(&ch.security_info.seccomp.as_str()) {
+                return Err(eother!(
+                    "CH seccomp mode `{}` is invalid, expected one of {:?}",
+                    ch.security_info.seccomp,
+                    CH_SECCOMP_MODES
+                ));
+            }
         }
 
         Ok(())
     }
 }
+
+// Check the root filesystem of a confidential guest can be measured: the
+// image is attached read-only, so its writes cannot reach the file, and
+// neither can the guest modify the root filesystem it was measured with.
+fn check_confidential_rootfs(confidential_guest: bool, boot_info: &BootInfo) -> Result<()> {
+    let verity_params = &boot_info.rootfs_verity_params;
+
+    if !confidential_guest {
+        if !verity_params.is_empty() {
+            return Err(eother!(
+                "CH rootfs verity parameters `{}` require a confidential guest",
+                verity_params
+            ));
+        }
+
+        return Ok(());
+    }
+
+    // The initrd is preferred to the image.
+    if !boot_info.initrd.is_empty() {
+        if !verity_params.is_empty() {
+            return Err(eother!(
+                "CH rootfs verity parameters `{}` require booting from an image, not an initrd",
+                verity_params
+            ));
+        }
+
+        return Ok(());
+    }
+
+    if boot_info.rootfs_writable || boot_info.rootfs_discard_writes == Some(false) {
+        return Err(eother!(
+            "CH confidential guests cannot boot from a mutable image, whose root filesystem could not be measured: unset rootfs_writable and rootfs_discard_writes"
+        ));
+    }
+
+    Ok(())
+}
+
+// Check the deterministic guest entropy options: the seed file replaces the
+// entropy source, and the kernel parameters must not let the guest seed its
+// random number generator from the CPU or the bootloader.
+fn check_deterministic_entropy(
+    deterministic: bool,
+    seed_file: &str,
+    entropy_source: &str,
+    kernel_params: &str,
+) -> Result<()> {
+    if !deterministic {
+        if !seed_file.is_empty() {
+            return Err(eother!(
+                "CH guest entropy seed file `{}` requires deterministic_guest_entropy",
+                seed_file
+            ));
+        }
+
+        return Ok(());
+    }
+
+    if !seed_file.is_empty() {
+        if !entropy_source.is_empty() && entropy_source != default::DEFAULT_CH_ENTROPY_SOURCE {
+            return Err(eother!(
+                "CH guest entropy seed file `{}` conflicts with entropy source `{}`",
+                seed_file,
+                entropy_source
+            ));
+        }
+
+        validate_path!(seed_file, "CH guest entropy seed file `{}` is invalid: {}")?;
+    }
+
+    for param in kernel_params.split_whitespace() {
+        let name = param.split('=').next().unwrap_or_default();
+
+        let conflicts = CH_DETERMINISTIC_ENTROPY_PARAMS
+            .iter()
+            .any(|p| p.split('=').next() == Some(name) && *p != param);
+
+        if conflicts {
+            return Err(eother!(
+                "CH kernel parameter `{}` conflicts with deterministic_guest_entropy",
+                param
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Check the string is a UUID in the canonical 8-4-4-4-12 hex digit format.
+fn is_valid_uuid(uuid: &str) -> bool {
+    let groups: Vec<&str> = uuid.split('-').collect();
+    let lengths = [8, 4, 4, 4, 12];
+
+    groups.len() == lengths.len()
+        && groups
+            .iter()
+            .zip(lengths.iter())
+            .all(|(g, l)| g.len() == *l && g.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_uuid() {
+        assert!(is_valid_uuid("4c4c4544-0047-3210-8052-b4c04f4b4e32"));
+        assert!(!is_valid_uuid(""));
+        assert!(!is_valid_uuid("4c4c4544-0047-3210-8052"));
+        assert!(!is_valid_uuid("4c4c4544-0047-3210-8052-b4c04f4b4e3z"));
+        assert!(!is_valid_uuid("4c4c45440-047-3210-8052-b4c04f4b4e32"));
+    }
+
+    #[test]
+    fn test_check_confidential_rootfs() {
+        let verity = "cc_rootfs_verity.scheme=dm-verity cc_rootfs_verity.hash=0123";
+
+        let mut boot_info = BootInfo {
+            image: "/opt/kata/kata-containers.img".to_string(),
+            ..Default::default()
+        };
+
+        assert!(check_confidential_rootfs(false, &boot_info).is_ok());
+        assert!(check_confidential_rootfs(true, &boot_info).is_ok());
+
+        boot_info.rootfs_verity_params = verity.to_string();
+
+        assert!(check_confidential_rootfs(false, &boot_info).is_err());
+        assert!(check_confidential_rootfs(true, &boot_info).is_ok());
+
+        // Mutable image.
+        boot_info.rootfs_discard_writes = Some(false);
+
+        let err = check_confidential_rootfs(true, &boot_info)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("mutable image"));
+
+        boot_info.rootfs_discard_writes = None;
+        boot_info.rootfs_writable = true;
+
+        assert!(check_confidential_rootfs(true, &boot_info).is_err());
+
+        boot_info.initrd = "/opt/kata/kata-containers-initrd.img".to_string();
+
+        assert!(check_confidential_rootfs(true, &boot_info).is_err());
+
+        // The initrd is loaded in the guest memory, and measured as such.
+        boot_info.rootfs_verity_params.clear();
+
+        assert!(check_confidential_rootfs(true, &boot_info).is_ok());
+    }
+
+    #[test]
+    fn test_check_deterministic_entropy() {
+        let urandom = default::DEFAULT_CH_ENTROPY_SOURCE;
+
+        assert!(check_deterministic_entropy(false, "", urandom, "quiet").is_ok());
+        assert!(check_deterministic_entropy(false, "/tmp", urandom, "").is_err());
+        assert!(check_deterministic_entropy(true, "", urandom, "random.trust_cpu=off").is_ok());
+        assert!(check_deterministic_entropy(true, "/tmp", urandom, "").is_ok());
+        assert!(check_deterministic_entropy(true, "/tmp", "/dev/hwrng", "").is_err());
+        assert!(check_deterministic_entropy(true, "/not/a/seed", urandom, "").is_err());
+
+        let err = check_deterministic_entropy(true, "", urandom, "quiet random.trust_cpu=on")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("random.trust_cpu=on"));
+    }
+}
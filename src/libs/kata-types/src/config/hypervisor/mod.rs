@@ -41,7 +41,15 @@ mod qemu;
 pub use self::qemu::{QemuConfig, HYPERVISOR_NAME_QEMU};
 
 mod ch;
-pub use self::ch::{CloudHypervisorConfig, HYPERVISOR_NAME_CH};
+pub use self::ch::{
+    CloudHypervisorConfig, CH_BLOCK_DEVICE_AIO_AIO, CH_BLOCK_DEVICE_AIO_IO_URING,
+    CH_CONSOLE_TYPE_OFF, CH_CONSOLE_TYPE_SERIAL, CH_CONSOLE_TYPE_VIRTIO,
+    CH_DETERMINISTIC_ENTROPY_PARAMS, CH_GUEST_CLOCK_SOURCES, CH_GUEST_PCI_HOTPLUG_ACPI,
+    CH_GUEST_PCI_HOTPLUG_NATIVE, CH_GUEST_PCI_HOTPLUG_NONE, CH_HYPERVISOR_DEVICE_KVM,
+    CH_HYPERVISOR_DEVICE_MSHV, CH_IOMMU_DEVICE_BLOCK, CH_IOMMU_DEVICE_NET, CH_IOMMU_DEVICE_PMEM,
+    CH_IOMMU_DEVICE_TYPES, CH_IOMMU_DEVICE_VDPA, CH_IOMMU_DEVICE_VFIO, CH_IOMMU_DEVICE_VSOCK,
+    CH_MAX_CONSOLE_BUFFER_SIZE_KB, CH_MAX_GUEST_CLOCK_OFFSET_SECS, HYPERVISOR_NAME_CH,
+};
 
 const VIRTIO_BLK: &str = "virtio-blk";
 const VIRTIO_BLK_MMIO: &str = "virtio-mmio";
@@ -51,10 +59,178 @@ const VIRTIO_PMEM: &str = "nvdimm";
 const VIRTIO_9P: &str = "virtio-9p";
 const VIRTIO_FS: &str = "virtio-fs";
 const VIRTIO_FS_INLINE: &str = "inline-virtio-fs";
+const VIRTIO_FS_SANDBOX_MODES: [&str; 3] = ["namespace", "chroot", "none"];
 const MAX_BRIDGE_SIZE: u32 = 5;
+// Sizes of the virtqueues of net devices, which are powers of two.
+const MIN_NET_QUEUE_SIZE: u32 = 2;
+const MAX_NET_QUEUE_SIZE: u32 = 32768;
+// Range of the OOM score adjustment of a process.
+const MIN_OOM_SCORE_ADJ: i32 = -1000;
+const MAX_OOM_SCORE_ADJ: i32 = 1000;
+// Sizes in bytes of the attestation data bound into the launch measurement of
+// a confidential guest: the SEV-SNP host data, and the TDX SHA-384 digests
+// (MR*).
+const SNP_HOST_DATA_SIZE: usize = 32;
+const TDX_MR_SIZE: usize = 48;
 
 const KERNEL_PARAM_DELIMITER: &str = " ";
 
+const KERNEL_KV_DELIMITER: char = '=';
+
+/// Policies applied when the firmware file is missing, see
+/// `BootInfo::missing_firmware_policy`.
+pub const MISSING_FIRMWARE_FAIL: &str = "fail";
+pub const MISSING_FIRMWARE_KERNEL: &str = "kernel";
+
+/// Guest kernel parameters which can never be appended through annotations, as they control the
+/// guest boot, its console routing or the agent. An entry ending with "*" matches any parameter
+/// name starting with the entry.
+pub const KERNEL_PARAMS_ANNOTATION_DENYLIST: &[&str] = &[
+    "init",
+    "rdinit",
+    "root",
+    "rootflags",
+    "rootfstype",
+    "console",
+    "earlycon",
+    "earlyprintk",
+    "systemd.*",
+    "agent.*",
+];
+
+// Characters which could be used to inject additional parameters, or commands
+// in the guest.
+const KERNEL_PARAM_FORBIDDEN_CHARS: &[char] = &[';', '"', '\'', '`', '$', '\\', '&', '|'];
+
+/// Return the name of a guest kernel parameter, without its value.
+pub fn kernel_param_name(param: &str) -> &str {
+    param
+        .split_once(KERNEL_KV_DELIMITER)
+        .map_or(param, |(name, _)| name)
+}
+
+/// Validate a single guest kernel parameter, rejecting parameters which could inject other
+/// parameters or shell commands.
+pub fn validate_kernel_param(param: &str) -> Result<()> {
+    if kernel_param_name(param).is_empty() {
+        return Err(eother!("Invalid kernel parameter {:?}: empty name", param));
+    }
+    if param
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || KERNEL_PARAM_FORBIDDEN_CHARS.contains(&c))
+    {
+        return Err(eother!(
+            "Invalid kernel parameter {:?}: forbidden character",
+            param
+        ));
+    }
+    Ok(())
+}
+
+/// Merge the `extra` guest kernel parameters into the `base` ones.
+///
+/// The extra parameters are appended verbatim, replacing the base parameter with the same name.
+/// Parameters given several values, such as `systemd.mask`, are not replaced: all their values
+/// are kept.
+pub fn merge_kernel_params(base: &str, extra: &str) -> String {
+    let count = |params: &str, name: &str| {
+        params
+            .split_whitespace()
+            .filter(|p| kernel_param_name(p) == name)
+            .count()
+    };
+
+    let mut params: Vec<&str> = base
+        .split_whitespace()
+        .filter(|p| {
+            let name = kernel_param_name(p);
+            count(extra, name) != 1 || count(base, name) != 1
+        })
+        .collect();
+
+    let extra = extra.trim();
+    if !extra.is_empty() {
+        params.push(extra);
+    }
+
+    params.join(KERNEL_PARAM_DELIMITER)
+}
+
+/// Decode attestation data of the specified size in bytes, either in hexadecimal or in base64.
+/// A value made of hexadecimal digits only is hexadecimal, so that a value of the wrong size is
+/// not mistaken for base64. The errors name the configuration field.
+pub fn decode_launch_data(name: &str, value: &str, size: usize) -> Result<Vec<u8>> {
+    let data = if value.chars().all(|c| c.is_ascii_hexdigit()) {
+        if value.len() % 2 != 0 {
+            return Err(eother!(
+                "Invalid {} {}: odd number of hexadecimal digits",
+                name,
+                value
+            ));
+        }
+
+        (0..value.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|e| eother!("Invalid {} {}: {}", name, value, e))?
+    } else {
+        base64::decode(value).map_err(|e| {
+            eother!(
+                "Invalid {} {}: neither hexadecimal nor base64: {}",
+                name,
+                value,
+                e
+            )
+        })?
+    };
+
+    if data.len() != size {
+        return Err(eother!(
+            "Invalid {} {}: {} bytes, expected {}",
+            name,
+            value,
+            data.len(),
+            size
+        ));
+    }
+
+    Ok(data)
+}
+
+/// Validate a token bucket rate limiter: the one time burst, allowed once on top of the rate,
+/// requires a rate limit and cannot be smaller than the bucket, whose size is the rate.
+pub fn validate_rate_limiter(name: &str, max_rate: u64, one_time_burst: u64) -> Result<()> {
+    if one_time_burst == 0 {
+        return Ok(());
+    }
+    if max_rate == 0 {
+        return Err(eother!(
+            "Invalid {}_one_time_burst {}: requires {}_max_rate",
+            name,
+            one_time_burst,
+            name
+        ));
+    }
+    if one_time_burst < max_rate {
+        return Err(eother!(
+            "Invalid {}_one_time_burst {}: smaller than {}_max_rate {}",
+            name,
+            one_time_burst,
+            name,
+            max_rate
+        ));
+    }
+    Ok(())
+}
+
+fn kernel_param_matches(name: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
 lazy_static! {
     static ref HYPERVISOR_PLUGINS: Mutex<HashMap<String, Arc<dyn ConfigPlugin>>> =
         Mutex::new(HashMap::new());
@@ -135,6 +311,34 @@ pub struct BlockDeviceInfo {
     /// The default if not set is empty (all annotations rejected.)
     #[serde(default)]
     pub valid_vhost_user_store_paths: Vec<String>,
+
+    /// Asynchronous I/O backend used by the hypervisor for block devices.
+    ///
+    /// For CH, the supported values are "io_uring" and "aio", the latter requiring CH v30.0 or
+    /// newer. The default empty value leaves the choice to the hypervisor, CH using io_uring if
+    /// supported by the host kernel. Shared filesystems are not affected: their I/O is done by
+    /// virtiofsd, not by CH.
+    #[serde(default)]
+    pub block_device_aio: String,
+
+    /// Maximum bandwidth of each block device, in bytes/sec. The default 0 means unlimited.
+    #[serde(default)]
+    pub disk_rate_limiter_bw_max_rate: u64,
+
+    /// Bytes each block device may transfer once on top of disk_rate_limiter_bw_max_rate, to
+    /// absorb short spikes. Cannot be smaller than the rate. The default 0 means no burst.
+    #[serde(default)]
+    pub disk_rate_limiter_bw_one_time_burst: u64,
+
+    /// Maximum I/O operations of each block device, in operations/sec. The default 0 means
+    /// unlimited.
+    #[serde(default)]
+    pub disk_rate_limiter_ops_max_rate: u64,
+
+    /// Operations each block device may do once on top of disk_rate_limiter_ops_max_rate, to
+    /// absorb short spikes. Cannot be smaller than the rate. The default 0 means no burst.
+    #[serde(default)]
+    pub disk_rate_limiter_ops_one_time_burst: u64,
 }
 
 impl BlockDeviceInfo {
@@ -168,6 +372,17 @@ impl BlockDeviceInfo {
 
     /// Validate the configuration information.
     pub fn validate(&self) -> Result<()> {
+        validate_rate_limiter(
+            "disk_rate_limiter_bw",
+            self.disk_rate_limiter_bw_max_rate,
+            self.disk_rate_limiter_bw_one_time_burst,
+        )?;
+        validate_rate_limiter(
+            "disk_rate_limiter_ops",
+            self.disk_rate_limiter_ops_max_rate,
+            self.disk_rate_limiter_ops_one_time_burst,
+        )?;
+
         if self.disable_block_device_use {
             return Ok(());
         }
@@ -216,11 +431,84 @@ pub struct BootInfo {
     /// Rootfs filesystem type.
     #[serde(default)]
     pub rootfs_type: String,
+    /// Whether the guest root filesystem, in the image or the initrd, is writable, default false.
+    /// Only used by Cloud Hypervisor.
+    ///
+    /// This selects the default of `rootfs_discard_writes`.
+    #[serde(default)]
+    pub rootfs_writable: bool,
+    /// Whether the writes of the guest to the image or the initrd are discarded. Only used by
+    /// Cloud Hypervisor.
+    ///
+    /// When set, the file is mapped copy-on-write: the guest sees its writes while it runs, but
+    /// they never reach the file and are lost when the VM stops. When unset, the writes modify
+    /// the file, which is shared by all the VMs booting from it. The default discards the writes
+    /// unless `rootfs_writable` is set.
+    #[serde(default)]
+    pub rootfs_discard_writes: Option<bool>,
+    /// Kernel parameters with which a confidential guest checks the integrity of the root
+    /// filesystem of the image, such as its dm-verity root hash. Only used by Cloud Hypervisor.
+    ///
+    /// Confidential guests boot from the image as a read-only disk, and these parameters are
+    /// added to the kernel parameters, so are measured along with them.
+    #[serde(default)]
+    pub rootfs_verity_params: String,
     /// Path to the firmware.
     ///
     /// If you want that qemu uses the default firmware leave this option empty.
     #[serde(default)]
     pub firmware: String,
+    /// What to do when the firmware file is missing. Only used by Cloud Hypervisor.
+    ///
+    /// - "fail" (default): fail to start the VM
+    /// - "kernel": boot the kernel directly, which is refused for confidential guests
+    #[serde(default)]
+    pub missing_firmware_policy: String,
+    /// Path of a snapshot directory to restore the VM from, instead of booting it. Only used by
+    /// Cloud Hypervisor.
+    ///
+    /// The snapshot must have been saved with the same hypervisor version and devices.
+    #[serde(default)]
+    pub restore_snapshot_path: String,
+    /// Prefault the guest memory when restoring the VM from a snapshot, default false.
+    ///
+    /// This makes the restore slower but avoids page faults when the VM resumes.
+    #[serde(default)]
+    pub restore_prefault: bool,
+    /// Compute the SHA-256 digests of the files the guest boots from, for attestation, default
+    /// false. Only used by Cloud Hypervisor.
+    ///
+    /// Setting any of the expected digests below implies it.
+    #[serde(default)]
+    pub measure_boot_files: bool,
+    /// Expected SHA-256 digest of the guest kernel, in hexadecimal. Only used by Cloud
+    /// Hypervisor.
+    ///
+    /// Starting the VM fails if the digest of the file does not match. The default empty digest
+    /// is not checked, which also applies to the other expected digests.
+    #[serde(default)]
+    pub kernel_sha256: String,
+    /// Expected SHA-256 digest of the initrd, in hexadecimal.
+    #[serde(default)]
+    pub initrd_sha256: String,
+    /// Expected SHA-256 digest of the root device image, in hexadecimal.
+    #[serde(default)]
+    pub image_sha256: String,
+    /// Expected SHA-256 digest of the firmware, in hexadecimal.
+    #[serde(default)]
+    pub firmware_sha256: String,
+    /// List of guest kernel parameter names which can be appended through annotations.
+    ///
+    /// An entry ending with "*" matches any parameter name starting with the entry. The default
+    /// if not set is empty (all parameters accepted, except the denied ones.)
+    #[serde(default)]
+    pub valid_annotation_kernel_params: Vec<String>,
+    /// List of guest kernel parameter names which cannot be appended through annotations, in
+    /// addition to `KERNEL_PARAMS_ANNOTATION_DENYLIST`.
+    ///
+    /// An entry ending with "*" matches any parameter name starting with the entry.
+    #[serde(default)]
+    pub denied_annotation_kernel_params: Vec<String>,
 }
 
 impl BootInfo {
@@ -229,7 +517,13 @@ impl BootInfo {
         resolve_path!(self.kernel, "guest kernel image file {} is invalid: {}")?;
         resolve_path!(self.image, "guest boot image file {} is invalid: {}")?;
         resolve_path!(self.initrd, "guest initrd image file {} is invalid: {}")?;
-        resolve_path!(self.firmware, "firmware image file {} is invalid: {}")?;
+        if !self.firmware_may_be_missing() {
+            resolve_path!(self.firmware, "firmware image file {} is invalid: {}")?;
+        }
+        resolve_path!(
+            self.restore_snapshot_path,
+            "snapshot directory {} is invalid: {}"
+        )?;
         Ok(())
     }
 
@@ -238,13 +532,41 @@ impl BootInfo {
         validate_path!(self.kernel, "guest kernel image file {} is invalid: {}")?;
         validate_path!(self.image, "guest boot image file {} is invalid: {}")?;
         validate_path!(self.initrd, "guest initrd image file {} is invalid: {}")?;
-        validate_path!(self.firmware, "firmware image file {} is invalid: {}")?;
+        if !self.firmware_may_be_missing() {
+            validate_path!(self.firmware, "firmware image file {} is invalid: {}")?;
+        }
+        validate_path!(
+            self.restore_snapshot_path,
+            "snapshot directory {} is invalid: {}"
+        )?;
         if !self.image.is_empty() && !self.initrd.is_empty() {
             return Err(eother!("Can not configure both initrd and image for boot"));
         }
+        for (name, digest) in [
+            ("kernel", &self.kernel_sha256),
+            ("initrd", &self.initrd_sha256),
+            ("image", &self.image_sha256),
+            ("firmware", &self.firmware_sha256),
+        ] {
+            if !digest.is_empty()
+                && (digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()))
+            {
+                return Err(eother!(
+                    "Invalid expected SHA-256 digest {:?} of the {}",
+                    digest,
+                    name
+                ));
+            }
+        }
         Ok(())
     }
 
+    /// Return true if a missing firmware file is left for the hypervisor to handle.
+    fn firmware_may_be_missing(&self) -> bool {
+        self.missing_firmware_policy == MISSING_FIRMWARE_KERNEL
+            && !Path::new(&self.firmware).exists()
+    }
+
     /// Add kernel parameters to bootinfo. It is always added before the original
     /// to let the original one takes priority
     pub fn add_kernel_params(&mut self, params: Vec<String>) {
@@ -255,6 +577,59 @@ impl BootInfo {
         self.kernel_params = p.join(KERNEL_PARAM_DELIMITER);
     }
 
+    /// Append guest kernel parameters from an annotation.
+    ///
+    /// The parameters must be allowed by the configured allowlist and denylist, and cannot
+    /// redefine the configured parameters.
+    pub fn append_annotation_kernel_params(&mut self, params: &str) -> Result<()> {
+        for param in params.split_whitespace() {
+            validate_kernel_param(param)?;
+
+            let name = kernel_param_name(param);
+            let denied = KERNEL_PARAMS_ANNOTATION_DENYLIST
+                .iter()
+                .copied()
+                .chain(
+                    self.denied_annotation_kernel_params
+                        .iter()
+                        .map(|p| p.as_str()),
+                )
+                .any(|p| kernel_param_matches(name, p));
+            if denied {
+                return Err(eother!(
+                    "Kernel parameter {:?} cannot be set by annotation",
+                    name
+                ));
+            }
+            if !self.valid_annotation_kernel_params.is_empty()
+                && !self
+                    .valid_annotation_kernel_params
+                    .iter()
+                    .any(|p| kernel_param_matches(name, p))
+            {
+                return Err(eother!(
+                    "Kernel parameter {:?} is not allowed by annotation",
+                    name
+                ));
+            }
+            if self
+                .kernel_params
+                .split_whitespace()
+                .any(|p| kernel_param_name(p) == name)
+            {
+                return Err(eother!(
+                    "Kernel parameter {:?} conflicts with the configured kernel parameters",
+                    name
+                ));
+            }
+        }
+
+        let params: Vec<&str> = params.split_whitespace().collect();
+        self.kernel_params =
+            merge_kernel_params(&self.kernel_params, &params.join(KERNEL_PARAM_DELIMITER));
+        Ok(())
+    }
+
     /// Validate guest kernel image annotaion
     pub fn validate_boot_path(&self, path: &str) -> Result<()> {
         validate_path!(path, "path {} is invalid{}")?;
@@ -300,6 +675,20 @@ pub struct CpuInfo {
     /// NOTICE: on arm platform with gicv2 interrupt controller, set it to 8.
     #[serde(default)]
     pub default_maxvcpus: u32,
+
+    /// Maximum number of vCPUs which may be hot added to a SB/VM, on top of the vCPUs it booted
+    /// with. Only used by Cloud Hypervisor.
+    ///
+    /// This is a policy limit below default_maxvcpus: resizing the vCPUs beyond it fails. The
+    /// default 0 means no limit other than default_maxvcpus.
+    #[serde(default)]
+    pub vcpu_hotplug_quota: u32,
+
+    /// Delay in milliseconds before requesting the removal of vCPUs again, when the guest did not
+    /// release them in time. The default 0 means 1000 milliseconds. Only used by Cloud
+    /// Hypervisor.
+    #[serde(default)]
+    pub vcpu_shrink_retry_delay_ms: u32,
 }
 
 impl CpuInfo {
@@ -365,6 +754,50 @@ pub struct DebugInfo {
     /// much disk space.
     #[serde(default)]
     pub guest_memory_dump_path: String,
+
+    /// Capture the guest console output to a log file in the sandbox directory.
+    ///
+    /// Only supported by Cloud Hypervisor. The console is also captured when `enable_debug`
+    /// is set.
+    #[serde(default)]
+    pub log_guest_console: bool,
+
+    /// Maximum size in MiB of the guest console log retained from a previous run of the
+    /// hypervisor.
+    #[serde(default)]
+    pub guest_console_log_max_size_mb: u32,
+
+    /// Type of the guest console device, only used by Cloud Hypervisor:
+    /// - serial (default): legacy serial port, i.e. ttyS0 in the guest
+    /// - virtio: virtio-console, i.e. hvc0 in the guest
+    /// - off: no guest console
+    #[serde(default)]
+    pub console_type: String,
+
+    /// Stream the guest console over a unix socket in the sandbox directory, next to the
+    /// hybrid vsock socket, instead of logging it to a file.
+    ///
+    /// Only supported by Cloud Hypervisor, with the serial console type.
+    #[serde(default)]
+    pub console_socket: bool,
+
+    /// Size in KiB of a buffer in memory retaining the end of the guest console, which can be
+    /// read even after the VM stopped. The default 0 disables it.
+    ///
+    /// Only supported by Cloud Hypervisor, with `console_socket`: the runtime then reads the
+    /// socket, which is no longer available to other readers.
+    #[serde(default)]
+    pub console_buffer_size_kb: u32,
+
+    /// Verbosity of the Cloud Hypervisor log, from 0 (warnings only) to 3 (trace).
+    ///
+    /// When `enable_debug` is set, the log is also written to a file in the sandbox directory.
+    #[serde(default)]
+    pub ch_log_level: u32,
+
+    /// Keep the hypervisor and guest console log files when the sandbox is removed.
+    #[serde(default)]
+    pub keep_logs: bool,
 }
 
 impl DebugInfo {
@@ -424,6 +857,33 @@ pub struct DeviceInfo {
     /// Enabling this will result in the VM device having iommu_platform=on set
     #[serde(default)]
     pub enable_iommu_platform: bool,
+
+    /// Number of PCI segments (PCI host bridges) of the VM.
+    ///
+    /// The default 0 value means one segment, or as many as required by the devices.
+    #[serde(default)]
+    pub num_pci_segments: u16,
+
+    /// PCI segments whose devices are all placed behind the virtual IOMMU.
+    ///
+    /// The default if not set is the segments of the devices requiring the IOMMU.
+    #[serde(default)]
+    pub iommu_segments: Vec<u16>,
+
+    /// Types of the devices placed behind the virtual IOMMU, which requires `enable_iommu`.
+    /// Only used by Cloud Hypervisor.
+    ///
+    /// Valid types are "block", "net", "pmem", "vdpa", "vfio" and "vsock".
+    #[serde(default)]
+    pub iommu_devices: Vec<String>,
+
+    /// PCI hotplug mechanism supported by the guest kernel. Only used by Cloud Hypervisor.
+    ///
+    /// Valid values are "acpi" (default), "native" and "none". Cloud Hypervisor only provides
+    /// ACPI hotplug, which requires a guest kernel built with CONFIG_HOTPLUG_PCI_ACPI. With
+    /// "none", devices can only be cold plugged, and adding a device once the VM started fails.
+    #[serde(default)]
+    pub guest_pci_hotplug: String,
 }
 
 impl DeviceInfo {
@@ -481,6 +941,93 @@ pub struct MachineInfo {
     /// The default if not set is empty (all annotations rejected.)
     #[serde(default)]
     pub valid_entropy_sources: Vec<String>,
+
+    /// Make the guest entropy deterministic, for reproducible test environments such as CI: the
+    /// guest kernel does not seed its random number generator from the CPU or the bootloader
+    /// (`random.trust_cpu=off random.trust_bootloader=off`), and the entropy device reads from
+    /// `guest_entropy_seed_file`, if set.
+    ///
+    /// WARNING: this weakens the guest entropy, so that secrets generated by the guest may be
+    /// predictable. Never enable it outside of tests. Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub deterministic_guest_entropy: bool,
+
+    /// File of fixed data the entropy device of the guest reads from, instead of
+    /// `entropy_source`, when `deterministic_guest_entropy` is set. The device provides no data
+    /// once the end of the file is read, so the file must be large enough for the guest. Only
+    /// used by Cloud Hypervisor.
+    #[serde(default)]
+    pub guest_entropy_seed_file: String,
+
+    /// SMBIOS system serial number exposed to the guest.
+    ///
+    /// Only supported by Cloud Hypervisor. Some workloads key their licensing off the
+    /// SMBIOS/DMI fields.
+    #[serde(default)]
+    pub smbios_serial_number: String,
+
+    /// SMBIOS system UUID exposed to the guest, only supported by Cloud Hypervisor.
+    #[serde(default)]
+    pub smbios_uuid: String,
+
+    /// SMBIOS OEM strings exposed to the guest, only supported by Cloud Hypervisor.
+    #[serde(default)]
+    pub smbios_oem_strings: Vec<String>,
+
+    /// Guest kernel clock source, e.g. "kvm-clock" or "tsc".
+    ///
+    /// If empty, the guest kernel selects the clock source.
+    #[serde(default)]
+    pub guest_clock_source: String,
+
+    /// Offset in seconds of the guest clock relative to the host clock.
+    ///
+    /// Useful for time-sensitive testing. Cloud Hypervisor cannot apply an RTC offset, so the
+    /// value is only validated and recorded for the guest side to apply.
+    #[serde(default)]
+    pub guest_clock_offset: i64,
+
+    /// Host virtualization device used by the hypervisor, "kvm" or "mshv".
+    ///
+    /// The default empty value lets the hypervisor use any available device.
+    #[serde(default)]
+    pub hypervisor_device: String,
+
+    /// Press the ACPI power button when stopping the VM, letting the guest shut down cleanly
+    /// before the hypervisor is asked to stop.
+    ///
+    /// Only supported by Cloud Hypervisor.
+    #[serde(default)]
+    pub acpi_shutdown: bool,
+
+    /// Time in seconds to wait for the guest to power off after the ACPI power button was
+    /// pressed, only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub acpi_shutdown_timeout_secs: u32,
+
+    /// Time in seconds to wait for the hypervisor to exit after requesting it to shut down,
+    /// before terminating it. Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub vmm_shutdown_timeout_secs: u32,
+
+    /// Time in seconds to wait for the hypervisor to exit after sending it SIGTERM, before
+    /// killing it. Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub vmm_terminate_timeout_secs: u32,
+
+    /// Time in seconds to wait for the VM to start, up to the guest agent accepting connections.
+    ///
+    /// If the VM does not start in time, the error reports the boot stages reached. The default
+    /// 0 uses the timeout of the runtime. Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub vm_start_timeout_secs: u32,
+
+    /// Have the hypervisor report its lifecycle events, such as the VM being paused or a device
+    /// being removed, so that the runtime tracks them as they happen.
+    ///
+    /// Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub enable_event_monitor: bool,
 }
 
 impl MachineInfo {
@@ -523,6 +1070,21 @@ pub struct MemoryInfo {
     #[serde(default)]
     pub default_memory: u32,
 
+    /// Default maximum memory in MiB per SB/VM.
+    ///
+    /// This is the limit up to which memory can be hot added to the sandbox/VM. Unspecified or
+    /// 0 means the amount of physical RAM of the host.
+    #[serde(default)]
+    pub default_maxmemory: u32,
+
+    /// Maximum memory in MiB which may be hot added to a SB/VM, on top of the memory it booted
+    /// with. Only used by Cloud Hypervisor.
+    ///
+    /// This is a policy limit below default_maxmemory: resizing the memory beyond it fails. The
+    /// default 0 means no limit other than default_maxmemory.
+    #[serde(default)]
+    pub memory_hotplug_quota_mb: u32,
+
     /// Default memory slots per SB/VM.
     ///
     /// This is will determine the times that memory will be hotadded to sandbox/VM.
@@ -559,6 +1121,30 @@ pub struct MemoryInfo {
     #[serde(default)]
     pub enable_hugepages: bool,
 
+    /// Host NUMA node the VM RAM is allocated from, and whose CPUs run the vCPUs. Only used by
+    /// Cloud Hypervisor.
+    ///
+    /// The guest then has a single NUMA node. Starting the VM fails if the host node does not
+    /// exist or has not enough free memory for the VM RAM. The default places the VM on any
+    /// node.
+    #[serde(default)]
+    pub host_numa_node: Option<u32>,
+
+    /// Size in MiB of the huge pages backing the VM RAM when enable_hugepages is set. Only used
+    /// by Cloud Hypervisor.
+    ///
+    /// The default 0 uses the default huge page size of the host. The huge pages requested by a
+    /// pod take precedence.
+    #[serde(default)]
+    pub hugepage_size_mb: u32,
+
+    /// Back the VM RAM with transparent huge pages, default false. Only used by Cloud Hypervisor.
+    ///
+    /// Unlike enable_hugepages, this does not require huge pages to be reserved on the host. It
+    /// is ignored if enable_hugepages is set.
+    #[serde(default)]
+    pub enable_thp: bool,
+
     /// Specifies virtio-mem will be enabled or not.
     ///
     /// Please note that this option should be used with the command
@@ -566,6 +1152,30 @@ pub struct MemoryInfo {
     #[serde(default)]
     pub enable_virtio_mem: bool,
 
+    /// Allow the memory hot added to a SB/VM to be removed, default false. Only used by Cloud
+    /// Hypervisor, and requires enable_virtio_mem.
+    ///
+    /// When disabled, resizing the memory of a SB/VM below its current size fails.
+    #[serde(default)]
+    pub enable_memory_unplug: bool,
+
+    /// Allow memory to be hot added to confidential (TDX or SEV-SNP) guests, default false. Only
+    /// used by Cloud Hypervisor.
+    ///
+    /// The private memory of these guests is sized statically by default, as hot adding it
+    /// requires a hypervisor and a guest kernel which accept it into the guest. Only enable it
+    /// with such versions.
+    #[serde(default)]
+    pub enable_confidential_memory_hotplug: bool,
+
+    /// Memory in MiB kept on top of the memory a SB/VM booted with, and on top of the memory
+    /// used by the guest when known, when its memory is removed. Only used by Cloud Hypervisor.
+    ///
+    /// Resizing the memory below this floor only removes the memory above it. The default 0
+    /// keeps no reserve.
+    #[serde(default)]
+    pub memory_shrink_reserve_mb: u32,
+
     /// Enable swap of vm memory. Default false.
     ///
     /// The behaviour is undefined if mem_prealloc is also set to true
@@ -584,6 +1194,53 @@ pub struct MemoryInfo {
     /// If swap_in_bytes and memory_limit_in_bytes is not set, the size should be default_memory.
     #[serde(default)]
     pub enable_guest_swap: bool,
+
+    /// Sizes in MiB of the SGX EPC (Enclave Page Cache) sections exposed to the guest.
+    ///
+    /// The default is an empty list, which disables SGX support for the guest.
+    #[serde(default)]
+    pub sgx_epc_sections_mb: Vec<u32>,
+
+    /// Prefault the SGX EPC sections when the guest is created, default false.
+    #[serde(default)]
+    pub sgx_epc_prefault: bool,
+
+    /// Create the guest with a virtio-balloon device, default false.
+    #[serde(default)]
+    pub enable_balloon: bool,
+
+    /// Initial size in MiB of the balloon, default 0.
+    #[serde(default)]
+    pub balloon_size_mb: u32,
+
+    /// Deflate the balloon when the guest is out of memory, default false.
+    #[serde(default)]
+    pub balloon_deflate_on_oom: bool,
+
+    /// Enable free page reporting from the guest to the balloon, default false.
+    ///
+    /// This allows the host to reclaim memory freed by the guest, at the cost of a
+    /// measurable overhead in the guest.
+    #[serde(default)]
+    pub balloon_free_page_reporting: bool,
+
+    /// Maximum percentage of the guest free memory the balloon may reclaim when the memory of a
+    /// SB/VM is shrunk without virtio-mem. Only used by Cloud Hypervisor, and requires
+    /// enable_balloon.
+    ///
+    /// Without virtio-mem, memory cannot be removed from the guest, so shrinking it inflates the
+    /// balloon instead. The default 0 disables this.
+    #[serde(default)]
+    pub balloon_reclaim_free_percent: u32,
+
+    /// Maximum percentage of the guest free memory the balloon may reclaim when the runtime
+    /// hints that memory is no longer needed, such as when a container of a SB/VM exits. Only
+    /// used by Cloud Hypervisor, and requires enable_balloon.
+    ///
+    /// The memory reclaimed on a hint is given back to the guest when a later memory resize
+    /// needs it. The default 0 ignores the hints.
+    #[serde(default)]
+    pub balloon_hint_free_percent: u32,
 }
 
 impl MemoryInfo {
@@ -608,6 +1265,46 @@ impl MemoryInfo {
         if self.memory_slots == 0 {
             return Err(eother!("Configured memory slots for guest VM are zero"));
         }
+        if self.default_maxmemory != 0 && self.default_maxmemory < self.default_memory {
+            return Err(eother!(
+                "Configured maximum memory {} MiB for guest VM is smaller than the memory size {} MiB",
+                self.default_maxmemory,
+                self.default_memory
+            ));
+        }
+        if self.enable_memory_unplug && !self.enable_virtio_mem {
+            return Err(eother!(
+                "Removing memory from the guest VM requires virtio-mem to be enabled"
+            ));
+        }
+        if self.hugepage_size_mb != 0 && !self.hugepage_size_mb.is_power_of_two() {
+            return Err(eother!(
+                "Invalid huge page size {} MiB, not a power of two",
+                self.hugepage_size_mb
+            ));
+        }
+        if self.balloon_reclaim_free_percent > 100 {
+            return Err(eother!(
+                "Invalid balloon reclaim percentage {} of the guest free memory",
+                self.balloon_reclaim_free_percent
+            ));
+        }
+        if self.balloon_reclaim_free_percent != 0 && !self.enable_balloon {
+            return Err(eother!(
+                "Reclaiming guest memory with the balloon requires the balloon to be enabled"
+            ));
+        }
+        if self.balloon_hint_free_percent > 100 {
+            return Err(eother!(
+                "Invalid balloon hint percentage {} of the guest free memory",
+                self.balloon_hint_free_percent
+            ));
+        }
+        if self.balloon_hint_free_percent != 0 && !self.enable_balloon {
+            return Err(eother!(
+                "Reclaiming guest memory on hints requires the balloon to be enabled"
+            ));
+        }
 
         Ok(())
     }
@@ -646,6 +1343,40 @@ pub struct NetworkInfo {
     /// network queues
     #[serde(default)]
     pub network_queues: u32,
+
+    /// Size of the receive virtqueues of net devices, a power of two from 2 to 32768. The
+    /// default 0 means the size of the transmit virtqueues if set, and the hypervisor default
+    /// otherwise.
+    #[serde(default)]
+    pub rx_queue_size: u32,
+
+    /// Size of the transmit virtqueues of net devices, a power of two from 2 to 32768. The
+    /// default 0 means the size of the receive virtqueues if set, and the hypervisor default
+    /// otherwise.
+    ///
+    /// Cloud Hypervisor uses a single size for both, so different sizes are rejected.
+    #[serde(default)]
+    pub tx_queue_size: u32,
+
+    /// Maximum bandwidth of each net device, in both directions, in bits/sec. The default 0
+    /// means unlimited.
+    #[serde(default)]
+    pub net_rate_limiter_bw_max_rate: u64,
+
+    /// Bits each net device may transfer once on top of net_rate_limiter_bw_max_rate, to absorb
+    /// short spikes. Cannot be smaller than the rate. The default 0 means no burst.
+    #[serde(default)]
+    pub net_rate_limiter_bw_one_time_burst: u64,
+
+    /// Maximum packets of each net device, in both directions, in packets/sec. The default 0
+    /// means unlimited.
+    #[serde(default)]
+    pub net_rate_limiter_ops_max_rate: u64,
+
+    /// Packets each net device may transfer once on top of net_rate_limiter_ops_max_rate, to
+    /// absorb short spikes. Cannot be smaller than the rate. The default 0 means no burst.
+    #[serde(default)]
+    pub net_rate_limiter_ops_one_time_burst: u64,
 }
 
 impl NetworkInfo {
@@ -656,6 +1387,32 @@ impl NetworkInfo {
 
     /// Validate the configuration information.
     pub fn validate(&self) -> Result<()> {
+        for (queue, size) in [("rx", self.rx_queue_size), ("tx", self.tx_queue_size)] {
+            if size != 0
+                && (!size.is_power_of_two()
+                    || !(MIN_NET_QUEUE_SIZE..=MAX_NET_QUEUE_SIZE).contains(&size))
+            {
+                return Err(eother!(
+                    "Invalid net {} queue size {}, not a power of two from {} to {}",
+                    queue,
+                    size,
+                    MIN_NET_QUEUE_SIZE,
+                    MAX_NET_QUEUE_SIZE
+                ));
+            }
+        }
+
+        validate_rate_limiter(
+            "net_rate_limiter_bw",
+            self.net_rate_limiter_bw_max_rate,
+            self.net_rate_limiter_bw_one_time_burst,
+        )?;
+        validate_rate_limiter(
+            "net_rate_limiter_ops",
+            self.net_rate_limiter_ops_max_rate,
+            self.net_rate_limiter_ops_one_time_burst,
+        )?;
+
         Ok(())
     }
 }
@@ -674,6 +1431,84 @@ pub struct SecurityInfo {
     #[serde(default)]
     pub disable_seccomp: bool,
 
+    /// Seccomp filtering mode of the hypervisor process, only used by Cloud Hypervisor:
+    /// - true: enable seccomp filtering
+    /// - log: log the offending system calls instead of killing the hypervisor
+    /// - false: disable seccomp filtering
+    ///
+    /// If not set, seccomp filtering is disabled, as Cloud Hypervisor was always launched
+    /// without it before this option existed.
+    #[serde(default)]
+    pub seccomp: String,
+
+    /// Enable Landlock filesystem sandboxing of the hypervisor process, only used by Cloud
+    /// Hypervisor.
+    ///
+    /// The list of paths the hypervisor may access is computed by the runtime from the
+    /// configured boot files and devices.
+    #[serde(default)]
+    pub enable_landlock: bool,
+
+    /// Confine the hypervisor to a chroot below the sandbox directory, in its own mount
+    /// namespace and with its capabilities dropped.
+    ///
+    /// Only the boot files, the sandbox directory and the paths of the configured devices are
+    /// made available in the chroot. The hypervisor binary must be statically linked.
+    ///
+    /// Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub enable_jail: bool,
+
+    /// Fail to start the hypervisor if Landlock is enabled but not supported by the host kernel.
+    ///
+    /// If false, the hypervisor is started without Landlock and a warning is logged.
+    #[serde(default)]
+    pub landlock_strict: bool,
+
+    /// Cgroup the hypervisor process and its helper processes are moved into once started, only
+    /// used by Cloud Hypervisor.
+    ///
+    /// The path is relative to the cgroup filesystem root and the cgroup must already exist. With
+    /// cgroup v1, the processes are moved into every hierarchy where the cgroup exists. The
+    /// default is an empty string, which leaves the processes in the cgroup of the runtime.
+    #[serde(default)]
+    pub vmm_cgroup_path: String,
+
+    /// User id allowed to use the API socket of the hypervisor, only used by Cloud Hypervisor.
+    ///
+    /// The socket file is restricted to that user, and the runtime refuses to drive an API
+    /// socket whose peer runs as another user. Defaults to the user id of the runtime.
+    #[serde(default)]
+    pub api_socket_uid: Option<u32>,
+
+    /// User id the hypervisor process runs as, only used by Cloud Hypervisor.
+    ///
+    /// The runtime must run as root to switch to that user. The sandbox directory and the sockets
+    /// used by the hypervisor are given to the user. If not set, the hypervisor runs as the user
+    /// of the runtime. Devices requiring extra privileges, such as VFIO devices, cannot be used.
+    #[serde(default)]
+    pub vmm_uid: Option<u32>,
+
+    /// Group id the hypervisor process runs as, only used by Cloud Hypervisor.
+    ///
+    /// Must be set along with `vmm_uid`. The members of the group, such as the shim, can use the
+    /// sockets of the hypervisor.
+    #[serde(default)]
+    pub vmm_gid: Option<u32>,
+
+    /// OOM score adjustment of the hypervisor process, from -1000 (never killed by the OOM
+    /// killer) to 1000 (killed first), only used by Cloud Hypervisor.
+    ///
+    /// If not set, the hypervisor inherits the adjustment of the runtime. Lowering it requires
+    /// the CAP_SYS_RESOURCE capability.
+    #[serde(default)]
+    pub vmm_oom_score_adj: Option<i32>,
+
+    /// Apply `vmm_oom_score_adj` to the helper processes of the hypervisor as well, such as
+    /// virtiofsd, so that they share its fate under memory pressure.
+    #[serde(default)]
+    pub helper_oom_score_adj: bool,
+
     /// Enable confidential guest support.
     ///
     /// Toggling that setting may trigger different hardware features, ranging from memory
@@ -683,6 +1518,46 @@ pub struct SecurityInfo {
     #[serde(default)]
     pub confidential_guest: bool,
 
+    /// Socket of the TDX quote generation service (QGS) on the host, which attestation clients
+    /// in the guest request quotes from, either "unix:<path>" or "<path>". Requires
+    /// confidential_guest. Only used by Cloud Hypervisor, whose hybrid vsock only connects the
+    /// guest to unix sockets.
+    #[serde(default)]
+    pub tdx_quote_generation_socket: String,
+
+    /// Vsock port on which the guest reaches the TDX quote generation service, 0 for the default
+    /// port 4050. Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub tdx_quote_generation_port: u32,
+
+    /// Run the confidential guest as an AMD SEV-SNP guest rather than as an Intel TDX guest.
+    /// Requires confidential_guest. Only used by Cloud Hypervisor, which loads the firmware as an
+    /// IGVM file.
+    #[serde(default)]
+    pub sev_snp_guest: bool,
+
+    /// Data the host provides to the SEV-SNP guest, reported in its attestation reports, as 32
+    /// bytes in hexadecimal or base64. Requires sev_snp_guest. Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub snp_host_data: String,
+
+    /// Identifier of the TDX guest configuration (MRCONFIGID), bound into its launch
+    /// measurement, as 48 bytes in hexadecimal or base64. Requires a TDX confidential guest.
+    /// Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub tdx_mrconfigid: String,
+
+    /// Identifier of the owner of the TDX guest (MROWNER), as 48 bytes in hexadecimal or base64.
+    /// Requires a TDX confidential guest. Only used by Cloud Hypervisor.
+    #[serde(default)]
+    pub tdx_mrowner: String,
+
+    /// Identifier of the configuration of the owner of the TDX guest (MROWNERCONFIG), as 48
+    /// bytes in hexadecimal or base64. Requires a TDX confidential guest. Only used by Cloud
+    /// Hypervisor.
+    #[serde(default)]
+    pub tdx_mrownerconfig: String,
+
     /// Path to OCI hook binaries in the *guest rootfs*.
     ///
     /// This does not affect host-side hooks which must instead be added to the OCI spec passed to
@@ -723,9 +1598,89 @@ impl SecurityInfo {
 
     /// Validate the configuration information.
     pub fn validate(&self) -> Result<()> {
+        if let Some(adj) = self.vmm_oom_score_adj {
+            if !(MIN_OOM_SCORE_ADJ..=MAX_OOM_SCORE_ADJ).contains(&adj) {
+                return Err(eother!(
+                    "Invalid OOM score adjustment {}, not from {} to {}",
+                    adj,
+                    MIN_OOM_SCORE_ADJ,
+                    MAX_OOM_SCORE_ADJ
+                ));
+            }
+        }
+
+        if self.sev_snp_guest && !self.confidential_guest {
+            return Err(eother!("SEV-SNP guests require confidential_guest"));
+        }
+
+        self.get_launch_data()?;
+
+        let qgs_socket = self.tdx_quote_generation_socket.as_str();
+
+        if qgs_socket.is_empty() {
+            return Ok(());
+        }
+
+        if !self.confidential_guest || self.sev_snp_guest {
+            return Err(eother!(
+                "TDX quote generation socket {} requires a TDX confidential guest",
+                qgs_socket
+            ));
+        }
+
+        if qgs_socket.starts_with("vsock:") {
+            return Err(eother!(
+                "TDX quote generation socket {} is not supported, only unix sockets are",
+                qgs_socket
+            ));
+        }
+
+        let path = qgs_socket.strip_prefix("unix:").unwrap_or(qgs_socket);
+
+        if !path.starts_with('/') {
+            return Err(eother!(
+                "TDX quote generation socket {} is not an absolute path",
+                qgs_socket
+            ));
+        }
+
+        validate_path!(path, "TDX quote generation socket {} is invalid: {}")?;
+
         Ok(())
     }
 
+    /// Return the attestation data to bind into the launch measurement of the guest, decoded,
+    /// by configuration field. Fails if a field is set for another kind of guest, or has an
+    /// invalid encoding or size.
+    pub fn get_launch_data(&self) -> Result<Vec<(&'static str, Vec<u8>)>> {
+        let snp = self.confidential_guest && self.sev_snp_guest;
+        let tdx = self.confidential_guest && !self.sev_snp_guest;
+
+        let snp_fields = [("snp_host_data", &self.snp_host_data, SNP_HOST_DATA_SIZE)];
+        let tdx_fields = [
+            ("tdx_mrconfigid", &self.tdx_mrconfigid, TDX_MR_SIZE),
+            ("tdx_mrowner", &self.tdx_mrowner, TDX_MR_SIZE),
+            ("tdx_mrownerconfig", &self.tdx_mrownerconfig, TDX_MR_SIZE),
+        ];
+
+        let mut launch_data = vec![];
+
+        for (supported, fields) in [(snp, &snp_fields[..]), (tdx, &tdx_fields[..])] {
+            for (name, value, size) in fields.iter().filter(|(_, v, _)| !v.is_empty()) {
+                if !supported {
+                    return Err(eother!(
+                        "{} is not supported by this kind of guest, check confidential_guest and sev_snp_guest",
+                        name
+                    ));
+                }
+
+                launch_data.push((*name, decode_launch_data(name, value, *size)?));
+            }
+        }
+
+        Ok(launch_data)
+    }
+
     /// Check whether annotation key is enabled or not.
     pub fn is_annotation_enabled(&self, path: &str) -> bool {
         if !path.starts_with(KATA_ANNO_CFG_HYPERVISOR_PREFIX) {
@@ -781,6 +1736,24 @@ pub struct SharedFsInfo {
     #[serde(default)]
     pub virtio_fs_cache: String,
 
+    /// Size of the thread pool of the virtio-fs daemon.
+    ///
+    /// If not set, the default of the daemon is used. Ignored if virtio_fs_extra_args already
+    /// specify the size of the thread pool.
+    #[serde(default)]
+    pub virtio_fs_thread_pool_size: Option<u32>,
+
+    /// Sandbox mode of the virtio-fs daemon, confining it to the shared directory:
+    /// - namespace: the daemon runs in its own mount, pid and network namespaces, which
+    ///   requires it to be started as root.
+    /// - chroot: the daemon chroots into the shared directory.
+    /// - none: the daemon is not confined.
+    ///
+    /// If not set, the most secure mode supported by the daemon and the runtime privileges is
+    /// used.
+    #[serde(default)]
+    pub virtio_fs_sandbox: String,
+
     /// Default size of DAX cache in MiB
     #[serde(default)]
     pub virtio_fs_cache_size: u32,
@@ -793,6 +1766,20 @@ pub struct SharedFsInfo {
     #[serde(default)]
     pub virtio_fs_is_dax: bool,
 
+    /// Fail to boot if two virtio-fs devices use the same daemon socket without being coalesced,
+    /// only used by Cloud Hypervisor.
+    ///
+    /// If false, a warning is logged.
+    #[serde(default)]
+    pub virtio_fs_strict_sockets: bool,
+
+    /// Time in milliseconds to wait for the virtio-fs daemon to listen on its socket before hot
+    /// plugging a virtio-fs device, only used by Cloud Hypervisor.
+    ///
+    /// The default 0 does not wait: adding the device fails if the daemon is not ready yet.
+    #[serde(default)]
+    pub virtio_fs_socket_wait_ms: u32,
+
     /// This is the msize used for 9p shares. It is the number of bytes used for 9p packet payload.
     #[serde(default)]
     pub msize_9p: u32,
@@ -892,6 +1879,26 @@ impl SharedFsInfo {
                 &self.virtio_fs_cache_size
             ));
         }
+        if !self.virtio_fs_sandbox.is_empty()
+            && !VIRTIO_FS_SANDBOX_MODES.contains(&self.virtio_fs_sandbox.as_str())
+        {
+            return Err(eother!(
+                "Invalid virtio-fs sandbox mode: {}",
+                &self.virtio_fs_sandbox
+            ));
+        }
+        if let Some(size) = self.virtio_fs_thread_pool_size {
+            if size == 0 {
+                return Err(eother!("Invalid virtio-fs thread pool size: {}", size));
+            }
+            let cpus = num_cpus::get() as u32;
+            if size > cpus {
+                warn!(
+                    sl!(),
+                    "virtio-fs thread pool size {} exceeds the number of host CPUs {}", size, cpus
+                );
+            }
+        }
         Ok(())
     }
 }
@@ -939,6 +1946,13 @@ pub struct Hypervisor {
     #[serde(default)]
     pub enable_iothreads: bool,
 
+    /// Size the VM once, when it is created, and disable the hotplug of vCPUs and memory. The
+    /// VM boots with default_vcpus and default_memory, which the runtime computes from the pod
+    /// spec when static_resource_mgmt is enabled, and the runtime sets this flag then. Only
+    /// used by Cloud Hypervisor.
+    #[serde(default)]
+    pub static_sandbox_resource_mgmt: bool,
+
     /// Block device configuration information.
     #[serde(default, flatten)]
     pub blockdev_info: BlockDeviceInfo,
@@ -1125,6 +2139,109 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_merge_kernel_params() {
+        assert_eq!(
+            merge_kernel_params("foo bar=1 baz", "bar=2 qux"),
+            "foo baz bar=2 qux"
+        );
+        assert_eq!(merge_kernel_params("", "foo"), "foo");
+        assert_eq!(merge_kernel_params("foo", ""), "foo");
+
+        // Parameters with several values are all kept.
+        assert_eq!(
+            merge_kernel_params("systemd.mask=a systemd.mask=b", "systemd.mask=c"),
+            "systemd.mask=a systemd.mask=b systemd.mask=c"
+        );
+        assert_eq!(
+            merge_kernel_params("systemd.mask=a", "systemd.mask=b systemd.mask=c"),
+            "systemd.mask=a systemd.mask=b systemd.mask=c"
+        );
+
+        // The extra parameters are not validated.
+        assert_eq!(
+            merge_kernel_params("foo", "bar=\"a b\" "),
+            "foo bar=\"a b\""
+        );
+    }
+
+    #[test]
+    fn test_validate_kernel_param() {
+        assert!(validate_kernel_param("foo").is_ok());
+        assert!(validate_kernel_param("foo=bar,baz").is_ok());
+
+        for param in [
+            "foo;reboot",
+            "foo=\"a",
+            "foo='a'",
+            "foo=`id`",
+            "foo=$(id)",
+            "=bar",
+            "foo\nbar",
+        ] {
+            assert!(validate_kernel_param(param).is_err(), "{}", param);
+        }
+    }
+
+    #[test]
+    fn test_append_annotation_kernel_params() {
+        let mut boot_info = BootInfo {
+            kernel_params: String::from("quiet panic=1"),
+            ..Default::default()
+        };
+
+        boot_info
+            .append_annotation_kernel_params("transparent_hugepage=never")
+            .unwrap();
+        assert_eq!(
+            boot_info.kernel_params,
+            "quiet panic=1 transparent_hugepage=never"
+        );
+
+        // Denied parameters
+        for params in [
+            "init=/bin/sh",
+            "console=ttyS1",
+            "agent.debug_console",
+            "systemd.unit=rescue.target",
+        ] {
+            assert!(boot_info.append_annotation_kernel_params(params).is_err());
+        }
+
+        // Conflict with the configured parameters
+        assert!(boot_info
+            .append_annotation_kernel_params("panic=0")
+            .is_err());
+
+        // Injection
+        assert!(boot_info
+            .append_annotation_kernel_params("foo=\"bar init=/bin/sh\"")
+            .is_err());
+        assert!(boot_info
+            .append_annotation_kernel_params("foo=bar\ninit=/bin/sh")
+            .is_err());
+
+        boot_info.denied_annotation_kernel_params = vec![String::from("mitigations")];
+        assert!(boot_info
+            .append_annotation_kernel_params("mitigations=off")
+            .is_err());
+
+        boot_info.valid_annotation_kernel_params = vec![String::from("numa_*")];
+        assert!(boot_info
+            .append_annotation_kernel_params("numa_balancing=disable")
+            .is_ok());
+        assert!(boot_info.append_annotation_kernel_params("nosmt").is_err());
+
+        // Nothing is appended on failure.
+        assert!(boot_info
+            .append_annotation_kernel_params("numa_foo nosmt")
+            .is_err());
+        assert_eq!(
+            boot_info.kernel_params,
+            "quiet panic=1 transparent_hugepage=never numa_balancing=disable"
+        );
+    }
+
     #[test]
     fn test_cpu_info_adjust_config() {
         // get CPU cores of the test node
@@ -1200,4 +2317,123 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_get_launch_data() {
+        let mut info = SecurityInfo::default();
+        assert!(info.get_launch_data().unwrap().is_empty());
+
+        // 32 bytes, in hexadecimal then in base64.
+        info.snp_host_data = "00".repeat(31) + "Ff";
+        assert!(info.get_launch_data().is_err());
+
+        info.confidential_guest = true;
+        info.sev_snp_guest = true;
+
+        let data = info.get_launch_data().unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].0, "snp_host_data");
+        assert_eq!(data[0].1[31], 0xff);
+
+        info.snp_host_data = base64::encode([1u8; 32]);
+        assert_eq!(info.get_launch_data().unwrap()[0].1, vec![1u8; 32]);
+
+        info.snp_host_data = "00".repeat(31);
+        let err = info.get_launch_data().unwrap_err().to_string();
+        assert!(err.contains("snp_host_data"), "{}", err);
+
+        // TDX fields are refused for SEV-SNP guests.
+        info.snp_host_data.clear();
+        info.tdx_mrconfigid = "ab".repeat(48);
+        assert!(info.get_launch_data().is_err());
+
+        info.sev_snp_guest = false;
+        assert_eq!(info.get_launch_data().unwrap()[0].0, "tdx_mrconfigid");
+
+        info.tdx_mrowner = "not encoded".to_string();
+        let err = info.get_launch_data().unwrap_err().to_string();
+        assert!(err.contains("tdx_mrowner"), "{}", err);
+    }
+
+    #[test]
+    fn test_decode_launch_data() {
+        assert_eq!(
+            decode_launch_data("tdx_mrowner", &"ab".repeat(48), 48).unwrap(),
+            vec![0xab; 48]
+        );
+        assert_eq!(
+            decode_launch_data("tdx_mrowner", &base64::encode([0xab; 48]), 48).unwrap(),
+            vec![0xab; 48]
+        );
+
+        // 64 hexadecimal digits are also valid base64 of 48 bytes, but are
+        // 32 bytes in hexadecimal.
+        let err = decode_launch_data("tdx_mrowner", &"0a".repeat(32), 48)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("32 bytes, expected 48"), "{}", err);
+
+        assert!(decode_launch_data("tdx_mrowner", &"a".repeat(95), 48).is_err());
+    }
+
+    #[test]
+    fn test_validate_tdx_quote_generation_socket() {
+        let dir = std::env::temp_dir();
+
+        let mut info = SecurityInfo {
+            tdx_quote_generation_socket: format!("unix:{}", dir.display()),
+            ..Default::default()
+        };
+        assert!(info.validate().is_err());
+
+        info.confidential_guest = true;
+        assert!(info.validate().is_ok());
+
+        info.tdx_quote_generation_socket = dir.display().to_string();
+        assert!(info.validate().is_ok());
+
+        info.tdx_quote_generation_socket = "vsock:4050".to_string();
+        let err = info.validate().unwrap_err().to_string();
+        assert!(err.contains("not supported"), "{}", err);
+
+        info.tdx_quote_generation_socket = "qgs.sock".to_string();
+        assert!(info.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_rate_limiter() {
+        assert!(validate_rate_limiter("disk_rate_limiter_bw", 0, 0).is_ok());
+        assert!(validate_rate_limiter("disk_rate_limiter_bw", 1000, 0).is_ok());
+        assert!(validate_rate_limiter("disk_rate_limiter_bw", 1000, 1000).is_ok());
+        assert!(validate_rate_limiter("disk_rate_limiter_bw", 1000, 5000).is_ok());
+
+        let err = validate_rate_limiter("disk_rate_limiter_bw", 0, 5000)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("requires disk_rate_limiter_bw_max_rate"),
+            "{}",
+            err
+        );
+
+        let err = validate_rate_limiter("disk_rate_limiter_bw", 1000, 999)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("smaller than"), "{}", err);
+
+        let mut info = NetworkInfo {
+            net_rate_limiter_ops_max_rate: 100,
+            net_rate_limiter_ops_one_time_burst: 50,
+            ..Default::default()
+        };
+        let err = info.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("net_rate_limiter_ops_one_time_burst"),
+            "{}",
+            err
+        );
+
+        info.net_rate_limiter_ops_one_time_burst = 200;
+        assert!(info.validate().is_ok());
+    }
 }
@@ -4,33 +4,137 @@
 
 use crate::net_util::MAC_ADDR_LEN;
 use crate::{
-    ConsoleConfig, ConsoleOutputMode, CpuTopology, CpusConfig, DeviceConfig, FsConfig, MacAddr,
-    MemoryConfig, NetConfig, PayloadConfig, PmemConfig, RngConfig, VmConfig, VsockConfig,
+    ConsoleConfig, ConsoleOutputMode, CpuTopology, CpusConfig, DeviceConfig, DiskConfig, FsConfig,
+    MacAddr, MemoryConfig, NetConfig, PayloadConfig, PmemConfig, RestoreConfig, RngConfig,
+    VmConfig, VmCounters, VmInfo, VmReceiveMigrationData, VmRemoveDeviceData, VmResize,
+    VmResizeZone, VmSendMigrationData, VmSnapshotConfig, VsockConfig,
 };
 use anyhow::{anyhow, Context, Result};
-use api_client::simple_api_full_command_and_response;
+use api_client::{
+    simple_api_full_command_and_response, simple_api_full_command_with_fds_and_response, StatusCode,
+};
 
-use std::fmt::Display;
+use std::fmt::{self, Display};
 use std::net::Ipv4Addr;
 use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
 use tokio::task;
 
+/// Error response of the CH API server.
+///
+/// Returned by the API functions as the source of their error, so that
+/// callers can use `downcast_ref()` to branch on the status code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChApiError {
+    /// HTTP status code of the response, 0 if unknown.
+    pub code: u16,
+    /// Error description returned by CH.
+    pub message: String,
+    /// API endpoint of the request, such as "vm.add-fs".
+    pub endpoint: String,
+}
+
+impl Display for ChApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} API request failed with status {}: {}",
+            self.endpoint, self.code, self.message
+        )
+    }
+}
+
+impl std::error::Error for ChApiError {}
+
+impl ChApiError {
+    fn new(endpoint: &str, status: StatusCode, body: Option<String>) -> Self {
+        let code = match status {
+            StatusCode::Continue => 100,
+            StatusCode::Ok => 200,
+            StatusCode::NoContent => 204,
+            StatusCode::BadRequest => 400,
+            StatusCode::NotFound => 404,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::Unknown => 0,
+        };
+
+        ChApiError {
+            code,
+            message: parse_error_body(body.as_deref().unwrap_or_default()),
+            endpoint: endpoint.to_string(),
+        }
+    }
+}
+
+// Convert an API client error, keeping the details of the CH error
+// responses.
+fn api_error(endpoint: &str, e: api_client::Error) -> anyhow::Error {
+    match e {
+        api_client::Error::ServerResponse(status, body) => {
+            anyhow!(ChApiError::new(endpoint, status, body))
+        }
+        e => anyhow!(e).context(format!("{} API request failed", endpoint)),
+    }
+}
+
+// CH describes errors either as plain text or as a JSON list of the
+// errors of the chain, from the outermost to the root cause.
+fn parse_error_body(body: &str) -> String {
+    match serde_json::from_str::<Vec<String>>(body) {
+        Ok(errors) => errors.join(": "),
+        Err(_) => body.trim().to_string(),
+    }
+}
+
 pub async fn cloud_hypervisor_vmm_ping(mut socket: UnixStream) -> Result<Option<String>> {
     task::spawn_blocking(move || -> Result<Option<String>> {
         let response = simple_api_full_command_and_response(&mut socket, "GET", "vmm.ping", None)
-            .map_err(|e| anyhow!(e))?;
+            .map_err(|e| api_error("vmm.ping", e))?;
 
         Ok(response)
     })
     .await?
 }
 
+pub async fn cloud_hypervisor_vm_info(mut socket: UnixStream) -> Result<VmInfo> {
+    task::spawn_blocking(move || -> Result<VmInfo> {
+        let response = simple_api_full_command_and_response(&mut socket, "GET", "vm.info", None)
+            .map_err(|e| api_error("vm.info", e))?
+            .ok_or_else(|| anyhow!("no response for vm.info"))?;
+
+        let info: VmInfo = serde_json::from_str(&response).context("invalid vm.info response")?;
+
+        Ok(info)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_counters(mut socket: UnixStream) -> Result<VmCounters> {
+    task::spawn_blocking(move || -> Result<VmCounters> {
+        let response =
+            simple_api_full_command_and_response(&mut socket, "GET", "vm.counters", None)
+                .map_err(|e| api_error("vm.counters", e))?;
+
+        // No device reports counters.
+        let response = match response {
+            Some(response) => response,
+            None => return Ok(VmCounters::new()),
+        };
+
+        let counters: VmCounters =
+            serde_json::from_str(&response).context("invalid vm.counters response")?;
+
+        Ok(counters)
+    })
+    .await?
+}
+
 pub async fn cloud_hypervisor_vmm_shutdown(mut socket: UnixStream) -> Result<Option<String>> {
     task::spawn_blocking(move || -> Result<Option<String>> {
         let response =
             simple_api_full_command_and_response(&mut socket, "PUT", "vmm.shutdown", None)
-                .map_err(|e| anyhow!(e))?;
+                .map_err(|e| api_error("vmm.shutdown", e))?;
 
         Ok(response)
     })
@@ -38,27 +142,16 @@ pub async fn cloud_hypervisor_vmm_shutdown(mut socket: UnixStream) -> Result<Opt
 }
 
 pub async fn cloud_hypervisor_vm_create(
-    sandbox_path: String,
-    vsock_socket_path: String,
     mut socket: UnixStream,
-    shared_fs_devices: Option<Vec<FsConfig>>,
-    pmem_devices: Option<Vec<PmemConfig>>,
+    cfg: VmConfig,
 ) -> Result<Option<String>> {
-    let cfg = cloud_hypervisor_vm_create_cfg(
-        sandbox_path,
-        vsock_socket_path,
-        shared_fs_devices,
-        pmem_devices,
-    )
-    .await?;
-
     let serialised = serde_json::to_string_pretty(&cfg)?;
 
     task::spawn_blocking(move || -> Result<Option<String>> {
         let data = Some(serialised.as_str());
 
         let response = simple_api_full_command_and_response(&mut socket, "PUT", "vm.create", data)
-            .map_err(|e| anyhow!(e))?;
+            .map_err(|e| api_error("vm.create", e))?;
 
         Ok(response)
     })
@@ -68,7 +161,7 @@ pub async fn cloud_hypervisor_vm_create(
 pub async fn cloud_hypervisor_vm_start(mut socket: UnixStream) -> Result<Option<String>> {
     task::spawn_blocking(move || -> Result<Option<String>> {
         let response = simple_api_full_command_and_response(&mut socket, "PUT", "vm.boot", None)
-            .map_err(|e| anyhow!(e))?;
+            .map_err(|e| api_error("vm.boot", e))?;
 
         Ok(response)
     })
@@ -80,7 +173,160 @@ pub async fn cloud_hypervisor_vm_stop(mut socket: UnixStream) -> Result<Option<S
     task::spawn_blocking(move || -> Result<Option<String>> {
         let response =
             simple_api_full_command_and_response(&mut socket, "PUT", "vm.shutdown", None)
-                .map_err(|e| anyhow!(e))?;
+                .map_err(|e| api_error("vm.shutdown", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_power_button(mut socket: UnixStream) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response =
+            simple_api_full_command_and_response(&mut socket, "PUT", "vm.power-button", None)
+                .map_err(|e| api_error("vm.power-button", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_pause(mut socket: UnixStream) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(&mut socket, "PUT", "vm.pause", None)
+            .map_err(|e| api_error("vm.pause", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_snapshot(
+    mut socket: UnixStream,
+    snapshot_config: VmSnapshotConfig,
+) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "PUT",
+            "vm.snapshot",
+            Some(&serde_json::to_string(&snapshot_config)?),
+        )
+        .map_err(|e| api_error("vm.snapshot", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_restore(
+    mut socket: UnixStream,
+    restore_config: RestoreConfig,
+) -> Result<Option<String>> {
+    // The tap fds of the net devices replace the ones of the host the
+    // snapshot was taken on.
+    let fds: Vec<i32> = restore_config
+        .net_fds
+        .iter()
+        .flatten()
+        .flat_map(|net| net.fds.iter().copied())
+        .collect();
+
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_with_fds_and_response(
+            &mut socket,
+            "PUT",
+            "vm.restore",
+            Some(&serde_json::to_string(&restore_config)?),
+            fds,
+        )
+        .map_err(|e| api_error("vm.restore", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_resume(mut socket: UnixStream) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(&mut socket, "PUT", "vm.resume", None)
+            .map_err(|e| api_error("vm.resume", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_resize(
+    mut socket: UnixStream,
+    resize: VmResize,
+) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "PUT",
+            "vm.resize",
+            Some(&serde_json::to_string(&resize)?),
+        )
+        .map_err(|e| api_error("vm.resize", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_resize_zone(
+    mut socket: UnixStream,
+    resize: VmResizeZone,
+) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "PUT",
+            "vm.resize-zone",
+            Some(&serde_json::to_string(&resize)?),
+        )
+        .map_err(|e| api_error("vm.resize-zone", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+// Note that this call only returns once the migration has completed (or
+// failed).
+pub async fn cloud_hypervisor_vm_send_migration(
+    mut socket: UnixStream,
+    data: VmSendMigrationData,
+) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "PUT",
+            "vm.send-migration",
+            Some(&serde_json::to_string(&data)?),
+        )
+        .map_err(|e| api_error("vm.send-migration", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+// Note that this call only returns once the migration has completed (or
+// failed).
+pub async fn cloud_hypervisor_vm_receive_migration(
+    mut socket: UnixStream,
+    data: VmReceiveMigrationData,
+) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "PUT",
+            "vm.receive-migration",
+            Some(&serde_json::to_string(&data)?),
+        )
+        .map_err(|e| api_error("vm.receive-migration", e))?;
 
         Ok(response)
     })
@@ -98,7 +344,43 @@ pub async fn cloud_hypervisor_vm_device_add(mut socket: UnixStream) -> Result<Op
             "vm.add-device",
             Some(&serde_json::to_string(&device_config)?),
         )
-        .map_err(|e| anyhow!(e))?;
+        .map_err(|e| api_error("vm.add-device", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_remove_device(
+    mut socket: UnixStream,
+    data: VmRemoveDeviceData,
+) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "PUT",
+            "vm.remove-device",
+            Some(&serde_json::to_string(&data)?),
+        )
+        .map_err(|e| api_error("vm.remove-device", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_disk_add(
+    mut socket: UnixStream,
+    disk_config: DiskConfig,
+) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "PUT",
+            "vm.add-disk",
+            Some(&serde_json::to_string(&disk_config)?),
+        )
+        .map_err(|e| api_error("vm.add-disk", e))?;
 
         Ok(response)
     })
@@ -116,7 +398,7 @@ pub async fn cloud_hypervisor_vm_fs_add(
             "vm.add-fs",
             Some(&serde_json::to_string(&fs_config)?),
         )
-        .map_err(|e| anyhow!(e))?;
+        .map_err(|e| api_error("vm.add-fs", e))?;
 
         Ok(response)
     })
@@ -125,6 +407,42 @@ pub async fn cloud_hypervisor_vm_fs_add(
     result
 }
 
+pub async fn cloud_hypervisor_vm_netdev_add(
+    mut socket: UnixStream,
+    net_config: NetConfig,
+) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "PUT",
+            "vm.add-net",
+            Some(&serde_json::to_string(&net_config)?),
+        )
+        .map_err(|e| api_error("vm.add-net", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
+pub async fn cloud_hypervisor_vm_pmem_add(
+    mut socket: UnixStream,
+    pmem_config: PmemConfig,
+) -> Result<Option<String>> {
+    task::spawn_blocking(move || -> Result<Option<String>> {
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "PUT",
+            "vm.add-pmem",
+            Some(&serde_json::to_string(&pmem_config)?),
+        )
+        .map_err(|e| api_error("vm.add-pmem", e))?;
+
+        Ok(response)
+    })
+    .await?
+}
+
 pub async fn cloud_hypervisor_vm_create_cfg(
     // FIXME:
     _sandbox_path: String,
@@ -272,3 +590,49 @@ where
 
     Ok(MacAddr { bytes })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_error() {
+        let e = api_error(
+            "vm.add-fs",
+            api_client::Error::ServerResponse(
+                StatusCode::InternalServerError,
+                Some(r#"["Error from API","The fs could not be added"]"#.to_string()),
+            ),
+        );
+
+        let err = e.downcast_ref::<ChApiError>().unwrap();
+
+        assert_eq!(
+            err,
+            &ChApiError {
+                code: 500,
+                message: "Error from API: The fs could not be added".to_string(),
+                endpoint: "vm.add-fs".to_string(),
+            }
+        );
+
+        let e = api_error(
+            "vm.boot",
+            api_client::Error::ServerResponse(StatusCode::BadRequest, None),
+        );
+
+        let err = e.downcast_ref::<ChApiError>().unwrap();
+        assert_eq!(err.code, 400);
+        assert_eq!(err.message, "");
+
+        let e = api_error("vmm.ping", api_client::Error::MissingProtocol);
+        assert!(e.downcast_ref::<ChApiError>().is_none());
+    }
+
+    #[test]
+    fn test_parse_error_body() {
+        assert_eq!(parse_error_body("Error from API\n"), "Error from API");
+        assert_eq!(parse_error_body(r#"["a","b","c"]"#), "a: b: c");
+        assert_eq!(parse_error_body(""), "");
+    }
+}
@@ -2,7 +2,9 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
 
@@ -10,11 +12,14 @@ pub mod ch_api;
 pub mod net_util;
 mod virtio_devices;
 
-use crate::virtio_devices::RateLimiterConfig;
+pub use crate::virtio_devices::{RateLimiterConfig, TokenBucketConfig};
 pub use net_util::MacAddr;
 
 pub const MAX_NUM_PCI_SEGMENTS: u16 = 16;
 
+// CH uses a single PCI segment unless told otherwise.
+pub const DEFAULT_NUM_PCI_SEGMENTS: u16 = 1;
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
 pub struct BalloonConfig {
     pub size: u64,
@@ -44,6 +49,8 @@ pub struct ConsoleConfig {
     pub mode: ConsoleOutputMode,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<PathBuf>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
@@ -53,6 +60,7 @@ pub enum ConsoleOutputMode {
     Pty,
     Tty,
     File,
+    Socket,
     Null,
 }
 
@@ -129,6 +137,9 @@ pub struct DiskConfig {
     // For testing use only. Not exposed in API.
     #[serde(default)]
     pub disable_io_uring: bool,
+    // For testing use only. Not exposed in API.
+    #[serde(default)]
+    pub disable_aio: bool,
     #[serde(default)]
     pub pci_segment: u16,
 }
@@ -187,6 +198,8 @@ pub struct MemoryConfig {
     #[serde(default)]
     pub prefault: bool,
     #[serde(default)]
+    pub thp: bool,
+    #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub zones: Option<Vec<MemoryZoneConfig>>,
 }
@@ -314,6 +327,35 @@ pub struct PayloadConfig {
     pub cmdline: Option<String>,
     #[serde(default)]
     pub initramfs: Option<PathBuf>,
+    /// Firmware of an SEV-SNP guest, in the IGVM format, loaded instead of
+    /// the firmware.
+    #[cfg(feature = "sev_snp")]
+    #[serde(default)]
+    pub igvm: Option<PathBuf>,
+    /// Data the host provides to an SEV-SNP guest, in hexadecimal.
+    #[cfg(feature = "sev_snp")]
+    #[serde(default)]
+    pub host_data: Option<String>,
+}
+
+impl PayloadConfig {
+    // Return the firmware the VM boots from, which SEV-SNP guests load as an
+    // IGVM file.
+    pub fn firmware_file(&self) -> Option<&PathBuf> {
+        #[cfg(feature = "sev_snp")]
+        if let Some(igvm) = self.igvm.as_ref() {
+            return Some(igvm);
+        }
+
+        self.firmware.as_ref()
+    }
+}
+
+// Returned by CH when a device is hot plugged.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct PciDeviceInfo {
+    pub id: String,
+    pub bdf: String,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
@@ -331,6 +373,20 @@ pub struct PlatformConfig {
     #[cfg(feature = "tdx")]
     #[serde(default)]
     pub tdx: bool,
+    #[cfg(feature = "sev_snp")]
+    #[serde(default)]
+    pub sev_snp: bool,
+    /// Measurement registers of a TDX guest set by the host, in
+    /// hexadecimal.
+    #[cfg(feature = "tdx")]
+    #[serde(default)]
+    pub mrconfigid: Option<String>,
+    #[cfg(feature = "tdx")]
+    #[serde(default)]
+    pub mrowner: Option<String>,
+    #[cfg(feature = "tdx")]
+    #[serde(default)]
+    pub mrownerconfig: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
@@ -348,6 +404,27 @@ pub struct PmemConfig {
     pub pci_segment: u16,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct RestoreConfig {
+    pub source_url: PathBuf,
+    #[serde(default)]
+    pub prefault: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net_fds: Option<Vec<RestoredNetConfig>>,
+}
+
+// Tap fds of a net device of a restored VM. The fds are sent along with the
+// restore request, in the order of the devices, so are not serialized.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct RestoredNetConfig {
+    pub id: String,
+    #[serde(default)]
+    pub num_fds: usize,
+    #[serde(skip)]
+    pub fds: Vec<i32>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
 pub struct RngConfig {
     pub src: PathBuf,
@@ -450,6 +527,294 @@ pub struct VmConfig {
     pub platform: Option<PlatformConfig>,
 }
 
+impl VmConfig {
+    // Check the config for the errors which CH is certain to reject, so that
+    // they are reported with some context rather than as a CH failure. All
+    // the violations found are listed in the returned error.
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::<String>::new();
+
+        if self.cpus.boot_vcpus == 0 {
+            errors.push("cpus.boot_vcpus: must be greater than zero".to_string());
+        }
+
+        if self.cpus.boot_vcpus > self.cpus.max_vcpus {
+            errors.push(format!(
+                "cpus.boot_vcpus: {} is greater than cpus.max_vcpus {}",
+                self.cpus.boot_vcpus, self.cpus.max_vcpus
+            ));
+        }
+
+        let has_memory_zones = self.memory.zones.as_ref().map_or(false, |z| !z.is_empty());
+
+        if self.memory.size == 0 && !has_memory_zones {
+            errors.push("memory.size: must be greater than zero".to_string());
+        }
+
+        if has_memory_zones && self.memory.size != 0 {
+            errors.push("memory.size: must be zero when memory zones are defined".to_string());
+        }
+
+        for zone in self.memory.zones.iter().flatten() {
+            if zone.file.is_some() && zone.hugepages {
+                errors.push(format!(
+                    "memory.zones.{}: file and hugepages are mutually exclusive",
+                    zone.id
+                ));
+            }
+        }
+
+        for node in self.numa.iter().flatten() {
+            for id in node.memory_zones.iter().flatten() {
+                if !self.memory.zones.iter().flatten().any(|z| &z.id == id) {
+                    errors.push(format!(
+                        "numa.{}: unknown memory zone {}",
+                        node.guest_numa_id, id
+                    ));
+                }
+            }
+
+            for vcpu in node.cpus.iter().flatten() {
+                if *vcpu >= self.cpus.max_vcpus {
+                    errors.push(format!(
+                        "numa.{}: vCPU {} is not lower than cpus.max_vcpus {}",
+                        node.guest_numa_id, vcpu, self.cpus.max_vcpus
+                    ));
+                }
+            }
+        }
+
+        for affinity in self.cpus.affinity.iter().flatten() {
+            if affinity.vcpu >= self.cpus.max_vcpus {
+                errors.push(format!(
+                    "cpus.affinity: vCPU {} is not lower than cpus.max_vcpus {}",
+                    affinity.vcpu, self.cpus.max_vcpus
+                ));
+            }
+        }
+
+        let has_payload = self
+            .payload
+            .as_ref()
+            .map_or(false, |p| p.kernel.is_some() || p.firmware_file().is_some());
+
+        if !has_payload && self.kernel.is_none() {
+            errors.push("payload: a kernel or firmware is required".to_string());
+        }
+
+        // vhost-user backends access the guest memory directly.
+        let shared_memory = self.memory.shared
+            || (has_memory_zones && self.memory.zones.iter().flatten().all(|z| z.shared));
+
+        if !shared_memory {
+            for fs in self.fs.iter().flatten() {
+                errors.push(format!(
+                    "fs.{}: vhost-user devices require memory.shared",
+                    fs.tag
+                ));
+            }
+
+            for net in self.net.iter().flatten().filter(|n| n.vhost_user) {
+                errors.push(format!(
+                    "net.{}: vhost-user devices require memory.shared",
+                    net.id.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+
+        let num_pci_segments = self
+            .platform
+            .as_ref()
+            .map_or(DEFAULT_NUM_PCI_SEGMENTS, |p| p.num_pci_segments);
+
+        if num_pci_segments == 0 || num_pci_segments > MAX_NUM_PCI_SEGMENTS {
+            errors.push(format!(
+                "platform.num_pci_segments: {} is not between 1 and {}",
+                num_pci_segments, MAX_NUM_PCI_SEGMENTS
+            ));
+        } else if let Some((segment, _)) = self
+            .device_pci_segments()
+            .iter()
+            .find(|(segment, _)| *segment >= num_pci_segments)
+        {
+            errors.push(format!(
+                "pci_segment: {} is not lower than platform.num_pci_segments {}",
+                segment, num_pci_segments
+            ));
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        Err(anyhow!("invalid VM config:\n  {}", errors.join("\n  ")))
+    }
+
+    // Return the PCI segment of every device in the config, along with
+    // whether the device is placed behind the virtual IOMMU.
+    pub fn device_pci_segments(&self) -> Vec<(u16, bool)> {
+        let mut segments = Vec::<(u16, bool)>::new();
+
+        if let Some(disks) = &self.disks {
+            segments.extend(disks.iter().map(|d| (d.pci_segment, d.iommu)));
+        }
+        if let Some(net) = &self.net {
+            segments.extend(net.iter().map(|n| (n.pci_segment, n.iommu)));
+        }
+        if let Some(fs) = &self.fs {
+            segments.extend(fs.iter().map(|f| (f.pci_segment, false)));
+        }
+        if let Some(pmem) = &self.pmem {
+            segments.extend(pmem.iter().map(|p| (p.pci_segment, p.iommu)));
+        }
+        if let Some(devices) = &self.devices {
+            segments.extend(devices.iter().map(|d| (d.pci_segment, d.iommu)));
+        }
+        if let Some(user_devices) = &self.user_devices {
+            segments.extend(user_devices.iter().map(|u| (u.pci_segment, false)));
+        }
+        if let Some(vdpa) = &self.vdpa {
+            segments.extend(vdpa.iter().map(|v| (v.pci_segment, v.iommu)));
+        }
+        if let Some(vsock) = &self.vsock {
+            segments.push((vsock.pci_segment, vsock.iommu));
+        }
+
+        segments
+    }
+
+    // Return the number of devices CH adds to the default PCI segment on its
+    // own, which device_pci_segments() does not list.
+    pub fn builtin_pci_devices(&self) -> usize {
+        let iommu = self.iommu
+            || self.console.iommu
+            || self.device_pci_segments().iter().any(|(_, iommu)| *iommu);
+
+        let virtio_mem = self.memory.hotplug_method == HotplugMethod::VirtioMem
+            && self.memory.hotplug_size.unwrap_or_default() > 0;
+
+        // The entropy device is always added.
+        [
+            true,
+            self.console.mode != ConsoleOutputMode::Off,
+            self.balloon.is_some(),
+            self.watchdog,
+            iommu,
+            virtio_mem,
+        ]
+        .iter()
+        .filter(|added| **added)
+        .count()
+    }
+
+    // Return true if the device with the specified id is passed through to
+    // the guest, with VFIO or vfio-user, rather than emulated as a virtio
+    // device.
+    pub fn is_passthrough_device(&self, id: &str) -> bool {
+        let vfio = self.devices.iter().flatten().map(|d| &d.id);
+        let user_devices = self.user_devices.iter().flatten().map(|u| &u.id);
+
+        vfio.chain(user_devices)
+            .any(|device_id| device_id.as_deref() == Some(id))
+    }
+
+    // Return the PCI segment of the device with the specified id, if the
+    // config holds it.
+    pub fn device_pci_segment(&self, id: &str) -> Option<u16> {
+        let mut devices = Vec::<(&Option<String>, u16)>::new();
+
+        if let Some(disks) = &self.disks {
+            devices.extend(disks.iter().map(|d| (&d.id, d.pci_segment)));
+        }
+        if let Some(net) = &self.net {
+            devices.extend(net.iter().map(|n| (&n.id, n.pci_segment)));
+        }
+        if let Some(fs) = &self.fs {
+            devices.extend(fs.iter().map(|f| (&f.id, f.pci_segment)));
+        }
+        if let Some(pmem) = &self.pmem {
+            devices.extend(pmem.iter().map(|p| (&p.id, p.pci_segment)));
+        }
+        if let Some(vfio) = &self.devices {
+            devices.extend(vfio.iter().map(|d| (&d.id, d.pci_segment)));
+        }
+        if let Some(user_devices) = &self.user_devices {
+            devices.extend(user_devices.iter().map(|u| (&u.id, u.pci_segment)));
+        }
+        if let Some(vdpa) = &self.vdpa {
+            devices.extend(vdpa.iter().map(|v| (&v.id, v.pci_segment)));
+        }
+        if let Some(vsock) = &self.vsock {
+            devices.push((&vsock.id, vsock.pci_segment));
+        }
+
+        devices
+            .into_iter()
+            .find(|(device_id, _)| device_id.as_deref() == Some(id))
+            .map(|(_, segment)| segment)
+    }
+}
+
+// Subset of the details returned by CH for the VM.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VmInfo {
+    pub state: String,
+    #[serde(default)]
+    pub memory_actual_size: Option<u64>,
+    #[serde(default)]
+    pub config: Option<VmConfig>,
+}
+
+// Counters of the devices of the VM, by device id and counter name.
+pub type VmCounters = BTreeMap<String, BTreeMap<String, u64>>;
+
+// Subset of the details returned by CH for the VMM.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VmmPingResponse {
+    pub version: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VmRemoveDeviceData {
+    pub id: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VmReceiveMigrationData {
+    pub receiver_url: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VmSendMigrationData {
+    pub destination_url: String,
+    #[serde(default)]
+    pub local: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VmSnapshotConfig {
+    pub destination_url: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VmResize {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desired_vcpus: Option<u8>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desired_ram: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desired_balloon: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VmResizeZone {
+    pub id: String,
+    pub desired_ram: u64,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
 pub struct VsockConfig {
     pub cid: u64,
@@ -479,3 +844,231 @@ fn usize_is_zero(v: &usize) -> bool {
 fn u16_is_zero(v: &u16) -> bool {
     *v == 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_config() -> VmConfig {
+        VmConfig {
+            cpus: CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 2,
+                ..Default::default()
+            },
+            memory: MemoryConfig {
+                size: 1 << 30,
+                shared: true,
+                ..Default::default()
+            },
+            payload: Some(PayloadConfig {
+                kernel: Some(PathBuf::from("/kernel")),
+                ..Default::default()
+            }),
+            fs: Some(vec![FsConfig {
+                tag: "kataShared".to_string(),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }
+    }
+
+    fn assert_invalid(cfg: &VmConfig, field: &str) {
+        let err = cfg.validate().unwrap_err().to_string();
+
+        assert!(err.contains(field), "{:?} does not mention {}", err, field);
+    }
+
+    #[test]
+    fn test_validate_valid() {
+        assert!(valid_config().validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_vcpus() {
+        let mut cfg = valid_config();
+        cfg.cpus.boot_vcpus = 0;
+        assert_invalid(&cfg, "cpus.boot_vcpus: must be");
+
+        let mut cfg = valid_config();
+        cfg.cpus.boot_vcpus = 4;
+        assert_invalid(&cfg, "cpus.max_vcpus");
+    }
+
+    #[test]
+    fn test_validate_memory() {
+        let mut cfg = valid_config();
+        cfg.memory.size = 0;
+        assert_invalid(&cfg, "memory.size");
+
+        // The memory can be entirely defined by zones.
+        cfg.memory.zones = Some(vec![MemoryZoneConfig {
+            id: "mem0".to_string(),
+            size: 1 << 30,
+            shared: true,
+            ..Default::default()
+        }]);
+        assert!(cfg.validate().is_ok());
+
+        cfg.memory.size = 1 << 30;
+        assert_invalid(&cfg, "memory.size: must be zero");
+
+        let mut cfg = valid_config();
+        cfg.memory.size = 0;
+        cfg.memory.zones = Some(vec![MemoryZoneConfig {
+            id: "mem0".to_string(),
+            size: 1 << 30,
+            file: Some(PathBuf::from("/dev/shm")),
+            shared: true,
+            hugepages: true,
+            ..Default::default()
+        }]);
+        assert_invalid(&cfg, "memory.zones.mem0: file and hugepages");
+    }
+
+    #[test]
+    fn test_validate_numa() {
+        let mut cfg = valid_config();
+        cfg.memory.size = 0;
+        cfg.memory.zones = Some(vec![MemoryZoneConfig {
+            id: "mem0".to_string(),
+            size: 1 << 30,
+            shared: true,
+            host_numa_node: Some(1),
+            ..Default::default()
+        }]);
+        cfg.numa = Some(vec![NumaConfig {
+            guest_numa_id: 0,
+            cpus: Some(vec![0, 1]),
+            memory_zones: Some(vec!["mem0".to_string()]),
+            ..Default::default()
+        }]);
+        cfg.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: 1,
+            host_cpus: vec![4, 5],
+        }]);
+        assert!(cfg.validate().is_ok());
+
+        cfg.cpus.max_vcpus = 1;
+        assert_invalid(&cfg, "numa.0: vCPU 1");
+        assert_invalid(&cfg, "cpus.affinity: vCPU 1");
+
+        let mut cfg = valid_config();
+        cfg.numa = Some(vec![NumaConfig {
+            memory_zones: Some(vec!["mem0".to_string()]),
+            ..Default::default()
+        }]);
+        assert_invalid(&cfg, "numa.0: unknown memory zone mem0");
+    }
+
+    #[test]
+    fn test_validate_payload() {
+        let mut cfg = valid_config();
+        cfg.payload = None;
+        assert_invalid(&cfg, "payload");
+
+        cfg.payload = Some(PayloadConfig {
+            firmware: Some(PathBuf::from("/firmware")),
+            ..Default::default()
+        });
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_shared_memory() {
+        let mut cfg = valid_config();
+        cfg.memory.shared = false;
+        assert_invalid(&cfg, "fs.kataShared");
+
+        cfg.fs = None;
+        cfg.net = Some(vec![NetConfig {
+            id: Some("eth0".to_string()),
+            vhost_user: true,
+            ..Default::default()
+        }]);
+        assert_invalid(&cfg, "net.eth0");
+
+        cfg.net.as_mut().unwrap()[0].vhost_user = false;
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_pci_segments() {
+        let mut cfg = valid_config();
+        cfg.platform = Some(PlatformConfig {
+            num_pci_segments: 0,
+            ..Default::default()
+        });
+        assert_invalid(&cfg, "platform.num_pci_segments");
+
+        cfg.platform = Some(PlatformConfig {
+            num_pci_segments: 2,
+            ..Default::default()
+        });
+        cfg.fs.as_mut().unwrap()[0].pci_segment = 2;
+        assert_invalid(&cfg, "pci_segment: 2");
+    }
+
+    #[test]
+    fn test_validate_aggregates_errors() {
+        let mut cfg = valid_config();
+        cfg.cpus.boot_vcpus = 0;
+        cfg.memory.size = 0;
+        cfg.payload = None;
+
+        let err = cfg.validate().unwrap_err().to_string();
+
+        assert!(err.contains("cpus.boot_vcpus"));
+        assert!(err.contains("memory.size"));
+        assert!(err.contains("payload"));
+    }
+
+    #[test]
+    fn test_device_pci_segment() {
+        let mut cfg = valid_config();
+        assert_eq!(cfg.device_pci_segment("kataShared"), None);
+
+        cfg.fs.as_mut().unwrap()[0].id = Some("kataShared".to_string());
+        cfg.fs.as_mut().unwrap()[0].pci_segment = 1;
+        cfg.pmem = Some(vec![PmemConfig {
+            id: Some("pmem0".to_string()),
+            ..Default::default()
+        }]);
+
+        assert_eq!(cfg.device_pci_segment("kataShared"), Some(1));
+        assert_eq!(cfg.device_pci_segment("pmem0"), Some(0));
+        assert_eq!(cfg.device_pci_segment("foo"), None);
+    }
+
+    #[test]
+    fn test_is_passthrough_device() {
+        let mut cfg = valid_config();
+        cfg.fs.as_mut().unwrap()[0].id = Some("kataShared".to_string());
+        cfg.devices = Some(vec![DeviceConfig {
+            id: Some("_vfio0".to_string()),
+            ..Default::default()
+        }]);
+
+        assert!(cfg.is_passthrough_device("_vfio0"));
+        assert!(!cfg.is_passthrough_device("kataShared"));
+        assert!(!cfg.is_passthrough_device("foo"));
+    }
+
+    #[test]
+    fn test_builtin_pci_devices() {
+        let mut cfg = valid_config();
+        assert_eq!(cfg.builtin_pci_devices(), 1);
+
+        cfg.console.mode = ConsoleOutputMode::Tty;
+        cfg.balloon = Some(BalloonConfig::default());
+        cfg.memory.hotplug_method = HotplugMethod::VirtioMem;
+        assert_eq!(cfg.builtin_pci_devices(), 3);
+
+        cfg.memory.hotplug_size = Some(1 << 30);
+        cfg.pmem = Some(vec![PmemConfig {
+            iommu: true,
+            ..Default::default()
+        }]);
+        assert_eq!(cfg.builtin_pci_devices(), 5);
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Builder for the cloud-hypervisor command line arguments.
+//
+// The arguments are always assembled in the same order, which makes it
+// possible to check the launch command without actually running CH.
+#[derive(Debug, Default, Clone)]
+pub struct ChArgsBuilder {
+    api_socket: String,
+    extra_args: Vec<String>,
+    log_level: u32,
+    log_file: Option<String>,
+    seccomp: Option<String>,
+    landlock_rules: Option<Vec<String>>,
+    event_monitor: Option<String>,
+}
+
+impl ChArgsBuilder {
+    pub fn new(api_socket: &str) -> Self {
+        ChArgsBuilder {
+            api_socket: api_socket.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn extra_args(mut self, args: &[String]) -> Self {
+        self.extra_args = args.to_vec();
+        self
+    }
+
+    // Number of "-v" options to pass.
+    pub fn log_level(mut self, level: u32) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    pub fn log_file(mut self, path: &str) -> Self {
+        self.log_file = Some(path.to_string());
+        self
+    }
+
+    pub fn seccomp(mut self, mode: &str) -> Self {
+        self.seccomp = Some(mode.to_string());
+        self
+    }
+
+    // Enable Landlock, restricting CH to the specified rules (which may be
+    // empty).
+    pub fn landlock(mut self, rules: Vec<String>) -> Self {
+        self.landlock_rules = Some(rules);
+        self
+    }
+
+    // Path of the file CH writes its events to.
+    pub fn event_monitor(mut self, path: &str) -> Self {
+        self.event_monitor = Some(path.to_string());
+        self
+    }
+
+    pub fn build(&self) -> Vec<String> {
+        let mut args = vec!["--api-socket".to_string(), self.api_socket.clone()];
+
+        args.extend(self.extra_args.iter().cloned());
+
+        for _ in 0..self.log_level {
+            args.push("-v".to_string());
+        }
+
+        if let Some(path) = &self.log_file {
+            args.push("--log-file".to_string());
+            args.push(path.clone());
+        }
+
+        if let Some(mode) = &self.seccomp {
+            args.push("--seccomp".to_string());
+            args.push(mode.clone());
+        }
+
+        if let Some(rules) = &self.landlock_rules {
+            args.push("--landlock".to_string());
+
+            if !rules.is_empty() {
+                args.push("--landlock-rules".to_string());
+                args.extend(rules.iter().cloned());
+            }
+        }
+
+        if let Some(path) = &self.event_monitor {
+            args.push("--event-monitor".to_string());
+            args.push(format!("path={}", path));
+        }
+
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ch_args_builder() {
+        let args = ChArgsBuilder::new("/run/ch-api.sock").build();
+        assert_eq!(args, vec!["--api-socket", "/run/ch-api.sock"]);
+
+        let args = ChArgsBuilder::new("/run/ch-api.sock")
+            .landlock(vec!["path=/run,access=rw".to_string()])
+            .event_monitor("/run/ch-events.fifo")
+            .seccomp("log")
+            .log_file("/run/vmm.log")
+            .log_level(2)
+            .extra_args(&["--foo".to_string()])
+            .build();
+
+        assert_eq!(
+            args,
+            vec![
+                "--api-socket",
+                "/run/ch-api.sock",
+                "--foo",
+                "-v",
+                "-v",
+                "--log-file",
+                "/run/vmm.log",
+                "--seccomp",
+                "log",
+                "--landlock",
+                "--landlock-rules",
+                "path=/run,access=rw",
+                "--event-monitor",
+                "path=/run/ch-events.fifo",
+            ]
+        );
+
+        let args = ChArgsBuilder::new("/run/ch-api.sock")
+            .landlock(vec![])
+            .build();
+        assert_eq!(args, vec!["--api-socket", "/run/ch-api.sock", "--landlock"]);
+    }
+}
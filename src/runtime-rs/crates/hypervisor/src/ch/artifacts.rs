@@ -0,0 +1,212 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// How an artifact is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    // A file or a socket.
+    File,
+    // A directory which other components may also use, only removed once
+    // empty.
+    Dir,
+    // A directory whose whole content was created for the VM.
+    Tree,
+}
+
+impl ArtifactKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ArtifactKind::File => "file",
+            ArtifactKind::Dir => "dir",
+            ArtifactKind::Tree => "tree",
+        }
+    }
+
+    fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "file" => Ok(ArtifactKind::File),
+            "dir" => Ok(ArtifactKind::Dir),
+            "tree" => Ok(ArtifactKind::Tree),
+            _ => Err(anyhow!("invalid artifact kind {:?}", name)),
+        }
+    }
+}
+
+// Files and directories created for the VM, by the driver or by CH at its
+// request, in creation order.
+//
+// Only the recorded paths are ever removed, so files the driver did not
+// create, such as a socket at a path provided by the user, are left alone.
+#[derive(Debug, Default)]
+pub struct Artifacts {
+    entries: Mutex<Vec<(PathBuf, ArtifactKind)>>,
+}
+
+impl Artifacts {
+    pub fn add<P: Into<PathBuf>>(&self, path: P, kind: ArtifactKind) {
+        let path = path.into();
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        if !entries.iter().any(|(p, _)| *p == path) {
+            entries.push((path, kind));
+        }
+    }
+
+    // Return the artifacts from their persisted state, as returned by
+    // to_state().
+    pub fn from_state(state: &[(String, String)]) -> Result<Self> {
+        let artifacts = Artifacts::default();
+
+        for (path, kind) in state {
+            artifacts.add(path, ArtifactKind::from_name(kind)?);
+        }
+
+        Ok(artifacts)
+    }
+
+    // Return the recorded artifacts as (path, kind) pairs, in creation
+    // order, so that a restored driver only removes what was created.
+    pub fn to_state(&self) -> Vec<(String, String)> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        entries
+            .iter()
+            .map(|(p, k)| (p.display().to_string(), k.as_str().to_string()))
+            .collect()
+    }
+
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        entries.iter().map(|(p, _)| p.clone()).collect()
+    }
+
+    // Remove the artifacts, most recent first, except the files for which
+    // keep() returns true. A failure does not stop the removal of the other
+    // artifacts: the failed ones stay recorded and all the failures are
+    // reported in the returned error.
+    pub fn remove<F: Fn(&Path) -> bool>(&self, keep: F) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut retained = Vec::new();
+        let mut errors = Vec::new();
+
+        for (path, kind) in entries.drain(..).rev() {
+            if kind == ArtifactKind::File && keep(&path) {
+                retained.push((path, kind));
+                continue;
+            }
+
+            let result = match kind {
+                ArtifactKind::File => std::fs::remove_file(&path),
+                ArtifactKind::Dir => std::fs::remove_dir(&path),
+                ArtifactKind::Tree => std::fs::remove_dir_all(&path),
+            };
+
+            match result {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                // Still holding files the driver did not create, or kept.
+                Err(_) if kind == ArtifactKind::Dir && !is_empty_dir(&path) => {
+                    retained.push((path, kind));
+                }
+                Err(e) => {
+                    errors.push(format!("{}: {}", path.display(), e));
+                    retained.push((path, kind));
+                }
+            }
+        }
+
+        retained.reverse();
+        *entries = retained;
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("failed to remove {}", errors.join(", ")))
+        }
+    }
+}
+
+fn is_empty_dir(path: &Path) -> bool {
+    path.read_dir()
+        .map(|mut entries| entries.next().is_none())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_artifacts_remove() {
+        let root = std::env::temp_dir().join(format!("ch-artifacts-{}", std::process::id()));
+        let tree = root.join("snapshot");
+        let log = root.join("console.log");
+        let socket = root.join("ch-api.sock");
+        let user_file = root.join("user.sock");
+
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("state.json"), "{}").unwrap();
+        std::fs::write(&log, "log").unwrap();
+        std::fs::write(&socket, "").unwrap();
+        std::fs::write(&user_file, "").unwrap();
+
+        let artifacts = Artifacts::default();
+        artifacts.add(&root, ArtifactKind::Dir);
+        artifacts.add(&tree, ArtifactKind::Tree);
+        artifacts.add(&log, ArtifactKind::File);
+        artifacts.add(&socket, ArtifactKind::File);
+        artifacts.add(&socket, ArtifactKind::File);
+        artifacts.add(root.join("vmm.log"), ArtifactKind::File);
+
+        assert_eq!(artifacts.paths().len(), 5);
+
+        // The directory holds a file the driver did not create.
+        artifacts.remove(|p| p == log).unwrap();
+
+        assert!(!tree.exists());
+        assert!(!socket.exists());
+        assert!(log.exists());
+        assert!(user_file.exists());
+        assert_eq!(artifacts.paths(), vec![root.clone(), log.clone()]);
+
+        std::fs::remove_file(&user_file).unwrap();
+
+        artifacts.remove(|_| false).unwrap();
+
+        assert!(!root.exists());
+        assert!(artifacts.paths().is_empty());
+    }
+
+    #[test]
+    fn test_artifacts_state() {
+        let artifacts = Artifacts::default();
+        artifacts.add("/run/vc/vm/foo", ArtifactKind::Dir);
+        artifacts.add("/run/vc/vm/foo/snapshot", ArtifactKind::Tree);
+        artifacts.add("/run/vc/vm/foo/ch-api.sock", ArtifactKind::File);
+
+        let state = artifacts.to_state();
+
+        assert_eq!(
+            state[1],
+            ("/run/vc/vm/foo/snapshot".to_string(), "tree".to_string())
+        );
+
+        let restored = Artifacts::from_state(&state).unwrap();
+
+        assert_eq!(restored.paths(), artifacts.paths());
+        assert_eq!(restored.to_state(), state);
+
+        let invalid = vec![("/run/vc/vm/foo".to_string(), "link".to_string())];
+
+        assert!(Artifacts::from_state(&invalid).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Stages reached by a VM while it starts, reported when it fails to start in
+// time so that the stage which stalled is known.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BootProgress {
+    // CH answered vmm.ping.
+    pub vmm_responding: bool,
+
+    // vm.info reported the VM running.
+    pub vm_running: bool,
+
+    // The guest kernel wrote to the console log. None if the console is not
+    // logged.
+    pub kernel_output: Option<bool>,
+
+    // The agent accepted a connection on the vsock.
+    pub agent_reachable: bool,
+}
+
+impl BootProgress {
+    // Return the first stage not reached, or None if the VM started. A
+    // restored guest writes nothing to the console, so the agent being
+    // reachable is enough.
+    pub fn stalled_stage(&self) -> Option<&'static str> {
+        if self.agent_reachable {
+            None
+        } else if !self.vmm_responding {
+            Some("hypervisor API")
+        } else if !self.vm_running {
+            Some("VM creation")
+        } else if self.kernel_output == Some(false) {
+            Some("guest kernel boot")
+        } else {
+            Some("guest agent startup")
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let yes_no = |reached: bool| if reached { "yes" } else { "no" };
+
+        let kernel_output = match self.kernel_output {
+            Some(output) => yes_no(output),
+            None => "unknown (console not logged)",
+        };
+
+        format!(
+            "vmm.ping answered: {}, VM running: {}, kernel console output: {}, agent vsock connected: {}",
+            yes_no(self.vmm_responding),
+            yes_no(self.vm_running),
+            kernel_output,
+            yes_no(self.agent_reachable)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_progress() {
+        let mut progress = BootProgress::default();
+
+        assert_eq!(progress.stalled_stage(), Some("hypervisor API"));
+
+        progress.vmm_responding = true;
+        progress.vm_running = true;
+        progress.kernel_output = Some(false);
+
+        assert_eq!(progress.stalled_stage(), Some("guest kernel boot"));
+        assert_eq!(
+            progress.summary(),
+            "vmm.ping answered: yes, VM running: yes, kernel console output: no, agent vsock connected: no"
+        );
+
+        // Without a console log, the kernel stage cannot be told apart.
+        progress.kernel_output = None;
+
+        assert_eq!(progress.stalled_stage(), Some("guest agent startup"));
+        assert!(progress
+            .summary()
+            .contains("kernel console output: unknown (console not logged)"));
+
+        progress.agent_reachable = true;
+
+        assert_eq!(progress.stalled_stage(), None);
+    }
+}
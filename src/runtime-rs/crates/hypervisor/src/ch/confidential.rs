@@ -0,0 +1,151 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Devices of confidential (TDX or SEV-SNP) guests, whose memory is private to
+// the guest: the host side of some devices cannot access it, and the host
+// could modify some others after the guest was measured. Such devices are
+// rejected, or adjusted, as listed in a single table.
+
+use super::errors::ChError;
+use crate::device::Device;
+use anyhow::Result;
+
+// Kinds of devices, as named in the policy table.
+pub const DEVICE_KIND_BLOCK: &str = "block";
+pub const DEVICE_KIND_NET: &str = "net";
+pub const DEVICE_KIND_VIRTIO_FS: &str = "virtio-fs";
+pub const DEVICE_KIND_VFIO: &str = "vfio";
+pub const DEVICE_KIND_VSOCK: &str = "vsock";
+pub const DEVICE_KIND_HYBRID_VSOCK: &str = "hybrid-vsock";
+pub const DEVICE_KIND_VHOST_USER_NET: &str = "vhost-user-net";
+pub const DEVICE_KIND_PMEM: &str = "pmem";
+pub const DEVICE_KIND_BALLOON_FREE_PAGE_REPORTING: &str = "balloon free page reporting";
+
+// What a confidential guest does with a kind of device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfidentialPolicy {
+    // The device is refused, for the reason.
+    Reject(&'static str),
+
+    // The device is changed to work in the guest, for the reason.
+    Adjust(&'static str),
+}
+
+// Policy of confidential guests, by kind of device. The devices not listed
+// work as in other guests.
+const CONFIDENTIAL_DEVICE_POLICIES: [(&str, ConfidentialPolicy); 5] = [
+    (
+        DEVICE_KIND_VIRTIO_FS,
+        ConfidentialPolicy::Reject(
+            "its vhost-user backend cannot access the private guest memory, use shared_fs = \"none\"",
+        ),
+    ),
+    (
+        DEVICE_KIND_VHOST_USER_NET,
+        ConfidentialPolicy::Reject("its vhost-user backend cannot access the private guest memory"),
+    ),
+    (
+        DEVICE_KIND_PMEM,
+        ConfidentialPolicy::Reject("the host could modify it after the guest was measured"),
+    ),
+    (
+        DEVICE_KIND_VFIO,
+        ConfidentialPolicy::Reject(
+            "the device cannot access the private guest memory without trusted device assignment, which CH does not provide",
+        ),
+    ),
+    (
+        DEVICE_KIND_BALLOON_FREE_PAGE_REPORTING,
+        ConfidentialPolicy::Adjust("the host cannot reclaim the private guest memory, disabling it"),
+    ),
+];
+
+// Return the policy of confidential guests for a kind of device, if any.
+pub fn get_confidential_policy(kind: &str) -> Option<ConfidentialPolicy> {
+    CONFIDENTIAL_DEVICE_POLICIES
+        .iter()
+        .find(|(k, _)| *k == kind)
+        .map(|(_, policy)| *policy)
+}
+
+// Return true if confidential guests reject the kind of device.
+pub fn is_rejected(kind: &str) -> bool {
+    matches!(
+        get_confidential_policy(kind),
+        Some(ConfidentialPolicy::Reject(_))
+    )
+}
+
+// Return the kind of a device, as named in the policy table.
+pub fn device_kind(device: &Device) -> &'static str {
+    match device {
+        Device::Block(_) => DEVICE_KIND_BLOCK,
+        Device::Network(_) => DEVICE_KIND_NET,
+        Device::ShareFsDevice(_) | Device::ShareFsMount(_) => DEVICE_KIND_VIRTIO_FS,
+        Device::Vfio(_) => DEVICE_KIND_VFIO,
+        Device::Vsock(_) => DEVICE_KIND_VSOCK,
+        Device::HybridVsock(_) => DEVICE_KIND_HYBRID_VSOCK,
+        Device::VhostUserNet(_) => DEVICE_KIND_VHOST_USER_NET,
+        Device::Pmem(_) => DEVICE_KIND_PMEM,
+    }
+}
+
+// Fail with ChError::ConfidentialDeviceRejected if confidential guests
+// reject the kind of device.
+pub fn check_confidential_device(kind: &str, id: Option<&str>) -> Result<()> {
+    let reason = match get_confidential_policy(kind) {
+        Some(ConfidentialPolicy::Reject(reason)) => reason,
+        _ => return Ok(()),
+    };
+
+    let device = match id.filter(|id| !id.is_empty()) {
+        Some(id) => format!("{} device {}", kind, id),
+        None => format!("{} device", kind),
+    };
+
+    Err(ChError::ConfidentialDeviceRejected {
+        device,
+        reason: reason.to_string(),
+    }
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_confidential_device() {
+        assert!(check_confidential_device(DEVICE_KIND_BLOCK, Some("blk0")).is_ok());
+        assert!(check_confidential_device(DEVICE_KIND_BALLOON_FREE_PAGE_REPORTING, None).is_ok());
+
+        let err = check_confidential_device(DEVICE_KIND_VIRTIO_FS, Some("kataShared")).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ChError>(),
+            Some(ChError::ConfidentialDeviceRejected { .. })
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("virtio-fs device kataShared not supported by confidential guests"));
+
+        let err = check_confidential_device(DEVICE_KIND_PMEM, Some(""))
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.starts_with("pmem device not supported"));
+    }
+
+    #[test]
+    fn test_get_confidential_policy() {
+        assert!(is_rejected(DEVICE_KIND_VFIO));
+        assert!(!is_rejected(DEVICE_KIND_NET));
+        assert!(!is_rejected(DEVICE_KIND_BALLOON_FREE_PAGE_REPORTING));
+        assert!(matches!(
+            get_confidential_policy(DEVICE_KIND_BALLOON_FREE_PAGE_REPORTING),
+            Some(ConfidentialPolicy::Adjust(_))
+        ));
+        assert_eq!(get_confidential_policy(DEVICE_KIND_VSOCK), None);
+    }
+}
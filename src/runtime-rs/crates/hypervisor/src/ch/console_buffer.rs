@@ -0,0 +1,132 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Buffer in memory retaining the end of the guest console, filled from the
+// console socket by a task. The buffer outlives the VM, so that the output
+// of a guest which crashed, or never reached the agent, can still be read.
+
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+use tokio::net::UnixStream;
+use tokio::sync::watch::Receiver;
+use tokio::time::Duration;
+
+// CH listens on the console socket once the VM is created, so connecting
+// is retried for a while.
+const CONSOLE_CONNECT_RETRIES: u32 = 50;
+const CONSOLE_CONNECT_RETRY_MS: u64 = 100;
+
+const CONSOLE_READ_SIZE: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct ConsoleBuffer {
+    capacity: usize,
+    data: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl ConsoleBuffer {
+    pub fn new(capacity: usize) -> Self {
+        ConsoleBuffer {
+            capacity,
+            data: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    // Append console output, dropping the oldest output beyond the
+    // capacity.
+    pub fn push(&self, output: &[u8]) {
+        let output = &output[output.len().saturating_sub(self.capacity)..];
+
+        let mut data = self.data.lock().unwrap();
+
+        let excess = (data.len() + output.len()).saturating_sub(self.capacity);
+
+        data.drain(..excess);
+        data.extend(output);
+    }
+
+    // Return the end of the console output, at most size bytes.
+    pub fn tail(&self, size: usize) -> Vec<u8> {
+        let data = self.data.lock().unwrap();
+
+        let start = data.len().saturating_sub(size);
+
+        data.range(start..).copied().collect()
+    }
+}
+
+// Copy the console output streamed on the socket to the buffer, until CH
+// closes the socket or a shutdown is requested.
+pub async fn capture_console(
+    buffer: ConsoleBuffer,
+    path: &Path,
+    mut shutdown: Receiver<bool>,
+) -> Result<()> {
+    let mut stream = connect_console(path).await?;
+
+    let mut chunk = [0u8; CONSOLE_READ_SIZE];
+
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => return Ok(()),
+            read = stream.read(&mut chunk) => {
+                match read.context(format!("failed to read console socket {:?}", path))? {
+                    0 => return Ok(()),
+                    n => buffer.push(&chunk[..n]),
+                }
+            }
+        }
+    }
+}
+
+async fn connect_console(path: &Path) -> Result<UnixStream> {
+    for _ in 0..CONSOLE_CONNECT_RETRIES {
+        if let Ok(stream) = UnixStream::connect(path).await {
+            return Ok(stream);
+        }
+
+        tokio::time::sleep(Duration::from_millis(CONSOLE_CONNECT_RETRY_MS)).await;
+    }
+
+    UnixStream::connect(path)
+        .await
+        .context(format!("failed to connect to console socket {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_console_buffer() {
+        let buffer = ConsoleBuffer::new(8);
+
+        assert!(buffer.tail(4).is_empty());
+
+        buffer.push(b"abc");
+        buffer.push(b"defg");
+
+        assert_eq!(buffer.tail(4), b"defg");
+        assert_eq!(buffer.tail(100), b"abcdefg");
+
+        // The oldest output is dropped.
+        buffer.push(b"hij");
+
+        assert_eq!(buffer.tail(100), b"cdefghij");
+
+        // Output larger than the buffer.
+        buffer.push(b"0123456789");
+
+        assert_eq!(buffer.tail(100), b"23456789");
+        assert!(buffer.tail(0).is_empty());
+
+        // Clones share the output.
+        buffer.clone().push(b"x");
+
+        assert_eq!(buffer.tail(2), b"9x");
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::Duration;
+use thiserror::Error;
+
+// Errors returned by CH, or by the host, which must be distinguishable by
+// callers. They are returned wrapped in an anyhow::Error, and can be
+// recovered with downcast_ref().
+#[derive(Error, Debug)]
+pub enum ChError {
+    // The host ran out of file descriptors or memory. The operation may
+    // succeed later, once resources have been released.
+    #[error("host resources exhausted while adding device {device}: {detail}")]
+    ResourceExhausted { device: String, detail: String },
+
+    // The CH process exited without being requested to, so the VM is gone.
+    // The status is either "status <code>" or "signal <number>".
+    #[error("VMM exited ({status})")]
+    VmmExited { status: String },
+
+    // The CH process of a sandbox being recovered after a shim restart is
+    // gone, or does not answer, so the sandbox is dead.
+    #[error("VMM lost: {reason}")]
+    VmmLost { reason: String },
+
+    // A resize would hot add more of a resource than the configured quota
+    // allows. The amounts are in MiB for the memory.
+    #[error("{resource} hotplug quota exceeded: {requested} requested, quota is {quota}")]
+    QuotaExceeded {
+        resource: String,
+        requested: u64,
+        quota: u64,
+    },
+
+    // The CH binary was built without a feature required by the
+    // configuration, such as io_uring or mshv.
+    #[error("feature {feature} not supported by this CH build")]
+    FeatureNotSupported { feature: String },
+
+    // The operation is disabled by the configuration, such as resizing a
+    // VM sized statically when it was created.
+    #[error("{operation} not supported: {reason}")]
+    Unsupported { operation: String, reason: String },
+
+    // The VM holds as many devices as CH allows, on a PCI segment or on all
+    // of them, so adding the device would be refused by CH.
+    #[error("device limit reached for {device}: at most {limit} devices {scope}")]
+    DeviceLimitReached {
+        device: String,
+        scope: String,
+        limit: usize,
+    },
+
+    // A device added to the VM was not ready to be used by the guest in
+    // time, such as a virtio device its driver did not activate.
+    #[error("device {device} not ready in the guest after {timeout:?}")]
+    DeviceNotReady { device: String, timeout: Duration },
+
+    // The device cannot be used by a confidential guest, as listed in the
+    // policy of such guests.
+    #[error("{device} not supported by confidential guests: {reason}")]
+    ConfidentialDeviceRejected { device: String, reason: String },
+}
+
+// Patterns of the errors reported when the host runs out of file
+// descriptors (EMFILE, ENFILE), memory (ENOMEM) or space (ENOSPC).
+const RESOURCE_EXHAUSTED_PATTERNS: [&str; 4] = [
+    "Too many open files",
+    "Cannot allocate memory",
+    "No space left on device",
+    "Out of memory",
+];
+
+// Errno values of ENOMEM, ENFILE, EMFILE and ENOSPC.
+const RESOURCE_EXHAUSTED_ERRNOS: [i32; 4] = [12, 23, 24, 28];
+
+// Prefix of the errno value in the message of an I/O error, as reported by
+// CH.
+const OS_ERROR_PREFIX: &str = "os error ";
+
+// Return true if the error, or any of its causes, reports the exhaustion
+// of a host resource.
+pub fn is_resource_exhausted(err: &anyhow::Error) -> bool {
+    let io_errno = err
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .filter_map(|e| e.raw_os_error())
+        .any(|errno| RESOURCE_EXHAUSTED_ERRNOS.contains(&errno));
+
+    if io_errno {
+        return true;
+    }
+
+    let msg = format!("{:#}", err);
+
+    RESOURCE_EXHAUSTED_PATTERNS
+        .iter()
+        .any(|pattern| msg.contains(pattern))
+        || get_os_errors(&msg).any(|errno| RESOURCE_EXHAUSTED_ERRNOS.contains(&errno))
+}
+
+// Return the errno values of the "os error <errno>" in the message.
+fn get_os_errors(msg: &str) -> impl Iterator<Item = i32> + '_ {
+    msg.split(OS_ERROR_PREFIX).skip(1).filter_map(|rest| {
+        let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+
+        digits.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_is_resource_exhausted() {
+        let err = anyhow!("Too many open files (os error 24)").context("fs add failed");
+        assert!(is_resource_exhausted(&err));
+
+        let err = anyhow!("Error creating device: Cannot allocate memory");
+        assert!(is_resource_exhausted(&err));
+
+        let err = anyhow!("Failed to open tap: os error 23");
+        assert!(is_resource_exhausted(&err));
+
+        let err = anyhow!("Invalid socket path").context("fs add failed");
+        assert!(!is_resource_exhausted(&err));
+
+        // Errno values sharing a prefix with the resource ones.
+        let err = anyhow!("Failed to add device: Operation canceled (os error 125)");
+        assert!(!is_resource_exhausted(&err));
+
+        let err = anyhow!("os error 122, then os error 28");
+        assert!(is_resource_exhausted(&err));
+
+        let err = anyhow::Error::from(std::io::Error::from_raw_os_error(24)).context("open");
+        assert!(is_resource_exhausted(&err));
+
+        let err = anyhow::Error::from(std::io::Error::from_raw_os_error(125));
+        assert!(!is_resource_exhausted(&err));
+    }
+
+    #[test]
+    fn test_resource_exhausted_downcast() {
+        let err: anyhow::Error = ChError::ResourceExhausted {
+            device: "fs0".to_string(),
+            detail: "Too many open files".to_string(),
+        }
+        .into();
+
+        let err = err.context("add device failed");
+
+        assert!(matches!(
+            err.downcast_ref::<ChError>(),
+            Some(ChError::ResourceExhausted { .. })
+        ));
+    }
+}
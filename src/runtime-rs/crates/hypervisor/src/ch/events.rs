@@ -0,0 +1,339 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Consumer of the CH event monitor.
+//
+// CH writes its lifecycle events as JSON objects to a FIFO created by the
+// driver. The FIFO is read by a blocking task, which translates the events
+// into VM state and device updates.
+//
+// The FIFO is opened for both reading and writing, which does not wait for
+// CH to open it, and keeps it usable when CH closes it and opens it again.
+// If reading fails, the FIFO is opened again.
+
+use crate::VmmState;
+use anyhow::{Context, Result};
+use nix::sys::stat::Mode;
+use nix::unistd::mkfifo;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Change of a device of the VM reported by CH.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+    /// The guest driver activated the device.
+    Activated(String),
+
+    /// The guest driver reset the device.
+    Reset(String),
+
+    /// The guest released the device, which is now removed from the VM.
+    Removed(String),
+}
+
+// Update of the state tracked by the driver.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum VmEvent {
+    State(VmmState),
+    Device(DeviceEvent),
+}
+
+// Event as written by CH. The timestamp is not used.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct ChEvent {
+    source: String,
+    event: String,
+    #[serde(default)]
+    properties: Option<HashMap<String, String>>,
+}
+
+// Return the update matching a CH event, if any.
+fn translate_event(event: &ChEvent) -> Option<VmEvent> {
+    let id = || event.properties.as_ref().and_then(|p| p.get("id")).cloned();
+
+    let update = match (event.source.as_str(), event.event.as_str()) {
+        ("vm", "booted") | ("vm", "resumed") | ("vm", "rebooted") => {
+            VmEvent::State(VmmState::VmRunning)
+        }
+        // A restored VM is paused until resumed.
+        ("vm", "paused") | ("vm", "restored") => VmEvent::State(VmmState::VmPaused),
+        ("vm", "shutdown") | ("vm", "deleted") | ("vmm", "shutdown") => {
+            VmEvent::State(VmmState::VmStopped)
+        }
+        ("virtio-device", "activated") => VmEvent::Device(DeviceEvent::Activated(id()?)),
+        ("virtio-device", "reset") => VmEvent::Device(DeviceEvent::Reset(id()?)),
+        (_, "device-removed") => VmEvent::Device(DeviceEvent::Removed(id()?)),
+        _ => return None,
+    };
+
+    Some(update)
+}
+
+// Splits the event stream into events. CH may write an event on several
+// lines, so lines are accumulated until they form a complete JSON object.
+// Invalid data is dropped, so that the parser recovers at the next event.
+#[derive(Debug, Default)]
+struct EventParser {
+    pending: String,
+}
+
+impl EventParser {
+    fn push_line(&mut self, line: &str) -> Option<Result<ChEvent>> {
+        if self.pending.is_empty() && line.trim().is_empty() {
+            return None;
+        }
+
+        self.pending.push_str(line);
+        self.pending.push('\n');
+
+        match serde_json::from_str::<ChEvent>(&self.pending) {
+            Ok(event) => {
+                self.pending.clear();
+
+                Some(Ok(event))
+            }
+            Err(e) if e.is_eof() => None,
+            Err(e) => {
+                let data = std::mem::take(&mut self.pending);
+
+                Some(Err(e).context(format!("invalid event {:?}", data.trim())))
+            }
+        }
+    }
+}
+
+// Reader of the events of a CH instance.
+#[derive(Debug)]
+pub(crate) struct EventMonitor {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    rx: UnboundedReceiver<VmEvent>,
+}
+
+impl EventMonitor {
+    // Create the FIFO CH writes its events to, and start reading it.
+    pub(crate) fn start(path: &Path) -> Result<Self> {
+        let _ = std::fs::remove_file(path);
+
+        mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)
+            .context(format!("failed to create event monitor FIFO {:?}", path))?;
+
+        Self::open(path)
+    }
+
+    // Start reading the existing FIFO of a running CH instance. CH would
+    // block once the FIFO is full if it was not read.
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let file = open_fifo(path)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = unbounded_channel();
+
+        let reader_path = path.to_path_buf();
+        let reader_stop = stop.clone();
+
+        // The reader runs as long as CH, so does not hold a thread of the
+        // blocking pool.
+        std::thread::Builder::new()
+            .name("ch-event-monitor".to_string())
+            .spawn(move || read_events(file, &reader_path, tx, &reader_stop))
+            .context("failed to start event monitor")?;
+
+        Ok(EventMonitor {
+            path: path.to_path_buf(),
+            stop,
+            rx,
+        })
+    }
+
+    // Return the next pending update, without waiting.
+    pub(crate) fn try_next(&mut self) -> Option<VmEvent> {
+        self.rx.try_recv().ok()
+    }
+
+    // Wait for the next update. Returns None once the reader stopped.
+    pub(crate) async fn next(&mut self) -> Option<VmEvent> {
+        self.rx.recv().await
+    }
+
+    // Stop the reader, which may be waiting for an event.
+    pub(crate) fn stop(&self) {
+        if self.stop.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // An empty line wakes up the reader. Opening the FIFO fails if the
+        // reader already stopped.
+        if let Ok(mut fifo) = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&self.path)
+        {
+            let _ = fifo.write_all(b"\n");
+        }
+    }
+}
+
+impl Drop for EventMonitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn open_fifo(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context(format!("failed to open event monitor FIFO {:?}", path))
+}
+
+fn read_events(file: File, path: &Path, tx: UnboundedSender<VmEvent>, stop: &AtomicBool) {
+    let mut file = file;
+
+    loop {
+        let mut parser = EventParser::default();
+
+        for line in BufReader::new(file).lines() {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!(sl!(), "failed to read event monitor FIFO: {}", e);
+                    break;
+                }
+            };
+
+            let event = match parser.push_line(&line) {
+                Some(Ok(event)) => event,
+                Some(Err(e)) => {
+                    warn!(sl!(), "{:#}", e);
+                    continue;
+                }
+                None => continue,
+            };
+
+            debug!(
+                sl!(),
+                "event {}/{}: {:?}", event.source, event.event, event.properties
+            );
+
+            if let Some(update) = translate_event(&event) {
+                // The driver is gone.
+                if tx.send(update).is_err() {
+                    return;
+                }
+            }
+        }
+
+        if stop.load(Ordering::SeqCst) {
+            return;
+        }
+
+        info!(sl!(), "reopening event monitor FIFO {:?}", path);
+
+        file = match open_fifo(path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!(sl!(), "{:#}", e);
+                return;
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_parser() {
+        let mut parser = EventParser::default();
+
+        // Pretty printed event, followed by a blank line.
+        let lines = [
+            "{",
+            "  \"timestamp\": {",
+            "    \"secs\": 0,",
+            "    \"nanos\": 12000",
+            "  },",
+            "  \"source\": \"virtio-device\",",
+            "  \"event\": \"activated\",",
+            "  \"properties\": {",
+            "    \"id\": \"_disk0\"",
+            "  }",
+            "}",
+            "",
+        ];
+
+        let events: Vec<ChEvent> = lines
+            .iter()
+            .filter_map(|l| parser.push_line(l))
+            .map(|r| r.unwrap())
+            .collect();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            translate_event(&events[0]),
+            Some(VmEvent::Device(DeviceEvent::Activated(
+                "_disk0".to_string()
+            )))
+        );
+
+        // Invalid data is dropped and the next event is parsed.
+        assert!(parser.push_line("{\"source\": ]").unwrap().is_err());
+
+        let event = parser
+            .push_line("{\"source\":\"vm\",\"event\":\"paused\",\"properties\":null}")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            translate_event(&event),
+            Some(VmEvent::State(VmmState::VmPaused))
+        );
+    }
+
+    #[test]
+    fn test_translate_event() {
+        let event = |source: &str, name: &str, id: Option<&str>| ChEvent {
+            source: source.to_string(),
+            event: name.to_string(),
+            properties: id.map(|id| {
+                let mut properties = HashMap::new();
+                properties.insert("id".to_string(), id.to_string());
+                properties
+            }),
+        };
+
+        assert_eq!(
+            translate_event(&event("vm", "booted", None)),
+            Some(VmEvent::State(VmmState::VmRunning))
+        );
+        assert_eq!(
+            translate_event(&event("vm", "restored", None)),
+            Some(VmEvent::State(VmmState::VmPaused))
+        );
+        assert_eq!(
+            translate_event(&event("vmm", "shutdown", None)),
+            Some(VmEvent::State(VmmState::VmStopped))
+        );
+        assert_eq!(
+            translate_event(&event("vm", "device-removed", Some("_net1"))),
+            Some(VmEvent::Device(DeviceEvent::Removed("_net1".to_string())))
+        );
+        assert_eq!(translate_event(&event("vm", "device-removed", None)), None);
+        assert_eq!(translate_event(&event("vm", "booting", None)), None);
+    }
+}
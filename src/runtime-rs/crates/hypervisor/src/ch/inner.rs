@@ -3,16 +3,35 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use super::artifacts::Artifacts;
+use super::console_buffer::ConsoleBuffer;
+use super::errors::ChError;
+use super::events::EventMonitor;
+use super::inner_device::PendingDevice;
+use super::inner_hypervisor::{HugePagesRequest, VcpuShrink};
+use super::jail::Jail;
+use super::measurements::{BootMeasurements, LaunchData};
+use super::pci_segments::PciSegmentAllocator;
+use super::recovery::{is_vmm_process, RecoveryState};
+use super::utils::{get_event_monitor_path, get_recovery_state_path, is_process_running};
 use super::HypervisorState;
-use crate::device::Device;
+use crate::device::AddedDevice;
 use crate::VmmState;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use ch_config::{HotplugMethod, VmCounters};
 use kata_types::capabilities::{Capabilities, CapabilityBits};
+use kata_types::config::default::DEFAULT_AGENT_VSOCK_PORT;
 use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
 use kata_types::config::hypervisor::HYPERVISOR_NAME_CH;
 use persist::sandbox_persist::Persist;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::os::unix::io::RawFd;
 use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use tokio::process::Child;
 use tokio::sync::watch::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
@@ -43,14 +62,171 @@ pub struct CloudHypervisorInner {
     // Subdirectory of vm_path.
     pub(crate) jailer_root: String,
 
+    // Set when CH is confined to jailer_root.
+    pub(crate) jail: Option<Jail>,
+
     /// List of devices that will be added to the VM once it boots
-    pub(crate) pending_devices: Option<Vec<Device>>,
+    pub(crate) pending_devices: Option<Vec<PendingDevice>>,
+
+    /// List of devices that will be added to the VM once it is resumed, in
+    /// the order they were requested.
+    pub(crate) paused_devices: Vec<PendingDevice>,
+
+    /// Path of the file capturing the guest console output, if enabled.
+    pub(crate) console_log_path: Option<String>,
+
+    /// Path of the socket streaming the guest console, if enabled.
+    pub(crate) console_socket_path: Option<String>,
+
+    /// End of the guest console read from the console socket, if enabled.
+    /// Kept once the VM stopped.
+    pub(crate) console_buffer: Option<ConsoleBuffer>,
+
+    /// Path of the CH log file, if enabled.
+    pub(crate) vmm_log_path: Option<String>,
+
+    /// Files and directories created for the VM, removed on cleanup.
+    pub(crate) artifacts: Artifacts,
+
+    /// Version reported by the running CH instance.
+    pub(crate) ch_version: Option<String>,
+
+    /// PCI segments of the hot plugged devices.
+    pub(crate) pci_segments: PciSegmentAllocator,
+
+    /// PCI segments of the devices the guest was asked to release, by id.
+    pub(crate) removing_devices: HashMap<String, u16>,
+
+    /// Devices added to the VM, by id.
+    pub(crate) added_devices: HashMap<String, AddedDevice>,
+
+    /// Ids of the virtio devices activated by the guest driver, as reported
+    /// by the event monitor.
+    pub(crate) activated_devices: HashSet<String>,
+
+    /// Set if the guest memory is shared with the host, as required by
+    /// vhost-user devices.
+    pub(crate) memory_shared: bool,
+
+    /// Maximum size in MiB of the guest memory, including hot added memory.
+    pub(crate) max_memory_mb: u64,
+
+    /// Size in MiB of the memory the VM booted with.
+    pub(crate) boot_memory_mb: u64,
+
+    /// How memory is hot added to the VM.
+    pub(crate) memory_hotplug_method: HotplugMethod,
+
+    /// Number of vCPUs the VM booted with.
+    pub(crate) boot_vcpus: u32,
+
+    /// Maximum number of vCPUs, including hot added vCPUs.
+    pub(crate) max_vcpus: u32,
+
+    /// vCPU shrink planned or in progress.
+    pub(crate) vcpu_shrink: Option<VcpuShrink>,
+
+    /// Free memory of the guest in MiB, from its most recent stats.
+    pub(crate) guest_free_mb: Option<u64>,
+
+    /// Size in MiB of the part of the balloon inflated on memory hints,
+    /// deflated first when the guest needs memory again.
+    pub(crate) hinted_balloon_mb: u64,
+
+    /// Requested offset in seconds of the guest clock relative to the host.
+    pub(crate) guest_clock_offset: i64,
+
+    /// Vsock port the guest agent listens on.
+    pub(crate) agent_vsock_port: u32,
+
+    /// Tap fds to restore the net devices of a snapshot with, by device id.
+    /// The fds are owned by the caller.
+    pub(crate) restore_net_fds: HashMap<String, Vec<RawFd>>,
+
+    /// Huge pages requested by the pod, backing the guest memory and
+    /// reserved in the guest.
+    pub(crate) hugepage_requests: Vec<HugePagesRequest>,
+
+    /// Host NUMA node to place the VM on, overriding the configured one,
+    /// such as the node selected by the Topology Manager.
+    pub(crate) host_numa_node: Option<u32>,
+
+    /// Digests of the files the VM booted from, if measured.
+    pub(crate) boot_measurements: Option<BootMeasurements>,
+
+    /// Data bound into the launch measurement of a confidential guest.
+    pub(crate) launch_data: LaunchData,
+
+    /// Values of the device counters when they were last reset, by device
+    /// id and counter name, subtracted from the values reported by CH.
+    pub(crate) counter_baselines: VmCounters,
+
+    /// Pids of the helper processes of the VM, such as virtiofsd.
+    pub(crate) helper_pids: Vec<u32>,
+
+    /// Reader of the CH events, if enabled.
+    pub(crate) event_monitor: Option<EventMonitor>,
 
     pub(crate) _capabilities: Capabilities,
 
     pub(crate) shutdown_tx: Option<Sender<bool>>,
     pub(crate) shutdown_rx: Option<Receiver<bool>>,
     pub(crate) tasks: Option<Vec<JoinHandle<Result<()>>>>,
+
+    /// Reports the exit of the CH process, once it has been launched.
+    pub(crate) exit_rx: Option<Receiver<Option<VmmExit>>>,
+
+    /// Set once the VMM is being stopped, so that its exit is expected.
+    pub(crate) stop_requested: Arc<AtomicBool>,
+}
+
+/// Exit of the CH process, as reported by the process monitor.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct VmmExit {
+    /// Exit status of the process: "status <code>" or "signal <number>".
+    pub(crate) status: String,
+
+    /// Set if the process exited because a shutdown was requested.
+    pub(crate) requested: bool,
+
+    /// Why the process exited.
+    pub(crate) reason: VmExitReason,
+}
+
+/// Reason the VMM terminated.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum VmExitReason {
+    /// The VMM was stopped by the runtime.
+    Stopped,
+
+    /// The guest shut down, so the VMM exited successfully.
+    GuestShutdown,
+
+    /// The guest watchdog expired.
+    Watchdog,
+
+    /// The VMM exited with the exit code.
+    Exited(i32),
+
+    /// The VMM was killed by the signal.
+    Signaled(i32),
+
+    /// The exit status of the VMM could not be determined.
+    #[default]
+    Unknown,
+}
+
+impl fmt::Display for VmExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmExitReason::Stopped => write!(f, "stopped"),
+            VmExitReason::GuestShutdown => write!(f, "guest shutdown"),
+            VmExitReason::Watchdog => write!(f, "guest watchdog expired"),
+            VmExitReason::Exited(code) => write!(f, "exited with status {}", code),
+            VmExitReason::Signaled(signal) => write!(f, "killed by signal {}", signal),
+            VmExitReason::Unknown => write!(f, "unknown exit status"),
+        }
+    }
 }
 
 unsafe impl Send for CloudHypervisorInner {}
@@ -81,24 +257,169 @@ impl CloudHypervisorInner {
             timeout_secs: CH_DEFAULT_TIMEOUT_SECS as i32,
             id: String::default(),
             jailer_root: String::default(),
+            jail: None,
             vm_path: String::default(),
             run_dir: String::default(),
             netns: None,
             pending_devices: None,
+            paused_devices: Vec::new(),
+            console_log_path: None,
+            console_socket_path: None,
+            console_buffer: None,
+            vmm_log_path: None,
+            artifacts: Artifacts::default(),
+            ch_version: None,
+            pci_segments: PciSegmentAllocator::default(),
+            removing_devices: HashMap::new(),
+            added_devices: HashMap::new(),
+            activated_devices: HashSet::new(),
+            memory_shared: false,
+            max_memory_mb: 0,
+            boot_memory_mb: 0,
+            memory_hotplug_method: HotplugMethod::default(),
+            boot_vcpus: 0,
+            max_vcpus: 0,
+            vcpu_shrink: None,
+            guest_free_mb: None,
+            hinted_balloon_mb: 0,
+            guest_clock_offset: 0,
+            agent_vsock_port: DEFAULT_AGENT_VSOCK_PORT,
+            restore_net_fds: HashMap::new(),
+            hugepage_requests: Vec::new(),
+            host_numa_node: None,
+            boot_measurements: None,
+            launch_data: LaunchData::new(),
+            counter_baselines: VmCounters::new(),
+            helper_pids: Vec::new(),
+            event_monitor: None,
             _capabilities: capabilities,
             shutdown_tx: Some(tx),
             shutdown_rx: Some(rx),
             tasks: None,
+            exit_rx: None,
+            stop_requested: Arc::new(AtomicBool::new(false)),
         }
     }
 
     pub fn set_hypervisor_config(&mut self, config: HypervisorConfig) {
+        self.guest_clock_offset = config.machine_info.guest_clock_offset;
         self.config = Some(config);
     }
 
     pub fn hypervisor_config(&self) -> HypervisorConfig {
         self.config.clone().unwrap_or_default()
     }
+
+    // Take the control back of the VM of a sandbox after a shim restart,
+    // from the state saved when the VM started. CH must still run the VM
+    // and answer on its API socket, otherwise ChError::VmmLost is returned.
+    pub(crate) async fn recover(id: &str) -> Result<Self> {
+        let path = get_recovery_state_path(id)?;
+
+        let saved = RecoveryState::load(Path::new(&path))?;
+
+        if saved.id != id {
+            return Err(anyhow!(
+                "recovery state {} is for sandbox {}",
+                path,
+                saved.id
+            ));
+        }
+
+        let proc_dir = PathBuf::from(format!("/proc/{}", saved.pid));
+
+        if !is_vmm_process(&proc_dir, Path::new(&saved.config.path)) {
+            return Err(ChError::VmmLost {
+                reason: format!("process {} is not {}", saved.pid, saved.config.path),
+            }
+            .into());
+        }
+
+        let jail = if saved.jailed {
+            Some(Jail::restore(&saved.jailer_root)?)
+        } else {
+            None
+        };
+
+        let mut ch = Self {
+            id: saved.id,
+            pid: Some(saved.pid),
+            vm_path: saved.vm_path,
+            run_dir: saved.run_dir,
+            netns: saved.netns,
+            jailer_root: saved.jailer_root,
+            jail,
+            pci_segments: saved.pci_segments,
+            added_devices: saved.added_devices,
+            boot_memory_mb: saved.boot_memory_mb,
+            max_memory_mb: saved.max_memory_mb,
+            memory_hotplug_method: saved.memory_hotplug_method,
+            boot_vcpus: saved.boot_vcpus,
+            max_vcpus: saved.max_vcpus,
+            memory_shared: saved.memory_shared,
+            hugepage_requests: saved.hugepage_requests,
+            // Helpers which exited while the shim was down are forgotten.
+            helper_pids: saved
+                .helper_pids
+                .into_iter()
+                .filter(|pid| is_process_running(&PathBuf::from(format!("/proc/{}", pid))))
+                .collect(),
+            console_log_path: saved.console_log_path,
+            console_socket_path: saved.console_socket_path,
+            console_buffer: None,
+            vmm_log_path: saved.vmm_log_path,
+            launch_data: saved.launch_data,
+            artifacts: Artifacts::from_state(&saved.artifacts)?,
+
+            ..Default::default()
+        };
+
+        ch.set_hypervisor_config(saved.config);
+
+        // The offset set for the sandbox overrides the configured one.
+        ch.guest_clock_offset = saved.guest_clock_offset;
+
+        // The API socket peer is checked to be the recorded process.
+        if let Err(e) = ch.adopt_api_socket_path(&saved.api_socket_path).await {
+            return Err(ChError::VmmLost {
+                reason: format!("{:#}", e),
+            }
+            .into());
+        }
+
+        if !matches!(ch.state, VmmState::VmRunning | VmmState::VmPaused) {
+            return Err(ChError::VmmLost {
+                reason: format!("no VM running, VMM state {:?}", ch.state),
+            }
+            .into());
+        }
+
+        ch.event_monitor = reopen_event_monitor(&ch.hypervisor_config(), &ch.id)?;
+
+        info!(
+            sl!(),
+            "recovered VM with PID {} and state {:?}", saved.pid, ch.state
+        );
+
+        Ok(ch)
+    }
+}
+
+// Read again the events of a running CH, which keeps writing them.
+fn reopen_event_monitor(config: &HypervisorConfig, id: &str) -> Result<Option<EventMonitor>> {
+    if !config.machine_info.enable_event_monitor {
+        return Ok(None);
+    }
+
+    let path = get_event_monitor_path(id)?;
+
+    match EventMonitor::open(Path::new(&path)) {
+        Ok(monitor) => Ok(Some(monitor)),
+        Err(e) => {
+            warn!(sl!(), "event monitor not restored: {:#}", e);
+            Ok(None)
+        }
+    }
 }
 
 impl Default for CloudHypervisorInner {
@@ -118,12 +439,15 @@ impl Persist for CloudHypervisorInner {
             hypervisor_type: HYPERVISOR_NAME_CH.to_string(),
             id: self.id.clone(),
             vm_path: self.vm_path.clone(),
-            jailed: false,
-            jailer_root: String::default(),
-            netns: None,
+            pid: self.pid.map(|pid| pid as i32),
+            jailed: self.jail.is_some(),
+            jailer_root: self.jailer_root.clone(),
+            netns: self.netns.clone(),
             config: self.hypervisor_config(),
             run_dir: self.run_dir.clone(),
             cached_block_devices: Default::default(),
+            launch_data: self.launch_data.clone(),
+            artifacts: self.artifacts.to_state(),
             ..Default::default()
         })
     }
@@ -133,12 +457,27 @@ impl Persist for CloudHypervisorInner {
         _hypervisor_args: Self::ConstructorArgs,
         hypervisor_state: Self::State,
     ) -> Result<Self> {
+        let jail = if hypervisor_state.jailed {
+            Some(Jail::restore(&hypervisor_state.jailer_root)?)
+        } else {
+            None
+        };
+
+        let event_monitor = reopen_event_monitor(&hypervisor_state.config, &hypervisor_state.id)?;
+
         let ch = Self {
             config: Some(hypervisor_state.config),
             state: VmmState::NotReady,
             id: hypervisor_state.id,
             vm_path: hypervisor_state.vm_path,
             run_dir: hypervisor_state.run_dir,
+            pid: hypervisor_state.pid.map(|pid| pid as u32),
+            netns: hypervisor_state.netns,
+            jailer_root: hypervisor_state.jailer_root,
+            jail,
+            event_monitor,
+            launch_data: hypervisor_state.launch_data,
+            artifacts: Artifacts::from_state(&hypervisor_state.artifacts)?,
 
             ..Default::default()
         };
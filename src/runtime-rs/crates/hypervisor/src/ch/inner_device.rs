@@ -4,48 +4,348 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use super::confidential::{check_confidential_device, device_kind};
+use super::errors::{is_resource_exhausted, ChError};
 use super::inner::CloudHypervisorInner;
-use crate::device::{Device, ShareFsDeviceConfig};
+use super::pci_segments::DeviceCounts;
+use super::utils::{get_backing_size, is_unix_socket_listening};
+use crate::device::{
+    AddedDevice, BlockConfig, Device, PmemDeviceConfig, ShareFsDeviceConfig, VhostUserNetConfig,
+};
 use crate::HybridVsockConfig;
 use crate::VmmState;
 use anyhow::{anyhow, Context, Result};
-use ch_config::ch_api::cloud_hypervisor_vm_fs_add;
-use ch_config::{FsConfig, PmemConfig};
+use ch_config::ch_api::{
+    cloud_hypervisor_vm_disk_add, cloud_hypervisor_vm_fs_add, cloud_hypervisor_vm_info,
+    cloud_hypervisor_vm_netdev_add, cloud_hypervisor_vm_pmem_add,
+    cloud_hypervisor_vm_remove_device,
+};
+use ch_config::{
+    DiskConfig, FsConfig, MacAddr, NetConfig, PciDeviceInfo, PmemConfig, RateLimiterConfig,
+    TokenBucketConfig, VmRemoveDeviceData,
+};
+use kata_types::config::hypervisor::{
+    BootInfo, CH_GUEST_PCI_HOTPLUG_NONE, CH_IOMMU_DEVICE_NET, CH_IOMMU_DEVICE_PMEM,
+};
 use safe_path::scoped_join;
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::future::Future;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use tokio::time::{Duration, Instant};
 
 const VIRTIO_FS: &str = "virtio-fs";
 
+// Delay before retrying to add a device after a host resource exhaustion.
+const DEVICE_ADD_RETRY_DELAY_MS: u64 = 500;
+
+// A virtio-net device needs at least one RX and one TX queue.
+const DEFAULT_NET_NUM_QUEUES: usize = 2;
+
+// Time to wait for the guest to release a device being removed.
+const DEVICE_REMOVE_TIMEOUT_SECS: u64 = 10;
+
+// Interval between checks that a device being removed was released.
+const DEVICE_REMOVE_POLL_MS: u64 = 100;
+
+// Interval between checks that a device added to the VM is ready.
+pub(crate) const DEVICE_READY_POLL_MS: u64 = 100;
+
+// Interval between checks that a vhost-user daemon listens on its socket.
+const VHOST_USER_SOCKET_POLL_MS: u64 = 50;
+
+// Time for the token buckets of the rate limiters to refill completely, so
+// that their size is the rate per second.
+const RATE_LIMITER_REFILL_TIME_MS: u64 = 1000;
+
+/// How a failure to add a device is handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeviceCriticality {
+    /// The failure is returned, failing the sandbox.
+    #[default]
+    Required,
+    /// The device is dropped and the sandbox carries on without it.
+    Optional,
+}
+
+/// How a device added before the VM boots is plugged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DevicePlugMode {
+    /// Add the device to the VM config used to boot the VM, when supported
+    /// for the device type.
+    #[default]
+    ColdPlug,
+    /// Hot plug the device once the VM is running.
+    HotPlug,
+}
+
+/// Outcome for a device of a group added by add_devices().
+#[derive(Debug)]
+pub enum DeviceGroupResult {
+    /// The device was added, as were all the devices of the group.
+    Added,
+    /// The device was added, then removed as another device of the group
+    /// failed.
+    RolledBack,
+    /// The device failed to be added.
+    Failed(anyhow::Error),
+    /// The device was not attempted, as a device before it failed.
+    Skipped,
+    /// The device was added and could not be removed after another device
+    /// of the group failed, so is still used by the VM.
+    RollbackFailed(anyhow::Error),
+}
+
+/// A device waiting for the VM to boot.
+#[derive(Debug)]
+pub(crate) struct PendingDevice {
+    pub(crate) device: Device,
+    pub(crate) criticality: DeviceCriticality,
+    pub(crate) plug_mode: DevicePlugMode,
+}
+
 impl CloudHypervisorInner {
     pub(crate) async fn add_device(&mut self, device: Device) -> Result<()> {
+        self.add_device_with_criticality(device, DeviceCriticality::default())
+            .await
+    }
+
+    pub(crate) async fn add_device_with_criticality(
+        &mut self,
+        device: Device,
+        criticality: DeviceCriticality,
+    ) -> Result<()> {
+        self.add_device_with_options(device, criticality, DevicePlugMode::default())
+            .await
+            .map(|_| ())
+    }
+
+    // Add a device, returning what the guest needs to use it once added.
+    // Returns None if the device is queued until the VM runs, or if it is
+    // optional and was dropped.
+    pub(crate) async fn add_device_with_options(
+        &mut self,
+        device: Device,
+        criticality: DeviceCriticality,
+        plug_mode: DevicePlugMode,
+    ) -> Result<Option<AddedDevice>> {
+        if let (Device::Vfio(cfg), Some((uid, gid))) = (&device, self.get_vmm_user()) {
+            return Err(anyhow!(
+                "VFIO device {} cannot be used with CH running as user {}:{}: VFIO requires access to the IOMMU groups and locking the guest memory",
+                cfg.id,
+                uid,
+                gid
+            ));
+        }
+
+        // A device added once the VM started is hot plugged, even if queued
+        // while the VM is paused.
+        if matches!(self.state, VmmState::VmRunning | VmmState::VmPaused) {
+            self.check_pci_hotplug()?;
+        }
+
+        if self.state == VmmState::VmPaused {
+            self.paused_devices.push(PendingDevice {
+                device,
+                criticality,
+                plug_mode,
+            });
+
+            return Ok(None);
+        }
+
         if self.state != VmmState::VmRunning {
-            let mut devices: Vec<Device> = if let Some(devices) = self.pending_devices.take() {
+            let mut devices: Vec<PendingDevice> = if let Some(devices) = self.pending_devices.take()
+            {
                 devices
             } else {
                 vec![]
             };
 
-            devices.insert(0, device);
+            devices.insert(
+                0,
+                PendingDevice {
+                    device,
+                    criticality,
+                    plug_mode,
+                },
+            );
 
             self.pending_devices = Some(devices);
 
-            return Ok(());
+            return Ok(None);
         }
 
-        self.handle_add_device(device).await?;
+        let description = device.to_string();
+        let kata_id = device_kata_id(&device).map(String::from);
+        let labels = device.labels().cloned().unwrap_or_default();
 
-        Ok(())
+        match self.handle_add_device(device).await {
+            Ok(mut added) => {
+                added.labels = labels;
+
+                if let Some(id) = kata_id {
+                    self.added_devices.insert(id, added.clone());
+                }
+
+                self.save_recovery_state();
+
+                Ok(Some(added))
+            }
+            Err(e) => handle_device_result(&description, criticality, Err(e)).map(|_| None),
+        }
     }
 
-    async fn handle_add_device(&mut self, device: Device) -> Result<()> {
+    // Add a group of devices, such as the network interfaces of a pod, as a
+    // unit: if a device fails, the devices of the group already added are
+    // removed, in reverse order, and the remaining ones are not attempted.
+    // Optional devices are not dropped silently, as the group would then be
+    // partially added.
+    pub(crate) async fn add_devices(&mut self, devices: Vec<Device>) -> Vec<DeviceGroupResult> {
+        let mut results: Vec<DeviceGroupResult> = Vec::with_capacity(devices.len());
+        let mut added: Vec<(usize, Option<String>)> = vec![];
+
+        for device in devices {
+            if results
+                .iter()
+                .any(|r| matches!(r, DeviceGroupResult::Failed(_)))
+            {
+                results.push(DeviceGroupResult::Skipped);
+                continue;
+            }
+
+            let kata_id = device_kata_id(&device).map(String::from);
+
+            match self
+                .add_device_with_options(
+                    device,
+                    DeviceCriticality::Required,
+                    DevicePlugMode::default(),
+                )
+                .await
+            {
+                Ok(_) => {
+                    added.push((results.len(), kata_id));
+                    results.push(DeviceGroupResult::Added);
+                }
+                Err(e) => {
+                    warn!(
+                        sl!(),
+                        "failed to add device {} of a group, rolling back {} device(s): {:?}",
+                        kata_id.as_deref().unwrap_or_default(),
+                        added.len(),
+                        e
+                    );
+
+                    results.push(DeviceGroupResult::Failed(e));
+                }
+            }
+        }
+
+        if added.len() == results.len() {
+            return results;
+        }
+
+        for (index, kata_id) in added.into_iter().rev() {
+            results[index] = match self.remove_group_device(kata_id.as_deref()).await {
+                Ok(()) => DeviceGroupResult::RolledBack,
+                Err(e) => {
+                    error!(sl!(), "failed to roll back device: {:?}", e);
+
+                    DeviceGroupResult::RollbackFailed(e)
+                }
+            };
+        }
+
+        results
+    }
+
+    // Remove a device added by add_devices(), whether it was queued or
+    // added to the VM.
+    async fn remove_group_device(&mut self, kata_id: Option<&str>) -> Result<()> {
+        let id = kata_id.ok_or_else(|| anyhow!("device without an id cannot be removed"))?;
+
+        if self.remove_pending_device(id) {
+            return Ok(());
+        }
+
+        self.remove_vm_device(id, Duration::from_secs(DEVICE_REMOVE_TIMEOUT_SECS))
+            .await
+    }
+
+    async fn handle_add_device(&mut self, device: Device) -> Result<AddedDevice> {
+        if self.is_confidential_guest() {
+            check_confidential_device(device_kind(&device), device_kata_id(&device))?;
+        }
+
         match device {
             Device::ShareFsDevice(cfg) => self.handle_share_fs_device(cfg).await,
             Device::HybridVsock(cfg) => self.handle_hvsock_device(&cfg).await,
+            Device::VhostUserNet(cfg) => {
+                let id = cfg.id.clone();
+
+                let address = self.handle_vhost_user_net_device(cfg).await?;
+
+                info!(
+                    sl!(),
+                    "vhost-user-net device {} added at guest address {}", id, address
+                );
+
+                Ok(AddedDevice {
+                    id,
+                    guest_pci_address: Some(address),
+                    ..Default::default()
+                })
+            }
+            Device::Pmem(cfg) => {
+                let id = cfg.id.clone();
+
+                let address = self.handle_pmem_device(cfg).await?;
+
+                info!(
+                    sl!(),
+                    "pmem device {} added at guest address {}", id, address
+                );
+
+                Ok(AddedDevice {
+                    id,
+                    guest_pci_address: Some(address),
+                    ..Default::default()
+                })
+            }
             _ => return Err(anyhow!("unhandled device: {:?}", device)),
         }
     }
 
+    /// Add the devices that were requested to be added while the VM was
+    /// paused. Optional devices which fail to be added are dropped. All the
+    /// devices are tried, and the failures reported together.
+    pub(crate) async fn handle_paused_devices_after_resume(&mut self) -> Result<()> {
+        let devices = std::mem::take(&mut self.paused_devices);
+
+        let mut errors = Vec::new();
+
+        for dev in devices {
+            let id = device_kata_id(&dev.device).unwrap_or_default().to_string();
+
+            if let Err(e) = self
+                .add_device_with_options(dev.device, dev.criticality, dev.plug_mode)
+                .await
+            {
+                errors.push(format!("{}: {:#}", id, e));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "failed to add devices queued while paused: {}",
+                errors.join("; ")
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Add the device that were requested to be added before the VMM was
     /// started.
     #[allow(dead_code)]
@@ -59,18 +359,440 @@ impl CloudHypervisorInner {
 
         if let Some(mut devices) = self.pending_devices.take() {
             while let Some(dev) = devices.pop() {
-                self.add_device(dev).await.context("add_device")?;
+                self.add_device_with_options(dev.device, dev.criticality, dev.plug_mode)
+                    .await
+                    .context("add_device")?;
             }
         }
 
         Ok(())
     }
 
-    pub(crate) async fn remove_device(&mut self, _device: Device) -> Result<()> {
+    pub(crate) async fn remove_device(&mut self, device: Device) -> Result<()> {
+        let id = match device_kata_id(&device) {
+            Some(id) => id.to_string(),
+            None => return Ok(()),
+        };
+
+        if self.remove_pending_device(&id) {
+            return Ok(());
+        }
+
+        self.remove_vm_device(&id, Duration::from_secs(DEVICE_REMOVE_TIMEOUT_SECS))
+            .await
+    }
+
+    /// Remove a device, tearing down its host side state if the guest does
+    /// not release it within the timeout. Returns true if the removal was
+    /// forced, in which case the guest may be left in a degraded state.
+    ///
+    /// The backend daemon of a device, such as virtiofsd, is stopped if it
+    /// is a helper process registered with the driver.
+    pub(crate) async fn remove_device_force(
+        &mut self,
+        device: Device,
+        timeout: Duration,
+    ) -> Result<bool> {
+        let id = match device_kata_id(&device) {
+            Some(id) => id.to_string(),
+            None => return Ok(false),
+        };
+
+        if self.remove_pending_device(&id) {
+            return Ok(false);
+        }
+
+        let err = match self.remove_vm_device(&id, timeout).await {
+            Ok(()) => return Ok(false),
+            Err(e) => e,
+        };
+
+        // The daemon serving the socket would keep the device usable.
+        // Only a socket created in the VM directory is removed, so that it
+        // is not reused by the next device: a socket at an absolute path
+        // is provided by the user.
+        if let Device::ShareFsDevice(cfg) = &device {
+            if cfg.sock_path.starts_with('/') {
+                self.stop_socket_helpers(Path::new(&cfg.sock_path)).await;
+            } else {
+                let path = scoped_join(&self.vm_path, &cfg.sock_path)?;
+
+                self.stop_socket_helpers(&path).await;
+
+                if let Err(e) = std::fs::remove_file(&path) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        warn!(sl!(), "failed to remove socket {:?}: {:?}", path, e);
+                    }
+                }
+            }
+        }
+
+        if let Some(segment) = self.removing_devices.remove(&id) {
+            self.pci_segments.release(segment);
+        }
+
+        let labels = self.get_device_labels(&id);
+
+        self.added_devices.remove(&id);
+        self.counter_baselines.remove(&id);
+
+        warn!(
+            sl!(),
+            "device {} forcibly removed, the guest may be degraded: {:#}", id, err;
+            "event" => "device-forced-removal",
+            "device" => id.clone(),
+            "labels" => labels
+        );
+
+        Ok(true)
+    }
+
+    // Point a block device at a new backing file of the same size, such as
+    // a rotated snapshot of a volume. A device not added to the VM yet is
+    // just updated.
+    //
+    // CH cannot change the backing file of a disk, so the disk is removed
+    // from the VM and added again with the same id on the same PCI segment,
+    // the original file being added back if the new one cannot be. CH only
+    // removes a disk once the guest releases it, which a paused guest
+    // cannot do, so the VM is not paused: the guest sees the disk go and
+    // come back, and must not use it meanwhile.
+    pub(crate) async fn replace_block_backing(
+        &mut self,
+        kata_id: &str,
+        new_path: &str,
+    ) -> Result<()> {
+        let new_path = PathBuf::from(new_path);
+        let new_size = get_backing_size(&new_path)?;
+
+        if let Some(cfg) = self.get_queued_block_device(kata_id) {
+            check_backing_size(get_backing_size(Path::new(&cfg.path_on_host))?, new_size)?;
+
+            info!(
+                sl!(),
+                "block device {} backing file replaced: {} -> {:?}",
+                kata_id,
+                cfg.path_on_host,
+                new_path
+            );
+
+            cfg.path_on_host = new_path.display().to_string();
+
+            return Ok(());
+        }
+
+        if self.state != VmmState::VmRunning {
+            return Err(anyhow!(
+                "cannot replace block device backing file with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        self.check_pci_hotplug()?;
+
+        let added = self.added_devices.get(kata_id).cloned();
+
+        let id = added
+            .as_ref()
+            .map(|d| d.id.clone())
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| kata_id.to_string());
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let info =
+            cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await?;
+
+        let disk = info
+            .config
+            .and_then(|c| c.disks)
+            .into_iter()
+            .flatten()
+            .find(|d| d.id.as_deref() == Some(id.as_str()))
+            .ok_or_else(|| anyhow!("block device {} not in the VM", kata_id))?;
+
+        let old_path = disk
+            .path
+            .clone()
+            .ok_or_else(|| anyhow!("block device {} has no backing file", kata_id))?;
+
+        check_backing_size(get_backing_size(&old_path)?, new_size)?;
+
+        let new_disk = DiskConfig {
+            path: Some(self.get_jail_path(&new_path, disk.readonly)?),
+            ..disk.clone()
+        };
+
+        self.remove_vm_device(&id, Duration::from_secs(DEVICE_REMOVE_TIMEOUT_SECS))
+            .await
+            .context(format!("failed to remove block device {}", kata_id))?;
+
+        let err = match self.add_disk(kata_id, added.clone(), new_disk).await {
+            Ok(()) => {
+                info!(
+                    sl!(),
+                    "block device {} backing file replaced: {:?} -> {:?}",
+                    kata_id,
+                    old_path,
+                    new_path
+                );
+
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        match self.add_disk(kata_id, added, disk).await {
+            Ok(()) => Err(err.context(format!(
+                "failed to replace the backing file of block device {}, restored {:?}",
+                kata_id, old_path
+            ))),
+            Err(e) => Err(anyhow!(
+                "failed to replace the backing file of block device {}: {:#}, and to restore {:?}: {:#}",
+                kata_id,
+                err,
+                old_path,
+                e
+            )),
+        }
+    }
+
+    // Return the configuration of a block device queued before the VM
+    // booted or while it was paused.
+    fn get_queued_block_device(&mut self, kata_id: &str) -> Option<&mut BlockConfig> {
+        self.paused_devices
+            .iter_mut()
+            .chain(self.pending_devices.iter_mut().flatten())
+            .find_map(|dev| match &mut dev.device {
+                Device::Block(cfg) if cfg.id == kata_id => Some(cfg),
+                _ => None,
+            })
+    }
+
+    // Add a disk to the VM on the PCI segment of its config, recording it
+    // as the device with the kata id.
+    async fn add_disk(
+        &mut self,
+        kata_id: &str,
+        added: Option<AddedDevice>,
+        disk: DiskConfig,
+    ) -> Result<()> {
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?
+            .try_clone()
+            .context("failed to clone socket")?;
+
+        let id = disk.id.clone().unwrap_or_default();
+        let pci_segment = disk.pci_segment;
+
+        self.pci_segments.try_reserve(pci_segment, &id)?;
+
+        let response = match cloud_hypervisor_vm_disk_add(socket, disk).await {
+            Ok(response) => response,
+            Err(e) => {
+                self.pci_segments.release(pci_segment);
+
+                return Err(e);
+            }
+        };
+
+        if let Some(detail) = &response {
+            debug!(sl!(), "disk add response: {:?}", detail);
+        }
+
+        let guest_pci_address = response
+            .as_deref()
+            .and_then(|detail| serde_json::from_str::<PciDeviceInfo>(detail).ok())
+            .map(|info| info.bdf);
+
+        if let Some(added) = added {
+            self.added_devices.insert(
+                kata_id.to_string(),
+                AddedDevice {
+                    id,
+                    guest_pci_address,
+                    ..added
+                },
+            );
+        }
+
+        self.save_recovery_state();
+
+        Ok(())
+    }
+
+    // Ask the guest to release a hot plugged device, then wait for CH to
+    // report it gone. Removing a device which is not in the VM succeeds.
+    //
+    // While the guest holds the device, its PCI segment remains allocated
+    // and is recorded in removing_devices.
+    async fn remove_vm_device(&mut self, id: &str, timeout: Duration) -> Result<()> {
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let segment = match self.get_device_pci_segment(id).await? {
+            Some(segment) => segment,
+            None => {
+                debug!(sl!(), "device {} not in the VM, nothing to remove", id);
+
+                return Ok(());
+            }
+        };
+
+        // A device already being removed is not removed again, only waited
+        // for.
+        if self
+            .removing_devices
+            .insert(id.to_string(), segment)
+            .is_none()
+        {
+            cloud_hypervisor_vm_remove_device(
+                socket.try_clone().context("failed to clone socket")?,
+                VmRemoveDeviceData { id: id.to_string() },
+            )
+            .await
+            .context(format!("failed to remove device {}", id))?;
+        }
+
+        let deadline = Instant::now() + timeout;
+
+        while self.get_device_pci_segment(id).await?.is_some() {
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "device {} not released by the guest after {:?}",
+                    id,
+                    timeout
+                ));
+            }
+
+            // The event monitor reports the release of the device as it
+            // happens.
+            self.wait_vm_events(Duration::from_millis(DEVICE_REMOVE_POLL_MS))
+                .await;
+        }
+
+        self.removing_devices.remove(id);
+        self.pci_segments.release(segment);
+
+        let labels = self.get_device_labels(id);
+
+        self.added_devices.remove(id);
+        self.counter_baselines.remove(id);
+
+        self.save_recovery_state();
+
+        info!(sl!(), "device {} removed", id; "labels" => labels);
+
         Ok(())
     }
 
-    async fn handle_share_fs_device(&mut self, cfg: ShareFsDeviceConfig) -> Result<()> {
+    // Return a device added to the VM if the guest can use it, or None if
+    // it is not ready yet. A virtio device is ready once its guest driver
+    // activated it, as reported by the event monitor. Passed through devices
+    // are not activated, and activations are not known without the event
+    // monitor, so such devices are ready once CH holds them.
+    pub(crate) async fn get_ready_device(&mut self, kata_id: &str) -> Result<Option<AddedDevice>> {
+        let added = self
+            .added_devices
+            .get(kata_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("device {} not added to the VM", kata_id))?;
+
+        let id = Some(added.id.clone())
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| kata_id.to_string());
+
+        self.apply_vm_events();
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let cfg = cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?)
+            .await?
+            .config
+            .unwrap_or_default();
+
+        if !is_device_ready(
+            cfg.device_pci_segment(&id).is_some(),
+            cfg.is_passthrough_device(&id),
+            self.event_monitor.is_some(),
+            self.activated_devices.contains(&id),
+        ) {
+            return Ok(None);
+        }
+
+        info!(sl!(), "device {} ready", kata_id; "labels" => self.get_device_labels(kata_id));
+
+        Ok(Some(added))
+    }
+
+    // Return the PCI segment of the device with the specified id, or None
+    // if the VM does not hold the device.
+    async fn get_device_pci_segment(&self, id: &str) -> Result<Option<u16>> {
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let info =
+            cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await?;
+
+        Ok(info.config.and_then(|config| config.device_pci_segment(id)))
+    }
+
+    /// Withdraw a device that was queued by add_device() before the VM
+    /// booted or while it was paused. No CH API call is made. Returns true
+    /// if the device was found (and removed) from the pending list.
+    pub(crate) fn remove_pending_device(&mut self, kata_id: &str) -> bool {
+        // A device queued while the VM is paused is not in the VM either.
+        let pos = self
+            .paused_devices
+            .iter()
+            .position(|dev| device_kata_id(&dev.device) == Some(kata_id));
+
+        if let Some(pos) = pos {
+            let dev = self.paused_devices.remove(pos);
+
+            debug!(sl!(), "removed paused device: {:?}", dev);
+
+            return true;
+        }
+
+        let devices = match self.pending_devices.as_mut() {
+            Some(devices) => devices,
+            None => return false,
+        };
+
+        let pos = devices
+            .iter()
+            .position(|dev| device_kata_id(&dev.device) == Some(kata_id));
+
+        if let Some(pos) = pos {
+            let dev = devices.remove(pos);
+
+            debug!(sl!(), "removed pending device: {:?}", dev);
+
+            return true;
+        }
+
+        false
+    }
+
+    async fn handle_share_fs_device(&mut self, cfg: ShareFsDeviceConfig) -> Result<AddedDevice> {
         if cfg.fs_type != VIRTIO_FS {
             return Err(anyhow!("cannot handle share fs type: {:?}", cfg.fs_type));
         }
@@ -93,60 +815,533 @@ impl CloudHypervisorInner {
             1024
         };
 
-        let socket_path = if cfg.sock_path.starts_with('/') {
-            PathBuf::from(cfg.sock_path)
+        let wait_ms = self
+            .config
+            .as_ref()
+            .map(|c| c.shared_fs.virtio_fs_socket_wait_ms)
+            .unwrap_or_default();
+
+        if wait_ms > 0 {
+            self.wait_vhost_user_socket(&cfg.sock_path, Duration::from_millis(wait_ms as u64))
+                .await?;
+        }
+
+        // Resolving and checking the socket path has no side effect, so
+        // can be retried as is.
+        let socket_path = self.get_vhost_user_socket_path(&cfg.sock_path)?;
+
+        self.grant_vmm_socket_access(&socket_path.display().to_string())?;
+
+        let tag = cfg.mount_tag;
+
+        let pci_segment = self.pci_segments.allocate(&tag)?;
+
+        // The mount tag is unique, so is used as the CH device id to be able
+        // to remove the device if adding it fails.
+        let fs_config = FsConfig {
+            tag: tag.clone(),
+            socket: socket_path,
+            num_queues,
+            queue_size,
+            id: Some(tag.clone()),
+            pci_segment,
+            ..Default::default()
+        };
+
+        let result = add_device_with_retry(&tag, || {
+            let socket = socket.try_clone();
+            let fs_config = fs_config.clone();
+
+            async move {
+                cloud_hypervisor_vm_fs_add(socket.context("failed to clone socket")?, fs_config)
+                    .await
+            }
+        })
+        .await;
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                // CH may have failed after connecting to the backend, or the
+                // device may have been added before the request timed out:
+                // remove it so that adding it again starts from a clean
+                // state. This fails harmlessly if the device does not exist.
+                let removed = cloud_hypervisor_vm_remove_device(
+                    socket.try_clone().context("failed to clone socket")?,
+                    VmRemoveDeviceData { id: tag.clone() },
+                )
+                .await;
+
+                if let Err(err) = removed {
+                    debug!(sl!(), "fs device {} not removed: {:?}", tag, err);
+                }
+
+                self.pci_segments.release(pci_segment);
+
+                return Err(e);
+            }
+        };
+
+        if let Some(detail) = &response {
+            debug!(sl!(), "fs add response: {:?}", detail);
+        }
+
+        // The response holds the guest PCI address of the device.
+        let guest_pci_address = response
+            .as_deref()
+            .and_then(|detail| serde_json::from_str::<PciDeviceInfo>(detail).ok())
+            .map(|info| info.bdf);
+
+        let guest_mount_point = Some(cfg.guest_mount_point).filter(|p| !p.is_empty());
+
+        Ok(AddedDevice {
+            id: tag.clone(),
+            guest_pci_address,
+            mount_tag: Some(tag),
+            guest_mount_point,
+        })
+    }
+
+    // Resolve the path of a vhost-user backend socket, relative paths being
+    // relative to the sandbox directory, and check the backend is listening
+    // on it.
+    fn get_vhost_user_socket_path(&self, sock_path: &str) -> Result<PathBuf> {
+        let socket_path = self.get_vhost_user_host_path(sock_path)?;
+
+        let metadata = std::fs::metadata(&socket_path)
+            .context(format!("vhost-user socket {:?} not found", socket_path))?;
+
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("vhost-user path {:?} is not a socket", socket_path));
+        }
+
+        self.get_jail_socket_path(&socket_path)
+    }
+
+    fn get_vhost_user_host_path(&self, sock_path: &str) -> Result<PathBuf> {
+        if sock_path.starts_with('/') {
+            Ok(PathBuf::from(sock_path))
+        } else {
+            Ok(scoped_join(&self.vm_path, sock_path)?)
+        }
+    }
+
+    // Wait for the daemon serving a vhost-user socket to listen on it, as it
+    // may still be starting. The socket is not connected to, as the daemon
+    // would take the connection as the one of CH.
+    async fn wait_vhost_user_socket(&self, sock_path: &str, timeout: Duration) -> Result<()> {
+        let socket_path = self.get_vhost_user_host_path(sock_path)?;
+
+        let deadline = Instant::now() + timeout;
+
+        while !is_unix_socket_listening(&socket_path) {
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "vhost-user daemon not listening on {:?} after {:?}",
+                    socket_path,
+                    timeout
+                ));
+            }
+
+            tokio::time::sleep(Duration::from_millis(VHOST_USER_SOCKET_POLL_MS)).await;
+        }
+
+        Ok(())
+    }
+
+    // Return the path CH uses to access a host path, making it available in
+    // the jail when CH is confined.
+    pub(crate) fn get_jail_path(&self, host_path: &Path, read_only: bool) -> Result<PathBuf> {
+        match &self.jail {
+            Some(jail) => jail.bind(host_path, read_only),
+            None => Ok(host_path.to_path_buf()),
+        }
+    }
+
+    // Same as get_jail_path() for a socket, whose directory is made
+    // available as the socket may be created again.
+    pub(crate) fn get_jail_socket_path(&self, host_path: &Path) -> Result<PathBuf> {
+        match &self.jail {
+            Some(jail) => jail.bind_socket(host_path),
+            None => Ok(host_path.to_path_buf()),
+        }
+    }
+
+    // Add a vhost-user-net device, returning its guest PCI address.
+    async fn handle_vhost_user_net_device(&mut self, cfg: VhostUserNetConfig) -> Result<String> {
+        if !self.memory_shared {
+            return Err(anyhow!(
+                "vhost-user-net device {} requires shared guest memory",
+                cfg.id
+            ));
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let socket_path = self.get_vhost_user_socket_path(&cfg.socket_path)?;
+
+        let mac = cfg
+            .guest_mac
+            .as_ref()
+            .map(|addr| MacAddr { bytes: addr.0 })
+            .ok_or_else(|| anyhow!("vhost-user-net device {} has no guest MAC", cfg.id))?;
+
+        let num_queues: usize = if cfg.num_queues > 0 {
+            cfg.num_queues
+        } else {
+            DEFAULT_NET_NUM_QUEUES
+        };
+
+        let queue_size = if cfg.queue_size > 0 {
+            cfg.queue_size
         } else {
-            scoped_join(&self.vm_path, cfg.sock_path)?
+            self.get_net_queue_size()?
         };
 
-        let fs_config = FsConfig {
-            tag: cfg.mount_tag,
-            socket: socket_path,
-            num_queues,
-            queue_size,
-            ..Default::default()
-        };
+        let pci_segment = self.pci_segments.allocate(&cfg.id)?;
+
+        let net_config = NetConfig {
+            id: Some(cfg.id),
+            mac,
+            num_queues,
+            queue_size,
+            vhost_user: true,
+            vhost_socket: Some(socket_path.to_string_lossy().to_string()),
+            iommu: self.iommu_device(CH_IOMMU_DEVICE_NET),
+            pci_segment,
+            ..Default::default()
+        };
+
+        let id = net_config.id.clone().unwrap_or_default();
+
+        let result = add_device_with_retry(&id, || {
+            let socket = socket.try_clone();
+            let net_config = net_config.clone();
+
+            async move {
+                cloud_hypervisor_vm_netdev_add(
+                    socket.context("failed to clone socket")?,
+                    net_config,
+                )
+                .await
+            }
+        })
+        .await;
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.pci_segments.release(pci_segment);
+
+                return Err(e);
+            }
+        };
+
+        let detail = response.ok_or_else(|| anyhow!("no response for net add"))?;
+
+        debug!(sl!(), "net add response: {:?}", detail);
+
+        let info: PciDeviceInfo =
+            serde_json::from_str(&detail).context("failed to parse net add response")?;
+
+        Ok(info.bdf)
+    }
+
+    // Add a pmem device, returning its guest PCI address.
+    async fn handle_pmem_device(&mut self, cfg: PmemDeviceConfig) -> Result<String> {
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?
+            .try_clone()
+            .context("failed to clone socket")?;
+
+        let file = PathBuf::from(&cfg.path_on_host);
+
+        let metadata =
+            std::fs::metadata(&file).context(format!("pmem backing file {:?} not found", file))?;
+
+        if !metadata.is_file() && !metadata.file_type().is_block_device() {
+            return Err(anyhow!("pmem backing path {:?} is not a file", file));
+        }
+
+        let file = self.get_jail_path(&file, cfg.discard_writes)?;
+
+        let pci_segment = self.pci_segments.allocate(&cfg.id)?;
+
+        let pmem_config = PmemConfig {
+            file,
+            size: cfg.size,
+            iommu: cfg.iommu || self.iommu_device(CH_IOMMU_DEVICE_PMEM),
+            discard_writes: cfg.discard_writes,
+            id: Some(cfg.id.clone()),
+            pci_segment,
+        };
+
+        let result = add_device_with_retry(&cfg.id, || {
+            let socket = socket.try_clone();
+            let pmem_config = pmem_config.clone();
+
+            async move {
+                cloud_hypervisor_vm_pmem_add(socket.context("failed to clone socket")?, pmem_config)
+                    .await
+            }
+        })
+        .await
+        .and_then(|response| {
+            let detail = response.ok_or_else(|| anyhow!("no response for pmem add"))?;
+
+            debug!(sl!(), "pmem add response: {:?}", detail);
+
+            let info: PciDeviceInfo =
+                serde_json::from_str(&detail).context("failed to parse pmem add response")?;
+
+            Ok(info.bdf)
+        });
+
+        if result.is_err() {
+            self.pci_segments.release(pci_segment);
+        }
+
+        result
+    }
+
+    async fn handle_hvsock_device(&mut self, _cfg: &HybridVsockConfig) -> Result<AddedDevice> {
+        Ok(AddedDevice::default())
+    }
+
+    // Remove the pending devices to cold plug which match the filter,
+    // returning them in the order they were added. The other devices remain
+    // queued.
+    fn take_cold_plug_devices<F>(&mut self, filter: F) -> Result<Vec<PendingDevice>>
+    where
+        F: Fn(&Device) -> bool,
+    {
+        let devices = match self.pending_devices.take() {
+            Some(devices) => devices,
+            None => return Ok(vec![]),
+        };
+
+        // Devices are queued in reverse order.
+        let (mut taken, kept): (Vec<PendingDevice>, Vec<PendingDevice>) = devices
+            .into_iter()
+            .partition(|dev| dev.plug_mode == DevicePlugMode::ColdPlug && filter(&dev.device));
+
+        taken.reverse();
+
+        self.pending_devices = Some(kept);
+
+        // Confidential guests reject some devices, which are dropped if
+        // optional.
+        if self.is_confidential_guest() {
+            let mut allowed = Vec::with_capacity(taken.len());
+
+            for dev in taken {
+                let result = check_confidential_device(
+                    device_kind(&dev.device),
+                    device_kata_id(&dev.device),
+                );
+
+                if result.is_ok() {
+                    allowed.push(dev);
+                } else {
+                    handle_device_result(&dev.device.to_string(), dev.criticality, result)?;
+                }
+            }
+
+            taken = allowed;
+        }
+
+        for dev in &taken {
+            if let Some(id) = device_kata_id(&dev.device) {
+                let added = AddedDevice {
+                    id: id.to_string(),
+                    labels: dev.device.labels().cloned().unwrap_or_default(),
+                    ..Default::default()
+                };
+
+                self.added_devices.insert(id.to_string(), added);
+            }
+        }
+
+        Ok(taken)
+    }
+
+    // Return the devices added to the VM, including the ones it booted with,
+    // sorted by id.
+    pub(crate) fn list_devices(&self) -> Vec<AddedDevice> {
+        let mut devices: Vec<AddedDevice> = self.added_devices.values().cloned().collect();
+
+        devices.sort_by(|a, b| a.id.cmp(&b.id));
+
+        devices
+    }
+
+    // Return the number of devices of the VM and the limits CH places on it.
+    pub(crate) fn get_device_counts(&self) -> DeviceCounts {
+        self.pci_segments.counts()
+    }
+
+    // Return true if the guest is a TDX or SEV-SNP guest, which are only
+    // available on x86_64.
+    pub(crate) fn is_confidential_guest(&self) -> bool {
+        cfg!(target_arch = "x86_64")
+            && self
+                .config
+                .as_ref()
+                .map_or(false, |c| c.security_info.confidential_guest)
+    }
+
+    // Fail with ChError::Unsupported if the guest kernel cannot hot plug PCI
+    // devices, rather than adding a device the guest would never see.
+    fn check_pci_hotplug(&self) -> Result<()> {
+        let unsupported = self.config.as_ref().map_or(false, |c| {
+            c.device_info.guest_pci_hotplug == CH_GUEST_PCI_HOTPLUG_NONE
+        });
+
+        if unsupported {
+            return Err(ChError::Unsupported {
+                operation: "device hotplug".to_string(),
+                reason: "the guest kernel does not support PCI hotplug, cold plug the device before the VM starts".to_string(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    // Return the labels of a device added to the VM, formatted for the logs.
+    pub(crate) fn get_device_labels(&self, id: &str) -> String {
+        self.added_devices
+            .get(id)
+            .map(|dev| format!("{:?}", dev.labels))
+            .unwrap_or_default()
+    }
+
+    // Check the queued share fs devices do not share a daemon socket by
+    // mistake, before the VM is booted.
+    fn check_shared_fs_sockets(&self) -> Result<()> {
+        let devices = match self.pending_devices.as_ref() {
+            Some(devices) => devices,
+            None => return Ok(()),
+        };
+
+        let conflicts = get_shared_fs_socket_conflicts(devices, &self.vm_path);
+
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+
+        let msg = format!(
+            "share fs devices use the same socket without being coalesced: {}",
+            conflicts.join(", ")
+        );
+
+        let strict = self
+            .config
+            .as_ref()
+            .map(|c| c.shared_fs.virtio_fs_strict_sockets)
+            .unwrap_or_default();
+
+        if strict {
+            return Err(anyhow!(msg));
+        }
+
+        warn!(sl!(), "{}", msg);
+
+        Ok(())
+    }
+
+    pub(crate) async fn get_shared_fs_devices(&mut self) -> Result<Option<Vec<FsConfig>>> {
+        self.check_shared_fs_sockets()?;
+
+        let devices = self.take_cold_plug_devices(|dev| matches!(dev, Device::ShareFsDevice(_)))?;
+
+        if devices.is_empty() {
+            return Ok(None);
+        }
+
+        let mut root_devices = get_fs_configs(devices, &self.vm_path)?;
 
-        let response = cloud_hypervisor_vm_fs_add(
-            socket.try_clone().context("failed to clone socket")?,
-            fs_config,
-        )
-        .await?;
+        // The daemon sockets are created by the daemons.
+        for fs_config in &mut root_devices {
+            self.grant_vmm_socket_access(&fs_config.socket.display().to_string())?;
 
-        if let Some(detail) = response {
-            debug!(sl!(), "fs add response: {:?}", detail);
+            fs_config.socket = self.get_jail_socket_path(&fs_config.socket)?;
         }
 
-        Ok(())
+        Ok(Some(root_devices))
     }
 
-    async fn handle_hvsock_device(&mut self, _cfg: &HybridVsockConfig) -> Result<()> {
-        Ok(())
+    // Return the network devices to add to the VM config used to boot the
+    // VM.
+    //
+    // Taps are referenced by name as CH ignores the fds passed along with
+    // the VM config: passing tap fds is only possible when hot plugging.
+    pub(crate) async fn get_net_devices(&mut self) -> Result<Option<Vec<NetConfig>>> {
+        let devices = self.take_cold_plug_devices(|dev| matches!(dev, Device::Network(_)))?;
+
+        if devices.is_empty() {
+            return Ok(None);
+        }
+
+        let net_devices = get_net_configs(
+            devices,
+            self.get_net_queue_size()?,
+            self.get_net_rate_limiter(),
+        )?;
+
+        Ok(Some(net_devices))
     }
 
-    pub(crate) async fn get_shared_fs_devices(&mut self) -> Result<Option<Vec<FsConfig>>> {
-        let pending_root_devices = self.pending_devices.take();
+    // Return the configured size of the virtqueues of net devices, 0 for
+    // the CH default.
+    fn get_net_queue_size(&self) -> Result<u16> {
+        let (rx, tx) = self
+            .config
+            .as_ref()
+            .map(|c| (c.network_info.rx_queue_size, c.network_info.tx_queue_size))
+            .unwrap_or_default();
 
-        let mut root_devices = Vec::<FsConfig>::new();
+        get_net_queue_size(rx, tx)
+    }
 
-        if let Some(devices) = pending_root_devices {
-            for dev in devices {
-                match dev {
-                    Device::ShareFsDevice(dev) => {
-                        let settings = ShareFsSettings::new(dev, self.vm_path.clone());
+    // Return the rate limiter of the net devices, if any. The bandwidth is
+    // configured in bits, the CH token bucket counts bytes.
+    fn get_net_rate_limiter(&self) -> Option<RateLimiterConfig> {
+        let info = &self.config.as_ref()?.network_info;
 
-                        let fs_cfg = FsConfig::try_from(settings)?;
+        get_rate_limiter_config(
+            (
+                info.net_rate_limiter_bw_max_rate / 8,
+                info.net_rate_limiter_bw_one_time_burst / 8,
+            ),
+            (
+                info.net_rate_limiter_ops_max_rate,
+                info.net_rate_limiter_ops_one_time_burst,
+            ),
+        )
+    }
 
-                        root_devices.push(fs_cfg);
-                    }
-                    _ => continue,
-                };
-            }
+    // Return the rate limiter of the block devices, if any.
+    pub(crate) fn get_disk_rate_limiter(&self) -> Option<RateLimiterConfig> {
+        let info = &self.config.as_ref()?.blockdev_info;
 
-            Ok(Some(root_devices))
-        } else {
-            Ok(None)
-        }
+        get_rate_limiter_config(
+            (
+                info.disk_rate_limiter_bw_max_rate,
+                info.disk_rate_limiter_bw_one_time_burst,
+            ),
+            (
+                info.disk_rate_limiter_ops_max_rate,
+                info.disk_rate_limiter_ops_one_time_burst,
+            ),
+        )
     }
 
     pub(crate) async fn get_boot_file(&mut self) -> Result<PathBuf> {
@@ -167,14 +1362,46 @@ impl CloudHypervisorInner {
         }
     }
 
+    // Return true if the devices of the specified type are placed behind
+    // the virtual IOMMU.
+    pub(crate) fn iommu_device(&self, device_type: &str) -> bool {
+        self.config
+            .as_ref()
+            .map(|c| {
+                c.device_info.enable_iommu
+                    && c.device_info.iommu_devices.iter().any(|t| t == device_type)
+            })
+            .unwrap_or_default()
+    }
+
+    // Return true if the writes of the guest to the boot file are discarded.
+    pub(crate) fn get_boot_file_discard_writes(&self) -> bool {
+        self.config
+            .as_ref()
+            .map(|c| get_discard_writes(&c.boot_info))
+            .unwrap_or(true)
+    }
+
     pub(crate) async fn get_pmem_devices(&mut self) -> Result<Option<Vec<PmemConfig>>> {
+        // The host could modify a pmem device after a confidential guest was
+        // measured, so such guests boot from a read-only disk instead.
+        if self.is_confidential_guest() {
+            return Ok(None);
+        }
+
         let file = self.get_boot_file().await?;
 
+        let discard_writes = self.get_boot_file_discard_writes();
+
+        if !discard_writes {
+            info!(sl!(), "guest writes to {:?} are written back to it", file);
+        }
+
         let pmem_cfg = PmemConfig {
             file,
             size: None,
             iommu: false,
-            discard_writes: true,
+            discard_writes,
             id: None,
             pci_segment: 0,
         };
@@ -185,6 +1412,272 @@ impl CloudHypervisorInner {
     }
 }
 
+// The guest sees a disk with the capacity of its backing file, so the new
+// backing file of a disk must have the same size.
+fn check_backing_size(old_size: u64, new_size: u64) -> Result<()> {
+    if new_size != old_size {
+        return Err(anyhow!(
+            "new backing file of {} bytes does not match the disk size of {} bytes",
+            new_size,
+            old_size
+        ));
+    }
+
+    Ok(())
+}
+
+// Return true if the writes of the guest to the boot file are discarded, as
+// configured, or by default if the root filesystem is not writable. Warns of
+// the combinations which are likely mistakes: the writes to a writable root
+// filesystem being lost, and a read-only one modifying the shared file.
+fn get_discard_writes(boot_info: &BootInfo) -> bool {
+    match (boot_info.rootfs_writable, boot_info.rootfs_discard_writes) {
+        (true, Some(true)) => {
+            warn!(
+                sl!(),
+                "the root filesystem is writable but its writes are discarded, so are lost when the VM stops"
+            );
+
+            true
+        }
+        (false, Some(false)) => {
+            warn!(
+                sl!(),
+                "the root filesystem is read-only but the writes to it are not discarded, so modify the boot file shared by the VMs"
+            );
+
+            false
+        }
+        (writable, discard_writes) => discard_writes.unwrap_or(!writable),
+    }
+}
+
+// Call the CH API to add a device, retrying once after a short delay if
+// the host resources were exhausted. A ChError::ResourceExhausted is
+// returned if the retry fails for the same reason.
+async fn add_device_with_retry<F, Fut>(device: &str, add: F) -> Result<Option<String>>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Option<String>>>,
+{
+    let err = match add().await {
+        Err(e) if is_resource_exhausted(&e) => e,
+        result => return result,
+    };
+
+    warn!(
+        sl!(),
+        "host resources exhausted adding device {}, retrying: {:#}", device, err
+    );
+
+    tokio::time::sleep(Duration::from_millis(DEVICE_ADD_RETRY_DELAY_MS)).await;
+
+    match add().await {
+        Err(e) if is_resource_exhausted(&e) => Err(ChError::ResourceExhausted {
+            device: device.to_string(),
+            detail: format!("{:#}", e),
+        }
+        .into()),
+        result => result,
+    }
+}
+
+// Apply the criticality of a device to the result of adding it: failures
+// of optional devices are only reported.
+fn handle_device_result(
+    description: &str,
+    criticality: DeviceCriticality,
+    result: Result<()>,
+) -> Result<()> {
+    match result {
+        Err(e) if criticality == DeviceCriticality::Optional => {
+            warn!(
+                sl!(),
+                "dropping optional device {} after failure: {:?}", description, e;
+                "event" => "device-dropped"
+            );
+
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+// Return true if a device is ready to be used by the guest: CH holds it and,
+// unless its activation cannot be reported, the guest driver activated it.
+fn is_device_ready(in_vm: bool, passthrough: bool, monitored: bool, activated: bool) -> bool {
+    in_vm && (passthrough || !monitored || activated)
+}
+
+// Return the pairs of queued share fs devices using the same socket with a
+// different tag or queue settings, unless both are coalesced, which usually
+// comes from a copy-pasted config.
+fn get_shared_fs_socket_conflicts(devices: &[PendingDevice], vm_path: &str) -> Vec<String> {
+    // The inline daemon does not use a socket. Relative paths are below
+    // vm_path.
+    let configs: Vec<(&ShareFsDeviceConfig, PathBuf)> = devices
+        .iter()
+        .filter_map(|dev| match &dev.device {
+            Device::ShareFsDevice(cfg) if !cfg.sock_path.is_empty() => {
+                Some((cfg, Path::new(vm_path).join(&cfg.sock_path)))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut conflicts = Vec::new();
+
+    for (i, (cfg, path)) in configs.iter().enumerate() {
+        for (other, other_path) in &configs[i + 1..] {
+            if path != other_path || (cfg.coalesce && other.coalesce) {
+                continue;
+            }
+
+            let same_settings = cfg.mount_tag == other.mount_tag
+                && cfg.queue_num == other.queue_num
+                && cfg.queue_size == other.queue_size;
+
+            if !same_settings {
+                conflicts.push(format!(
+                    "{:?} used by tags {:?} and {:?}",
+                    path, cfg.mount_tag, other.mount_tag
+                ));
+            }
+        }
+    }
+
+    conflicts
+}
+
+// Convert the queued share fs devices to the CH configuration used to boot
+// the VM. Other device types are ignored.
+fn get_fs_configs(devices: Vec<PendingDevice>, vm_path: &str) -> Result<Vec<FsConfig>> {
+    let mut fs_configs = Vec::<FsConfig>::new();
+
+    for dev in devices {
+        let cfg = match dev.device {
+            Device::ShareFsDevice(cfg) => cfg,
+            _ => continue,
+        };
+
+        let description = format!("{:?}", cfg);
+
+        let settings = ShareFsSettings::new(cfg, vm_path.to_string());
+
+        let result = FsConfig::try_from(settings).map(|fs_cfg| fs_configs.push(fs_cfg));
+
+        handle_device_result(&description, dev.criticality, result)?;
+    }
+
+    Ok(fs_configs)
+}
+
+// Return the size of the virtqueues of net devices from the configured
+// sizes of their receive and transmit queues, either of which may be unset.
+// CH uses a single size for both.
+fn get_net_queue_size(rx_queue_size: u32, tx_queue_size: u32) -> Result<u16> {
+    let size = match (rx_queue_size, tx_queue_size) {
+        (size, 0) | (0, size) => size,
+        (rx, tx) if rx == tx => rx,
+        (rx, tx) => {
+            return Err(ChError::Unsupported {
+                operation: format!("net queue sizes rx {} and tx {}", rx, tx),
+                reason: "the receive and transmit queues of CH have the same size".to_string(),
+            }
+            .into())
+        }
+    };
+
+    u16::try_from(size).context(format!("invalid net queue size {}", size))
+}
+
+// Return the token bucket for a (max rate, one time burst) limit, None if
+// the rate is unlimited. The kata-types validation ensures that a burst
+// comes with a rate, and is not smaller than the bucket.
+fn get_token_bucket((max_rate, one_time_burst): (u64, u64)) -> Option<TokenBucketConfig> {
+    if max_rate == 0 {
+        return None;
+    }
+
+    Some(TokenBucketConfig {
+        size: max_rate,
+        one_time_burst: Some(one_time_burst).filter(|burst| *burst > 0),
+        refill_time: RATE_LIMITER_REFILL_TIME_MS,
+    })
+}
+
+// Return the rate limiter for the bandwidth and operations limits, None if
+// neither is limited.
+fn get_rate_limiter_config(bandwidth: (u64, u64), ops: (u64, u64)) -> Option<RateLimiterConfig> {
+    let config = RateLimiterConfig {
+        bandwidth: get_token_bucket(bandwidth),
+        ops: get_token_bucket(ops),
+    };
+
+    if config.bandwidth.is_none() && config.ops.is_none() {
+        return None;
+    }
+
+    Some(config)
+}
+
+// Convert the queued network devices to the CH configuration used to boot
+// the VM, with virtqueues of queue_size and the rate limiter. Other device
+// types are ignored.
+fn get_net_configs(
+    devices: Vec<PendingDevice>,
+    queue_size: u16,
+    rate_limiter: Option<RateLimiterConfig>,
+) -> Result<Vec<NetConfig>> {
+    let mut net_configs = Vec::<NetConfig>::new();
+
+    for dev in devices {
+        let cfg = match dev.device {
+            Device::Network(cfg) => cfg,
+            _ => continue,
+        };
+
+        let description = format!("{:?}", cfg);
+
+        let result = match cfg.guest_mac.as_ref() {
+            Some(addr) => {
+                net_configs.push(NetConfig {
+                    id: Some(cfg.id.clone()),
+                    tap: Some(cfg.host_dev_name.clone()),
+                    mac: MacAddr { bytes: addr.0 },
+                    num_queues: DEFAULT_NET_NUM_QUEUES,
+                    queue_size,
+                    rate_limiter_config: rate_limiter,
+                    ..Default::default()
+                });
+
+                Ok(())
+            }
+            None => Err(anyhow!("network device {} has no guest MAC", cfg.id)),
+        };
+
+        handle_device_result(&description, dev.criticality, result)?;
+    }
+
+    Ok(net_configs)
+}
+
+// Return the kata identifier of the specified device. Share fs devices do
+// not have an explicit id, so the mount tag (which must be unique) is used.
+fn device_kata_id(device: &Device) -> Option<&str> {
+    match device {
+        Device::Block(cfg) => Some(&cfg.id),
+        Device::Network(cfg) => Some(&cfg.id),
+        Device::ShareFsDevice(cfg) => Some(&cfg.mount_tag),
+        Device::Vfio(cfg) => Some(&cfg.id),
+        Device::Vsock(cfg) => Some(&cfg.id),
+        Device::HybridVsock(cfg) => Some(&cfg.id),
+        Device::VhostUserNet(cfg) => Some(&cfg.id),
+        Device::Pmem(cfg) => Some(&cfg.id),
+        Device::ShareFsMount(_) => None,
+    }
+}
+
 #[derive(Debug)]
 pub struct ShareFsSettings {
     cfg: ShareFsDeviceConfig,
@@ -233,3 +1726,543 @@ impl TryFrom<ShareFsSettings> for FsConfig {
         Ok(fs_cfg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, BlockConfig, DeviceLabels, NetworkConfig, VfioBusMode, VfioConfig};
+    use futures::executor::block_on;
+    use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
+
+    fn new_block_device(id: &str) -> PendingDevice {
+        PendingDevice {
+            device: Device::Block(BlockConfig {
+                id: id.to_string(),
+                path_on_host: format!("/dev/{}", id),
+                is_readonly: false,
+                no_drop: false,
+                index: 0,
+                labels: DeviceLabels::new(),
+            }),
+            criticality: DeviceCriticality::Required,
+            plug_mode: DevicePlugMode::ColdPlug,
+        }
+    }
+
+    fn new_net_device(id: &str, mac: Option<[u8; 6]>, plug_mode: DevicePlugMode) -> PendingDevice {
+        PendingDevice {
+            device: Device::Network(NetworkConfig {
+                id: id.to_string(),
+                host_dev_name: format!("tap_{}", id),
+                guest_mac: mac.map(Address),
+                labels: DeviceLabels::new(),
+            }),
+            criticality: DeviceCriticality::Optional,
+            plug_mode,
+        }
+    }
+
+    fn new_fs_device(tag: &str, queue_size: u64, criticality: DeviceCriticality) -> PendingDevice {
+        PendingDevice {
+            device: Device::ShareFsDevice(ShareFsDeviceConfig {
+                fs_type: VIRTIO_FS.to_string(),
+                sock_path: format!("{}.sock", tag),
+                mount_tag: tag.to_string(),
+                host_path: String::default(),
+                queue_size,
+                queue_num: 1,
+                coalesce: false,
+                guest_mount_point: String::default(),
+                labels: DeviceLabels::new(),
+            }),
+            criticality,
+            plug_mode: DevicePlugMode::ColdPlug,
+        }
+    }
+
+    #[test]
+    fn test_remove_device_force() {
+        let dir = std::env::temp_dir().join(format!("ch-force-remove-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut ch = CloudHypervisorInner::new();
+        ch.vm_path = dir.display().to_string();
+
+        // The VM cannot release the device without API socket.
+        let device = new_fs_device("fs0", 1024, DeviceCriticality::Critical).device;
+        let socket = dir.join("fs0.sock");
+        std::fs::write(&socket, "").unwrap();
+
+        assert!(block_on(ch.remove_device_force(device, Duration::from_secs(1))).unwrap());
+        assert!(!socket.exists());
+
+        // A socket provided by the user is left alone.
+        let user_socket = dir.join("user.sock");
+        std::fs::write(&user_socket, "").unwrap();
+
+        let mut device = new_fs_device("fs1", 1024, DeviceCriticality::Critical).device;
+        if let Device::ShareFsDevice(cfg) = &mut device {
+            cfg.sock_path = user_socket.display().to_string();
+        }
+
+        assert!(block_on(ch.remove_device_force(device, Duration::from_secs(1))).unwrap());
+        assert!(user_socket.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_take_socket_helpers() {
+        let socket = Path::new("/run/kata/vm/foo/virtiofsd.sock");
+
+        let mut ch = CloudHypervisorInner::new();
+
+        // The extra argument is $0 of the shell.
+        let mut child = std::process::Command::new("sh")
+            .args([
+                "-c",
+                "sleep 10; true",
+                "--socket-path=/run/kata/vm/foo/virtiofsd.sock",
+            ])
+            .spawn()
+            .unwrap();
+
+        ch.helper_pids = vec![std::process::id(), child.id()];
+
+        assert_eq!(ch.take_socket_helpers(socket), vec![child.id()]);
+        assert_eq!(ch.helper_pids, vec![std::process::id()]);
+        assert!(ch.take_socket_helpers(socket).is_empty());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_remove_pending_device() {
+        let mut ch = CloudHypervisorInner::new();
+
+        assert!(!ch.remove_pending_device("foo"));
+
+        ch.pending_devices = Some(vec![new_block_device("foo"), new_block_device("bar")]);
+
+        assert!(ch.remove_pending_device("foo"));
+        assert!(!ch.remove_pending_device("foo"));
+
+        let devices = ch.pending_devices.as_ref().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(device_kata_id(&devices[0].device), Some("bar"));
+    }
+
+    #[test]
+    fn test_add_devices() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let devices = vec![
+            new_block_device("blk0").device,
+            new_block_device("blk1").device,
+        ];
+
+        let results = block_on(ch.add_devices(devices));
+
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, DeviceGroupResult::Added)));
+        assert_eq!(ch.pending_devices.as_ref().unwrap().len(), 2);
+
+        // VFIO devices cannot be added when CH does not run as root.
+        let mut config = HypervisorConfig::default();
+        config.security_info.vmm_uid = Some(1000);
+        config.security_info.vmm_gid = Some(1000);
+        ch.set_hypervisor_config(config);
+
+        let vfio = Device::Vfio(VfioConfig {
+            id: "vfio0".to_string(),
+            sysfs_path: String::default(),
+            bus_slot_func: "01:00.0".to_string(),
+            mode: VfioBusMode::PCI,
+            labels: DeviceLabels::new(),
+        });
+
+        let devices = vec![
+            new_block_device("blk2").device,
+            new_block_device("blk3").device,
+            vfio,
+            new_block_device("blk4").device,
+        ];
+
+        let results = block_on(ch.add_devices(devices));
+
+        assert!(matches!(results[0], DeviceGroupResult::RolledBack));
+        assert!(matches!(results[1], DeviceGroupResult::RolledBack));
+        assert!(matches!(results[2], DeviceGroupResult::Failed(_)));
+        assert!(matches!(results[3], DeviceGroupResult::Skipped));
+
+        // Only the devices of the first group are left.
+        let ids: Vec<&str> = ch
+            .pending_devices
+            .as_ref()
+            .unwrap()
+            .iter()
+            .filter_map(|dev| device_kata_id(&dev.device))
+            .collect();
+        assert_eq!(ids, vec!["blk1", "blk0"]);
+    }
+
+    #[test]
+    fn test_replace_block_backing() {
+        let dir = std::env::temp_dir().join(format!("ch-replace-{}", std::process::id()));
+        let old_path = dir.join("old.img");
+        let new_path = dir.join("new.img");
+        let small_path = dir.join("small.img");
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&old_path, vec![0u8; 4096]).unwrap();
+        std::fs::write(&new_path, vec![0u8; 4096]).unwrap();
+        std::fs::write(&small_path, vec![0u8; 512]).unwrap();
+
+        assert!(check_backing_size(4096, 4096).is_ok());
+        assert!(check_backing_size(4096, 512).is_err());
+
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut dev = new_block_device("blk0");
+        if let Device::Block(cfg) = &mut dev.device {
+            cfg.path_on_host = old_path.display().to_string();
+        }
+        ch.pending_devices = Some(vec![dev]);
+
+        let path_of =
+            |ch: &CloudHypervisorInner| match &ch.pending_devices.as_ref().unwrap()[0].device {
+                Device::Block(cfg) => cfg.path_on_host.clone(),
+                _ => unreachable!(),
+            };
+
+        // The new file must exist and have the size of the disk.
+        assert!(block_on(ch.replace_block_backing("blk0", "/not/a/file")).is_err());
+        assert!(block_on(ch.replace_block_backing("blk0", small_path.to_str().unwrap())).is_err());
+        assert_eq!(path_of(&ch), old_path.display().to_string());
+
+        // A queued device is updated in place.
+        block_on(ch.replace_block_backing("blk0", new_path.to_str().unwrap())).unwrap();
+        assert_eq!(path_of(&ch), new_path.display().to_string());
+
+        // A device of the VM needs the VM to run.
+        assert!(block_on(ch.replace_block_backing("blk1", new_path.to_str().unwrap())).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_discard_writes() {
+        let mut boot_info = BootInfo::default();
+        assert!(get_discard_writes(&boot_info));
+
+        boot_info.rootfs_writable = true;
+        assert!(!get_discard_writes(&boot_info));
+
+        // Explicit settings win, even when they look wrong.
+        boot_info.rootfs_discard_writes = Some(true);
+        assert!(get_discard_writes(&boot_info));
+
+        boot_info.rootfs_writable = false;
+        boot_info.rootfs_discard_writes = Some(false);
+        assert!(!get_discard_writes(&boot_info));
+    }
+
+    #[test]
+    fn test_handle_device_result() {
+        let ok = || -> Result<()> { Ok(()) };
+        let err = || -> Result<()> { Err(anyhow!("failed")) };
+
+        assert!(handle_device_result("dev", DeviceCriticality::Required, ok()).is_ok());
+        assert!(handle_device_result("dev", DeviceCriticality::Optional, ok()).is_ok());
+        assert!(handle_device_result("dev", DeviceCriticality::Required, err()).is_err());
+        assert!(handle_device_result("dev", DeviceCriticality::Optional, err()).is_ok());
+    }
+
+    #[test]
+    fn test_get_fs_configs_mixed_criticality() {
+        // A queue size which does not fit in a u16 makes the conversion fail.
+        let bad_queue_size = u16::MAX as u64 + 1;
+
+        let devices = vec![
+            new_fs_device("good", 1024, DeviceCriticality::Required),
+            new_block_device("blk"),
+            new_fs_device("bad", bad_queue_size, DeviceCriticality::Optional),
+            new_fs_device("other", 512, DeviceCriticality::Optional),
+        ];
+
+        let configs = get_fs_configs(devices, "/run/vm").unwrap();
+
+        let tags: Vec<&str> = configs.iter().map(|c| c.tag.as_str()).collect();
+        assert_eq!(tags, vec!["good", "other"]);
+        assert_eq!(configs[0].socket, PathBuf::from("/run/vm/good.sock"));
+
+        let devices = vec![
+            new_fs_device("good", 1024, DeviceCriticality::Optional),
+            new_fs_device("bad", bad_queue_size, DeviceCriticality::Required),
+        ];
+
+        assert!(get_fs_configs(devices, "/run/vm").is_err());
+    }
+
+    #[test]
+    fn test_get_shared_fs_socket_conflicts() {
+        let mut devices = vec![
+            new_fs_device("a", 1024, DeviceCriticality::Required),
+            new_fs_device("b", 1024, DeviceCriticality::Required),
+            new_block_device("blk"),
+        ];
+
+        assert!(get_shared_fs_socket_conflicts(&devices, "/run/vm").is_empty());
+
+        // Copy-pasted socket path, relative and absolute.
+        for dev in devices.iter_mut() {
+            if let Device::ShareFsDevice(cfg) = &mut dev.device {
+                if cfg.mount_tag == "b" {
+                    cfg.sock_path = "/run/vm/a.sock".to_string();
+                }
+            }
+        }
+
+        let conflicts = get_shared_fs_socket_conflicts(&devices, "/run/vm");
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("/run/vm/a.sock"));
+
+        for dev in devices.iter_mut() {
+            if let Device::ShareFsDevice(cfg) = &mut dev.device {
+                cfg.coalesce = true;
+            }
+        }
+
+        assert!(get_shared_fs_socket_conflicts(&devices, "/run/vm").is_empty());
+
+        let mut ch = CloudHypervisorInner::new();
+        ch.vm_path = "/run/vm".to_string();
+        ch.pending_devices = Some(vec![
+            new_fs_device("a", 1024, DeviceCriticality::Required),
+            new_fs_device("a", 512, DeviceCriticality::Required),
+        ]);
+
+        assert!(ch.check_shared_fs_sockets().is_ok());
+
+        let mut config = HypervisorConfig::default();
+        config.shared_fs.virtio_fs_strict_sockets = true;
+        ch.set_hypervisor_config(config);
+
+        assert!(ch.check_shared_fs_sockets().is_err());
+    }
+
+    #[test]
+    fn test_get_net_configs() {
+        let devices = vec![
+            new_net_device("eth0", Some([2, 0, 0, 0, 0, 1]), DevicePlugMode::ColdPlug),
+            new_block_device("blk"),
+            new_net_device("eth1", None, DevicePlugMode::ColdPlug),
+            new_net_device("eth2", Some([2, 0, 0, 0, 0, 3]), DevicePlugMode::ColdPlug),
+        ];
+
+        // The optional device without a MAC is dropped.
+        let rate_limiter = get_rate_limiter_config((1000, 0), (0, 0));
+
+        let configs = get_net_configs(devices, 512, rate_limiter).unwrap();
+
+        let ids: Vec<&str> = configs.iter().map(|c| c.id.as_deref().unwrap()).collect();
+        assert_eq!(ids, vec!["eth0", "eth2"]);
+        assert_eq!(configs[0].tap.as_deref(), Some("tap_eth0"));
+        assert_eq!(configs[1].mac.bytes, [2, 0, 0, 0, 0, 3]);
+        assert_eq!(configs[1].queue_size, 512);
+        assert_eq!(configs[1].rate_limiter_config, rate_limiter);
+    }
+
+    #[test]
+    fn test_get_rate_limiter_config() {
+        assert_eq!(get_rate_limiter_config((0, 0), (0, 0)), None);
+
+        let config = get_rate_limiter_config((1 << 20, 4 << 20), (0, 0)).unwrap();
+
+        assert_eq!(
+            config.bandwidth,
+            Some(TokenBucketConfig {
+                size: 1 << 20,
+                one_time_burst: Some(4 << 20),
+                refill_time: RATE_LIMITER_REFILL_TIME_MS,
+            })
+        );
+        assert_eq!(config.ops, None);
+
+        // No burst.
+        let config = get_rate_limiter_config((0, 0), (500, 0)).unwrap();
+
+        assert_eq!(config.bandwidth, None);
+        assert_eq!(config.ops.unwrap().one_time_burst, None);
+    }
+
+    #[test]
+    fn test_get_net_queue_size() {
+        assert_eq!(get_net_queue_size(0, 0).unwrap(), 0);
+
+        // A single size applies to both queues.
+        assert_eq!(get_net_queue_size(1024, 0).unwrap(), 1024);
+        assert_eq!(get_net_queue_size(0, 512).unwrap(), 512);
+        assert_eq!(get_net_queue_size(256, 256).unwrap(), 256);
+
+        assert!(matches!(
+            get_net_queue_size(1024, 256)
+                .unwrap_err()
+                .downcast_ref::<ChError>(),
+            Some(ChError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn test_take_cold_plug_devices() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mac = Some([2, 0, 0, 0, 0, 1]);
+
+        let mut eth2 = new_net_device("eth2", mac, DevicePlugMode::ColdPlug);
+
+        if let Device::Network(cfg) = &mut eth2.device {
+            cfg.labels.insert("tenant".to_string(), "a".to_string());
+        }
+
+        // Queue as add_device() does: in reverse order.
+        let mut devices = vec![
+            new_net_device("eth0", mac, DevicePlugMode::ColdPlug),
+            new_fs_device("fs0", 1024, DeviceCriticality::Required),
+            new_net_device("eth1", mac, DevicePlugMode::HotPlug),
+            new_block_device("blk"),
+            eth2,
+        ];
+        devices.reverse();
+        ch.pending_devices = Some(devices);
+
+        let taken = ch
+            .take_cold_plug_devices(|dev| matches!(dev, Device::Network(_)))
+            .unwrap();
+
+        let ids: Vec<&str> = taken
+            .iter()
+            .filter_map(|dev| device_kata_id(&dev.device))
+            .collect();
+        assert_eq!(ids, vec!["eth0", "eth2"]);
+
+        let kept: Vec<&str> = ch
+            .pending_devices
+            .as_ref()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter_map(|dev| device_kata_id(&dev.device))
+            .collect();
+        assert_eq!(kept, vec!["fs0", "eth1", "blk"]);
+
+        // The devices the VM boots with are listed with their labels.
+        let listed = ch.list_devices();
+
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].id, "eth0");
+        assert!(listed[0].labels.is_empty());
+        assert_eq!(listed[1].id, "eth2");
+        assert_eq!(
+            listed[1].labels.get("tenant").map(String::as_str),
+            Some("a")
+        );
+        assert_eq!(ch.get_device_labels("eth2"), "{\"tenant\": \"a\"}");
+    }
+
+    #[test]
+    fn test_is_device_ready() {
+        // Not held by CH yet.
+        assert!(!is_device_ready(false, false, false, false));
+
+        // Without the event monitor, holding the device is enough.
+        assert!(is_device_ready(true, false, false, false));
+
+        // A virtio device must be activated by the guest driver.
+        assert!(!is_device_ready(true, false, true, false));
+        assert!(is_device_ready(true, false, true, true));
+
+        // Passed through devices are not activated.
+        assert!(is_device_ready(true, true, true, false));
+    }
+
+    #[test]
+    fn test_add_device_without_hotplug() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut config = HypervisorConfig::default();
+        config.device_info.guest_pci_hotplug = CH_GUEST_PCI_HOTPLUG_NONE.to_string();
+        ch.set_hypervisor_config(config);
+
+        // Cold plugged.
+        assert!(block_on(ch.add_device(new_block_device("blk0").device)).is_ok());
+        assert_eq!(ch.pending_devices.as_ref().unwrap().len(), 1);
+
+        // Hot plugged once the VM is resumed.
+        ch.state = VmmState::VmPaused;
+
+        let err = block_on(ch.add_device(new_block_device("blk1").device)).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ChError>(),
+            Some(ChError::Unsupported { .. })
+        ));
+        assert!(err.to_string().contains("cold plug"));
+        assert!(ch.paused_devices.is_empty());
+    }
+
+    #[test]
+    fn test_handle_paused_devices_after_resume() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut config = HypervisorConfig::default();
+        config.device_info.guest_pci_hotplug = CH_GUEST_PCI_HOTPLUG_NONE.to_string();
+        ch.set_hypervisor_config(config);
+
+        ch.state = VmmState::VmRunning;
+        ch.paused_devices = vec![new_block_device("blk0"), new_block_device("blk1")];
+
+        // All the devices are tried, not only the first one.
+        let err = block_on(ch.handle_paused_devices_after_resume()).unwrap_err();
+        let message = format!("{:#}", err);
+
+        assert!(message.contains("blk0: "), "{}", message);
+        assert!(message.contains("blk1: "), "{}", message);
+        assert!(ch.paused_devices.is_empty());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_confidential_cold_plug_devices() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut config = HypervisorConfig::default();
+        config.security_info.confidential_guest = true;
+        ch.set_hypervisor_config(config);
+
+        // An optional device is dropped.
+        ch.pending_devices = Some(vec![new_fs_device(
+            "fs0",
+            1024,
+            DeviceCriticality::Optional,
+        )]);
+
+        assert_eq!(block_on(ch.get_shared_fs_devices()).unwrap(), None);
+
+        ch.pending_devices = Some(vec![new_fs_device(
+            "fs1",
+            1024,
+            DeviceCriticality::Required,
+        )]);
+
+        let err = block_on(ch.get_shared_fs_devices()).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ChError>(),
+            Some(ChError::ConfidentialDeviceRejected { .. })
+        ));
+        assert!(err.to_string().contains("fs1"));
+    }
+}
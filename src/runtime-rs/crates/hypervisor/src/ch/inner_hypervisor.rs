@@ -3,38 +3,299 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use super::inner::CloudHypervisorInner;
+use super::inner::{CloudHypervisorInner, VmExitReason, VmmExit};
+use crate::ch::args::ChArgsBuilder;
+use crate::ch::artifacts::ArtifactKind;
+use crate::ch::boot_progress::BootProgress;
+#[cfg(target_arch = "x86_64")]
+use crate::ch::confidential::{
+    check_confidential_device, get_confidential_policy, ConfidentialPolicy,
+    DEVICE_KIND_BALLOON_FREE_PAGE_REPORTING, DEVICE_KIND_PMEM,
+};
+use crate::ch::confidential::{is_rejected, DEVICE_KIND_VFIO, DEVICE_KIND_VIRTIO_FS};
+use crate::ch::console_buffer::{capture_console, ConsoleBuffer};
+use crate::ch::errors::ChError;
+use crate::ch::events::{DeviceEvent, EventMonitor, VmEvent};
+use crate::ch::jail::Jail;
+use crate::ch::measurements::{get_launch_data, measure_boot_file, BootMeasurements, LaunchData};
+use crate::ch::metrics::{
+    render_prometheus, sample_process, subtract_counter_baselines, HypervisorMetrics,
+};
+use crate::ch::pci_segments::PciSegmentAllocator;
+use crate::ch::recovery::RecoveryState;
+use crate::ch::snapshot::{
+    check_snapshot_compatibility, check_snapshot_devices, check_snapshot_mode,
+    get_boot_memory_size, get_restored_net_configs, get_snapshot_devices, prepare_clone_snapshot,
+    SnapshotMetadata, SnapshotMode, CLONE_SNAPSHOT_DIR_NAME, SNAPSHOT_DIR_NAME,
+};
 use crate::ch::utils::get_api_socket_path;
-use crate::ch::utils::{get_jailer_root, get_sandbox_path, get_vsock_path};
+use crate::ch::utils::{
+    check_host_hypervisor, check_host_io_uring, check_hybrid_vsock_port, check_user_access,
+    create_log_file, get_ch_features, get_ch_version, get_console_log_path, get_event_monitor_path,
+    get_executable_path, get_file_tail, get_host_memory_mb, get_host_phys_bits,
+    get_hypervisor_device_path, get_hypervisor_feature, get_jailer_root, get_numa_node_cpus,
+    get_numa_node_free_mb, get_pci_bars_size, get_pci_device_path, get_peer_credentials,
+    get_recovery_state_path, get_sandbox_path, get_vcpu_thread_ids, get_vmm_log_path,
+    get_vsock_path, host_supports_landlock, is_process_running, is_process_using_path,
+    move_to_cgroup, parse_ch_version, parse_qgs_socket, restrict_socket_access, rotate_log_file,
+    set_oom_score_adj, CH_LOG_FILE_NAMES,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::ch::utils::{
+    check_host_sev_snp, check_host_tdx, get_hybrid_vsock_host_path, host_supports_sgx,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::kernel_param::KernelParams;
 use crate::Device;
 use crate::VsockConfig;
-use crate::{VcpuThreadIds, VmmState};
+use crate::{ResourceCapacity, VcpuThreadIds, VmmState};
+#[cfg(target_arch = "x86_64")]
+use crate::{VM_ROOTFS_DRIVER_BLK, VM_ROOTFS_FILESYSTEM_EXT4};
 use anyhow::{anyhow, Context, Result};
 use ch_config::ch_api::{
-    cloud_hypervisor_vm_create, cloud_hypervisor_vm_start, cloud_hypervisor_vmm_ping,
-    cloud_hypervisor_vmm_shutdown,
+    cloud_hypervisor_vm_counters, cloud_hypervisor_vm_create, cloud_hypervisor_vm_create_cfg,
+    cloud_hypervisor_vm_info, cloud_hypervisor_vm_pause, cloud_hypervisor_vm_power_button,
+    cloud_hypervisor_vm_receive_migration, cloud_hypervisor_vm_resize,
+    cloud_hypervisor_vm_resize_zone, cloud_hypervisor_vm_restore, cloud_hypervisor_vm_resume,
+    cloud_hypervisor_vm_send_migration, cloud_hypervisor_vm_snapshot, cloud_hypervisor_vm_start,
+    cloud_hypervisor_vmm_ping, cloud_hypervisor_vmm_shutdown,
+};
+use ch_config::{
+    BalloonConfig, ConsoleConfig, ConsoleOutputMode, CpuAffinity, CpuTopology, CpusConfig,
+    HotplugMethod, MemoryConfig, MemoryZoneConfig, NumaConfig, PlatformConfig, RestoreConfig,
+    VmConfig, VmCounters, VmInfo, VmReceiveMigrationData, VmResize, VmResizeZone,
+    VmSendMigrationData, VmSnapshotConfig, VmmPingResponse, DEFAULT_NUM_PCI_SEGMENTS,
+    MAX_NUM_PCI_SEGMENTS,
 };
+#[cfg(target_arch = "x86_64")]
+use ch_config::{DiskConfig, SgxEpcConfig};
 use core::future::poll_fn;
-use futures::executor::block_on;
 use futures::future::join_all;
 use kata_types::capabilities::{Capabilities, CapabilityBits};
+use kata_types::config::default::DEFAULT_CH_SECCOMP;
+#[cfg(target_arch = "x86_64")]
+use kata_types::config::hypervisor::BootInfo;
+use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
+use kata_types::config::hypervisor::{
+    merge_kernel_params, MemoryInfo, CH_BLOCK_DEVICE_AIO_AIO, CH_BLOCK_DEVICE_AIO_IO_URING,
+    CH_CONSOLE_TYPE_OFF, CH_CONSOLE_TYPE_SERIAL, CH_CONSOLE_TYPE_VIRTIO,
+    CH_DETERMINISTIC_ENTROPY_PARAMS, CH_IOMMU_DEVICE_BLOCK, CH_IOMMU_DEVICE_NET,
+    CH_IOMMU_DEVICE_PMEM, CH_IOMMU_DEVICE_VDPA, CH_IOMMU_DEVICE_VFIO, CH_IOMMU_DEVICE_VSOCK,
+    CH_MAX_GUEST_CLOCK_OFFSET_SECS, MISSING_FIRMWARE_KERNEL,
+};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{chown, geteuid, Gid, Pid, Uid};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::fs::create_dir_all;
+#[cfg(target_arch = "x86_64")]
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::path::Path;
-use std::process::Stdio;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tokio::io::AsyncBufReadExt;
-use tokio::io::BufReader;
+use tokio::io::{BufReader, Lines};
 use tokio::process::{Child, Command};
-use tokio::sync::watch::Receiver;
+use tokio::sync::watch::{channel, Receiver, Sender};
 use tokio::task;
 use tokio::task::JoinHandle;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 
 const CH_NAME: &str = "cloud-hypervisor";
 
+// CH requires the hot pluggable memory size to be a multiple of this size.
+const CH_MEMORY_HOTPLUG_ALIGN_MB: u64 = 128;
+
+const MIB: u64 = 1024 * 1024;
+
+// Memory zone of the guest memory, when backed by a file.
+const CH_MEMORY_ZONE_ID: &str = "mem0";
+
+// Maximum size in MiB of memory removed from the guest at once, so that a
+// shrink stops early if the guest fails to release memory.
+const CH_MEMORY_SHRINK_STEP_MB: u64 = 1024;
+
 /// Number of milliseconds to wait before retrying a CH operation.
 const CH_POLL_TIME_MS: u64 = 50;
 
+// Number of lines of the CH log file added to a VM start failure.
+const CH_LOG_TAIL_LINES: usize = 20;
+
+// Number of bytes of the console buffer added to a VM start failure.
+const CH_CONSOLE_TAIL_BYTES: usize = 4096;
+
+// Transports supported by CH for the migration URLs.
+const CH_MIGRATION_URL_SCHEMES: [&str; 2] = ["unix:", "tcp:"];
+
+// VM states reported by CH.
+const CH_VM_STATE_RUNNING: &str = "Running";
+const CH_VM_STATE_PAUSED: &str = "Paused";
+
+// Kernel parameters routing the guest console to the legacy serial port
+// and to the virtio-console device respectively.
+const CH_SERIAL_CONSOLE_PARAM: &str = "console=ttyS0,115200n8";
+const CH_VIRTIO_CONSOLE_PARAM: &str = "console=hvc0";
+
+// Name of the socket streaming the guest console, created by CH in the
+// directory of the hybrid vsock socket.
+const CH_CONSOLE_SOCKET_NAME: &str = "console.sock";
+
+// Number of seconds to wait for CH to exit once killed.
+const CH_KILL_TIMEOUT_SECS: u32 = 5;
+
+// Oldest CH release the driver hot plugs VFIO devices with.
+const CH_VFIO_HOTPLUG_MIN_VERSION: (u32, u32) = (30, 0);
+
+// Oldest CH release with the aio block device backend and the setting
+// disabling it.
+const CH_BLOCK_DEVICE_AIO_MIN_VERSION: (u32, u32) = (30, 0);
+
+// Oldest CH release accepting new fds for the net devices of a restored
+// VM, newer than the API the driver is built against.
+const CH_RESTORE_NET_FDS_MIN_VERSION: (u32, u32) = (39, 0);
+
+// Oldest CH release booting SEV-SNP guests from an IGVM file.
+#[cfg(all(target_arch = "x86_64", feature = "sev_snp"))]
+const CH_SEV_SNP_MIN_VERSION: (u32, u32) = (41, 0);
+
+// Oldest CH release binding the SEV-SNP host data and the TDX measurement
+// registers set by the host into the launch measurement.
+const CH_LAUNCH_DATA_MIN_VERSION: (u32, u32) = (42, 0);
+
+// Shared filesystem type CH has no device for.
+const CH_UNSUPPORTED_SHARED_FS: &str = "virtio-9p";
+
+// CH supports a single vsock device.
+const CH_MAX_VSOCK_DEVICES: u32 = 1;
+
+// Prefix of the ids of the SGX EPC sections.
+#[cfg(target_arch = "x86_64")]
+const CH_SGX_EPC_ID_PREFIX: &str = "epc";
+
+// Kernel parameter disabling the guest SGX driver.
+const CH_NO_SGX_PARAM: &str = "nosgx";
+
+// Interval between the checks of the boot progress of the VM.
+const CH_BOOT_POLL_MS: u64 = 250;
+
+// Logged by CH when the guest watchdog expires.
+const CH_WATCHDOG_EXPIRED: &str = "Watchdog timer expired";
+
+// Vsock port the guest reaches the TDX quote generation service on, unless
+// configured.
+#[cfg(target_arch = "x86_64")]
+const CH_TDX_QGS_DEFAULT_PORT: u32 = 4050;
+
+// Defaults of CH for the queues of a disk.
+#[cfg(target_arch = "x86_64")]
+const CH_DISK_NUM_QUEUES: usize = 1;
+#[cfg(target_arch = "x86_64")]
+const CH_DISK_QUEUE_SIZE: u16 = 128;
+
+// Time to wait for CH to report the new number of vCPUs after a resize.
+const CH_VCPU_RESIZE_TIMEOUT_SECS: u64 = 5;
+
+// Number of times the removal of vCPUs is requested before giving up, and
+// the delay between the requests unless configured.
+pub(crate) const CH_VCPU_SHRINK_ATTEMPTS: u32 = 3;
+const CH_VCPU_SHRINK_RETRY_DELAY_MS: u64 = 1000;
+
+// CH takes the numbers of vCPUs as u8.
+const CH_MAX_VCPUS: u32 = u8::MAX as u32;
+
+// Device nodes opened by CH to create the tap devices and to seed the guest
+// entropy, made available in the jail.
+const CH_TUN_DEVICE: &str = "/dev/net/tun";
+const CH_URANDOM_DEVICE: &str = "/dev/urandom";
+
+// Optional features of CH builds required by the configuration.
+const CH_FEATURE_IO_URING: &str = "io_uring";
+#[cfg(target_arch = "x86_64")]
+const CH_FEATURE_TDX: &str = "tdx";
+#[cfg(target_arch = "x86_64")]
+const CH_FEATURE_SEV_SNP: &str = "sev_snp";
+
+// Guest physical addresses not usable for the memory or the 64-bit BARs: the
+// 32-bit device hole on x86_64, and the devices below the memory on aarch64.
+const CH_RESERVED_ADDRESS_SPACE_MB: u64 = 1024;
+
+// Physical address bits assumed when the host does not report them, the
+// smallest found on the hosts CH supports.
+const CH_DEFAULT_PHYS_BITS: u32 = 40;
+
+// Share of the guest physical address space the layout may use without a
+// warning, leaving room for the alignment of the BARs.
+const CH_ADDRESS_SPACE_WARN_PERCENT: u64 = 90;
+
+/// Result of a resize of the guest memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResizedMemory {
+    /// Size in MiB of the memory plugged in the guest.
+    pub size_mb: u64,
+
+    /// Whether the agent must online the hot added memory blocks, which is
+    /// the case with ACPI. The virtio-mem driver onlines them itself.
+    pub online_required: bool,
+
+    /// Set if the memory was shrunk by inflating the balloon rather than by
+    /// removing memory. The reclaimed memory is still plugged, so the
+    /// guest's view of its total memory must not be updated.
+    pub balloon_reclaim: bool,
+
+    /// Size in MiB of the balloon after the resize.
+    pub balloon_mb: u64,
+}
+
+/// Huge pages of one size requested by a pod, such as with a
+/// hugepages-2Mi resource.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HugePagesRequest {
+    /// Size in bytes of the huge pages.
+    pub page_size: u64,
+
+    /// Size in bytes of the huge pages requested, reserved in the guest.
+    pub size: u64,
+}
+
+/// vCPUs to remove from the VM, which the agent must offline in the guest
+/// before the removal is requested.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VcpuShrinkPlan {
+    /// Number of vCPUs present when the shrink was planned.
+    pub from: u32,
+
+    /// Number of vCPUs left once the shrink completed.
+    pub to: u32,
+
+    /// Guest ids of the vCPUs removed. CH removes the highest ones, so vCPU 0
+    /// is never part of them.
+    pub offline_vcpus: Vec<u32>,
+}
+
+// Progress of a vCPU shrink.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum VcpuShrink {
+    // Waiting for the agent to offline the vCPUs.
+    Planned(VcpuShrinkPlan),
+
+    // Requested from CH, waiting for the guest to release the vCPUs.
+    Requested(VcpuShrinkPlan),
+}
+
+impl VcpuShrink {
+    fn plan(&self) -> &VcpuShrinkPlan {
+        match self {
+            VcpuShrink::Planned(plan) | VcpuShrink::Requested(plan) => plan,
+        }
+    }
+}
+
 impl CloudHypervisorInner {
     async fn start_hypervisor(&mut self, timeout_secs: i32) -> Result<()> {
         self.cloud_hypervisor_launch(timeout_secs)
@@ -55,9 +316,11 @@ impl CloudHypervisorInner {
     }
 
     async fn boot_vm(&mut self) -> Result<()> {
-        let shared_fs_devices = self.get_shared_fs_devices().await?;
+        let cfg = self.get_vm_config().await?;
 
-        let pmem_devices = self.get_pmem_devices().await?;
+        self.boot_measurements = self
+            .measure_boot_files(&cfg)
+            .context("failed to measure the boot files")?;
 
         let socket = self
             .api_socket
@@ -65,28 +328,24 @@ impl CloudHypervisorInner {
             .ok_or("missing socket")
             .map_err(|e| anyhow!(e))?;
 
-        let sandbox_path = get_sandbox_path(&self.id)?;
-
-        std::fs::create_dir_all(sandbox_path.clone()).context("failed to create sandbox path")?;
-
-        let vsock_socket_path = get_vsock_path(&self.id)?;
-
-        let response = cloud_hypervisor_vm_create(
-            sandbox_path,
-            vsock_socket_path,
-            socket.try_clone().context("failed to clone socket")?,
-            shared_fs_devices,
-            pmem_devices,
-        )
-        .await?;
+        let response =
+            cloud_hypervisor_vm_create(socket.try_clone().context("failed to clone socket")?, cfg)
+                .await
+                .map_err(|e| self.get_boot_error(e))?;
 
         if let Some(detail) = response {
             debug!(sl!(), "vm boot response: {:?}", detail);
         }
 
+        // CH listens on the console socket once the VM is created, and the
+        // capture starts before the VM so that the output of early boot is
+        // not lost.
+        self.start_console_capture()?;
+
         let response =
             cloud_hypervisor_vm_start(socket.try_clone().context("failed to clone socket")?)
-                .await?;
+                .await
+                .map_err(|e| self.get_boot_error(e))?;
 
         if let Some(detail) = response {
             debug!(sl!(), "vm start response: {:?}", detail);
@@ -97,390 +356,6696 @@ impl CloudHypervisorInner {
         Ok(())
     }
 
-    async fn cloud_hypervisor_setup_comms(&mut self) -> Result<()> {
-        let api_socket_path = get_api_socket_path(&self.id)?;
+    // Explain why CH failed to boot an SEV-SNP guest, telling a host unable to
+    // run such guests from a guest policy refused by the host.
+    fn get_boot_error(&self, err: anyhow::Error) -> anyhow::Error {
+        let sev_snp_guest = self.config.as_ref().map_or(false, |c| {
+            c.security_info.confidential_guest && c.security_info.sev_snp_guest
+        });
 
-        // The hypervisor has just been spawned, but may not yet have created
-        // the API socket, so repeatedly try to connect for up to
-        // timeout_secs.
-        let join_handle: JoinHandle<Result<UnixStream>> =
-            task::spawn_blocking(move || -> Result<UnixStream> {
-                let api_socket: UnixStream;
+        if !sev_snp_guest {
+            return err;
+        }
 
-                loop {
-                    let result = UnixStream::connect(api_socket_path.clone());
+        match get_snp_failure(&format!("{:#}", err)) {
+            Some(failure) => err.context(failure),
+            None => err,
+        }
+    }
 
-                    if let Ok(result) = result {
-                        api_socket = result;
-                        break;
-                    }
+    // Restore the VM from the snapshot in the specified directory, instead
+    // of booting it. The snapshot must match the VM config which would be
+    // used to boot the VM.
+    //
+    // A snapshot of another sandbox is restored as a clone, from a copy
+    // using the host resources of this sandbox. The API socket is already
+    // specific to the sandbox.
+    async fn restore_vm(&mut self, dir: &str, prefault: bool) -> Result<()> {
+        let mut dir = PathBuf::from(dir);
 
-                    std::thread::sleep(Duration::from_millis(CH_POLL_TIME_MS));
-                }
+        let metadata = SnapshotMetadata::read(&dir)?;
 
-                Ok(api_socket)
-            });
+        let cfg = self.get_vm_config().await?;
 
-        let timeout_msg = format!(
-            "API socket connect timed out after {} seconds",
-            self.timeout_secs
-        );
+        if !metadata.sandbox_id.is_empty() && metadata.sandbox_id != self.id {
+            let clone_dir = Path::new(&self.vm_path).join(CLONE_SNAPSHOT_DIR_NAME);
 
-        let result =
-            tokio::time::timeout(Duration::from_secs(self.timeout_secs as u64), join_handle)
-                .await
-                .context(timeout_msg)?;
+            self.artifacts.add(&clone_dir, ArtifactKind::Tree);
 
-        let result = result?;
+            prepare_clone_snapshot(&dir, &clone_dir, &cfg).context(format!(
+                "failed to prepare clone of sandbox {} snapshot",
+                metadata.sandbox_id
+            ))?;
 
-        let api_socket = result?;
+            info!(
+                sl!(),
+                "restoring clone of sandbox {} from {:?}", metadata.sandbox_id, dir
+            );
 
-        self.api_socket = Some(api_socket);
+            dir = clone_dir;
+        }
 
-        Ok(())
-    }
+        let dir = dir.as_path();
 
-    async fn cloud_hypervisor_check_running(&mut self) -> Result<()> {
-        let timeout_secs = self.timeout_secs;
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
 
-        let timeout_msg = format!(
-            "API socket connect timed out after {} seconds",
-            timeout_secs
-        );
+        let ch_version =
+            cloud_hypervisor_vmm_ping(socket.try_clone().context("failed to clone socket")?)
+                .await?
+                .map(|detail| serde_json::from_str::<VmmPingResponse>(&detail))
+                .transpose()
+                .context("invalid ping response")?
+                .map(|r| r.version)
+                .unwrap_or_default();
 
-        let join_handle = self.cloud_hypervisor_ping_until_ready(CH_POLL_TIME_MS);
+        check_snapshot_compatibility(&metadata, &ch_version, &cfg)
+            .context(format!("snapshot {:?} cannot be restored", dir))?;
 
-        let result = tokio::time::timeout(Duration::new(timeout_secs as u64, 0), join_handle)
-            .await
-            .context(timeout_msg)?;
+        // Without fds, the net devices use the taps named in the snapshot,
+        // which only exist on the host it was taken on.
+        let net_fds = if self.restore_net_fds.is_empty() {
+            None
+        } else {
+            // An older CH ignores the fds, and the restored net devices
+            // would use the taps named in the snapshot instead.
+            check_min_version(
+                &ch_version,
+                "restoring net devices with new fds",
+                CH_RESTORE_NET_FDS_MIN_VERSION,
+            )
+            .context(format!("snapshot {:?} cannot be restored", dir))?;
 
-        result
-    }
+            Some(
+                get_restored_net_configs(&metadata, &self.restore_net_fds)
+                    .context(format!("snapshot {:?} cannot be restored", dir))?,
+            )
+        };
 
-    async fn cloud_hypervisor_ensure_not_launched(&self) -> Result<()> {
-        if let Some(child) = &self.process {
-            return Err(anyhow!(
-                "{} already running with PID {}",
-                CH_NAME,
-                child.id().unwrap_or(0)
-            ));
+        let source = self.get_jail_path(&dir, true)?;
+
+        let response = cloud_hypervisor_vm_restore(
+            socket.try_clone().context("failed to clone socket")?,
+            RestoreConfig {
+                source_url: PathBuf::from(format!("file://{}", source.display())),
+                prefault,
+                net_fds,
+            },
+        )
+        .await
+        .context(format!("failed to restore VM from {:?}", dir))?;
+
+        if let Some(detail) = response {
+            debug!(sl!(), "vm restore response: {:?}", detail);
         }
 
+        self.start_console_capture()?;
+
+        // A restored VM is paused.
+        let response =
+            cloud_hypervisor_vm_resume(socket.try_clone().context("failed to clone socket")?)
+                .await
+                .context("failed to resume restored VM")?;
+
+        if let Some(detail) = response {
+            debug!(sl!(), "vm resume response: {:?}", detail);
+        }
+
+        self.state = VmmState::VmRunning;
+
+        // The counters of the restored devices start again from zero.
+        self.counter_baselines.clear();
+
+        info!(sl!(), "VM restored from {:?}", dir);
+
         Ok(())
     }
 
-    async fn cloud_hypervisor_launch(&mut self, _timeout_secs: i32) -> Result<()> {
-        self.cloud_hypervisor_ensure_not_launched().await?;
+    // Build the config of the VM from the hypervisor config and the devices
+    // added before the VM is started.
+    async fn get_vm_config(&mut self) -> Result<VmConfig> {
+        let shared_fs_devices = self.get_shared_fs_devices().await?;
 
-        let debug = false;
+        let net_devices = self.get_net_devices().await?;
 
-        let disable_seccomp = true;
+        let pmem_devices = self.get_pmem_devices().await?;
 
-        let api_socket_path = get_api_socket_path(&self.id)?;
+        let sandbox_path = get_sandbox_path(&self.id)?;
 
-        let _ = std::fs::remove_file(api_socket_path.clone());
+        std::fs::create_dir_all(sandbox_path.clone()).context("failed to create sandbox path")?;
 
-        let binary_path = self
-            .config
-            .as_ref()
-            .ok_or("no hypervisor config for CH")
-            .map_err(|e| anyhow!(e))?
-            .path
-            .to_string();
+        self.artifacts.add(&sandbox_path, ArtifactKind::Dir);
 
-        let path = Path::new(&binary_path).canonicalize()?;
+        let vsock_socket_path = get_vsock_path(&self.id)?;
 
-        let mut cmd = Command::new(path);
+        self.artifacts.add(&vsock_socket_path, ArtifactKind::File);
 
-        cmd.current_dir("/");
+        let mut cfg = cloud_hypervisor_vm_create_cfg(
+            sandbox_path,
+            vsock_socket_path,
+            shared_fs_devices,
+            pmem_devices,
+        )
+        .await?;
 
-        cmd.stdin(Stdio::null());
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
+        if net_devices.is_some() {
+            cfg.net = net_devices;
+        }
 
-        cmd.env("RUST_BACKTRACE", "full");
+        self.memory_shared = cfg.memory.shared;
 
-        cmd.args(["--api-socket", &api_socket_path]);
+        // Invalid launch data fails the sandbox creation.
+        self.launch_data = match self.config.as_ref() {
+            Some(config) => get_launch_data(&config.security_info)?,
+            None => LaunchData::new(),
+        };
 
-        if let Some(extra_args) = &self.extra_args {
-            cmd.args(extra_args);
-        }
+        if !self.launch_data.is_empty() {
+            // An older CH would not bind the data into the measurement.
+            self.check_ch_version("launch data", CH_LAUNCH_DATA_MIN_VERSION)?;
 
-        if debug {
-            cmd.arg("-v");
+            info!(sl!(), "launch data: {:?}", self.launch_data);
         }
 
-        if disable_seccomp {
-            cmd.args(["--seccomp", "false"]);
+        if let Some(config) = self.config.as_ref() {
+            let (boot_vcpus, max_vcpus) = get_vcpus(
+                config.cpu_info.default_vcpus,
+                config.cpu_info.default_maxvcpus,
+            );
+
+            debug!(
+                sl!(),
+                "{} vCPUs, maximum {} (default_vcpus {}, default_maxvcpus {})",
+                boot_vcpus,
+                max_vcpus,
+                config.cpu_info.default_vcpus,
+                config.cpu_info.default_maxvcpus
+            );
+
+            // A VM sized statically cannot have more vCPUs than it boots with.
+            let max_vcpus = if config.static_sandbox_resource_mgmt {
+                boot_vcpus
+            } else {
+                max_vcpus
+            };
+
+            cfg.cpus.boot_vcpus = boot_vcpus as u8;
+            cfg.cpus.max_vcpus = max_vcpus as u8;
+
+            // The topology must describe all the vCPUs the VM may have.
+            cfg.cpus.topology = Some(CpuTopology {
+                threads_per_core: 1,
+                cores_per_die: max_vcpus as u8,
+                dies_per_package: 1,
+                packages: 1,
+            });
+
+            let (hugepages, thp) = get_memory_backing(&config.memory_info);
+
+            let (hugepages, hugepage_size) =
+                get_hugepages_config(&config.memory_info, hugepages, &self.hugepage_requests);
+
+            if let Some(page_size) = hugepage_size {
+                if cfg.memory.size % page_size != 0 {
+                    return Err(anyhow!(
+                        "memory size {} MiB is not a multiple of the huge page size {} MiB",
+                        cfg.memory.size / MIB,
+                        page_size / MIB
+                    ));
+                }
+            }
+
+            cfg.memory.hugepages = hugepages;
+            cfg.memory.hugepage_size = hugepage_size;
+            cfg.memory.thp = thp && !hugepages;
         }
 
-        let child = cmd.spawn().context(format!("{} spawn failed", CH_NAME))?;
+        let (hotplug_method, hotplug_size) = self.get_memory_hotplug_config(cfg.memory.size)?;
 
-        // Save process PID
-        self.pid = child.id();
+        self.memory_hotplug_method = hotplug_method;
 
-        let shutdown = self
-            .shutdown_rx
-            .as_ref()
-            .ok_or("no receiver channel")
-            .map_err(|e| anyhow!(e))?
-            .clone();
+        cfg.memory.hotplug_method = hotplug_method;
+        cfg.memory.hotplug_size = hotplug_size;
 
-        let ch_outputlogger_task = tokio::spawn(cloud_hypervisor_log_output(child, shutdown));
+        if let Some(file) = self.get_memory_file_backend()? {
+            if cfg.memory.hotplug_size.is_some() {
+                warn!(
+                    sl!(),
+                    "memory hotplug is not supported with a memory backend file, disabling it"
+                );
+            }
 
-        let tasks = vec![ch_outputlogger_task];
+            set_memory_file_backend(&mut cfg.memory, file);
+        }
 
-        self.tasks = Some(tasks);
+        self.set_numa_placement(&mut cfg)?;
 
-        Ok(())
-    }
+        let hotplug_size = get_memory_hotplug_size(&cfg);
+        self.boot_memory_mb = get_boot_memory_size(&cfg) / MIB;
+        self.max_memory_mb = self.boot_memory_mb + hotplug_size / MIB;
 
-    async fn cloud_hypervisor_shutdown(&mut self) -> Result<()> {
-        let socket = self
-            .api_socket
+        self.check_address_space();
+
+        self.boot_vcpus = cfg.cpus.boot_vcpus as u32;
+        self.max_vcpus = cfg.cpus.max_vcpus as u32;
+
+        cfg.balloon = self
+            .config
             .as_ref()
-            .ok_or("missing socket")
-            .map_err(|e| anyhow!(e))?;
+            .and_then(|c| get_balloon_config(&c.memory_info));
 
-        let response =
-            cloud_hypervisor_vmm_shutdown(socket.try_clone().context("shutdown failed")?).await?;
+        self.set_entropy_config(&mut cfg)?;
 
-        if let Some(detail) = response {
-            debug!(sl!(), "shutdown response: {:?}", detail);
-        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            cfg.sgx_epc = self.get_sgx_epc_config()?;
 
-        // Trigger a controlled shutdown
-        self.shutdown_tx
-            .as_mut()
-            .ok_or("no shutdown channel")
-            .map_err(|e| anyhow!(e))?
-            .send(true)
-            .map_err(|e| anyhow!(e).context("failed to request shutdown"))?;
+            let (confidential_guest, sev_snp_guest) = self
+                .config
+                .as_ref()
+                .map(|c| {
+                    (
+                        c.security_info.confidential_guest,
+                        c.security_info.sev_snp_guest,
+                    )
+                })
+                .unwrap_or_default();
 
-        let tasks = self
-            .tasks
-            .take()
-            .ok_or("no tasks")
-            .map_err(|e| anyhow!(e))?;
+            if let Some(config) = self.config.as_ref().filter(|_| confidential_guest) {
+                let (guest, feature, built, check_host): (_, _, _, fn() -> Result<()>) =
+                    if sev_snp_guest {
+                        (
+                            "SEV-SNP",
+                            CH_FEATURE_SEV_SNP,
+                            cfg!(feature = "sev_snp"),
+                            check_host_sev_snp,
+                        )
+                    } else {
+                        ("TDX", CH_FEATURE_TDX, cfg!(feature = "tdx"), check_host_tdx)
+                    };
 
-        let results = join_all(tasks).await;
+                if !built {
+                    return Err(anyhow!(
+                        "{} guests unavailable: runtime built without the {} feature",
+                        guest,
+                        feature
+                    ));
+                }
 
-        let mut wait_errors: Vec<tokio::task::JoinError> = vec![];
+                self.check_ch_feature(feature)
+                    .and_then(|_| check_host())
+                    .context(format!("{} guests unavailable", guest))?;
 
-        for result in results {
-            if let Err(e) = result {
-                eprintln!("wait task error: {:#?}", e);
+                set_confidential_config(&mut cfg, &config.boot_info)
+                    .context(format!("invalid configuration for a {} guest", guest))?;
 
-                wait_errors.push(e);
+                self.memory_shared = cfg.memory.shared;
             }
-        }
 
-        if wait_errors.is_empty() {
-            Ok(())
-        } else {
-            Err(anyhow!("wait all tasks failed: {:#?}", wait_errors))
+            self.setup_qgs_socket(confidential_guest && !sev_snp_guest)
+                .context("failed to set up TDX quote generation socket")?;
         }
-    }
 
-    #[allow(dead_code)]
-    async fn cloud_hypervisor_wait(&mut self) -> Result<()> {
-        let mut child = self
-            .process
-            .take()
-            .ok_or(format!("{} not running", CH_NAME))
-            .map_err(|e| anyhow!(e))?;
+        let vsock_socket = cfg.vsock.as_ref().map(|vsock| vsock.socket.clone());
 
-        let _pid = child
-            .id()
-            .ok_or(format!("{} missing PID", CH_NAME))
-            .map_err(|e| anyhow!(e))?;
+        let (serial, console) =
+            self.get_console_config(cfg.serial.clone(), vsock_socket.as_deref())?;
 
-        // Note that this kills _and_ waits for the process!
-        child.kill().await?;
+        cfg.serial = serial;
+        cfg.console = console;
 
-        Ok(())
-    }
+        if let Some(payload) = cfg.payload.as_mut() {
+            let cmdline = payload.cmdline.take().unwrap_or_default();
 
-    async fn cloud_hypervisor_ping_until_ready(&mut self, _poll_time_ms: u64) -> Result<()> {
-        let socket = self
-            .api_socket
+            payload.cmdline = Some(self.get_kernel_cmdline(&cmdline));
+        }
+
+        if let Some(firmware) = self.get_firmware()? {
+            let confidential_guest = self
+                .config
+                .as_ref()
+                .map(|c| c.security_info.confidential_guest)
+                .unwrap_or_default();
+
+            let payload = cfg.payload.get_or_insert_with(Default::default);
+
+            payload.firmware = Some(firmware);
+
+            // CH boots either a firmware or a kernel, except for confidential
+            // guests whose firmware loads the kernel.
+            if !confidential_guest {
+                payload.kernel = None;
+            }
+        }
+
+        #[cfg(all(target_arch = "x86_64", feature = "sev_snp"))]
+        self.set_snp_payload(&mut cfg)?;
+
+        let (disable_io_uring, disable_aio) = self.get_disk_io_backend()?;
+
+        let disk_rate_limiter = self.get_disk_rate_limiter();
+
+        for disk in cfg.disks.iter_mut().flatten() {
+            disk.disable_io_uring = disable_io_uring;
+            disk.disable_aio = disable_aio;
+
+            // The I/O of vhost-user disks is not done by CH.
+            if !disk.vhost_user {
+                disk.rate_limiter_config = disk_rate_limiter;
+            }
+        }
+
+        self.set_iommu_config(&mut cfg);
+
+        // Set last as it depends on all the devices in the config.
+        cfg.platform = self
+            .get_platform_config(&cfg.device_pci_segments())
+            .context("invalid PCI segment configuration")?;
+
+        cfg.validate()?;
+
+        let num_pci_segments = cfg
+            .platform
             .as_ref()
-            .ok_or("missing socket")
-            .map_err(|e| anyhow!(e))?;
+            .map_or(DEFAULT_NUM_PCI_SEGMENTS, |p| p.num_pci_segments);
 
-        loop {
-            let response =
-                cloud_hypervisor_vmm_ping(socket.try_clone().context("failed to clone socket")?)
-                    .await
-                    .context("ping failed");
+        self.pci_segments = PciSegmentAllocator::new(num_pci_segments);
 
-            if let Ok(response) = response {
-                if let Some(detail) = response {
-                    debug!(sl!(), "ping response: {:?}", detail);
-                }
-                break;
+        for (segment, _) in cfg.device_pci_segments() {
+            self.pci_segments.reserve(segment);
+        }
+
+        // The devices CH adds on its own take slots of the default segment.
+        for _ in 0..cfg.builtin_pci_devices() {
+            self.pci_segments.reserve(0);
+        }
+
+        self.pci_segments
+            .check_limits("the devices of the VM config")?;
+
+        Ok(cfg)
+    }
+
+    // Return the firmware to boot, if any. A missing firmware file fails the
+    // boot unless the policy allows booting the kernel directly instead,
+    // which is refused for confidential guests as they require the firmware.
+    fn get_firmware(&self) -> Result<Option<PathBuf>> {
+        let config = match self.config.as_ref() {
+            Some(config) => config,
+            None => return Ok(None),
+        };
+
+        let boot_info = &config.boot_info;
+
+        // A confidential guest is launched from its firmware, the TDX
+        // virtual firmware or the IGVM file of an SEV-SNP guest.
+        if boot_info.firmware.is_empty() {
+            if config.security_info.confidential_guest {
+                return Err(anyhow!("confidential guests require a firmware"));
             }
 
-            tokio::time::sleep(Duration::from_millis(CH_POLL_TIME_MS)).await;
+            return Ok(None);
         }
 
-        Ok(())
+        let firmware = PathBuf::from(&boot_info.firmware);
+
+        if firmware.exists() {
+            return Ok(Some(firmware));
+        }
+
+        if config.security_info.confidential_guest {
+            return Err(anyhow!(
+                "firmware {:?} not found, it is required for confidential guests",
+                firmware
+            ));
+        }
+
+        if boot_info.missing_firmware_policy != MISSING_FIRMWARE_KERNEL {
+            return Err(anyhow!("firmware {:?} not found", firmware));
+        }
+
+        warn!(
+            sl!(),
+            "firmware {:?} not found, booting the kernel directly", firmware
+        );
+
+        Ok(None)
     }
 
-    pub(crate) async fn prepare_vm(&mut self, id: &str, netns: Option<String>) -> Result<()> {
-        self.id = id.to_string();
-        self.state = VmmState::NotReady;
+    // Load the firmware of an SEV-SNP guest as an IGVM file, and pass it the
+    // host data of the launch data, if any. The kernel, if configured, is booted directly and
+    // measured along with the firmware.
+    #[cfg(all(target_arch = "x86_64", feature = "sev_snp"))]
+    fn set_snp_payload(&self, cfg: &mut VmConfig) -> Result<()> {
+        let sev_snp_guest = self
+            .config
+            .as_ref()
+            .map_or(false, |c| c.security_info.sev_snp_guest);
 
-        self.setup_environment().await?;
+        if !sev_snp_guest {
+            return Ok(());
+        }
 
-        self.netns = netns;
+        // An older CH would fail on the unknown settings, or boot a guest
+        // which is not confidential.
+        self.check_ch_version("SEV-SNP guests", CH_SEV_SNP_MIN_VERSION)?;
 
-        let vsock_cfg = VsockConfig::new(self.id.clone()).await?;
+        let payload = cfg.payload.get_or_insert_with(Default::default);
 
-        let dev = Device::Vsock(vsock_cfg);
-        self.add_device(dev).await.context("add vsock device")?;
+        let igvm = payload
+            .firmware
+            .take()
+            .ok_or_else(|| anyhow!("SEV-SNP guests require a firmware, in the IGVM format"))?;
+
+        payload.igvm = Some(igvm);
 
-        self.start_hypervisor(self.timeout_secs).await?;
+        payload.host_data = self.launch_data.get("snp_host_data").cloned();
 
         Ok(())
     }
 
-    async fn setup_environment(&mut self) -> Result<()> {
-        // run_dir and vm_path are the same (shared)
-        self.run_dir = get_sandbox_path(&self.id)?;
-        self.vm_path = self.run_dir.to_string();
+    // Return the digests of the files the VM boots from, checked against the
+    // expected digests, or None if the boot files are not measured. The
+    // kernel is only booted from if in the VM config, and the initrd or the
+    // image as selected by get_boot_file().
+    fn measure_boot_files(&self, cfg: &VmConfig) -> Result<Option<BootMeasurements>> {
+        let boot_info = match self.config.as_ref() {
+            Some(config) => &config.boot_info,
+            None => return Ok(None),
+        };
 
-        create_dir_all(&self.run_dir)
-            .with_context(|| anyhow!("failed to create sandbox directory {}", self.run_dir))?;
+        let expected = [
+            &boot_info.kernel_sha256,
+            &boot_info.initrd_sha256,
+            &boot_info.image_sha256,
+            &boot_info.firmware_sha256,
+        ];
 
-        if !self.jailer_root.is_empty() {
-            create_dir_all(self.jailer_root.as_str())
-                .map_err(|e| anyhow!("Failed to create dir {} err : {:?}", self.jailer_root, e))?;
+        if !boot_info.measure_boot_files && expected.iter().all(|d| d.is_empty()) {
+            return Ok(None);
         }
 
-        Ok(())
-    }
+        let payload = cfg.payload.clone().unwrap_or_default();
 
-    pub(crate) async fn start_vm(&mut self, timeout_secs: i32) -> Result<()> {
-        self.setup_environment().await?;
+        let initrd = Some(Path::new(&boot_info.initrd)).filter(|_| !boot_info.initrd.is_empty());
+        let image = Some(Path::new(&boot_info.image))
+            .filter(|_| boot_info.initrd.is_empty() && !boot_info.image.is_empty());
 
-        self.timeout_secs = timeout_secs;
+        let measurements = BootMeasurements {
+            kernel: measure_boot_file(
+                "kernel",
+                payload.kernel.as_deref(),
+                &boot_info.kernel_sha256,
+            )?,
+            initrd: measure_boot_file("initrd", initrd, &boot_info.initrd_sha256)?,
+            image: measure_boot_file("image", image, &boot_info.image_sha256)?,
+            firmware: measure_boot_file(
+                "firmware",
+                payload.firmware_file().map(|f| f.as_path()),
+                &boot_info.firmware_sha256,
+            )?,
+        };
 
-        self.boot_vm().await?;
+        info!(sl!(), "boot files measured: {:?}", measurements);
 
-        Ok(())
+        Ok(Some(measurements))
     }
 
-    pub(crate) fn stop_vm(&mut self) -> Result<()> {
-        block_on(self.cloud_hypervisor_shutdown())?;
+    pub(crate) fn get_boot_measurements(&self) -> Option<BootMeasurements> {
+        self.boot_measurements.clone()
+    }
 
-        Ok(())
+    pub(crate) fn get_launch_data(&self) -> LaunchData {
+        self.launch_data.clone()
     }
 
-    pub(crate) fn pause_vm(&self) -> Result<()> {
-        Ok(())
+    // Add the virtio-iommu device if enabled, placing the configured types
+    // of devices behind it.
+    fn set_iommu_config(&self, cfg: &mut VmConfig) {
+        let (enable_iommu, has_iommu_segments) = self
+            .config
+            .as_ref()
+            .map(|c| {
+                (
+                    c.device_info.enable_iommu,
+                    !c.device_info.iommu_segments.is_empty(),
+                )
+            })
+            .unwrap_or_default();
+
+        cfg.iommu = enable_iommu;
+
+        if !enable_iommu {
+            return;
+        }
+
+        let block = self.iommu_device(CH_IOMMU_DEVICE_BLOCK);
+        let net = self.iommu_device(CH_IOMMU_DEVICE_NET);
+        let pmem = self.iommu_device(CH_IOMMU_DEVICE_PMEM);
+        let vdpa = self.iommu_device(CH_IOMMU_DEVICE_VDPA);
+        let vfio = self.iommu_device(CH_IOMMU_DEVICE_VFIO);
+        let vsock = self.iommu_device(CH_IOMMU_DEVICE_VSOCK);
+
+        cfg.disks
+            .iter_mut()
+            .flatten()
+            .for_each(|d| d.iommu |= block);
+        cfg.net.iter_mut().flatten().for_each(|n| n.iommu |= net);
+        cfg.pmem.iter_mut().flatten().for_each(|p| p.iommu |= pmem);
+        cfg.vdpa.iter_mut().flatten().for_each(|v| v.iommu |= vdpa);
+        cfg.devices
+            .iter_mut()
+            .flatten()
+            .for_each(|d| d.iommu |= vfio);
+        cfg.vsock.iter_mut().for_each(|v| v.iommu |= vsock);
+
+        let used = has_iommu_segments || cfg.device_pci_segments().iter().any(|(_, iommu)| *iommu);
+
+        if !used {
+            warn!(
+                sl!(),
+                "virtual IOMMU enabled but no device of the VM is placed behind it"
+            );
+        }
     }
 
-    pub(crate) fn resume_vm(&self) -> Result<()> {
-        Ok(())
+    // Return the platform configuration, which holds the SMBIOS strings
+    // exposed to the guest, or None if no platform option is set.
+    //
+    // Note that CH does not support injecting custom OEM ACPI tables, so
+    // only the SMBIOS strings can be configured.
+    //
+    // The PCI segments are checked against the segments used by the
+    // devices, specified as (segment, iommu) pairs.
+    fn get_platform_config(&self, devices: &[(u16, bool)]) -> Result<Option<PlatformConfig>> {
+        let config = match self.config.as_ref() {
+            Some(config) => config,
+            None => return Ok(None),
+        };
+
+        let machine_info = &config.machine_info;
+        let device_info = &config.device_info;
+
+        let (num_pci_segments, iommu_segments) = get_pci_segments(
+            device_info.num_pci_segments,
+            &device_info.iommu_segments,
+            devices,
+        )?;
+
+        let non_empty = |s: &String| -> Option<String> {
+            if s.is_empty() {
+                None
+            } else {
+                Some(s.to_string())
+            }
+        };
+
+        let serial_number = non_empty(&machine_info.smbios_serial_number);
+        let uuid = non_empty(&machine_info.smbios_uuid);
+
+        let oem_strings = if machine_info.smbios_oem_strings.is_empty() {
+            None
+        } else {
+            Some(machine_info.smbios_oem_strings.clone())
+        };
+
+        // Confidential guests are only available on x86_64.
+        let confidential_guest =
+            cfg!(target_arch = "x86_64") && config.security_info.confidential_guest;
+
+        if serial_number.is_none()
+            && uuid.is_none()
+            && oem_strings.is_none()
+            && num_pci_segments == DEFAULT_NUM_PCI_SEGMENTS
+            && iommu_segments.is_none()
+            && !confidential_guest
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(PlatformConfig {
+            num_pci_segments,
+            iommu_segments,
+            serial_number,
+            uuid,
+            oem_strings,
+            #[cfg(feature = "tdx")]
+            tdx: confidential_guest && !config.security_info.sev_snp_guest,
+            #[cfg(feature = "sev_snp")]
+            sev_snp: confidential_guest && config.security_info.sev_snp_guest,
+            #[cfg(feature = "tdx")]
+            mrconfigid: self.launch_data.get("tdx_mrconfigid").cloned(),
+            #[cfg(feature = "tdx")]
+            mrowner: self.launch_data.get("tdx_mrowner").cloned(),
+            #[cfg(feature = "tdx")]
+            mrownerconfig: self.launch_data.get("tdx_mrownerconfig").cloned(),
+            ..Default::default()
+        }))
     }
 
-    pub(crate) async fn save_vm(&self) -> Result<()> {
-        Ok(())
+    // Return the SGX EPC sections to expose to the guest, if any.
+    //
+    // The section ids are derived from the section index so that they are
+    // the same on every boot.
+    #[cfg(target_arch = "x86_64")]
+    fn get_sgx_epc_config(&self) -> Result<Option<Vec<SgxEpcConfig>>> {
+        let memory_info = match self.config.as_ref() {
+            Some(config) => &config.memory_info,
+            None => return Ok(None),
+        };
+
+        if memory_info.sgx_epc_sections_mb.is_empty() {
+            return Ok(None);
+        }
+
+        if !host_supports_sgx() {
+            return Err(anyhow!(
+                "SGX EPC sections configured for {} but the host does not support SGX",
+                CH_NAME
+            ));
+        }
+
+        let sections = memory_info
+            .sgx_epc_sections_mb
+            .iter()
+            .enumerate()
+            .map(|(i, size_mb)| SgxEpcConfig {
+                id: format!("{}{}", CH_SGX_EPC_ID_PREFIX, i),
+                size: *size_mb as u64 * 1024 * 1024,
+                prefault: memory_info.sgx_epc_prefault,
+            })
+            .collect();
+
+        Ok(Some(sections))
     }
 
-    pub(crate) async fn get_agent_socket(&self) -> Result<String> {
-        const HYBRID_VSOCK_SCHEME: &str = "hvsock";
+    fn sgx_enabled(&self) -> bool {
+        self.config
+            .as_ref()
+            .map(|c| !c.memory_info.sgx_epc_sections_mb.is_empty())
+            .unwrap_or(false)
+    }
 
-        let vsock_path = get_vsock_path(&self.id)?;
+    // Return the (disable_io_uring, disable_aio) flags of the block devices
+    // for the configured I/O backend.
+    fn get_disk_io_backend(&self) -> Result<(bool, bool)> {
+        let aio = self
+            .config
+            .as_ref()
+            .map(|c| c.blockdev_info.block_device_aio.as_str())
+            .unwrap_or_default();
 
-        let uri = format!("{}://{}", HYBRID_VSOCK_SCHEME, vsock_path);
+        match aio {
+            CH_BLOCK_DEVICE_AIO_IO_URING => {
+                check_host_io_uring()
+                    .and_then(|_| self.check_ch_feature(CH_FEATURE_IO_URING))
+                    .context("io_uring block device backend unavailable")?;
 
-        Ok(uri)
+                // Releases without aio fall back to synchronous I/O only,
+                // which CH does not choose when io_uring is available.
+                let disable_aio = self
+                    .check_ch_version("aio block device backend", CH_BLOCK_DEVICE_AIO_MIN_VERSION)
+                    .is_ok();
+
+                Ok((false, disable_aio))
+            }
+            CH_BLOCK_DEVICE_AIO_AIO => {
+                self.check_ch_version("aio block device backend", CH_BLOCK_DEVICE_AIO_MIN_VERSION)?;
+
+                Ok((true, false))
+            }
+            // Let CH choose.
+            _ => Ok((false, false)),
+        }
     }
 
-    pub(crate) async fn disconnect(&mut self) {
-        self.state = VmmState::NotReady;
+    fn get_console_type(&self) -> &str {
+        self.config
+            .as_ref()
+            .map(|c| c.debug_info.console_type.as_str())
+            .filter(|t| !t.is_empty())
+            .unwrap_or(CH_CONSOLE_TYPE_SERIAL)
     }
 
-    pub(crate) async fn get_thread_ids(&self) -> Result<VcpuThreadIds> {
-        Ok(VcpuThreadIds::default())
+    // Return the (serial, console) configurations: the device selected by
+    // the console type gets the backend, the other one is turned off.
+    //
+    // If guest console logging is enabled, the backend is a file in the
+    // sandbox directory. Otherwise, the specified default backend is used.
+    // CH owns the file while the VM is running, so any log left by a
+    // previous run is rotated (and capped) before the VM is created.
+    //
+    // If the console socket is enabled, the serial port is streamed over a
+    // socket CH listens on instead, placed next to the hybrid vsock socket.
+    fn get_console_config(
+        &mut self,
+        default_backend: ConsoleConfig,
+        vsock_socket: Option<&Path>,
+    ) -> Result<(ConsoleConfig, ConsoleConfig)> {
+        let off = ConsoleConfig {
+            mode: ConsoleOutputMode::Off,
+            ..Default::default()
+        };
+
+        let console_type = self.get_console_type().to_string();
+
+        if console_type == CH_CONSOLE_TYPE_OFF {
+            return Ok((off.clone(), off));
+        }
+
+        let console_socket = self
+            .config
+            .as_ref()
+            .map(|c| c.debug_info.console_socket)
+            .unwrap_or_default();
+
+        if console_socket {
+            // CH only supports the socket backend for the serial port.
+            if console_type != CH_CONSOLE_TYPE_SERIAL {
+                return Err(anyhow!(
+                    "console socket not supported with console type {:?}",
+                    console_type
+                ));
+            }
+
+            let path = vsock_socket
+                .ok_or("console socket requires the hybrid vsock device")
+                .map_err(|e| anyhow!(e))?
+                .with_file_name(CH_CONSOLE_SOCKET_NAME);
+
+            // CH fails to listen on a socket left by a previous run.
+            let _ = std::fs::remove_file(&path);
+
+            self.artifacts.add(&path, ArtifactKind::File);
+
+            self.console_socket_path = Some(path.display().to_string());
+
+            let serial = ConsoleConfig {
+                file: None,
+                mode: ConsoleOutputMode::Socket,
+                iommu: false,
+                socket: Some(path),
+            };
+
+            return Ok((serial, off));
+        }
+
+        let log_console = self
+            .config
+            .as_ref()
+            .map(|c| c.debug_info.enable_debug || c.debug_info.log_guest_console)
+            .unwrap_or_default();
+
+        let backend = if log_console {
+            let max_size_mb = self
+                .config
+                .as_ref()
+                .map(|c| c.debug_info.guest_console_log_max_size_mb)
+                .unwrap_or_default();
+
+            let path = get_console_log_path(&self.id)?;
+
+            rotate_log_file(&path, max_size_mb as u64 * 1024 * 1024)
+                .context("failed to rotate console log")?;
+
+            self.artifacts.add(&path, ArtifactKind::File);
+            self.artifacts
+                .add(format!("{}.1", path), ArtifactKind::File);
+
+            self.console_log_path = Some(path.clone());
+
+            ConsoleConfig {
+                file: Some(PathBuf::from(path)),
+                mode: ConsoleOutputMode::File,
+                iommu: false,
+                socket: None,
+            }
+        } else {
+            default_backend
+        };
+
+        if console_type == CH_CONSOLE_TYPE_VIRTIO {
+            Ok((off, backend))
+        } else {
+            Ok((backend, off))
+        }
     }
 
-    pub(crate) async fn cleanup(&self) -> Result<()> {
-        Ok(())
+    // Adjust the generated kernel command line: route the guest console to
+    // the configured console device, select the clock source and merge the
+    // user kernel parameters.
+    fn get_kernel_cmdline(&self, cmdline: &str) -> String {
+        let console_type = self.get_console_type();
+
+        let console_param = match console_type {
+            CH_CONSOLE_TYPE_VIRTIO => Some(CH_VIRTIO_CONSOLE_PARAM),
+            CH_CONSOLE_TYPE_OFF => None,
+            _ => Some(CH_SERIAL_CONSOLE_PARAM),
+        };
+
+        // A console forced by the user kernel parameters which doesn't
+        // match the configured device is a common source of missing
+        // console output.
+        let user_params = self
+            .config
+            .as_ref()
+            .map(|c| c.boot_info.kernel_params.as_str())
+            .unwrap_or_default();
+
+        for param in user_params.split_whitespace() {
+            if let Some(device) = param.strip_prefix("console=") {
+                let expected = console_param.and_then(|p| p.strip_prefix("console="));
+
+                let matches = expected
+                    .map(|e| e.split(',').next() == device.split(',').next())
+                    .unwrap_or(false);
+
+                if !matches {
+                    warn!(
+                        sl!(),
+                        "kernel parameter {:?} does not match console type {:?}",
+                        param,
+                        console_type
+                    );
+                }
+            }
+        }
+
+        // The guest SGX driver must not be disabled if EPC sections are
+        // provided.
+        let sgx_enabled = self.sgx_enabled();
+
+        if sgx_enabled && user_params.split_whitespace().any(|p| p == CH_NO_SGX_PARAM) {
+            warn!(
+                sl!(),
+                "ignoring kernel parameter {:?} as SGX EPC sections are configured",
+                CH_NO_SGX_PARAM
+            );
+        }
+
+        let mut params: Vec<String> = cmdline
+            .split_whitespace()
+            .filter(|p| !p.starts_with("console="))
+            .filter(|p| !(sgx_enabled && *p == CH_NO_SGX_PARAM))
+            .map(String::from)
+            .collect();
+
+        if let Some(param) = console_param {
+            params.push(param.to_string());
+        }
+
+        params.append(&mut self.get_clock_kernel_params());
+        params.append(&mut self.get_entropy_kernel_params());
+        params.append(&mut get_guest_hugepage_params(&self.hugepage_requests));
+
+        // The user parameters, including the ones appended by annotations,
+        // are appended as is, replacing the generated ones with the same
+        // name.
+        let user_params = if sgx_enabled {
+            user_params
+                .split_whitespace()
+                .filter(|p| *p != CH_NO_SGX_PARAM)
+                .collect::<Vec<&str>>()
+                .join(" ")
+        } else {
+            user_params.to_string()
+        };
+
+        merge_kernel_params(&params.join(" "), &user_params)
     }
 
-    pub(crate) async fn get_pids(&self) -> Result<Vec<u32>> {
-        Ok(Vec::<u32>::new())
+    // Return the kernel parameters selecting the guest clock source.
+    fn get_clock_kernel_params(&self) -> Vec<String> {
+        let clock_source = self
+            .config
+            .as_ref()
+            .map(|c| c.machine_info.guest_clock_source.as_str())
+            .unwrap_or_default();
+
+        if clock_source.is_empty() {
+            return vec![];
+        }
+
+        vec![format!("clocksource={}", clock_source)]
     }
 
-    pub(crate) async fn check(&self) -> Result<()> {
-        Ok(())
+    // Return the kernel parameters keeping the guest from seeding its random
+    // number generator on its own, if its entropy is deterministic.
+    fn get_entropy_kernel_params(&self) -> Vec<String> {
+        let deterministic = self
+            .config
+            .as_ref()
+            .map(|c| c.machine_info.deterministic_guest_entropy)
+            .unwrap_or_default();
+
+        if !deterministic {
+            return vec![];
+        }
+
+        CH_DETERMINISTIC_ENTROPY_PARAMS
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
     }
 
-    pub(crate) async fn get_jailer_root(&self) -> Result<String> {
-        let root_path = get_jailer_root(&self.id)?;
+    // Have the entropy device of a guest with deterministic entropy read from
+    // the seed file, if configured.
+    fn set_entropy_config(&self, cfg: &mut VmConfig) -> Result<()> {
+        let machine_info = match self.config.as_ref() {
+            Some(config) if config.machine_info.deterministic_guest_entropy => &config.machine_info,
+            _ => return Ok(()),
+        };
 
-        std::fs::create_dir_all(&root_path)?;
+        warn!(
+            sl!(),
+            "deterministic guest entropy enabled: the guest randomness is weakened, only use it for tests"
+        );
 
-        Ok(root_path)
+        let seed_file = &machine_info.guest_entropy_seed_file;
+
+        if !seed_file.is_empty() {
+            cfg.rng.src = self
+                .get_jail_path(Path::new(seed_file), true)
+                .context("failed to make the guest entropy seed file available")?;
+        }
+
+        Ok(())
     }
 
-    pub(crate) async fn capabilities(&self) -> Result<Capabilities> {
-        let mut caps = Capabilities::default();
-        caps.set(CapabilityBits::FsSharingSupport);
-        Ok(caps)
+    // The user allowed to use the API socket: by default, the user CH runs
+    // as.
+    fn get_api_socket_uid(&self) -> u32 {
+        let security_info = self.config.as_ref().map(|c| &c.security_info);
+
+        security_info
+            .and_then(|s| s.api_socket_uid.or(s.vmm_uid))
+            .unwrap_or_else(|| geteuid().as_raw())
     }
-}
 
-// Log all output from the CH process until a shutdown signal is received.
-// When that happens, stop logging and wait for the child process to finish
-// before returning.
-async fn cloud_hypervisor_log_output(mut child: Child, mut shutdown: Receiver<bool>) -> Result<()> {
-    let stdout = child
-        .stdout
-        .as_mut()
-        .ok_or("failed to get child stdout")
-        .map_err(|e| anyhow!(e))?;
+    // Return the user and group CH runs as, if not the ones of the runtime.
+    pub(crate) fn get_vmm_user(&self) -> Option<(u32, u32)> {
+        let security_info = &self.config.as_ref()?.security_info;
 
-    let stdout_reader = BufReader::new(stdout);
-    let mut stdout_lines = stdout_reader.lines();
+        Some((security_info.vmm_uid?, security_info.vmm_gid?))
+    }
 
-    let stderr = child
-        .stderr
-        .as_mut()
-        .ok_or("failed to get child stderr")
-        .map_err(|e| anyhow!(e))?;
+    // Give the group CH runs as access to a socket CH connects to or creates,
+    // if CH does not run as the user of the runtime.
+    pub(crate) fn grant_vmm_socket_access(&self, path: &str) -> Result<()> {
+        match self.get_vmm_user() {
+            Some((_, gid)) => restrict_socket_access(path, None, Some(gid)),
+            None => Ok(()),
+        }
+    }
 
-    let stderr_reader = BufReader::new(stderr);
-    let mut stderr_lines = stderr_reader.lines();
+    // CH owns the listening end of the API socket, so the credentials are
+    // checked on connect: the peer must run as the allowed user and, once
+    // spawned, be the CH process itself. This rejects a socket served by
+    // another process which took over the socket path. Returns the pid of
+    // the peer.
+    fn check_api_socket_peer(&self, socket: &UnixStream) -> Result<u32> {
+        let (pid, uid) = get_peer_credentials(socket)?;
 
-    loop {
-        tokio::select! {
-            _ = shutdown.changed() => {
-                info!(sl!(), "got shutdown request");
-                break;
-            },
-            stderr_line = poll_fn(|cx| Pin::new(&mut stderr_lines).poll_next_line(cx)) => {
-                if let Ok(line) = stderr_line {
-                    let line = line.ok_or("missing stderr line").map_err(|e| anyhow!(e))?;
+        let allowed_uid = self.get_api_socket_uid();
 
-                    info!(sl!(), "{:?}", line; "stream" => "stderr");
-                }
-            },
-            stdout_line = poll_fn(|cx| Pin::new(&mut stdout_lines).poll_next_line(cx)) => {
-                if let Ok(line) = stdout_line {
-                    let line = line.ok_or("missing stdout line").map_err(|e| anyhow!(e))?;
+        let denied = uid != allowed_uid || self.pid.map_or(false, |p| p as i32 != pid);
 
-                    info!(sl!(), "{:?}", line; "stream" => "stdout");
-                }
-            },
-        };
+        if denied {
+            warn!(
+                sl!(),
+                "denied {} API socket peer (pid {}, uid {}), expected uid {}",
+                CH_NAME,
+                pid,
+                uid,
+                allowed_uid
+            );
+
+            return Err(anyhow!(
+                "{} API socket peer pid {} uid {} not allowed",
+                CH_NAME,
+                pid,
+                uid
+            ));
+        }
+
+        Ok(pid as u32)
     }
 
-    // Note that this kills _and_ waits for the process!
-    child.kill().await?;
+    async fn cloud_hypervisor_setup_comms(&mut self) -> Result<()> {
+        let api_socket_path = get_api_socket_path(&self.id)?;
+        let socket_path = api_socket_path.clone();
+
+        // The hypervisor has just been spawned, but may not yet have created
+        // the API socket, so repeatedly try to connect for up to
+        // timeout_secs.
+        let join_handle: JoinHandle<Result<UnixStream>> =
+            task::spawn_blocking(move || -> Result<UnixStream> {
+                let api_socket: UnixStream;
+
+                loop {
+                    let result = UnixStream::connect(api_socket_path.clone());
+
+                    if let Ok(result) = result {
+                        api_socket = result;
+                        break;
+                    }
+
+                    std::thread::sleep(Duration::from_millis(CH_POLL_TIME_MS));
+                }
+
+                Ok(api_socket)
+            });
+
+        let timeout_msg = format!(
+            "API socket connect timed out after {} seconds",
+            self.timeout_secs
+        );
+
+        let result =
+            tokio::time::timeout(Duration::from_secs(self.timeout_secs as u64), join_handle)
+                .await
+                .context(timeout_msg)?;
+
+        let result = result?;
+
+        let api_socket = result?;
+
+        self.check_api_socket_peer(&api_socket)?;
+
+        let gid = self.get_vmm_user().map(|(_, gid)| gid);
+
+        restrict_socket_access(&socket_path, Some(self.get_api_socket_uid()), gid)?;
+
+        self.api_socket = Some(api_socket);
+
+        Ok(())
+    }
+
+    async fn cloud_hypervisor_check_running(&mut self) -> Result<()> {
+        let timeout_secs = self.timeout_secs;
+
+        let timeout_msg = format!(
+            "API socket connect timed out after {} seconds",
+            timeout_secs
+        );
+
+        let join_handle = self.cloud_hypervisor_ping_until_ready(CH_POLL_TIME_MS);
+
+        let result = tokio::time::timeout(Duration::new(timeout_secs as u64, 0), join_handle)
+            .await
+            .context(timeout_msg)?;
+
+        result
+    }
+
+    async fn cloud_hypervisor_ensure_not_launched(&self) -> Result<()> {
+        if let Some(child) = &self.process {
+            return Err(anyhow!(
+                "{} already running with PID {}",
+                CH_NAME,
+                child.id().unwrap_or(0)
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Create the jail CH is confined to, with the CH binary, the sandbox
+    // directory, the boot files and the device nodes CH opens. The paths of
+    // the devices are made available as the devices are added.
+    fn cloud_hypervisor_setup_jail(&mut self) -> Result<()> {
+        let config = self
+            .config
+            .as_ref()
+            .ok_or("no hypervisor config for CH")
+            .map_err(|e| anyhow!(e))?;
+
+        let boot_info = &config.boot_info;
+
+        // The boot file is written to if the writes are not discarded.
+        let discard_writes = self.get_boot_file_discard_writes();
+
+        let mut read_only: Vec<PathBuf> = [
+            &config.path,
+            &boot_info.kernel,
+            &boot_info.restore_snapshot_path,
+        ]
+        .iter()
+        .filter(|p| !p.is_empty())
+        .map(|p| PathBuf::from(p.as_str()))
+        .collect();
+
+        let mut read_write = vec![
+            PathBuf::from(&self.vm_path),
+            PathBuf::from(get_hypervisor_device_path(
+                &config.machine_info.hypervisor_device,
+            )),
+            PathBuf::from(CH_TUN_DEVICE),
+        ];
+
+        for path in [&boot_info.image, &boot_info.initrd] {
+            if path.is_empty() {
+                continue;
+            }
+
+            if discard_writes {
+                read_only.push(PathBuf::from(path));
+            } else {
+                read_write.push(PathBuf::from(path));
+            }
+        }
+
+        // The firmware may be skipped, depending on the policy.
+        if let Some(firmware) = self.get_firmware()? {
+            read_only.push(firmware);
+        }
+
+        read_only.push(PathBuf::from(CH_URANDOM_DEVICE));
+
+        let jail = Jail::new(get_jailer_root(&self.id)?);
+
+        self.artifacts.add(jail.root(), ArtifactKind::Dir);
+
+        let result = jail.setup().and_then(|_| {
+            for path in &read_write {
+                jail.bind(path, false)?;
+            }
+
+            for path in &read_only {
+                jail.bind(path, true)?;
+            }
+
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            if let Err(te) = jail.teardown() {
+                warn!(sl!(), "failed to tear down jail: {:?}", te);
+            }
+
+            return Err(e.context(format!("failed to set up {} jail", CH_NAME)));
+        }
+
+        self.jailer_root = jail.root().display().to_string();
+        self.jail = Some(jail);
+
+        Ok(())
+    }
+
+    async fn cloud_hypervisor_launch(&mut self, _timeout_secs: i32) -> Result<()> {
+        self.cloud_hypervisor_ensure_not_launched().await?;
+
+        let jailed = self
+            .config
+            .as_ref()
+            .map(|c| c.security_info.enable_jail)
+            .unwrap_or_default();
+
+        if jailed {
+            self.cloud_hypervisor_setup_jail()?;
+        }
+
+        let api_socket_path = get_api_socket_path(&self.id)?;
+
+        let _ = std::fs::remove_file(api_socket_path.clone());
+
+        self.artifacts.add(&api_socket_path, ArtifactKind::File);
+
+        let config = self
+            .config
+            .as_ref()
+            .ok_or("no hypervisor config for CH")
+            .map_err(|e| anyhow!(e))?;
+
+        let binary_path = config.path.to_string();
+
+        let seccomp = config.security_info.seccomp.to_string();
+
+        let landlock = config.security_info.enable_landlock;
+        let landlock_strict = config.security_info.landlock_strict;
+
+        let cgroup_path = self.get_cgroup_path().unwrap_or_default();
+
+        let vmm_user = self.get_vmm_user();
+
+        let log_level = config.debug_info.ch_log_level;
+        let log_to_file = config.debug_info.enable_debug;
+
+        let event_monitor = config.machine_info.enable_event_monitor;
+
+        let path = get_executable_path(&binary_path)
+            .context(format!("{} binary path check failed", CH_NAME))?;
+
+        check_host_hypervisor(&config.machine_info.hypervisor_device)
+            .and_then(|_| {
+                self.check_ch_feature(get_hypervisor_feature(
+                    &config.machine_info.hypervisor_device,
+                ))
+            })
+            .context(format!("{} host hypervisor check failed", CH_NAME))?;
+
+        // CH creates its sockets and files in the sandbox directory.
+        if let Some((uid, gid)) = vmm_user {
+            let device = get_hypervisor_device_path(&config.machine_info.hypervisor_device);
+
+            check_user_access(device, uid, gid)
+                .context(format!("{} cannot run as user {}:{}", CH_NAME, uid, gid))?;
+
+            chown(
+                self.vm_path.as_str(),
+                Some(Uid::from_raw(uid)),
+                Some(Gid::from_raw(gid)),
+            )
+            .context(format!("failed to change owner of {}", self.vm_path))?;
+        }
+
+        let mut args = ChArgsBuilder::new(&api_socket_path).log_level(log_level);
+
+        if let Some(extra_args) = &self.extra_args {
+            args = args.extra_args(extra_args);
+        }
+
+        if log_to_file {
+            let log_path = get_vmm_log_path(&self.id)?;
+
+            create_log_file(&log_path)?;
+
+            if let Some((uid, gid)) = vmm_user {
+                chown(
+                    log_path.as_str(),
+                    Some(Uid::from_raw(uid)),
+                    Some(Gid::from_raw(gid)),
+                )
+                .context(format!("failed to change owner of {}", log_path))?;
+            }
+
+            self.artifacts.add(&log_path, ArtifactKind::File);
+
+            args = args.log_file(&log_path);
+
+            self.vmm_log_path = Some(log_path);
+        }
+
+        if event_monitor {
+            let path = get_event_monitor_path(&self.id)?;
+
+            let monitor = EventMonitor::start(Path::new(&path))?;
+
+            self.artifacts.add(&path, ArtifactKind::File);
+
+            self.grant_vmm_socket_access(&path)?;
+
+            args = args.event_monitor(&path);
+
+            self.event_monitor = Some(monitor);
+        }
+
+        // An empty mode means the config was not adjusted. CH enables
+        // seccomp filtering by default, which must be requested instead.
+        let seccomp = if seccomp.is_empty() {
+            DEFAULT_CH_SECCOMP.to_string()
+        } else {
+            seccomp
+        };
+
+        info!(sl!(), "{} seccomp mode: {}", CH_NAME, seccomp);
+
+        args = args.seccomp(&seccomp);
+
+        if landlock {
+            if host_supports_landlock() {
+                let rules = self.cloud_hypervisor_landlock_rules();
+
+                info!(sl!(), "{} landlock rules: {:?}", CH_NAME, rules);
+
+                args = args.landlock(rules);
+            } else if landlock_strict {
+                return Err(anyhow!(
+                    "landlock enabled for {} but not supported by the host kernel",
+                    CH_NAME
+                ));
+            } else {
+                warn!(
+                    sl!(),
+                    "landlock not supported by the host kernel, starting {} without it", CH_NAME
+                );
+            }
+        }
+
+        let args = args.build();
+
+        debug!(sl!(), "{} launch: {:?} {:?}", CH_NAME, path, args);
+
+        let mut cmd = Command::new(path);
+
+        cmd.current_dir("/");
+
+        cmd.stdin(Stdio::null());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+
+        cmd.env("RUST_BACKTRACE", "full");
+
+        cmd.args(args);
+
+        if let Some((uid, gid)) = vmm_user {
+            info!(sl!(), "{} runs as user {}:{}", CH_NAME, uid, gid);
+        }
+
+        // The user is switched when entering the jail, as entering it
+        // requires privileges. The supplementary groups of the runtime are
+        // dropped.
+        match &self.jail {
+            Some(jail) => {
+                let entry = jail.entry(vmm_user)?;
+
+                info!(sl!(), "{} confined to {:?}", CH_NAME, jail.root());
+
+                // SAFETY: enter() only makes system calls, so is safe to
+                // call between fork and exec.
+                unsafe {
+                    cmd.pre_exec(move || entry.enter());
+                }
+            }
+            None => {
+                if let Some((uid, gid)) = vmm_user {
+                    cmd.uid(uid);
+                    cmd.gid(gid);
+                }
+            }
+        }
+
+        let mut child = cmd.spawn().context(format!("{} spawn failed", CH_NAME))?;
+
+        // Save process PID
+        self.pid = child.id();
+
+        // Move CH before the VM is created, so that all the resources of the
+        // guest are accounted to the cgroup.
+        if !cgroup_path.is_empty() {
+            let result = match self.pid {
+                Some(pid) => move_to_cgroup(&cgroup_path, pid),
+                None => Err(anyhow!("{} exited before it could be moved", CH_NAME)),
+            };
+
+            if let Err(e) = result {
+                let _ = child.start_kill();
+
+                return Err(e.context(format!("failed to place {} in its cgroup", CH_NAME)));
+            }
+
+            info!(sl!(), "{} moved to cgroup {}", CH_NAME, cgroup_path);
+        }
+
+        if let Some(pid) = self.pid {
+            if let Err(e) = self.apply_oom_score_adj(pid, false) {
+                let _ = child.start_kill();
+
+                return Err(e.context(format!(
+                    "failed to set the OOM score adjustment of {}",
+                    CH_NAME
+                )));
+            }
+        }
+
+        let shutdown = self
+            .shutdown_rx
+            .as_ref()
+            .ok_or("no receiver channel")
+            .map_err(|e| anyhow!(e))?
+            .clone();
+
+        let (exit_tx, exit_rx) = channel(None);
+
+        self.exit_rx = Some(exit_rx);
+
+        let ch_outputlogger_task = tokio::spawn(cloud_hypervisor_log_output(
+            child,
+            shutdown,
+            exit_tx,
+            self.stop_requested.clone(),
+            self.vmm_log_path.clone(),
+        ));
+
+        let tasks = vec![ch_outputlogger_task];
+
+        self.tasks = Some(tasks);
+
+        Ok(())
+    }
+
+    // Make the TDX quote generation service (QGS) reachable from the guest on
+    // a vsock port of the host: the hybrid vsock connects the guest to the
+    // socket named after its own socket and the port, which is a symlink to
+    // the QGS socket.
+    #[cfg(target_arch = "x86_64")]
+    fn setup_qgs_socket(&mut self, confidential_guest: bool) -> Result<()> {
+        let (socket, port) = match &self.config {
+            Some(c) if !c.security_info.tdx_quote_generation_socket.is_empty() => (
+                c.security_info.tdx_quote_generation_socket.clone(),
+                c.security_info.tdx_quote_generation_port,
+            ),
+            _ => return Ok(()),
+        };
+
+        if !confidential_guest {
+            return Err(anyhow!(
+                "TDX quote generation socket {} requires a confidential guest",
+                socket
+            ));
+        }
+
+        let host_path = parse_qgs_socket(&socket)?;
+
+        let metadata = std::fs::metadata(&host_path)
+            .context(format!("TDX quote generation socket {:?}", host_path))?;
+
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!(
+                "TDX quote generation socket {:?} is not a socket",
+                host_path
+            ));
+        }
+
+        let port = if port == 0 {
+            CH_TDX_QGS_DEFAULT_PORT
+        } else {
+            port
+        };
+
+        if port == self.agent_vsock_port {
+            return Err(anyhow!(
+                "TDX quote generation port {} is used by the agent",
+                port
+            ));
+        }
+
+        let jail_path = self.get_jail_socket_path(&host_path)?;
+
+        let link = get_hybrid_vsock_host_path(&get_vsock_path(&self.id)?, port);
+
+        // A link left by a previous run would make the creation fail.
+        let _ = std::fs::remove_file(&link);
+
+        std::os::unix::fs::symlink(&jail_path, &link)
+            .context(format!("failed to link {} to {:?}", link, jail_path))?;
+
+        self.artifacts.add(&link, ArtifactKind::File);
+
+        info!(
+            sl!(),
+            "TDX quote generation socket {:?} reachable on vsock port {}", host_path, port
+        );
+
+        Ok(())
+    }
+
+    // Compute the list of Landlock rules for the paths CH needs to access,
+    // based on the boot files and the queued devices, so CH must be launched
+    // once the devices of the VM are queued. Share fs sockets specified as
+    // relative paths live below vm_path, so are covered by the vm_path rule.
+    fn cloud_hypervisor_landlock_rules(&self) -> Vec<String> {
+        let mut rules: Vec<(String, &str)> = vec![(self.vm_path.clone(), "rw")];
+
+        if let Some(config) = &self.config {
+            let boot_info = &config.boot_info;
+
+            let boot_file_access = if self.get_boot_file_discard_writes() {
+                "r"
+            } else {
+                "rw"
+            };
+
+            for (path, access) in [
+                (&boot_info.kernel, "r"),
+                (&boot_info.image, boot_file_access),
+                (&boot_info.initrd, boot_file_access),
+                (&boot_info.firmware, "r"),
+                (&config.machine_info.guest_entropy_seed_file, "r"),
+            ] {
+                if !path.is_empty() {
+                    rules.push((path.to_string(), access));
+                }
+            }
+        }
+
+        if let Some(config) = &self.config {
+            let qgs_socket = &config.security_info.tdx_quote_generation_socket;
+
+            if let Ok(path) = parse_qgs_socket(qgs_socket) {
+                rules.push((path.display().to_string(), "rw"));
+            }
+        }
+
+        if let Some(devices) = &self.pending_devices {
+            for dev in devices {
+                match &dev.device {
+                    Device::ShareFsDevice(cfg) => {
+                        if cfg.sock_path.starts_with('/') {
+                            if let Some(dir) = Path::new(&cfg.sock_path).parent() {
+                                rules.push((dir.display().to_string(), "rw"));
+                            }
+                        }
+
+                        if !cfg.host_path.is_empty() {
+                            rules.push((cfg.host_path.clone(), "rw"));
+                        }
+                    }
+                    Device::Block(cfg) => {
+                        let access = if cfg.is_readonly { "r" } else { "rw" };
+
+                        rules.push((cfg.path_on_host.clone(), access));
+                    }
+                    Device::Pmem(cfg) => {
+                        let access = if cfg.discard_writes { "r" } else { "rw" };
+
+                        rules.push((cfg.path_on_host.clone(), access));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        rules.sort();
+        rules.dedup();
+
+        rules
+            .iter()
+            .map(|(path, access)| format!("path={},access={}", path, access))
+            .collect()
+    }
+
+    // Stop CH, escalating from the gentlest mechanism to the most forceful
+    // one until it exits:
+    //
+    // 1. ACPI power button, if enabled, so that the guest shuts down cleanly
+    // 2. vmm.shutdown request
+    // 3. SIGTERM
+    // 4. SIGKILL, sent by the monitor task
+    //
+    // Stopping CH again, or once it has exited, does nothing but wait for
+    // the monitor task. The helper processes of the VM are stopped once CH
+    // exited, see stop_helpers().
+    async fn cloud_hypervisor_shutdown(&mut self) -> Result<()> {
+        let already_stopped = self.stop_requested.swap(true, Ordering::SeqCst);
+
+        if already_stopped && self.tasks.is_none() {
+            debug!(sl!(), "{} already stopped", CH_NAME);
+
+            return Ok(());
+        }
+
+        let start = Instant::now();
+
+        let stage = self.cloud_hypervisor_stop_stages().await?;
+
+        info!(
+            sl!(),
+            "{} stopped by {} after {:?}",
+            CH_NAME,
+            stage,
+            start.elapsed()
+        );
+
+        self.state = VmmState::VmStopped;
+
+        if let Some(monitor) = self.event_monitor.take() {
+            monitor.stop();
+        }
+
+        // Nothing to wait for if CH was neither launched nor adopted.
+        let tasks = match self.tasks.take() {
+            Some(tasks) => tasks,
+            None => return Ok(()),
+        };
+
+        let results = join_all(tasks).await;
+
+        let mut wait_errors: Vec<tokio::task::JoinError> = vec![];
+
+        for result in results {
+            if let Err(e) = result {
+                eprintln!("wait task error: {:#?}", e);
+
+                wait_errors.push(e);
+            }
+        }
+
+        if wait_errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("wait all tasks failed: {:#?}", wait_errors))
+        }
+    }
+
+    // Run the stop stages until CH exits, returning the stage which stopped
+    // it.
+    async fn cloud_hypervisor_stop_stages(&mut self) -> Result<&'static str> {
+        if let Some(exit) = self.exit_rx.as_ref().and_then(|rx| rx.borrow().clone()) {
+            info!(sl!(), "{} already exited ({})", CH_NAME, exit.status);
+
+            return Ok("none (already exited)");
+        }
+
+        let (acpi_shutdown, acpi_timeout, shutdown_timeout, terminate_timeout) = self
+            .config
+            .as_ref()
+            .map(|c| {
+                (
+                    c.machine_info.acpi_shutdown,
+                    c.machine_info.acpi_shutdown_timeout_secs,
+                    c.machine_info.vmm_shutdown_timeout_secs,
+                    c.machine_info.vmm_terminate_timeout_secs,
+                )
+            })
+            .unwrap_or_default();
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?
+            .try_clone()
+            .context("failed to clone socket")?;
+
+        // Only a running guest can react to the power button.
+        if acpi_shutdown && self.state == VmmState::VmRunning {
+            match cloud_hypervisor_vm_power_button(
+                socket.try_clone().context("failed to clone socket")?,
+            )
+            .await
+            {
+                Ok(_) => {
+                    if self.wait_vmm_exit_for(acpi_timeout).await {
+                        return Ok("ACPI power button");
+                    }
+                }
+                Err(e) => warn!(sl!(), "failed to press ACPI power button: {:?}", e),
+            }
+        }
+
+        match cloud_hypervisor_vmm_shutdown(socket).await {
+            Ok(response) => {
+                if let Some(detail) = response {
+                    debug!(sl!(), "shutdown response: {:?}", detail);
+                }
+
+                if self.wait_vmm_exit_for(shutdown_timeout).await {
+                    return Ok("vmm.shutdown");
+                }
+            }
+            Err(e) => warn!(sl!(), "failed to request {} shutdown: {:?}", CH_NAME, e),
+        }
+
+        if let Some(pid) = self.pid {
+            match kill(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+                Ok(_) => {
+                    if self.wait_vmm_exit_for(terminate_timeout).await {
+                        return Ok("SIGTERM");
+                    }
+                }
+                Err(e) => warn!(sl!(), "failed to terminate {}: {:?}", CH_NAME, e),
+            }
+        }
+
+        // The monitor task kills CH and waits for it.
+        self.shutdown_tx
+            .as_mut()
+            .ok_or("no shutdown channel")
+            .map_err(|e| anyhow!(e))?
+            .send(true)
+            .map_err(|e| anyhow!(e).context("failed to request shutdown"))?;
+
+        Ok("SIGKILL")
+    }
+
+    // Wait up to the specified number of seconds for CH to exit. Returns
+    // true if it exited.
+    async fn wait_vmm_exit_for(&mut self, timeout_secs: u32) -> bool {
+        let exit_rx = match self.exit_rx.as_mut() {
+            Some(rx) => rx,
+            None => return false,
+        };
+
+        let wait = async {
+            while exit_rx.borrow().is_none() {
+                if exit_rx.changed().await.is_err() {
+                    // The monitor task is gone, so CH was reaped.
+                    break;
+                }
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(timeout_secs as u64), wait)
+            .await
+            .is_ok()
+    }
+
+    #[allow(dead_code)]
+    async fn cloud_hypervisor_wait(&mut self) -> Result<()> {
+        let mut child = self
+            .process
+            .take()
+            .ok_or(format!("{} not running", CH_NAME))
+            .map_err(|e| anyhow!(e))?;
+
+        let _pid = child
+            .id()
+            .ok_or(format!("{} missing PID", CH_NAME))
+            .map_err(|e| anyhow!(e))?;
+
+        // Note that this kills _and_ waits for the process!
+        child.kill().await?;
+
+        Ok(())
+    }
+
+    async fn cloud_hypervisor_ping_until_ready(&mut self, _poll_time_ms: u64) -> Result<()> {
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        loop {
+            let response =
+                cloud_hypervisor_vmm_ping(socket.try_clone().context("failed to clone socket")?)
+                    .await
+                    .context("ping failed");
+
+            if let Ok(response) = response {
+                if let Some(detail) = response {
+                    debug!(sl!(), "ping response: {:?}", detail);
+
+                    self.ch_version = serde_json::from_str::<VmmPingResponse>(&detail)
+                        .map(|r| r.version)
+                        .ok();
+                }
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(CH_POLL_TIME_MS)).await;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn prepare_vm(&mut self, id: &str, netns: Option<String>) -> Result<()> {
+        self.id = id.to_string();
+        self.state = VmmState::NotReady;
+
+        self.setup_environment().await?;
+
+        self.netns = netns;
+
+        let vsock_cfg = VsockConfig::new(self.id.clone()).await?;
+
+        let dev = Device::Vsock(vsock_cfg);
+        self.add_device(dev).await.context("add vsock device")?;
+
+        // The Landlock rules of CH cannot change once it runs, so CH is only
+        // launched when the VM starts, once the devices are queued.
+        if !self.landlock_enabled() {
+            self.start_hypervisor(self.timeout_secs).await?;
+        }
+
+        Ok(())
+    }
+
+    fn landlock_enabled(&self) -> bool {
+        self.config
+            .as_ref()
+            .map_or(false, |c| c.security_info.enable_landlock)
+    }
+
+    async fn setup_environment(&mut self) -> Result<()> {
+        // run_dir and vm_path are the same (shared)
+        self.run_dir = get_sandbox_path(&self.id)?;
+        self.vm_path = self.run_dir.to_string();
+
+        create_dir_all(&self.run_dir)
+            .with_context(|| anyhow!("failed to create sandbox directory {}", self.run_dir))?;
+
+        self.artifacts.add(&self.run_dir, ArtifactKind::Dir);
+
+        if !self.jailer_root.is_empty() {
+            create_dir_all(self.jailer_root.as_str())
+                .map_err(|e| anyhow!("Failed to create dir {} err : {:?}", self.jailer_root, e))?;
+
+            self.artifacts.add(&self.jailer_root, ArtifactKind::Dir);
+        }
+
+        Ok(())
+    }
+
+    // Start the VM and wait for the guest agent, for up to the configured
+    // start timeout, or else the runtime timeout in milliseconds.
+    pub(crate) async fn start_vm(&mut self, timeout_ms: i32) -> Result<()> {
+        self.setup_environment().await?;
+
+        if self.landlock_enabled() {
+            self.start_hypervisor(self.timeout_secs).await?;
+        }
+
+        let configured_secs = self
+            .config
+            .as_ref()
+            .map(|c| c.machine_info.vm_start_timeout_secs)
+            .unwrap_or_default();
+
+        let timeout = match configured_secs {
+            0 => Duration::from_millis(timeout_ms.max(0) as u64),
+            secs => Duration::from_secs(secs as u64),
+        };
+
+        let (snapshot_path, prefault) = self
+            .config
+            .as_ref()
+            .map(|c| {
+                (
+                    c.boot_info.restore_snapshot_path.clone(),
+                    c.boot_info.restore_prefault,
+                )
+            })
+            .unwrap_or_default();
+
+        let result = if snapshot_path.is_empty() {
+            self.boot_vm().await
+        } else {
+            self.restore_vm(&snapshot_path, prefault).await
+        };
+
+        if let Err(e) = result {
+            return Err(self.add_vmm_log_context(e));
+        }
+
+        // Let the members of the group CH runs as, such as the shim, use the
+        // vsock socket CH created.
+        self.grant_vmm_socket_access(&get_vsock_path(&self.id)?)?;
+
+        if let Err(e) = self.wait_vm_started(timeout).await {
+            let e = self.add_vmm_log_context(e);
+
+            return Err(self.add_console_context(e));
+        }
+
+        self.save_recovery_state();
+
+        Ok(())
+    }
+
+    // Wait for the guest agent to accept connections, recording the boot
+    // stages reached so that a timeout reports the stage which stalled.
+    async fn wait_vm_started(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+
+        let mut progress = BootProgress::default();
+
+        loop {
+            self.update_boot_progress(&mut progress).await?;
+
+            let stage = match progress.stalled_stage() {
+                Some(stage) => stage,
+                None => {
+                    info!(sl!(), "VM started ({})", progress.summary());
+
+                    return Ok(());
+                }
+            };
+
+            if self.vmm_exited() {
+                return Err(anyhow!(
+                    "{} exited while the VM was starting, at {} ({})",
+                    CH_NAME,
+                    stage,
+                    progress.summary()
+                ));
+            }
+
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "VM did not start within {:?}, stalled at {} ({})",
+                    timeout,
+                    stage,
+                    progress.summary()
+                ));
+            }
+
+            tokio::time::sleep(Duration::from_millis(CH_BOOT_POLL_MS)).await;
+        }
+    }
+
+    // Check the boot stages not reached yet. A stage once reached stays
+    // reached.
+    async fn update_boot_progress(&self, progress: &mut BootProgress) -> Result<()> {
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        if !progress.vmm_responding {
+            progress.vmm_responding =
+                cloud_hypervisor_vmm_ping(socket.try_clone().context("failed to clone socket")?)
+                    .await
+                    .is_ok();
+        }
+
+        if progress.vmm_responding && !progress.vm_running {
+            progress.vm_running =
+                cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?)
+                    .await
+                    .map(|info| info.state == CH_VM_STATE_RUNNING)
+                    .unwrap_or_default();
+        }
+
+        if let Some(path) = &self.console_log_path {
+            let output = std::fs::metadata(path)
+                .map(|m| m.len() > 0)
+                .unwrap_or_default();
+
+            progress.kernel_output = Some(progress.kernel_output.unwrap_or_default() || output);
+        }
+
+        if progress.vm_running && !progress.agent_reachable {
+            let vsock_path = get_vsock_path(&self.id)?;
+            let agent_port = self.agent_vsock_port;
+
+            progress.agent_reachable = task::spawn_blocking(move || {
+                check_hybrid_vsock_port(
+                    &vsock_path,
+                    agent_port,
+                    Duration::from_millis(CH_BOOT_POLL_MS),
+                )
+            })
+            .await
+            .unwrap_or_default();
+        }
+
+        Ok(())
+    }
+
+    // The CH log almost always explains why a VM failed to start, so add
+    // the end of it to the error.
+    fn add_vmm_log_context(&self, e: anyhow::Error) -> anyhow::Error {
+        let log_path = match &self.vmm_log_path {
+            Some(path) => path,
+            None => return e,
+        };
+
+        match get_file_tail(log_path, CH_LOG_TAIL_LINES) {
+            Ok(lines) if !lines.is_empty() => e.context(format!(
+                "{} log ({}):\n{}",
+                CH_NAME,
+                log_path,
+                lines.join("\n")
+            )),
+            Ok(_) => e,
+            Err(err) => {
+                warn!(sl!(), "failed to read {} log: {:?}", CH_NAME, err);
+                e
+            }
+        }
+    }
+
+    // Add the end of the guest console, if buffered, which tells how far
+    // the guest went.
+    fn add_console_context(&self, e: anyhow::Error) -> anyhow::Error {
+        let tail = match &self.console_buffer {
+            Some(buffer) => buffer.tail(CH_CONSOLE_TAIL_BYTES),
+            None => return e,
+        };
+
+        if tail.is_empty() {
+            return e;
+        }
+
+        e.context(format!(
+            "guest console:\n{}",
+            String::from_utf8_lossy(&tail)
+        ))
+    }
+
+    // Start copying the guest console to the console buffer, if enabled.
+    // The task stops with CH, and the buffer is kept.
+    fn start_console_capture(&mut self) -> Result<()> {
+        let size_kb = self
+            .config
+            .as_ref()
+            .map(|c| c.debug_info.console_buffer_size_kb)
+            .unwrap_or_default();
+
+        if size_kb == 0 {
+            return Ok(());
+        }
+
+        let path = self
+            .console_socket_path
+            .clone()
+            .ok_or("console buffer requires the console socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let shutdown = self
+            .shutdown_rx
+            .as_ref()
+            .ok_or("no receiver channel")
+            .map_err(|e| anyhow!(e))?
+            .clone();
+
+        let buffer = ConsoleBuffer::new(size_kb as usize * 1024);
+
+        self.console_buffer = Some(buffer.clone());
+
+        let task = tokio::spawn(async move {
+            let result = capture_console(buffer, Path::new(&path), shutdown).await;
+
+            if let Err(e) = &result {
+                warn!(sl!(), "guest console capture stopped: {:#}", e);
+            }
+
+            result
+        });
+
+        self.tasks.get_or_insert_with(Vec::new).push(task);
+
+        Ok(())
+    }
+
+    pub(crate) async fn stop_vm(&mut self) -> Result<()> {
+        let result = self.cloud_hypervisor_shutdown().await;
+
+        self.counter_baselines.clear();
+
+        if result.is_ok() {
+            self.stop_helpers().await;
+        }
+
+        // A failed stop stage would otherwise leave the files of the VM
+        // behind, as the caller does not clean up after a failed stop.
+        if let Err(e) = &result {
+            warn!(sl!(), "failed to stop {}: {:?}", CH_NAME, e);
+
+            if self.cloud_hypervisor_ensure_dead().await {
+                self.stop_helpers().await;
+
+                if let Err(e) = self.cleanup().await {
+                    warn!(sl!(), "failed to clean up after failed stop: {:?}", e);
+                }
+            } else {
+                warn!(sl!(), "{} may still be running, not cleaning up", CH_NAME);
+            }
+        }
+
+        result
+    }
+
+    // Make sure CH is dead, killing it if needed. Returns false if its exit
+    // could not be confirmed.
+    async fn cloud_hypervisor_ensure_dead(&mut self) -> bool {
+        if self.vmm_exited() {
+            return true;
+        }
+
+        let pid = match self.pid {
+            Some(pid) => pid,
+            None => return false,
+        };
+
+        if let Err(e) = kill(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+            warn!(sl!(), "failed to kill {}: {:?}", CH_NAME, e);
+        }
+
+        self.wait_vmm_exit_for(CH_KILL_TIMEOUT_SECS).await
+    }
+
+    // Stop the helper processes of the VM, such as virtiofsd and swtpm, once
+    // CH exited, with the same escalation as CH: they are given the
+    // shutdown timeout to exit on their own, as virtiofsd does once CH
+    // disconnects, then sent SIGTERM, and finally SIGKILL. They are reaped
+    // by the process which started them, so a zombie counts as stopped.
+    async fn stop_helpers(&mut self) {
+        let (shutdown_timeout, terminate_timeout) = self
+            .config
+            .as_ref()
+            .map(|c| {
+                (
+                    c.machine_info.vmm_shutdown_timeout_secs,
+                    c.machine_info.vmm_terminate_timeout_secs,
+                )
+            })
+            .unwrap_or_default();
+
+        let stages = [
+            ("exit", None, shutdown_timeout),
+            ("SIGTERM", Some(Signal::SIGTERM), terminate_timeout),
+            ("SIGKILL", Some(Signal::SIGKILL), CH_KILL_TIMEOUT_SECS),
+        ];
+
+        let mut running: Vec<u32> = std::mem::take(&mut self.helper_pids);
+
+        if running.is_empty() {
+            return;
+        }
+
+        for (stage, signal, timeout_secs) in stages {
+            if let Some(signal) = signal {
+                for pid in &running {
+                    info!(sl!(), "sending {} to helper process {}", stage, pid);
+
+                    if let Err(e) = kill(Pid::from_raw(*pid as i32), signal) {
+                        warn!(sl!(), "failed to signal helper process {}: {:?}", pid, e);
+                    }
+                }
+            }
+
+            running = wait_processes_exit(running, timeout_secs).await;
+
+            if running.is_empty() {
+                info!(sl!(), "helper processes stopped by {}", stage);
+
+                return;
+            }
+        }
+
+        warn!(sl!(), "helper processes {:?} still running", running);
+    }
+
+    // Stop the helper processes serving a device socket, such as the
+    // virtiofsd of a forcibly removed device. CH may still be connected to
+    // them, so they are sent SIGTERM and then SIGKILL.
+    pub(crate) async fn stop_socket_helpers(&mut self, socket: &Path) {
+        let terminate_timeout = self
+            .config
+            .as_ref()
+            .map(|c| c.machine_info.vmm_terminate_timeout_secs)
+            .unwrap_or_default();
+
+        let stages = [
+            ("SIGTERM", Signal::SIGTERM, terminate_timeout),
+            ("SIGKILL", Signal::SIGKILL, CH_KILL_TIMEOUT_SECS),
+        ];
+
+        let mut running = self.take_socket_helpers(socket);
+
+        if running.is_empty() {
+            return;
+        }
+
+        for (stage, signal, timeout_secs) in stages {
+            for pid in &running {
+                info!(sl!(), "sending {} to helper process {}", stage, pid);
+
+                if let Err(e) = kill(Pid::from_raw(*pid as i32), signal) {
+                    warn!(sl!(), "failed to signal helper process {}: {:?}", pid, e);
+                }
+            }
+
+            running = wait_processes_exit(running, timeout_secs).await;
+
+            if running.is_empty() {
+                info!(
+                    sl!(),
+                    "helper processes of {:?} stopped by {}", socket, stage
+                );
+
+                return;
+            }
+        }
+
+        warn!(sl!(), "helper processes {:?} still running", running);
+    }
+
+    // Stop tracking the helper processes serving the socket, returning their
+    // pids.
+    pub(crate) fn take_socket_helpers(&mut self, socket: &Path) -> Vec<u32> {
+        let (helpers, others): (Vec<u32>, Vec<u32>) = std::mem::take(&mut self.helper_pids)
+            .into_iter()
+            .partition(|pid| is_process_using_path(Path::new(&format!("/proc/{}", pid)), socket));
+
+        self.helper_pids = others;
+
+        if !helpers.is_empty() {
+            self.save_recovery_state();
+        }
+
+        helpers
+    }
+
+    // Return true if the CH process is known not to be running: the monitor
+    // task reported its exit, or else its pid is gone. Without either, CH
+    // may be running.
+    fn vmm_exited(&self) -> bool {
+        match (&self.exit_rx, self.pid) {
+            (Some(rx), _) => rx.borrow().is_some(),
+            (None, Some(pid)) => !is_process_running(Path::new(&format!("/proc/{}", pid))),
+            (None, None) => false,
+        }
+    }
+
+    // Pause the VM. Pausing a paused VM succeeds. Devices added while the
+    // VM is paused are queued until it is resumed.
+    pub(crate) async fn pause_vm(&mut self) -> Result<()> {
+        match self.state {
+            VmmState::VmPaused => return Ok(()),
+            VmmState::VmRunning => (),
+            _ => return Err(anyhow!("cannot pause VM with VMM state {:?}", self.state)),
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let response =
+            cloud_hypervisor_vm_pause(socket.try_clone().context("failed to clone socket")?)
+                .await
+                .context("failed to pause VM")?;
+
+        if let Some(detail) = response {
+            debug!(sl!(), "vm pause response: {:?}", detail);
+        }
+
+        self.state = VmmState::VmPaused;
+
+        info!(sl!(), "VM paused");
+
+        Ok(())
+    }
+
+    // Resume the VM, then add the devices queued while it was paused.
+    // Resuming a running VM succeeds.
+    pub(crate) async fn resume_vm(&mut self) -> Result<()> {
+        match self.state {
+            VmmState::VmRunning => return Ok(()),
+            VmmState::VmPaused => (),
+            _ => return Err(anyhow!("cannot resume VM with VMM state {:?}", self.state)),
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let response =
+            cloud_hypervisor_vm_resume(socket.try_clone().context("failed to clone socket")?)
+                .await
+                .context("failed to resume VM")?;
+
+        if let Some(detail) = response {
+            debug!(sl!(), "vm resume response: {:?}", detail);
+        }
+
+        self.state = VmmState::VmRunning;
+
+        info!(sl!(), "VM resumed");
+
+        self.handle_paused_devices_after_resume()
+            .await
+            .context("failed to add devices queued while paused")
+    }
+
+    // Snapshot the VM to the specified directory, or to a directory below
+    // vm_path. A running VM is paused while the snapshot is taken, then
+    // resumed. A paused VM is left paused.
+    pub(crate) async fn save_vm(&self, path: Option<&str>, mode: SnapshotMode) -> Result<String> {
+        check_snapshot_mode(mode)?;
+        check_not_confidential(self.config.as_ref(), "VM snapshot")?;
+
+        let was_running = self.state == VmmState::VmRunning;
+
+        if !was_running && self.state != VmmState::VmPaused {
+            return Err(anyhow!("cannot save VM with VMM state {:?}", self.state));
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let info =
+            cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await?;
+
+        let vm_config = info.config.unwrap_or_default();
+
+        check_snapshot_devices(&vm_config)?;
+
+        let ch_version =
+            cloud_hypervisor_vmm_ping(socket.try_clone().context("failed to clone socket")?)
+                .await?
+                .map(|detail| serde_json::from_str::<VmmPingResponse>(&detail))
+                .transpose()
+                .context("invalid ping response")?
+                .map(|r| r.version)
+                .unwrap_or_default();
+
+        let dir = match path {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let dir = Path::new(&self.vm_path).join(SNAPSHOT_DIR_NAME);
+
+                self.artifacts.add(&dir, ArtifactKind::Tree);
+
+                self.save_recovery_state();
+
+                dir
+            }
+        };
+
+        create_dir_all(&dir).context(format!("failed to create snapshot directory {:?}", dir))?;
+
+        if dir.read_dir()?.next().is_some() {
+            return Err(anyhow!("snapshot directory {:?} is not empty", dir));
+        }
+
+        let metadata = SnapshotMetadata {
+            ch_version,
+            devices: get_snapshot_devices(&vm_config),
+            memory_size: get_boot_memory_size(&vm_config),
+            sandbox_id: self.id.clone(),
+        };
+
+        if was_running {
+            cloud_hypervisor_vm_pause(socket.try_clone().context("failed to clone socket")?)
+                .await
+                .context("failed to pause VM")?;
+        }
+
+        let destination = self.get_jail_path(&dir, false)?;
+
+        let result = cloud_hypervisor_vm_snapshot(
+            socket.try_clone().context("failed to clone socket")?,
+            VmSnapshotConfig {
+                destination_url: format!("file://{}", destination.display()),
+            },
+        )
+        .await
+        .context(format!("failed to snapshot VM to {:?}", dir));
+
+        // Resume the VM even if the snapshot failed, so that it keeps running.
+        if was_running {
+            let resumed =
+                cloud_hypervisor_vm_resume(socket.try_clone().context("failed to clone socket")?)
+                    .await
+                    .context("failed to resume VM after snapshot");
+
+            result?;
+            resumed?;
+        } else {
+            result?;
+        }
+
+        metadata.write(&dir)?;
+
+        info!(sl!(), "VM saved to {:?}", dir);
+
+        Ok(dir.display().to_string())
+    }
+
+    // Send the running VM to the CH instance listening on dest_url. On
+    // success the source VM is stopped. On failure the source VM is
+    // resumed so it keeps running.
+    pub(crate) async fn migrate_send(&mut self, dest_url: &str) -> Result<()> {
+        check_migration_url(dest_url)?;
+        check_not_confidential(self.config.as_ref(), "VM migration")?;
+
+        if self.state != VmmState::VmRunning {
+            return Err(anyhow!(
+                "cannot send migration with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?
+            .try_clone()
+            .context("failed to clone socket")?;
+
+        self.state = VmmState::VmMigrating;
+
+        let data = VmSendMigrationData {
+            destination_url: dest_url.to_string(),
+            local: false,
+        };
+
+        let result = cloud_hypervisor_vm_send_migration(
+            socket.try_clone().context("failed to clone socket")?,
+            data,
+        )
+        .await;
+
+        match result {
+            Ok(response) => {
+                if let Some(detail) = response {
+                    debug!(sl!(), "send migration response: {:?}", detail);
+                }
+
+                self.state = VmmState::VmStopped;
+
+                Ok(())
+            }
+            Err(e) => {
+                error!(sl!(), "send migration to {} failed: {:?}", dest_url, e);
+
+                // CH may have paused the VM before the failure, so make
+                // sure it runs again.
+                if let Err(err) = cloud_hypervisor_vm_resume(socket).await {
+                    warn!(
+                        sl!(),
+                        "failed to resume VM after migration failure: {:?}", err
+                    );
+                }
+
+                self.state = VmmState::VmRunning;
+
+                Err(e.context(format!("failed to send migration to {}", dest_url)))
+            }
+        }
+    }
+
+    // Receive a VM migrated from another host on listen_url. The VMM must
+    // have been launched, but no VM created.
+    pub(crate) async fn migrate_receive(&mut self, listen_url: &str) -> Result<()> {
+        check_migration_url(listen_url)?;
+
+        if self.state != VmmState::VmmServerReady {
+            return Err(anyhow!(
+                "cannot receive migration with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?
+            .try_clone()
+            .context("failed to clone socket")?;
+
+        self.state = VmmState::VmMigrating;
+
+        let data = VmReceiveMigrationData {
+            receiver_url: listen_url.to_string(),
+        };
+
+        match cloud_hypervisor_vm_receive_migration(socket, data).await {
+            Ok(response) => {
+                if let Some(detail) = response {
+                    debug!(sl!(), "receive migration response: {:?}", detail);
+                }
+
+                self.state = VmmState::VmRunning;
+
+                Ok(())
+            }
+            Err(e) => {
+                self.state = VmmState::VmmServerReady;
+
+                Err(e.context(format!("failed to receive migration on {}", listen_url)))
+            }
+        }
+    }
+
+    pub(crate) async fn get_agent_socket(&self) -> Result<String> {
+        const HYBRID_VSOCK_SCHEME: &str = "hvsock";
+
+        let vsock_path = get_vsock_path(&self.id)?;
+
+        let uri = format!("{}://{}", HYBRID_VSOCK_SCHEME, vsock_path);
+
+        Ok(uri)
+    }
+
+    pub(crate) fn set_agent_vsock_port(&mut self, port: u32) {
+        self.agent_vsock_port = port;
+    }
+
+    // CH cannot change the guest RTC, so the offset is only validated and
+    // recorded here: applying it is the guest side's responsibility.
+    pub(crate) fn set_guest_clock_offset(&mut self, offset_secs: i64) -> Result<()> {
+        if offset_secs.abs() > CH_MAX_GUEST_CLOCK_OFFSET_SECS {
+            return Err(anyhow!(
+                "guest clock offset {} is out of range (maximum {} seconds)",
+                offset_secs,
+                CH_MAX_GUEST_CLOCK_OFFSET_SECS
+            ));
+        }
+
+        info!(sl!(), "guest clock offset set to {} seconds", offset_secs);
+
+        self.guest_clock_offset = offset_secs;
+
+        self.save_recovery_state();
+
+        Ok(())
+    }
+
+    pub(crate) fn get_guest_clock_offset(&self) -> i64 {
+        self.guest_clock_offset
+    }
+
+    // Return the configured cgroup to place CH and its helper processes in,
+    // if any. Otherwise they stay in the cgroup the resource manager placed
+    // the shim in, the sandbox cgroup or the overhead one.
+    fn get_cgroup_path(&self) -> Option<String> {
+        self.config
+            .as_ref()
+            .map(|c| c.security_info.vmm_cgroup_path.to_string())
+            .filter(|p| !p.is_empty())
+    }
+
+    pub(crate) fn set_host_numa_node(&mut self, node: Option<u32>) {
+        info!(sl!(), "host NUMA node: {:?}", node);
+
+        self.host_numa_node = node;
+    }
+
+    // Return the host NUMA node to place the VM on, if any.
+    fn get_host_numa_node(&self) -> Option<u32> {
+        self.host_numa_node.or_else(|| {
+            self.config
+                .as_ref()
+                .and_then(|c| c.memory_info.host_numa_node)
+        })
+    }
+
+    // Place the VM on the host NUMA node, if any: the guest memory is
+    // allocated from the node, the vCPUs run on its CPUs, and the guest has
+    // a single NUMA node. Fails if the node does not exist, or has not enough
+    // free memory for the memory the VM boots with.
+    fn set_numa_placement(&self, cfg: &mut VmConfig) -> Result<()> {
+        let node = match self.get_host_numa_node() {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+
+        let host_cpus = get_numa_node_cpus(node)?;
+
+        let free_mb = get_numa_node_free_mb(node, cfg.memory.hugepages, cfg.memory.hugepage_size)?;
+        let boot_mb = get_boot_memory_size(cfg) / MIB;
+
+        if free_mb < boot_mb {
+            return Err(anyhow!(
+                "host NUMA node {} has {} MiB of free memory, the VM boots with {} MiB",
+                node,
+                free_mb,
+                boot_mb
+            ));
+        }
+
+        set_numa_memory_zone(&mut cfg.memory, node, self.memory_hotplug_method);
+
+        cfg.cpus.affinity = Some(get_vcpu_affinity(cfg.cpus.max_vcpus, &host_cpus)?);
+
+        cfg.numa = Some(vec![NumaConfig {
+            guest_numa_id: 0,
+            cpus: Some((0..cfg.cpus.max_vcpus).collect()),
+            memory_zones: Some(
+                cfg.memory
+                    .zones
+                    .iter()
+                    .flatten()
+                    .map(|z| z.id.clone())
+                    .collect(),
+            ),
+            ..Default::default()
+        }]);
+
+        info!(
+            sl!(),
+            "VM placed on host NUMA node {}: CPUs {:?}, {} MiB free", node, host_cpus, free_mb
+        );
+
+        Ok(())
+    }
+
+    pub(crate) fn set_hugepage_requests(&mut self, requests: Vec<HugePagesRequest>) {
+        info!(sl!(), "huge pages requested by the pod: {:?}", requests);
+
+        self.hugepage_requests = requests;
+
+        self.save_recovery_state();
+    }
+
+    pub(crate) fn set_restore_net_fds(&mut self, net_fds: HashMap<String, Vec<RawFd>>) {
+        info!(
+            sl!(),
+            "net devices to restore with new tap fds: {:?}",
+            net_fds.keys().collect::<Vec<&String>>()
+        );
+
+        self.restore_net_fds = net_fds;
+    }
+
+    // Fail with ChError::Unsupported if memory cannot be hot added to the
+    // guest, being a confidential guest without the explicit opt-in.
+    fn check_memory_hotplug(&self) -> Result<()> {
+        let enabled = self
+            .config
+            .as_ref()
+            .map_or(false, |c| c.memory_info.enable_confidential_memory_hotplug);
+
+        check_confidential_memory_hotplug(self.is_confidential_guest(), enabled)
+    }
+
+    // Return the memory hotplug method and the hot pluggable memory size in
+    // bytes, based on the maximum memory of the VM (all of the host memory if
+    // unset) and the boot memory size.
+    //
+    // With virtio-mem, the guest kernel must be built with CONFIG_VIRTIO_MEM
+    // and the memory is plugged by the virtio-mem driver, which onlines it
+    // according to the memhp_default_state kernel parameter. With ACPI, the
+    // hot added memory blocks are onlined by the agent.
+    //
+    // A VM sized statically has no memory to hot plug, nor has a
+    // confidential guest unless explicitly allowed.
+    fn get_memory_hotplug_config(&self, boot_size: u64) -> Result<(HotplugMethod, Option<u64>)> {
+        let memory_info = match self.config.as_ref() {
+            Some(config) if config.static_sandbox_resource_mgmt => {
+                return Ok((HotplugMethod::default(), None))
+            }
+            Some(config) => &config.memory_info,
+            None => return Ok((HotplugMethod::default(), None)),
+        };
+
+        if let Err(e) = self.check_memory_hotplug() {
+            info!(sl!(), "{}, sizing the memory statically", e);
+
+            return Ok((HotplugMethod::default(), None));
+        }
+
+        let memory_offset_mb = self
+            .config
+            .as_ref()
+            .map(|c| c.blockdev_info.memory_offset)
+            .unwrap_or_default();
+
+        let max_memory_mb = match memory_info.default_maxmemory {
+            0 => get_host_memory_mb()?,
+            max => max as u64,
+        };
+
+        get_memory_hotplug(
+            memory_info,
+            max_memory_mb + memory_offset_mb,
+            boot_size / MIB,
+        )
+    }
+
+    // Return the size in bytes of the memory BARs of the VFIO devices added
+    // before the VM boots. A device whose BARs cannot be read is ignored.
+    fn get_vfio_bars_size(&self) -> u64 {
+        let devices = match &self.pending_devices {
+            Some(devices) => devices,
+            None => return 0,
+        };
+
+        let mut size: u64 = 0;
+
+        for dev in devices {
+            let cfg = match &dev.device {
+                Device::Vfio(cfg) => cfg,
+                _ => continue,
+            };
+
+            match get_pci_bars_size(&get_pci_device_path(&cfg.bus_slot_func)) {
+                Ok(bars_size) => {
+                    debug!(
+                        sl!(),
+                        "VFIO device {} ({}) BARs: {} MiB",
+                        cfg.id,
+                        cfg.bus_slot_func,
+                        bars_size / MIB
+                    );
+
+                    size = size.saturating_add(bars_size);
+                }
+                Err(e) => warn!(
+                    sl!(),
+                    "cannot reserve address space for VFIO device {}: {:#}", cfg.id, e
+                ),
+            }
+        }
+
+        size
+    }
+
+    // Warn if the maximum memory, which includes the memory offset, and the
+    // BARs of the VFIO devices nearly fill the guest physical address space,
+    // as the guest could then fail to hot add memory or to map the devices.
+    fn check_address_space(&self) {
+        let bars_size = self.get_vfio_bars_size();
+        let phys_bits = get_host_phys_bits().unwrap_or(CH_DEFAULT_PHYS_BITS);
+
+        if let Some(warning) = get_address_space_warning(self.max_memory_mb, bars_size, phys_bits) {
+            warn!(sl!(), "{}", warning);
+        }
+    }
+
+    // Return the canonical path of the file, or directory, backing the guest
+    // memory, if configured. The path must be allowed by the valid memory
+    // backends of the configuration, if any.
+    fn get_memory_file_backend(&self) -> Result<Option<PathBuf>> {
+        let memory_info = match self.config.as_ref() {
+            Some(config) if !config.memory_info.file_mem_backend.is_empty() => &config.memory_info,
+            _ => return Ok(None),
+        };
+
+        let path = Path::new(&memory_info.file_mem_backend)
+            .canonicalize()
+            .context(format!(
+                "invalid memory backend path {:?}",
+                memory_info.file_mem_backend
+            ))?;
+
+        if !memory_info.valid_file_mem_backends.is_empty() {
+            memory_info
+                .validate_memory_backend_path(&path)
+                .map_err(|e| anyhow!(e))
+                .context(format!("memory backend path {:?} not allowed", path))?;
+        }
+
+        if memory_info.enable_hugepages {
+            return Err(anyhow!(
+                "memory backend file {:?} cannot be used with huge pages",
+                path
+            ));
+        }
+
+        Ok(Some(path))
+    }
+
+    // Resize the guest memory, returning the size in MiB of the memory CH
+    // reports as plugged, which may differ from the requested size as CH
+    // aligns it. The size is limited to the maximum memory configured when
+    // the VM was booted, and the hot added memory to the configured quota.
+    // Memory can only be removed with virtio-mem, if enabled, and is kept
+    // above the boot memory and the memory used by the guest, if known, plus
+    // the configured reserve: a shrink below this floor only removes the
+    // memory above it. Without virtio-mem, the memory is shrunk by inflating
+    // the balloon if enabled, as long as the guest keeps enough free memory,
+    // which requires the caller to provide the free memory of the guest in
+    // MiB. The memory reclaimed on hints is given back first when the guest
+    // needs more.
+    pub(crate) async fn resize_memory(
+        &mut self,
+        new_mem_mb: u64,
+        guest_free_mb: Option<u64>,
+    ) -> Result<ResizedMemory> {
+        check_resizable(self.config.as_ref(), "memory resize")?;
+
+        self.check_memory_hotplug()?;
+
+        if self.state != VmmState::VmRunning {
+            return Err(anyhow!(
+                "cannot resize memory with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        let (quota_mb, enable_unplug, reclaim_percent, reserve_mb) = self
+            .config
+            .as_ref()
+            .map(|c| {
+                (
+                    c.memory_info.memory_hotplug_quota_mb,
+                    c.memory_info.enable_memory_unplug,
+                    c.memory_info.balloon_reclaim_free_percent,
+                    c.memory_info.memory_shrink_reserve_mb,
+                )
+            })
+            .unwrap_or_default();
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let info =
+            cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await?;
+
+        let cfg = info.config.unwrap_or_default();
+
+        if guest_free_mb.is_some() {
+            self.guest_free_mb = guest_free_mb;
+        }
+
+        let current_mb = get_plugged_memory_size(&cfg) / MIB;
+        let mut balloon_mb = cfg.balloon.map(|b| b.size / MIB).unwrap_or_default();
+
+        let deflate_mb =
+            get_hint_deflation(new_mem_mb, current_mb, balloon_mb, self.hinted_balloon_mb);
+
+        if deflate_mb > 0 {
+            self.resize_balloon(balloon_mb - deflate_mb).await?;
+
+            info!(
+                sl!(),
+                "balloon deflated by {} MiB reclaimed on hints ({} MiB of memory requested)",
+                deflate_mb,
+                new_mem_mb
+            );
+
+            balloon_mb -= deflate_mb;
+            self.hinted_balloon_mb -= deflate_mb;
+        }
+
+        let balloon_fallback =
+            self.memory_hotplug_method != HotplugMethod::VirtioMem && reclaim_percent != 0;
+
+        if balloon_fallback && (new_mem_mb < current_mb || balloon_mb > 0) {
+            let size_mb = get_balloon_reclaim(
+                new_mem_mb,
+                current_mb,
+                balloon_mb,
+                guest_free_mb,
+                reclaim_percent,
+            )?;
+
+            if size_mb != balloon_mb {
+                self.resize_balloon(size_mb).await?;
+
+                info!(
+                    sl!(),
+                    "balloon resized from {} MiB to {} MiB ({} MiB of memory requested)",
+                    balloon_mb,
+                    size_mb,
+                    new_mem_mb
+                );
+
+                balloon_mb = size_mb;
+            }
+
+            // The balloon now matches the requested size.
+            self.hinted_balloon_mb = self.hinted_balloon_mb.min(balloon_mb);
+
+            // Memory is only hot added once the balloon is deflated.
+            if new_mem_mb <= current_mb {
+                return Ok(ResizedMemory {
+                    size_mb: current_mb,
+                    online_required: false,
+                    balloon_reclaim: true,
+                    balloon_mb,
+                });
+            }
+        }
+
+        let target_mb = get_memory_resize(new_mem_mb, self.boot_memory_mb, self.max_memory_mb)?;
+
+        check_hotplug_quota("memory", target_mb - self.boot_memory_mb, quota_mb as u64)?;
+
+        let online_required = self.memory_hotplug_method == HotplugMethod::Acpi;
+
+        if target_mb == current_mb {
+            debug!(
+                sl!(),
+                "{} MiB of memory already plugged, not resizing", current_mb
+            );
+
+            return Ok(ResizedMemory {
+                size_mb: current_mb,
+                online_required: false,
+                balloon_reclaim: false,
+                balloon_mb,
+            });
+        }
+
+        let zones = cfg.memory.zones.is_some();
+
+        let plugged_mb = if target_mb < current_mb {
+            check_memory_unplug(self.memory_hotplug_method, enable_unplug).context(format!(
+                "cannot resize memory from {} MiB to {} MiB",
+                current_mb, target_mb
+            ))?;
+
+            let floor_mb = get_memory_shrink_floor(
+                self.boot_memory_mb,
+                reserve_mb as u64,
+                current_mb,
+                self.guest_free_mb,
+            );
+
+            if target_mb < floor_mb {
+                warn!(
+                    sl!(),
+                    "memory shrink to {} MiB limited to {} MiB (guest free memory {:?} MiB, reserve {} MiB)",
+                    target_mb,
+                    floor_mb,
+                    self.guest_free_mb,
+                    reserve_mb
+                );
+            }
+
+            let target_mb = target_mb.max(floor_mb).min(current_mb);
+
+            self.shrink_memory(zones, current_mb, target_mb).await?
+        } else {
+            let plugged_mb = self.request_memory_resize(zones, target_mb).await?;
+
+            check_resized_memory(target_mb, plugged_mb)?;
+
+            plugged_mb
+        };
+
+        info!(
+            sl!(),
+            "memory resized from {} MiB to {} MiB ({} MiB requested)",
+            current_mb,
+            plugged_mb,
+            new_mem_mb
+        );
+
+        Ok(ResizedMemory {
+            size_mb: plugged_mb,
+            online_required: online_required && plugged_mb > current_mb,
+            balloon_reclaim: false,
+            balloon_mb,
+        })
+    }
+
+    // Shrink the memory in steps, checking the memory CH reports plugged
+    // after each one, so that the shrink stops as soon as the guest fails to
+    // release memory. Returns the memory plugged when the shrink completed
+    // or stopped, failing only if no memory was removed.
+    async fn shrink_memory(&self, zones: bool, current_mb: u64, target_mb: u64) -> Result<u64> {
+        let mut plugged_mb = current_mb;
+
+        for step_mb in get_memory_shrink_steps(current_mb, target_mb, CH_MEMORY_SHRINK_STEP_MB) {
+            let result = self
+                .request_memory_resize(zones, step_mb)
+                .await
+                .and_then(|plugged_mb| {
+                    check_resized_memory(step_mb, plugged_mb)?;
+
+                    Ok(plugged_mb)
+                });
+
+            match result {
+                Ok(step_plugged_mb) => plugged_mb = step_plugged_mb,
+                Err(e) if plugged_mb < current_mb => {
+                    warn!(
+                        sl!(),
+                        "memory shrink to {} MiB stopped at {} MiB: {:#}", target_mb, plugged_mb, e
+                    );
+
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(plugged_mb)
+    }
+
+    // Request CH to resize the memory, returning the size in MiB it then
+    // reports as plugged. CH only resizes the memory zones, when the memory
+    // is defined by zones.
+    async fn request_memory_resize(&self, zones: bool, size_mb: u64) -> Result<u64> {
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let response = if zones {
+            let resize = VmResizeZone {
+                id: CH_MEMORY_ZONE_ID.to_string(),
+                desired_ram: size_mb * MIB,
+            };
+
+            cloud_hypervisor_vm_resize_zone(
+                socket.try_clone().context("failed to clone socket")?,
+                resize,
+            )
+            .await?
+        } else {
+            let resize = VmResize {
+                desired_ram: Some(size_mb * MIB),
+                ..Default::default()
+            };
+
+            cloud_hypervisor_vm_resize(
+                socket.try_clone().context("failed to clone socket")?,
+                resize,
+            )
+            .await?
+        };
+
+        if let Some(detail) = response {
+            debug!(sl!(), "memory resize response: {:?}", detail);
+        }
+
+        let info =
+            cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await?;
+
+        Ok(get_plugged_memory_size(&info.config.unwrap_or_default()) / MIB)
+    }
+
+    pub(crate) fn set_guest_free_memory(&mut self, free_mb: u64) {
+        self.guest_free_mb = Some(free_mb);
+    }
+
+    // Inflate the balloon by the memory the runtime hints the guest no
+    // longer needs, such as the memory of an exited container, returning
+    // the size in MiB by which it was inflated. The inflation is limited to
+    // the configured percentage of the guest free memory, from its most
+    // recent stats, and to nothing if the stats are unknown.
+    pub(crate) async fn shrink_memory_hint(&mut self, bytes: u64) -> Result<u64> {
+        if self.state != VmmState::VmRunning {
+            return Err(anyhow!(
+                "cannot shrink memory with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        let free_percent = self
+            .config
+            .as_ref()
+            .map(|c| c.memory_info.balloon_hint_free_percent)
+            .unwrap_or_default();
+
+        if free_percent == 0 {
+            debug!(sl!(), "memory hint of {} bytes ignored", bytes);
+
+            return Ok(0);
+        }
+
+        let inflate_mb = get_hint_inflation(bytes / MIB, self.guest_free_mb, free_percent);
+
+        if inflate_mb == 0 {
+            return Ok(0);
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let info =
+            cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await?;
+
+        let balloon_mb = info
+            .config
+            .unwrap_or_default()
+            .balloon
+            .map(|b| b.size / MIB)
+            .ok_or_else(|| anyhow!("balloon device not enabled"))?;
+
+        self.resize_balloon(balloon_mb + inflate_mb).await?;
+
+        self.hinted_balloon_mb += inflate_mb;
+
+        // The reclaimed memory is no longer free in the guest.
+        self.guest_free_mb = self.guest_free_mb.map(|m| m.saturating_sub(inflate_mb));
+
+        info!(
+            sl!(),
+            "balloon inflated by {} MiB on a hint of {} bytes", inflate_mb, bytes
+        );
+
+        Ok(inflate_mb)
+    }
+
+    async fn resize_balloon(&self, size_mb: u64) -> Result<()> {
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let resize = VmResize {
+            desired_balloon: Some(size_mb * MIB),
+            ..Default::default()
+        };
+
+        let response = cloud_hypervisor_vm_resize(
+            socket.try_clone().context("failed to clone socket")?,
+            resize,
+        )
+        .await?;
+
+        if let Some(detail) = response {
+            debug!(sl!(), "balloon resize response: {:?}", detail);
+        }
+
+        Ok(())
+    }
+
+    // Resize the number of vCPUs, returning the number of vCPUs before and
+    // after the resize. The request is clamped between the vCPUs the VM
+    // booted with and the maximum vCPUs of the VM, so the caller must use
+    // the returned number when onlining the vCPUs in the guest. Growing
+    // beyond the configured quota of hot added vCPUs fails. A VM sized
+    // statically cannot be resized.
+    pub(crate) async fn resize_vcpu(
+        &mut self,
+        old_vcpus: u32,
+        new_vcpus: u32,
+    ) -> Result<(u32, u32)> {
+        check_resizable(self.config.as_ref(), "vCPU resize")?;
+
+        let cpus = self.get_cpus_config().await?;
+
+        // CH records the current number of vCPUs as the boot vCPUs.
+        let current = cpus.boot_vcpus as u32;
+
+        if current != old_vcpus {
+            debug!(
+                sl!(),
+                "{} vCPUs present, {} expected by the caller", current, old_vcpus
+            );
+        }
+
+        self.update_vcpu_shrink(current);
+
+        let desired = get_vcpu_resize(new_vcpus, self.boot_vcpus, cpus.max_vcpus as u32);
+
+        if desired != new_vcpus {
+            info!(
+                sl!(),
+                "vCPU resize to {} clamped to {} (boot vCPUs {}, maximum vCPUs {})",
+                new_vcpus,
+                desired,
+                self.boot_vcpus,
+                cpus.max_vcpus
+            );
+        }
+
+        check_vcpu_shrink(self.vcpu_shrink.as_ref(), current, desired)?;
+
+        // Growing back to the vCPUs present when a requested shrink was
+        // planned cancels it, whether or not the guest released some of the
+        // vCPUs: CH is asked for all of them again.
+        let cancel_shrink = matches!(
+            &self.vcpu_shrink,
+            Some(VcpuShrink::Requested(plan)) if desired >= plan.from
+        );
+
+        if desired == current && !cancel_shrink {
+            debug!(sl!(), "{} vCPUs already present, not resizing", current);
+
+            return Ok((current, current));
+        }
+
+        if desired < current && !cancel_shrink {
+            self.shrink_vcpus(desired).await?;
+        } else {
+            let quota = self
+                .config
+                .as_ref()
+                .map(|c| c.cpu_info.vcpu_hotplug_quota)
+                .unwrap_or_default();
+
+            check_hotplug_quota(
+                "vCPU",
+                desired.saturating_sub(self.boot_vcpus) as u64,
+                quota as u64,
+            )?;
+
+            self.request_vcpu_resize(desired).await?;
+
+            // A shrink planned but not requested is abandoned.
+            if let Some(shrink) = self.vcpu_shrink.take() {
+                info!(sl!(), "vCPU shrink to {} cancelled", shrink.plan().to);
+            }
+        }
+
+        info!(sl!(), "vCPUs resized from {} to {}", current, desired);
+
+        Ok((current, desired))
+    }
+
+    // Plan the removal of vCPUs down to new_vcpus, which the agent must
+    // offline before resize_vcpu() removes them. The plan of a shrink in
+    // progress to the same number of vCPUs is returned again.
+    pub(crate) async fn plan_vcpu_shrink(&mut self, new_vcpus: u32) -> Result<VcpuShrinkPlan> {
+        check_resizable(self.config.as_ref(), "vCPU resize")?;
+
+        let cpus = self.get_cpus_config().await?;
+
+        let current = cpus.boot_vcpus as u32;
+
+        self.update_vcpu_shrink(current);
+
+        let desired = get_vcpu_resize(new_vcpus, self.boot_vcpus, cpus.max_vcpus as u32);
+
+        if let Some(VcpuShrink::Requested(plan)) = &self.vcpu_shrink {
+            if plan.to != desired {
+                return Err(anyhow!(
+                    "vCPU shrink from {} to {} still in progress",
+                    plan.from,
+                    plan.to
+                ));
+            }
+
+            return Ok(plan.clone());
+        }
+
+        if desired >= current {
+            return Err(anyhow!(
+                "cannot shrink {} vCPUs to {} (boot vCPUs {})",
+                current,
+                new_vcpus,
+                self.boot_vcpus
+            ));
+        }
+
+        let plan = get_vcpu_shrink_plan(current, desired);
+
+        info!(
+            sl!(),
+            "vCPU shrink from {} to {} planned, vCPUs to offline: {:?}",
+            plan.from,
+            plan.to,
+            plan.offline_vcpus
+        );
+
+        self.vcpu_shrink = Some(VcpuShrink::Planned(plan.clone()));
+
+        Ok(plan)
+    }
+
+    async fn get_cpus_config(&self) -> Result<CpusConfig> {
+        if self.state != VmmState::VmRunning {
+            return Err(anyhow!(
+                "cannot resize vCPUs with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let info =
+            cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await?;
+
+        Ok(info.config.unwrap_or_default().cpus)
+    }
+
+    // Forget the shrink in progress once the vCPUs were removed.
+    fn update_vcpu_shrink(&mut self, current: u32) {
+        if let Some(VcpuShrink::Requested(plan)) = &self.vcpu_shrink {
+            if current <= plan.to {
+                info!(sl!(), "vCPU shrink to {} completed", plan.to);
+
+                self.vcpu_shrink = None;
+            }
+        }
+    }
+
+    // Remove the vCPUs of the planned shrink. The guest may not release
+    // them in time, such as when a vCPU is still online, in which case the
+    // shrink stays in progress: resize_vcpu() to the same number requests
+    // the removal again.
+    async fn shrink_vcpus(&mut self, desired: u32) -> Result<()> {
+        let plan = self
+            .vcpu_shrink
+            .as_ref()
+            .map(|s| s.plan().clone())
+            .unwrap_or_default();
+
+        self.vcpu_shrink = Some(VcpuShrink::Requested(plan));
+
+        self.request_vcpu_resize(desired).await?;
+
+        self.vcpu_shrink = None;
+
+        Ok(())
+    }
+
+    // Return the delay in milliseconds before requesting again the removal
+    // of vCPUs, if a requested shrink did not complete.
+    pub(crate) fn get_vcpu_shrink_retry_delay_ms(&self) -> Option<u64> {
+        if !matches!(self.vcpu_shrink, Some(VcpuShrink::Requested(_))) {
+            return None;
+        }
+
+        let delay_ms = self
+            .config
+            .as_ref()
+            .map(|c| c.cpu_info.vcpu_shrink_retry_delay_ms as u64)
+            .filter(|ms| *ms != 0)
+            .unwrap_or(CH_VCPU_SHRINK_RETRY_DELAY_MS);
+
+        Some(delay_ms)
+    }
+
+    // Request CH to resize the vCPUs, and wait for it to report the new
+    // number of vCPUs.
+    async fn request_vcpu_resize(&self, desired: u32) -> Result<()> {
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let resize = VmResize {
+            desired_vcpus: Some(desired as u8),
+            ..Default::default()
+        };
+
+        let response = cloud_hypervisor_vm_resize(
+            socket.try_clone().context("failed to clone socket")?,
+            resize,
+        )
+        .await?;
+
+        if let Some(detail) = response {
+            debug!(sl!(), "vCPU resize response: {:?}", detail);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(CH_VCPU_RESIZE_TIMEOUT_SECS);
+
+        loop {
+            let info =
+                cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?)
+                    .await?;
+
+            let present = info.config.unwrap_or_default().cpus.boot_vcpus as u32;
+
+            if present == desired {
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "vCPU resize to {} requested but {} vCPUs present after {} seconds",
+                    desired,
+                    present,
+                    CH_VCPU_RESIZE_TIMEOUT_SECS
+                ));
+            }
+
+            tokio::time::sleep(Duration::from_millis(CH_POLL_TIME_MS)).await;
+        }
+    }
+
+    // Set the size of the balloon, which is the amount of memory reclaimed
+    // from the guest.
+    pub(crate) async fn set_balloon(&mut self, size_mb: u32) -> Result<()> {
+        if self.state != VmmState::VmRunning {
+            return Err(anyhow!(
+                "cannot set balloon with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        let balloon_enabled = self
+            .config
+            .as_ref()
+            .map(|c| c.memory_info.enable_balloon)
+            .unwrap_or_default();
+
+        if !balloon_enabled {
+            return Err(anyhow!("balloon device not enabled"));
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let response = cloud_hypervisor_vm_resize(
+            socket.try_clone().context("failed to clone socket")?,
+            get_balloon_resize(size_mb),
+        )
+        .await?;
+
+        if let Some(detail) = response {
+            debug!(sl!(), "balloon resize response: {:?}", detail);
+        }
+
+        info!(sl!(), "balloon set to {} MiB", size_mb);
+
+        Ok(())
+    }
+
+    // Use the API socket of a CH process owned by another process, such as
+    // a supervisor. The VMM state is set from the state reported by CH.
+    pub(crate) async fn adopt_api_socket(&mut self, socket: UnixStream) -> Result<()> {
+        if self.api_socket.is_some() || self.process.is_some() {
+            return Err(anyhow!("{} API socket already set", CH_NAME));
+        }
+
+        let peer_pid = self.check_api_socket_peer(&socket)?;
+
+        self.api_socket = Some(socket);
+
+        let result = self.cloud_hypervisor_adopted_state().await;
+
+        match result {
+            Ok(state) => {
+                info!(
+                    sl!(),
+                    "adopted {} API socket with state {:?}", CH_NAME, state
+                );
+
+                self.state = state;
+
+                // CH is not a child of the shim, so its exit is observed
+                // through its pid.
+                if self.exit_rx.is_none() {
+                    self.monitor_vmm_pid(self.pid.unwrap_or(peer_pid))?;
+                }
+
+                Ok(())
+            }
+            Err(e) => {
+                self.api_socket = None;
+
+                Err(e)
+            }
+        }
+    }
+
+    // Start the task reporting the exit of a CH process which is not a
+    // child of the shim, and killing it once the shutdown is requested.
+    fn monitor_vmm_pid(&mut self, pid: u32) -> Result<()> {
+        let shutdown = self
+            .shutdown_rx
+            .as_ref()
+            .ok_or("no receiver channel")
+            .map_err(|e| anyhow!(e))?
+            .clone();
+
+        let (exit_tx, exit_rx) = channel(None);
+
+        self.exit_rx = Some(exit_rx);
+
+        let task = tokio::spawn(cloud_hypervisor_monitor_pid(
+            pid,
+            shutdown,
+            exit_tx,
+            self.stop_requested.clone(),
+            self.vmm_log_path.clone(),
+        ));
+
+        self.tasks.get_or_insert_with(Vec::new).push(task);
+
+        Ok(())
+    }
+
+    // Note that the ownership of the fd is transferred.
+    pub(crate) async fn adopt_api_socket_fd(&mut self, fd: RawFd) -> Result<()> {
+        if fd < 0 {
+            return Err(anyhow!("invalid API socket fd {}", fd));
+        }
+
+        // SAFETY: the caller hands over an open socket fd, which is then
+        // only owned by the UnixStream.
+        let socket = unsafe { UnixStream::from_raw_fd(fd) };
+
+        self.adopt_api_socket(socket).await
+    }
+
+    pub(crate) async fn adopt_api_socket_path(&mut self, path: &str) -> Result<()> {
+        let socket = UnixStream::connect(path).context(format!(
+            "failed to connect to {} API socket {}",
+            CH_NAME, path
+        ))?;
+
+        self.adopt_api_socket(socket).await
+    }
+
+    // Detach the API socket, returning its fd to the caller which then owns
+    // it. The VMM can no longer be controlled until a socket is adopted
+    // again.
+    pub(crate) fn export_api_socket_fd(&mut self) -> Result<RawFd> {
+        let socket = self
+            .api_socket
+            .take()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        self.state = VmmState::NotReady;
+
+        Ok(socket.into_raw_fd())
+    }
+
+    async fn cloud_hypervisor_adopted_state(&mut self) -> Result<VmmState> {
+        self.cloud_hypervisor_check_running()
+            .await
+            .context("adopted hypervisor running check failed")?;
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        // Fails if no VM has been created yet.
+        let info =
+            cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await;
+
+        let state = match info {
+            Ok(info) => match info.state.as_str() {
+                CH_VM_STATE_RUNNING => VmmState::VmRunning,
+                CH_VM_STATE_PAUSED => VmmState::VmPaused,
+                _ => VmmState::VmmServerReady,
+            },
+            Err(e) => {
+                debug!(sl!(), "no VM info: {:?}", e);
+
+                VmmState::VmmServerReady
+            }
+        };
+
+        Ok(state)
+    }
+
+    // Return the features of the CH binary, or None if it does not report
+    // them.
+    pub(crate) fn get_ch_features(&self) -> Result<Option<Vec<String>>> {
+        let config = self
+            .config
+            .as_ref()
+            .ok_or("no hypervisor config for CH")
+            .map_err(|e| anyhow!(e))?;
+
+        get_ch_features(Path::new(&config.path))
+    }
+
+    // Return the version of the running CH, or of the configured binary
+    // when CH has not been launched yet.
+    pub(crate) fn get_ch_version(&self) -> Result<String> {
+        if let Some(version) = &self.ch_version {
+            return Ok(version.clone());
+        }
+
+        let config = self
+            .config
+            .as_ref()
+            .ok_or("no hypervisor config for CH")
+            .map_err(|e| anyhow!(e))?;
+
+        get_ch_version(Path::new(&config.path))
+    }
+
+    // Fail with ChError::Unsupported if CH is older than the release which
+    // introduced the feature, or its version is unknown, rather than letting
+    // CH reject or ignore the configuration of the feature.
+    pub(crate) fn check_ch_version(&self, feature: &str, min_version: (u32, u32)) -> Result<()> {
+        let version = self
+            .get_ch_version()
+            .context(format!("failed to get {} version", CH_NAME))?;
+
+        check_min_version(&version, feature, min_version)
+    }
+
+    // Fail with ChError::FeatureNotSupported if CH was built without the
+    // feature. A CH binary which does not report its features is assumed to
+    // support it, and fails as before if it does not.
+    pub(crate) fn check_ch_feature(&self, feature: &str) -> Result<()> {
+        let features = match self.get_ch_features() {
+            Ok(Some(features)) => features,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                warn!(sl!(), "failed to get {} features: {:?}", CH_NAME, e);
+
+                return Ok(());
+            }
+        };
+
+        if !features.iter().any(|f| f == feature) {
+            return Err(anyhow!(ChError::FeatureNotSupported {
+                feature: feature.to_string(),
+            }));
+        }
+
+        Ok(())
+    }
+
+    // Check the host can run the VM, without launching CH.
+    pub(crate) fn check_host(&self) -> Result<()> {
+        let device = self
+            .config
+            .as_ref()
+            .map(|c| c.machine_info.hypervisor_device.as_str())
+            .unwrap_or_default();
+
+        check_host_hypervisor(device)
+    }
+
+    pub(crate) fn get_console_log_path(&self) -> Option<String> {
+        self.console_log_path.clone()
+    }
+
+    pub(crate) fn get_console_socket_path(&self) -> Option<String> {
+        self.console_socket_path.clone()
+    }
+
+    // Return the end of the guest console, at most size bytes, which is
+    // kept once the VM stopped. Invalid UTF-8 is replaced.
+    pub(crate) fn get_console_tail(&self, size: usize) -> Result<String> {
+        let buffer = self
+            .console_buffer
+            .as_ref()
+            .ok_or("console buffer not enabled")
+            .map_err(|e| anyhow!(e))?;
+
+        Ok(String::from_utf8_lossy(&buffer.tail(size)).into_owned())
+    }
+
+    // Connect to the socket streaming the guest console, returning a
+    // reader of the console lines. The socket is not available when read
+    // by the console buffer.
+    pub(crate) async fn console_reader(&self) -> Result<Lines<BufReader<tokio::net::UnixStream>>> {
+        let path = self
+            .console_socket_path
+            .as_ref()
+            .ok_or("console socket not enabled")
+            .map_err(|e| anyhow!(e))?;
+
+        if self.console_buffer.is_some() {
+            return Err(anyhow!(
+                "console socket read by the console buffer, use get_console_tail()"
+            ));
+        }
+
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .context(format!("failed to connect to console socket {}", path))?;
+
+        Ok(BufReader::new(stream).lines())
+    }
+
+    pub(crate) async fn disconnect(&mut self) {
+        self.state = VmmState::NotReady;
+    }
+
+    // Return the host thread ids of the vCPUs, read on every call as vCPUs
+    // can be hot plugged. No thread is returned if the CH process is not
+    // known, as when adopted from another process.
+    pub(crate) async fn get_thread_ids(&self) -> Result<VcpuThreadIds> {
+        let pid = match self.pid {
+            Some(pid) => pid,
+            None => return Ok(VcpuThreadIds::default()),
+        };
+
+        let task_dir = PathBuf::from(format!("/proc/{}/task", pid));
+
+        let vcpus = get_vcpu_thread_ids(&task_dir).context("failed to get vCPU threads")?;
+
+        debug!(sl!(), "vCPU threads: {:?}", vcpus);
+
+        Ok(VcpuThreadIds { vcpus })
+    }
+
+    // Remove the files and directories created for the VM. Only the
+    // artifacts the driver recorded are removed, once CH is known to have
+    // exited.
+    pub(crate) async fn cleanup(&self) -> Result<()> {
+        if !self.vmm_exited() {
+            return Err(anyhow!(
+                "cannot clean up as {} may still be running",
+                CH_NAME
+            ));
+        }
+
+        let keep_logs = self
+            .config
+            .as_ref()
+            .map(|c| c.debug_info.keep_logs)
+            .unwrap_or_default();
+
+        if let Some(monitor) = &self.event_monitor {
+            monitor.stop();
+        }
+
+        // The mounts of the jail must be removed first, so that the host
+        // paths made available in it are not removed along with it.
+        if let Some(jail) = &self.jail {
+            jail.teardown()
+                .context(format!("failed to tear down {} jail", CH_NAME))?;
+        }
+
+        self.artifacts.remove(|path| {
+            keep_logs
+                && path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .map(|n| CH_LOG_FILE_NAMES.iter().any(|l| n.starts_with(l)))
+                    .unwrap_or_default()
+        })?;
+
+        if keep_logs {
+            info!(sl!(), "kept {} log files in {}", CH_NAME, self.vm_path);
+        }
+
+        Ok(())
+    }
+
+    // Save the state needed to take the control back of the VM after a shim
+    // restart. Failing to save it only prevents the recovery, so is logged.
+    pub(crate) fn save_recovery_state(&self) {
+        let result = || -> Result<()> {
+            // An adopted CH process cannot be checked on recovery.
+            let pid = match self.pid {
+                Some(pid) => pid,
+                None => return Ok(()),
+            };
+
+            let path = get_recovery_state_path(&self.id)?;
+
+            self.artifacts.add(&path, ArtifactKind::File);
+
+            let state = RecoveryState {
+                id: self.id.clone(),
+                pid,
+                api_socket_path: get_api_socket_path(&self.id)?,
+                vm_path: self.vm_path.clone(),
+                run_dir: self.run_dir.clone(),
+                netns: self.netns.clone(),
+                jailed: self.jail.is_some(),
+                jailer_root: self.jailer_root.clone(),
+                config: self.hypervisor_config(),
+                pci_segments: self.pci_segments.clone(),
+                added_devices: self.added_devices.clone(),
+                boot_memory_mb: self.boot_memory_mb,
+                max_memory_mb: self.max_memory_mb,
+                memory_hotplug_method: self.memory_hotplug_method,
+                boot_vcpus: self.boot_vcpus,
+                max_vcpus: self.max_vcpus,
+                memory_shared: self.memory_shared,
+                hugepage_requests: self.hugepage_requests.clone(),
+                guest_clock_offset: self.guest_clock_offset,
+                helper_pids: self.helper_pids.clone(),
+                console_log_path: self.console_log_path.clone(),
+                console_socket_path: self.console_socket_path.clone(),
+                vmm_log_path: self.vmm_log_path.clone(),
+                launch_data: self.launch_data.clone(),
+                artifacts: self.artifacts.to_state(),
+                ..Default::default()
+            };
+
+            state.save(Path::new(&path))
+        };
+
+        if let Err(e) = result() {
+            warn!(sl!(), "failed to save recovery state: {:#}", e);
+        }
+    }
+
+    // Register a helper process of the VM, such as virtiofsd: it is moved
+    // into the cgroup configured for CH, if any, and given the OOM score
+    // adjustment of CH if configured for helpers. The process is also
+    // included in the metrics, and stopped along with CH.
+    pub(crate) fn register_helper(&mut self, pid: u32) -> Result<()> {
+        if !self.helper_pids.contains(&pid) {
+            self.helper_pids.push(pid);
+
+            self.save_recovery_state();
+        }
+
+        self.apply_oom_score_adj(pid, true)?;
+
+        let cgroup_path = match self.get_cgroup_path() {
+            Some(cgroup_path) => cgroup_path,
+            None => return Ok(()),
+        };
+
+        move_to_cgroup(&cgroup_path, pid)?;
+
+        info!(
+            sl!(),
+            "helper process {} moved to cgroup {}", pid, cgroup_path
+        );
+
+        Ok(())
+    }
+
+    // Set the configured OOM score adjustment of CH, or of one of its helper
+    // processes if configured for them as well.
+    fn apply_oom_score_adj(&self, pid: u32, helper: bool) -> Result<()> {
+        let security_info = match &self.config {
+            Some(config) => &config.security_info,
+            None => return Ok(()),
+        };
+
+        let adj = match security_info.vmm_oom_score_adj {
+            Some(adj) if !helper || security_info.helper_oom_score_adj => adj,
+            _ => return Ok(()),
+        };
+
+        set_oom_score_adj(Path::new(&format!("/proc/{}", pid)), adj)?;
+
+        info!(
+            sl!(),
+            "OOM score adjustment of process {} set to {}", pid, adj
+        );
+
+        Ok(())
+    }
+
+    // Sample the resource usage of CH and of its helper processes. This
+    // only reads a few files of /proc, so it can be called on every metrics
+    // scrape. The processes which are not running are left out.
+    pub(crate) fn hypervisor_metrics(&self) -> HypervisorMetrics {
+        let sample = |pid: u32| sample_process(Path::new(&format!("/proc/{}", pid)), pid);
+
+        HypervisorMetrics {
+            vmm: self.get_running_vmm_pid().ok().and_then(sample),
+            helpers: self
+                .helper_pids
+                .iter()
+                .filter_map(|pid| sample(*pid))
+                .collect(),
+        }
+    }
+
+    // Return the counters of the devices of the VM, since they were last
+    // reset.
+    pub(crate) async fn get_counters(&self) -> Result<VmCounters> {
+        let counters = self.get_raw_counters().await?;
+
+        Ok(subtract_counter_baselines(
+            &counters,
+            &self.counter_baselines,
+        ))
+    }
+
+    // Return the resources of the VM and how far they can grow. The maximum
+    // sizes, cached when the VM is created, are taken from vm.info for a VM
+    // restored from a snapshot.
+    pub(crate) async fn get_resource_capacity(&self) -> Result<ResourceCapacity> {
+        if self.state != VmmState::VmRunning && self.state != VmmState::VmPaused {
+            return Err(anyhow!(
+                "cannot get the resource capacity with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        let info =
+            cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await?;
+
+        Ok(get_resource_capacity(
+            &info.config.unwrap_or_default(),
+            self.max_vcpus,
+            self.max_memory_mb,
+        ))
+    }
+
+    // Reset the counters of a device of the VM. CH cannot reset them, so
+    // their current values are recorded as the baseline subtracted from the
+    // values it reports afterwards.
+    pub(crate) async fn reset_counters(&mut self, device_id: &str) -> Result<()> {
+        let mut counters = self.get_raw_counters().await?;
+
+        let device_counters = counters
+            .remove(device_id)
+            .ok_or_else(|| anyhow!("no counters for device {}", device_id))?;
+
+        self.counter_baselines
+            .insert(device_id.to_string(), device_counters);
+
+        info!(sl!(), "counters of device {} reset", device_id);
+
+        Ok(())
+    }
+
+    // Return the details of the VM reported by CH.
+    async fn get_vm_info(&self) -> Result<VmInfo> {
+        if self.state != VmmState::VmRunning && self.state != VmmState::VmPaused {
+            return Err(anyhow!(
+                "cannot get the VM info with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        cloud_hypervisor_vm_info(socket.try_clone().context("failed to clone socket")?).await
+    }
+
+    // Return the counters of the devices of the VM, as reported by CH.
+    async fn get_raw_counters(&self) -> Result<VmCounters> {
+        if self.state != VmmState::VmRunning && self.state != VmmState::VmPaused {
+            return Err(anyhow!(
+                "cannot get the counters with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        let socket = self
+            .api_socket
+            .as_ref()
+            .ok_or("missing socket")
+            .map_err(|e| anyhow!(e))?;
+
+        cloud_hypervisor_vm_counters(socket.try_clone().context("failed to clone socket")?).await
+    }
+
+    // Render the resource usage, the state and size and the device counters
+    // of the VM in the Prometheus text format. The data of the VM is left
+    // out if it cannot be queried, as when the VM is not running, so that a
+    // scrape does not fail. The counters are not affected by their resets,
+    // as Prometheus counters only reset when the process restarts.
+    pub(crate) async fn prometheus_metrics(&self) -> String {
+        let metrics = self.hypervisor_metrics();
+
+        let info = match self.get_vm_info().await {
+            Ok(info) => Some(info),
+            Err(e) => {
+                debug!(sl!(), "no VM info for the metrics: {:?}", e);
+
+                None
+            }
+        };
+
+        let counters = match self.get_raw_counters().await {
+            Ok(counters) => counters,
+            Err(e) => {
+                debug!(sl!(), "no device counters for the metrics: {:?}", e);
+
+                VmCounters::new()
+            }
+        };
+
+        render_prometheus(&self.id, &metrics, info.as_ref(), &counters)
+    }
+
+    // Return the pid of the CH process, failing if it was not launched or
+    // exited.
+    fn get_running_vmm_pid(&self) -> Result<u32> {
+        let pid = self
+            .pid
+            .ok_or(format!("{} not started", CH_NAME))
+            .map_err(|e| anyhow!(e))?;
+
+        let exited = match &self.exit_rx {
+            Some(rx) => rx.borrow().is_some(),
+            // Not monitored, as when restored from the persisted state.
+            None => !Path::new(&format!("/proc/{}", pid)).exists(),
+        };
+
+        if exited {
+            return Err(anyhow!("{} (pid {}) exited", CH_NAME, pid));
+        }
+
+        Ok(pid)
+    }
+
+    pub(crate) async fn get_pids(&self) -> Result<Vec<u32>> {
+        Ok(vec![self.get_running_vmm_pid()?])
+    }
+
+    // Return the id of the main thread of CH, which is its pid.
+    pub(crate) async fn get_vmm_master_tid(&self) -> Result<u32> {
+        let pid = self.get_running_vmm_pid()?;
+
+        let main_thread = format!("/proc/{}/task/{}", pid, pid);
+
+        if !Path::new(&main_thread).exists() {
+            return Err(anyhow!("{} main thread not found", CH_NAME));
+        }
+
+        Ok(pid)
+    }
+
+    // Return the path of the network namespace CH runs in: the one passed
+    // to prepare_vm() if any, or the namespace of the CH process.
+    pub(crate) async fn get_ns_path(&self) -> Result<String> {
+        let pid = self.get_running_vmm_pid()?;
+
+        let path = match &self.netns {
+            Some(netns) => netns.clone(),
+            None => format!("/proc/{}/ns/net", pid),
+        };
+
+        Ok(path)
+    }
+
+    pub(crate) async fn check(&mut self) -> Result<()> {
+        self.check_vmm_exited()
+    }
+
+    // Return the exit of the CH process, if it exited without being
+    // requested to.
+    fn vmm_unexpected_exit(&self) -> Option<VmmExit> {
+        self.exit_rx
+            .as_ref()
+            .and_then(|rx| rx.borrow().clone())
+            .filter(|exit| !exit.requested)
+    }
+
+    // Fail if the CH process exited unexpectedly, in which case the VM is
+    // recorded as stopped.
+    pub(crate) fn check_vmm_exited(&mut self) -> Result<()> {
+        self.apply_vm_events();
+
+        match self.vmm_unexpected_exit() {
+            Some(exit) => {
+                self.state = VmmState::VmStopped;
+
+                Err(ChError::VmmExited {
+                    status: exit.status,
+                }
+                .into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Apply the updates reported by the CH event monitor since the last call.
+    pub(crate) fn apply_vm_events(&mut self) {
+        while let Some(event) = self.event_monitor.as_mut().and_then(|m| m.try_next()) {
+            self.apply_vm_event(event);
+        }
+    }
+
+    fn apply_vm_event(&mut self, event: VmEvent) {
+        match event {
+            // A migration only completes or fails as a whole, so the state is
+            // left to the migration.
+            VmEvent::State(state) => {
+                if self.state != state && self.state != VmmState::VmMigrating {
+                    info!(
+                        sl!(),
+                        "VM state changed from {:?} to {:?} by {}", self.state, state, CH_NAME
+                    );
+
+                    self.state = state;
+                }
+            }
+            VmEvent::Device(DeviceEvent::Activated(id)) => {
+                info!(
+                    sl!(),
+                    "device {} activated by the guest", id;
+                    "event" => "device-activated",
+                    "labels" => self.get_device_labels(&id)
+                );
+
+                self.activated_devices.insert(id);
+            }
+            VmEvent::Device(DeviceEvent::Reset(id)) => {
+                info!(
+                    sl!(),
+                    "device {} reset by the guest", id;
+                    "event" => "device-reset",
+                    "labels" => self.get_device_labels(&id)
+                );
+
+                self.activated_devices.remove(&id);
+            }
+            VmEvent::Device(DeviceEvent::Removed(id)) => {
+                info!(
+                    sl!(),
+                    "device {} released by the guest", id;
+                    "event" => "device-removed",
+                    "labels" => self.get_device_labels(&id)
+                );
+
+                self.activated_devices.remove(&id);
+            }
+        }
+    }
+
+    // Wait up to timeout for updates from the CH event monitor, applying
+    // them as they come. Without the event monitor, this just sleeps.
+    pub(crate) async fn wait_vm_events(&mut self, timeout: Duration) {
+        let monitor = match self.event_monitor.as_mut() {
+            Some(monitor) => monitor,
+            None => {
+                tokio::time::sleep(timeout).await;
+                return;
+            }
+        };
+
+        match tokio::time::timeout(timeout, monitor.next()).await {
+            Ok(Some(event)) => self.apply_vm_event(event),
+            // The reader stopped.
+            Ok(None) => tokio::time::sleep(timeout).await,
+            Err(_) => (),
+        }
+
+        self.apply_vm_events();
+    }
+
+    // Report an operation which failed because the CH process exited while
+    // it was in progress as such, rather than as the resulting API error.
+    pub(crate) async fn check_vmm_result<T>(&mut self, result: Result<T>) -> Result<T> {
+        let e = match result {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+
+        // The API socket is closed before the monitor task reaps CH, so
+        // give the task a moment to report the exit.
+        if let Some(rx) = self.exit_rx.as_mut() {
+            if rx.borrow().is_none() {
+                let _ = tokio::time::timeout(Duration::from_millis(CH_POLL_TIME_MS), rx.changed())
+                    .await;
+            }
+        }
+
+        if let Err(exited) = self.check_vmm_exited() {
+            debug!(sl!(), "operation failed as {} exited: {:?}", CH_NAME, e);
+
+            return Err(exited);
+        }
+
+        Err(e)
+    }
+
+    // Return a receiver reporting the exit of the CH process, which can be
+    // waited on without holding the hypervisor lock.
+    pub(crate) fn get_exit_receiver(&self) -> Result<Receiver<Option<VmmExit>>> {
+        self.exit_rx
+            .clone()
+            .ok_or(format!("{} not launched", CH_NAME))
+            .map_err(|e| anyhow!(e))
+    }
+
+    pub(crate) async fn get_jailer_root(&self) -> Result<String> {
+        let root_path = get_jailer_root(&self.id)?;
+
+        std::fs::create_dir_all(&root_path)?;
+
+        self.artifacts.add(&root_path, ArtifactKind::Dir);
+
+        Ok(root_path)
+    }
+
+    // Report the capabilities of CH for the configuration. The version of
+    // the running CH instance is used, or the configured binary is asked for
+    // it when CH has not been launched yet.
+    pub(crate) async fn capabilities(&self) -> Result<Capabilities> {
+        let config = self
+            .config
+            .as_ref()
+            .ok_or("no hypervisor config for CH")
+            .map_err(|e| anyhow!(e))?;
+
+        let boot_size = config.memory_info.default_memory as u64 * MIB;
+
+        let memory_hotplug = match self.get_memory_hotplug_config(boot_size) {
+            Ok((_, size)) => size.is_some(),
+            Err(e) => {
+                warn!(sl!(), "failed to get memory hotplug config: {:?}", e);
+                false
+            }
+        };
+
+        let ch_version = match self.get_ch_version() {
+            Ok(version) => Some(version),
+            Err(e) => {
+                warn!(sl!(), "failed to get {} version: {:?}", CH_NAME, e);
+                None
+            }
+        };
+
+        Ok(get_capabilities(
+            config,
+            memory_hotplug,
+            ch_version.as_deref(),
+        ))
+    }
+}
+
+// Return the number of PCI segments and the IOMMU segments of the VM.
+//
+// Unless configured, the number of segments is the minimum required by the
+// devices, and the IOMMU segments are the (non-default) segments holding
+// devices requiring the IOMMU: devices on the default segment are placed
+// behind the IOMMU individually.
+fn get_pci_segments(
+    num_pci_segments: u16,
+    iommu_segments: &[u16],
+    devices: &[(u16, bool)],
+) -> Result<(u16, Option<Vec<u16>>)> {
+    let mut iommu_segments = iommu_segments.to_vec();
+
+    if iommu_segments.is_empty() {
+        iommu_segments = devices
+            .iter()
+            .filter(|(segment, iommu)| *iommu && *segment != 0)
+            .map(|(segment, _)| *segment)
+            .collect();
+    }
+
+    iommu_segments.sort_unstable();
+    iommu_segments.dedup();
+
+    let num_pci_segments = if num_pci_segments > 0 {
+        num_pci_segments
+    } else {
+        devices
+            .iter()
+            .map(|(segment, _)| *segment)
+            .chain(iommu_segments.iter().copied())
+            .max()
+            .map(|segment| segment.saturating_add(1))
+            .unwrap_or(DEFAULT_NUM_PCI_SEGMENTS)
+    };
+
+    if num_pci_segments > MAX_NUM_PCI_SEGMENTS {
+        return Err(anyhow!(
+            "{} PCI segments required, maximum is {}",
+            num_pci_segments,
+            MAX_NUM_PCI_SEGMENTS
+        ));
+    }
+
+    if let Some(segment) = devices
+        .iter()
+        .map(|(segment, _)| *segment)
+        .chain(iommu_segments.iter().copied())
+        .find(|segment| *segment >= num_pci_segments)
+    {
+        return Err(anyhow!(
+            "PCI segment {} is invalid, only {} PCI segments are available",
+            segment,
+            num_pci_segments
+        ));
+    }
+
+    let iommu_segments = if iommu_segments.is_empty() {
+        None
+    } else {
+        Some(iommu_segments)
+    };
+
+    Ok((num_pci_segments, iommu_segments))
+}
+
+// Return the capabilities of CH for the configuration, given whether the
+// guest memory can be hot plugged and the version of CH, if known.
+fn get_capabilities(
+    config: &HypervisorConfig,
+    memory_hotplug: bool,
+    ch_version: Option<&str>,
+) -> Capabilities {
+    let mut flags = CapabilityBits::BlockDeviceSupport
+        | CapabilityBits::BlockDeviceHotplugSupport
+        | CapabilityBits::HybridVsockSupport;
+
+    // Confidential guests are only available on x86_64, and reject some
+    // devices, which the sandbox must not choose.
+    let confidential_guest =
+        cfg!(target_arch = "x86_64") && config.security_info.confidential_guest;
+
+    let allowed = |kind: &str| !(confidential_guest && is_rejected(kind));
+
+    if config.shared_fs.shared_fs.as_deref() != Some(CH_UNSUPPORTED_SHARED_FS)
+        && allowed(DEVICE_KIND_VIRTIO_FS)
+    {
+        flags |= CapabilityBits::FsSharingSupport | CapabilityBits::VirtioFsSupport;
+    }
+
+    if memory_hotplug {
+        flags |= CapabilityBits::MemoryHotplugSupport;
+    }
+
+    let vfio_hotplug = ch_version
+        .and_then(parse_ch_version)
+        .map(|v| v >= CH_VFIO_HOTPLUG_MIN_VERSION)
+        .unwrap_or_default();
+
+    if vfio_hotplug && allowed(DEVICE_KIND_VFIO) {
+        flags |= CapabilityBits::VfioHotplugSupport;
+    }
+
+    let mut caps = Capabilities::new();
+    caps.set(flags);
+    caps.set_max_vsock_devices(CH_MAX_VSOCK_DEVICES);
+
+    caps
+}
+
+// Return the balloon device settings, or None if the balloon is disabled.
+fn get_balloon_config(memory_info: &MemoryInfo) -> Option<BalloonConfig> {
+    if !memory_info.enable_balloon {
+        return None;
+    }
+
+    Some(BalloonConfig {
+        size: memory_info.balloon_size_mb as u64 * 1024 * 1024,
+        deflate_on_oom: memory_info.balloon_deflate_on_oom,
+        free_page_reporting: memory_info.balloon_free_page_reporting,
+    })
+}
+
+// Return whether the guest memory is backed by explicit huge pages and by
+// transparent huge pages. Explicit huge pages take precedence.
+fn get_memory_backing(memory_info: &MemoryInfo) -> (bool, bool) {
+    if memory_info.enable_hugepages && memory_info.enable_thp {
+        warn!(
+            sl!(),
+            "both huge pages and transparent huge pages enabled, ignoring transparent huge pages"
+        );
+
+        return (true, false);
+    }
+
+    (memory_info.enable_hugepages, memory_info.enable_thp)
+}
+
+// Return whether the guest memory is backed by huge pages, and the size in
+// bytes of the huge pages, None for the default size of the host. CH backs
+// the memory with a single size, so the largest size requested by the pod is
+// used, in favor of the configured one.
+fn get_hugepages_config(
+    memory_info: &MemoryInfo,
+    hugepages: bool,
+    requests: &[HugePagesRequest],
+) -> (bool, Option<u64>) {
+    let configured_size = match memory_info.hugepage_size_mb {
+        0 => None,
+        size_mb => Some(size_mb as u64 * MIB),
+    };
+
+    let requested_size = requests
+        .iter()
+        .filter(|r| r.size != 0)
+        .map(|r| r.page_size)
+        .max();
+
+    let page_size = match requested_size {
+        Some(page_size) => page_size,
+        None => return (hugepages, configured_size.filter(|_| hugepages)),
+    };
+
+    if !hugepages {
+        info!(
+            sl!(),
+            "backing the guest memory with the {} KiB huge pages requested by the pod",
+            page_size / 1024
+        );
+    } else if configured_size.map_or(false, |size| size != page_size) {
+        info!(
+            sl!(),
+            "backing the guest memory with the {} KiB huge pages requested by the pod instead of the configured {} MiB ones",
+            page_size / 1024,
+            memory_info.hugepage_size_mb
+        );
+    }
+
+    (true, Some(page_size))
+}
+
+// Return the kernel parameters reserving the huge pages requested by the
+// pod in the guest, by increasing page size.
+fn get_guest_hugepage_params(requests: &[HugePagesRequest]) -> Vec<String> {
+    let mut requests: Vec<&HugePagesRequest> = requests
+        .iter()
+        .filter(|r| r.page_size != 0 && r.size != 0)
+        .collect();
+
+    requests.sort_by_key(|r| r.page_size);
+
+    let mut params = vec![];
+
+    for request in requests {
+        let page_size = if request.page_size % (1024 * MIB) == 0 {
+            format!("{}G", request.page_size / (1024 * MIB))
+        } else if request.page_size % MIB == 0 {
+            format!("{}M", request.page_size / MIB)
+        } else {
+            format!("{}K", request.page_size / 1024)
+        };
+
+        let pages = (request.size + request.page_size - 1) / request.page_size;
+
+        params.push(format!("hugepagesz={}", page_size));
+        params.push(format!("hugepages={}", pages));
+    }
+
+    params
+}
+
+// Allocate the guest memory from a host NUMA node: CH only supports this
+// for memory zones, so the boot memory is moved to a single zone, unless
+// already. Memory hotplug requires virtio-mem with zones, so is disabled
+// with ACPI.
+fn set_numa_memory_zone(memory: &mut MemoryConfig, node: u32, hotplug_method: HotplugMethod) {
+    if let Some(zones) = memory.zones.as_mut() {
+        zones.iter_mut().for_each(|z| z.host_numa_node = Some(node));
+        return;
+    }
+
+    let hotplug_size = match (memory.hotplug_size, hotplug_method) {
+        (Some(size), HotplugMethod::VirtioMem) => Some(size),
+        (Some(_), _) => {
+            warn!(
+                sl!(),
+                "memory hotplug requires virtio-mem with a host NUMA node, disabling it"
+            );
+
+            None
+        }
+        (None, _) => None,
+    };
+
+    let zone = MemoryZoneConfig {
+        id: CH_MEMORY_ZONE_ID.to_string(),
+        size: memory.size,
+        shared: memory.shared,
+        hugepages: memory.hugepages,
+        hugepage_size: memory.hugepage_size,
+        host_numa_node: Some(node),
+        hotplug_size,
+        prefault: memory.prefault,
+        ..Default::default()
+    };
+
+    memory.size = 0;
+    memory.hotplug_size = None;
+    memory.zones = Some(vec![zone]);
+}
+
+// Return the affinity of the vCPUs to the CPUs of a host NUMA node. CH takes
+// the host CPUs as u8.
+fn get_vcpu_affinity(max_vcpus: u8, host_cpus: &[u32]) -> Result<Vec<CpuAffinity>> {
+    if host_cpus.is_empty() {
+        return Err(anyhow!("host NUMA node without CPUs"));
+    }
+
+    let host_cpus = host_cpus
+        .iter()
+        .map(|cpu| u8::try_from(*cpu).map_err(|_| anyhow!("host CPU {} cannot be used by CH", cpu)))
+        .collect::<Result<Vec<u8>>>()?;
+
+    Ok((0..max_vcpus)
+        .map(|vcpu| CpuAffinity {
+            vcpu,
+            host_cpus: host_cpus.clone(),
+        })
+        .collect())
+}
+
+// Back the guest memory with a file: CH only supports this for memory zones,
+// so the boot memory is moved to a single zone. If the file is a directory, CH
+// creates an unnamed file in it. Memory hotplug is disabled as it would also
+// require zones.
+fn set_memory_file_backend(memory: &mut MemoryConfig, file: PathBuf) {
+    let zone = MemoryZoneConfig {
+        id: CH_MEMORY_ZONE_ID.to_string(),
+        size: memory.size,
+        file: Some(file),
+        shared: memory.shared,
+        hugepages: memory.hugepages,
+        hugepage_size: memory.hugepage_size,
+        prefault: memory.prefault,
+        ..Default::default()
+    };
+
+    memory.size = 0;
+    memory.hotplug_size = None;
+    memory.zones = Some(vec![zone]);
+}
+
+// Return the memory hotplug method and the size in bytes of the memory which
+// can be hot added on top of the boot memory, aligned down as CH requires.
+fn get_memory_hotplug(
+    memory_info: &MemoryInfo,
+    max_memory_mb: u64,
+    boot_memory_mb: u64,
+) -> Result<(HotplugMethod, Option<u64>)> {
+    if max_memory_mb < boot_memory_mb {
+        return Err(anyhow!(
+            "maximum memory {} MiB is smaller than the boot memory {} MiB",
+            max_memory_mb,
+            boot_memory_mb
+        ));
+    }
+
+    let method = if memory_info.enable_virtio_mem {
+        HotplugMethod::VirtioMem
+    } else {
+        HotplugMethod::Acpi
+    };
+
+    let hotplug_mb = max_memory_mb - boot_memory_mb;
+    let hotplug_mb = hotplug_mb - hotplug_mb % CH_MEMORY_HOTPLUG_ALIGN_MB;
+
+    if hotplug_mb == 0 {
+        return Ok((method, None));
+    }
+
+    Ok((method, Some(hotplug_mb * MIB)))
+}
+
+// Return the resources of the VM according to the VM config reported by CH,
+// with the cached maximum sizes unless unknown.
+fn get_resource_capacity(cfg: &VmConfig, max_vcpus: u32, max_memory_mb: u64) -> ResourceCapacity {
+    let max_vcpus = if max_vcpus > 0 {
+        max_vcpus
+    } else {
+        cfg.cpus.max_vcpus as u32
+    };
+
+    let max_memory_mb = if max_memory_mb > 0 {
+        max_memory_mb
+    } else {
+        (get_boot_memory_size(cfg) + get_memory_hotplug_size(cfg)) / MIB
+    };
+
+    ResourceCapacity {
+        vcpus: cfg.cpus.boot_vcpus as u32,
+        max_vcpus,
+        memory_mb: get_plugged_memory_size(cfg) / MIB,
+        max_memory_mb,
+        balloon_mb: cfg.balloon.as_ref().map(|b| b.size / MIB),
+    }
+}
+
+// Return the size in bytes of the guest memory plugged, according to the
+// VM config reported by CH: hot plugging with ACPI grows the boot memory
+// while virtio-mem records the hot plugged size separately.
+fn get_plugged_memory_size(cfg: &VmConfig) -> u64 {
+    let zones_hotplugged_size: u64 = cfg
+        .memory
+        .zones
+        .iter()
+        .flatten()
+        .map(|z| z.hotplugged_size.unwrap_or_default())
+        .sum();
+
+    get_boot_memory_size(cfg)
+        + cfg.memory.hotplugged_size.unwrap_or_default()
+        + zones_hotplugged_size
+}
+
+// Return the hot pluggable memory size in bytes, of the VM or of its memory
+// zones.
+fn get_memory_hotplug_size(cfg: &VmConfig) -> u64 {
+    let zones_hotplug_size: u64 = cfg
+        .memory
+        .zones
+        .iter()
+        .flatten()
+        .map(|z| z.hotplug_size.unwrap_or_default())
+        .sum();
+
+    cfg.memory.hotplug_size.unwrap_or_default() + zones_hotplug_size
+}
+
+// Return the size in MiB to resize the memory to: the memory the VM booted
+// with, which cannot be removed, plus the requested hot added memory rounded
+// up to the CH hotplug granularity. Fails if no memory can be hot added, or
+// if the size exceeds the maximum memory.
+fn get_memory_resize(requested_mb: u64, boot_memory_mb: u64, max_memory_mb: u64) -> Result<u64> {
+    if max_memory_mb <= boot_memory_mb {
+        return Err(anyhow!(
+            "cannot resize memory: no memory hotplug size configured when the VM was booted"
+        ));
+    }
+
+    let hotplug_mb = requested_mb.saturating_sub(boot_memory_mb);
+    let hotplug_mb = (hotplug_mb + CH_MEMORY_HOTPLUG_ALIGN_MB - 1) / CH_MEMORY_HOTPLUG_ALIGN_MB
+        * CH_MEMORY_HOTPLUG_ALIGN_MB;
+
+    let size_mb = boot_memory_mb + hotplug_mb;
+
+    if size_mb > max_memory_mb {
+        return Err(anyhow!(
+            "cannot resize memory to {} MiB: maximum memory is {} MiB",
+            requested_mb,
+            max_memory_mb
+        ));
+    }
+
+    Ok(size_mb)
+}
+
+// Return the size in MiB the memory cannot be shrunk below: the memory the
+// VM booted with plus the reserve, and the memory used by the guest plus
+// the reserve if the guest free memory is known. The size is aligned as the
+// hot plugged memory.
+fn get_memory_shrink_floor(
+    boot_memory_mb: u64,
+    reserve_mb: u64,
+    plugged_mb: u64,
+    guest_free_mb: Option<u64>,
+) -> u64 {
+    let used_mb = guest_free_mb
+        .map(|free_mb| plugged_mb.saturating_sub(free_mb))
+        .unwrap_or_default();
+
+    let floor_mb = boot_memory_mb.max(used_mb) + reserve_mb;
+
+    let hotplug_mb = floor_mb - boot_memory_mb;
+    let hotplug_mb = (hotplug_mb + CH_MEMORY_HOTPLUG_ALIGN_MB - 1) / CH_MEMORY_HOTPLUG_ALIGN_MB
+        * CH_MEMORY_HOTPLUG_ALIGN_MB;
+
+    boot_memory_mb + hotplug_mb
+}
+
+// Return the sizes in MiB to shrink the memory to, one per step of at most
+// step_mb, the last one being the target.
+fn get_memory_shrink_steps(current_mb: u64, target_mb: u64, step_mb: u64) -> Vec<u64> {
+    let step_mb = step_mb.max(1);
+
+    let mut steps = vec![];
+    let mut size_mb = current_mb;
+
+    while size_mb > target_mb {
+        size_mb = size_mb.saturating_sub(step_mb).max(target_mb);
+        steps.push(size_mb);
+    }
+
+    steps
+}
+
+// Only virtio-mem can remove memory from the guest, which must be allowed by
+// the configuration as the guest may fail to release it.
+fn check_memory_unplug(method: HotplugMethod, enable_unplug: bool) -> Result<()> {
+    if method != HotplugMethod::VirtioMem {
+        return Err(anyhow!(
+            "memory cannot be removed with {:?} hotplug",
+            method
+        ));
+    }
+
+    if !enable_unplug {
+        return Err(anyhow!("memory removal is disabled in the configuration"));
+    }
+
+    Ok(())
+}
+
+// Wait up to the specified number of seconds for the processes to exit,
+// returning the ones still running.
+async fn wait_processes_exit(pids: Vec<u32>, timeout_secs: u32) -> Vec<u32> {
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs as u64);
+
+    loop {
+        let running: Vec<u32> = pids
+            .iter()
+            .copied()
+            .filter(|pid| is_process_running(Path::new(&format!("/proc/{}", pid))))
+            .collect();
+
+        if running.is_empty() || Instant::now() >= deadline {
+            return running;
+        }
+
+        tokio::time::sleep(Duration::from_millis(CH_POLL_TIME_MS)).await;
+    }
+}
+
+// Return the size in MiB of the balloon reclaiming the plugged memory above
+// the requested size. Inflating the balloon by more than the allowed
+// percentage of the guest free memory fails, as the guest would run out of
+// memory, as does inflating it without knowing the guest free memory.
+fn get_balloon_reclaim(
+    requested_mb: u64,
+    plugged_mb: u64,
+    balloon_mb: u64,
+    guest_free_mb: Option<u64>,
+    free_percent: u32,
+) -> Result<u64> {
+    let size_mb = plugged_mb.saturating_sub(requested_mb);
+
+    if size_mb <= balloon_mb {
+        return Ok(size_mb);
+    }
+
+    let free_mb = guest_free_mb.ok_or_else(|| {
+        anyhow!(
+            "cannot shrink memory to {} MiB with the balloon: guest free memory unknown",
+            requested_mb
+        )
+    })?;
+
+    let max_mb = free_mb * free_percent as u64 / 100;
+
+    if size_mb - balloon_mb > max_mb {
+        return Err(anyhow!(
+            "cannot shrink memory to {} MiB with the balloon: reclaiming {} MiB exceeds {}% of the {} MiB free in the guest",
+            requested_mb,
+            size_mb - balloon_mb,
+            free_percent,
+            free_mb
+        ));
+    }
+
+    Ok(size_mb)
+}
+
+// Return the size in MiB to inflate the balloon by on a hint, at most the
+// percentage of the guest free memory, and nothing if it is unknown.
+fn get_hint_inflation(hint_mb: u64, guest_free_mb: Option<u64>, free_percent: u32) -> u64 {
+    let max_mb = guest_free_mb.unwrap_or_default() * free_percent as u64 / 100;
+
+    hint_mb.min(max_mb)
+}
+
+// Return the size in MiB to deflate the balloon inflated on hints by, for
+// the guest to have the requested memory: the memory available in the
+// guest is the plugged memory, less the balloon.
+fn get_hint_deflation(requested_mb: u64, plugged_mb: u64, balloon_mb: u64, hinted_mb: u64) -> u64 {
+    let available_mb = plugged_mb.saturating_sub(balloon_mb);
+
+    requested_mb
+        .saturating_sub(available_mb)
+        .min(hinted_mb)
+        .min(balloon_mb)
+}
+
+// Fail if the memory plugged after a resize does not match the requested
+// size, allowing for the alignment applied by CH.
+fn check_resized_memory(requested_mb: u64, plugged_mb: u64) -> Result<()> {
+    if requested_mb.abs_diff(plugged_mb) >= CH_MEMORY_HOTPLUG_ALIGN_MB {
+        return Err(anyhow!(
+            "memory resize to {} MiB requested but {} MiB plugged",
+            requested_mb,
+            plugged_mb
+        ));
+    }
+
+    Ok(())
+}
+
+// Return a warning if the guest physical addresses used by the memory, the
+// BARs and the reserved ranges exceed the share of the address space of the
+// physical address bits which leaves room for the alignment of the BARs.
+fn get_address_space_warning(max_memory_mb: u64, bars_size: u64, phys_bits: u32) -> Option<String> {
+    let limit_mb = 1u64.checked_shl(phys_bits)? / MIB;
+    let bars_mb = (bars_size / MIB) + u64::from(bars_size % MIB != 0);
+
+    let used_mb = max_memory_mb
+        .saturating_add(CH_RESERVED_ADDRESS_SPACE_MB)
+        .saturating_add(bars_mb);
+
+    if used_mb.saturating_mul(100) <= limit_mb.saturating_mul(CH_ADDRESS_SPACE_WARN_PERCENT) {
+        return None;
+    }
+
+    Some(format!(
+        "guest physical address space nearly exhausted: {} MiB of memory including the hotplug region and memory offset, {} MiB of VFIO BARs and {} MiB reserved, out of {} MiB ({} bits)",
+        max_memory_mb,
+        bars_mb,
+        CH_RESERVED_ADDRESS_SPACE_MB,
+        limit_mb,
+        phys_bits
+    ))
+}
+
+// Return the numbers of vCPUs the VM boots with and may have, from the
+// default_vcpus and default_maxvcpus settings, already adjusted to the host
+// CPUs when the configuration was loaded. Both are limited to the maximum
+// number of vCPUs of CH, and the VM has at least one vCPU.
+fn get_vcpus(default_vcpus: i32, default_maxvcpus: u32) -> (u32, u32) {
+    let max_vcpus = default_maxvcpus.clamp(1, CH_MAX_VCPUS);
+
+    let boot_vcpus = (default_vcpus.max(1) as u32).min(max_vcpus);
+
+    (boot_vcpus, max_vcpus)
+}
+
+// Return the number of vCPUs to resize the VM to. The vCPUs the VM booted
+// with are kept, as the sandbox itself needs them: the CPU limits of the
+// containers only add to them.
+fn get_vcpu_resize(requested: u32, boot_vcpus: u32, max_vcpus: u32) -> u32 {
+    let max_vcpus = max_vcpus.max(1);
+
+    requested.max(boot_vcpus).clamp(1, max_vcpus)
+}
+
+// Return the plan of a shrink from current to desired vCPUs. CH removes the
+// vCPUs with the highest ids, and at least one vCPU is kept.
+fn get_vcpu_shrink_plan(current: u32, desired: u32) -> VcpuShrinkPlan {
+    let desired = desired.max(1);
+
+    VcpuShrinkPlan {
+        from: current,
+        to: desired,
+        offline_vcpus: (desired..current).rev().collect(),
+    }
+}
+
+// Check a resize from current to desired vCPUs matches the shrink in
+// progress, if any: a shrink must be planned first, so that the agent
+// offlines the vCPUs, and until the vCPUs of a requested shrink are
+// removed, the only other resize possible is growing back to the vCPUs
+// present when it was planned, which cancels it.
+fn check_vcpu_shrink(shrink: Option<&VcpuShrink>, current: u32, desired: u32) -> Result<()> {
+    match shrink {
+        Some(VcpuShrink::Requested(plan)) if plan.to != desired && desired < plan.from => {
+            Err(anyhow!(
+                "vCPU shrink from {} to {} still in progress",
+                plan.from,
+                plan.to
+            ))
+        }
+        Some(VcpuShrink::Planned(plan)) if desired < current && plan.to != desired => Err(anyhow!(
+            "vCPU shrink planned to {} vCPUs, not {}",
+            plan.to,
+            desired
+        )),
+        None if desired < current => Err(anyhow!(
+            "vCPU shrink from {} to {} not planned, the agent must offline the vCPUs first",
+            current,
+            desired
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Fail with ChError::QuotaExceeded if more of a resource would be hot added
+// than the quota allows. A quota of 0 means no quota.
+fn check_hotplug_quota(resource: &str, hotplugged: u64, quota: u64) -> Result<()> {
+    if quota != 0 && hotplugged > quota {
+        return Err(ChError::QuotaExceeded {
+            resource: resource.to_string(),
+            requested: hotplugged,
+            quota,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+// Fail with ChError::Unsupported for a confidential guest, whose state CH
+// cannot save as its memory and vCPU state are encrypted.
+fn check_not_confidential(config: Option<&HypervisorConfig>, operation: &str) -> Result<()> {
+    if config.map(|c| c.security_info.confidential_guest) == Some(true) {
+        return Err(ChError::Unsupported {
+            operation: operation.to_string(),
+            reason: "the guest is confidential".to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+// Classify a failure of CH to launch an SEV-SNP guest. The AMD secure
+// processor refuses to launch a guest whose policy, set in the IGVM file,
+// the host does not allow, such as a too old firmware version. The other
+// SEV-SNP launch failures are caused by a host unable to run such guests.
+fn get_snp_failure(detail: &str) -> Option<&'static str> {
+    let detail = detail.to_lowercase();
+
+    if detail.contains("policy") {
+        return Some("SEV-SNP guest policy rejected by the host");
+    }
+
+    let host_errors = ["/dev/sev", "not supported", "unsupported", "no such device"];
+
+    if (detail.contains("snp") || detail.contains("sev"))
+        && host_errors.iter().any(|e| detail.contains(e))
+    {
+        return Some("host not SEV-SNP capable");
+    }
+
+    None
+}
+
+// Adapt the VM config to a TDX or SEV-SNP guest, whose memory is private to
+// the guest, rejecting or adjusting its devices as the policy of such guests
+// requires: vhost-user backends such as virtiofsd cannot access its memory,
+// the boot file cannot be mapped as a pmem device, and the balloon cannot
+// report free pages. The guest boots from the initrd alone, or from the image
+// attached read-only as the first disk, so that the root filesystem the
+// guest was measured with cannot change.
+#[cfg(target_arch = "x86_64")]
+fn set_confidential_config(cfg: &mut VmConfig, boot_info: &BootInfo) -> Result<()> {
+    for fs in cfg.fs.iter().flatten() {
+        check_confidential_device(DEVICE_KIND_VIRTIO_FS, Some(&fs.tag))?;
+    }
+
+    for pmem in cfg.pmem.iter().flatten() {
+        check_confidential_device(DEVICE_KIND_PMEM, Some(&pmem.file.to_string_lossy()))?;
+    }
+
+    cfg.memory.shared = false;
+
+    let payload = cfg.payload.get_or_insert_with(Default::default);
+
+    let cmdline = payload.cmdline.take().unwrap_or_default();
+
+    // The initrd is preferred to the image.
+    if !boot_info.initrd.is_empty() {
+        payload.initramfs = Some(PathBuf::from(&boot_info.initrd));
+        payload.cmdline = Some(get_confidential_cmdline(&cmdline, None, "")?);
+    } else {
+        payload.cmdline = Some(get_confidential_cmdline(
+            &cmdline,
+            Some(VM_ROOTFS_DRIVER_BLK),
+            &boot_info.rootfs_verity_params,
+        )?);
+
+        let mut disks = vec![DiskConfig {
+            path: Some(PathBuf::from(&boot_info.image)),
+            readonly: true,
+            num_queues: CH_DISK_NUM_QUEUES,
+            queue_size: CH_DISK_QUEUE_SIZE,
+            ..Default::default()
+        }];
+
+        disks.extend(cfg.disks.take().into_iter().flatten());
+
+        cfg.disks = Some(disks);
+    }
+
+    let free_page_reporting = get_confidential_policy(DEVICE_KIND_BALLOON_FREE_PAGE_REPORTING);
+
+    if let (Some(balloon), Some(ConfidentialPolicy::Adjust(reason))) =
+        (cfg.balloon.as_mut(), free_page_reporting)
+    {
+        if balloon.free_page_reporting {
+            warn!(
+                sl!(),
+                "{} not supported by confidential guests: {}",
+                DEVICE_KIND_BALLOON_FREE_PAGE_REPORTING,
+                reason
+            );
+
+            balloon.free_page_reporting = false;
+        }
+    }
+
+    Ok(())
+}
+
+// Replace the root filesystem parameters of the kernel command line with
+// the ones of a root filesystem on the specified driver, the first
+// virtio-blk disk, along with the parameters checking its integrity. The
+// root filesystem of a guest booting from its initrd alone has none.
+#[cfg(target_arch = "x86_64")]
+fn get_confidential_cmdline(
+    cmdline: &str,
+    rootfs_driver: Option<&str>,
+    verity_params: &str,
+) -> Result<String> {
+    let rootfs_type = cmdline
+        .split_whitespace()
+        .find_map(|p| p.strip_prefix("rootfstype="))
+        .unwrap_or(VM_ROOTFS_FILESYSTEM_EXT4);
+
+    let rootfs_params = match rootfs_driver {
+        Some(driver) => KernelParams::new_rootfs_kernel_params(driver, rootfs_type)?.to_string()?,
+        None => String::new(),
+    };
+
+    let mut params: Vec<&str> = cmdline
+        .split_whitespace()
+        .filter(|p| {
+            *p != "ro"
+                && !["root=", "rootflags=", "rootfstype="]
+                    .iter()
+                    .any(|k| p.starts_with(k))
+        })
+        .collect();
+
+    params.extend(rootfs_params.split_whitespace());
+    params.extend(verity_params.split_whitespace());
+
+    Ok(params.join(" "))
+}
+
+// Fail with ChError::Unsupported if memory cannot be hot added to a
+// confidential guest, which requires an explicit opt-in.
+fn check_confidential_memory_hotplug(confidential_guest: bool, enabled: bool) -> Result<()> {
+    if confidential_guest && !enabled {
+        return Err(ChError::Unsupported {
+            operation: "memory hotplug".to_string(),
+            reason: "the private memory of confidential guests is sized statically, set enable_confidential_memory_hotplug if CH and the guest kernel support hot adding it".to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+// Fail with ChError::Unsupported if the VM was sized statically, so cannot
+// be resized.
+fn check_resizable(config: Option<&HypervisorConfig>, operation: &str) -> Result<()> {
+    if config.map(|c| c.static_sandbox_resource_mgmt) == Some(true) {
+        return Err(ChError::Unsupported {
+            operation: operation.to_string(),
+            reason: "the VM is sized statically".to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+// Fail with ChError::Unsupported unless the CH version is at least the
+// specified one, required by the feature.
+fn check_min_version(version: &str, feature: &str, min_version: (u32, u32)) -> Result<()> {
+    let supported = parse_ch_version(version)
+        .map(|v| v >= min_version)
+        .unwrap_or_default();
+
+    if !supported {
+        return Err(ChError::Unsupported {
+            operation: feature.to_string(),
+            reason: format!(
+                "requires {} v{}.{} or newer, version is {:?}",
+                CH_NAME, min_version.0, min_version.1, version
+            ),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+// Return the resize request which only changes the balloon size.
+fn get_balloon_resize(size_mb: u32) -> VmResize {
+    VmResize {
+        desired_balloon: Some(size_mb as u64 * 1024 * 1024),
+        ..Default::default()
+    }
+}
+
+fn check_migration_url(url: &str) -> Result<()> {
+    if !CH_MIGRATION_URL_SCHEMES
+        .iter()
+        .any(|scheme| url.starts_with(scheme) && url.len() > scheme.len())
+    {
+        return Err(anyhow!(
+            "invalid migration URL {:?}, expected one of {:?} followed by an address",
+            url,
+            CH_MIGRATION_URL_SCHEMES
+        ));
+    }
+
+    Ok(())
+}
+
+// Wait for a VMM exit reported by the process monitor. Returns None if CH
+// exited because a shutdown was requested, or the reason it exited.
+pub(crate) async fn wait_vmm_exit(exit_rx: Receiver<Option<VmmExit>>) -> Result<Option<String>> {
+    let exit = wait_vmm_exited(exit_rx).await?;
+
+    if exit.requested {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        ChError::VmmExited {
+            status: exit.status,
+        }
+        .to_string(),
+    ))
+}
+
+pub(crate) async fn wait_vm_exit_reason(
+    exit_rx: Receiver<Option<VmmExit>>,
+) -> Result<VmExitReason> {
+    let exit = wait_vmm_exited(exit_rx).await?;
+
+    Ok(exit.reason)
+}
+
+async fn wait_vmm_exited(mut exit_rx: Receiver<Option<VmmExit>>) -> Result<VmmExit> {
+    loop {
+        if let Some(exit) = exit_rx.borrow().clone() {
+            return Ok(exit);
+        }
+
+        exit_rx
+            .changed()
+            .await
+            .context(format!("{} monitor stopped", CH_NAME))?;
+    }
+}
+
+// Return why CH exited. CH exits successfully when the guest shuts down,
+// and reports the expiry of the guest watchdog in its output.
+fn get_vm_exit_reason(
+    status: &std::io::Result<ExitStatus>,
+    requested: bool,
+    output: &[String],
+) -> VmExitReason {
+    if requested {
+        return VmExitReason::Stopped;
+    }
+
+    if output.iter().any(|l| l.contains(CH_WATCHDOG_EXPIRED)) {
+        return VmExitReason::Watchdog;
+    }
+
+    let status = match status {
+        Ok(status) => status,
+        Err(_) => return VmExitReason::Unknown,
+    };
+
+    match (status.code(), status.signal()) {
+        (Some(0), _) => VmExitReason::GuestShutdown,
+        (Some(code), _) => VmExitReason::Exited(code),
+        (None, Some(signal)) => VmExitReason::Signaled(signal),
+        (None, None) => VmExitReason::Unknown,
+    }
+}
+
+// Describe the exit status of CH as "status <code>" or "signal <number>".
+fn exit_status_string(status: &ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("status {}", code),
+        (None, Some(signal)) => format!("signal {}", signal),
+        (None, None) => "unknown status".to_string(),
+    }
+}
+
+// Log all output from the CH process until it exits or a shutdown signal
+// is received. In the latter case, stop logging and kill the child process.
+// Either way, the exit of the process is reported on the exit channel. An
+// unexpected exit is logged along with the last lines output by CH.
+async fn cloud_hypervisor_log_output(
+    mut child: Child,
+    mut shutdown: Receiver<bool>,
+    exit_tx: Sender<Option<VmmExit>>,
+    stop_requested: Arc<AtomicBool>,
+    log_path: Option<String>,
+) -> Result<()> {
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or("failed to get child stdout")
+        .map_err(|e| anyhow!(e))?;
+
+    let stdout_reader = BufReader::new(stdout);
+    let mut stdout_lines = stdout_reader.lines();
+    let mut stdout_open = true;
+
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or("failed to get child stderr")
+        .map_err(|e| anyhow!(e))?;
+
+    let stderr_reader = BufReader::new(stderr);
+    let mut stderr_lines = stderr_reader.lines();
+    let mut stderr_open = true;
+
+    // CH reports fatal errors on stderr when not logging to a file.
+    let mut stderr_tail: VecDeque<String> = VecDeque::with_capacity(CH_LOG_TAIL_LINES);
+
+    let exited = loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                info!(sl!(), "got shutdown request");
+                break None;
+            },
+            status = child.wait() => {
+                break Some(status);
+            },
+            stderr_line = poll_fn(|cx| Pin::new(&mut stderr_lines).poll_next_line(cx)), if stderr_open => {
+                match stderr_line {
+                    Ok(Some(line)) => {
+                        info!(sl!(), "{:?}", line; "stream" => "stderr");
+
+                        if stderr_tail.len() == CH_LOG_TAIL_LINES {
+                            stderr_tail.pop_front();
+                        }
+
+                        stderr_tail.push_back(line);
+                    }
+                    _ => stderr_open = false,
+                }
+            },
+            stdout_line = poll_fn(|cx| Pin::new(&mut stdout_lines).poll_next_line(cx)), if stdout_open => {
+                match stdout_line {
+                    Ok(Some(line)) => info!(sl!(), "{:?}", line; "stream" => "stdout"),
+                    _ => stdout_open = false,
+                }
+            },
+        };
+    };
+
+    // CH may exit by itself while it is being stopped, before the shutdown
+    // signal.
+    let requested = exited.is_none() || stop_requested.load(Ordering::SeqCst);
+
+    let status = match exited {
+        Some(status) => status,
+        None => {
+            // Note that this kills _and_ waits for the process!
+            child.kill().await?;
+
+            child.wait().await
+        }
+    };
+
+    let status_string = match &status {
+        Ok(status) => exit_status_string(status),
+        Err(e) => format!("unknown status: {}", e),
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+
+    if !requested {
+        lines = stderr_tail.into_iter().collect();
+
+        if let Some(path) = &log_path {
+            match get_file_tail(path, CH_LOG_TAIL_LINES) {
+                Ok(tail) if !tail.is_empty() => lines = tail,
+                Ok(_) => (),
+                Err(e) => warn!(sl!(), "failed to read {} log: {:?}", CH_NAME, e),
+            }
+        }
+
+        error!(
+            sl!(),
+            "{} exited unexpectedly ({}), last output:\n{}",
+            CH_NAME,
+            status_string,
+            lines.join("\n")
+        );
+    }
+
+    let reason = get_vm_exit_reason(&status, requested, &lines);
+
+    // Nobody may be waiting for the exit any more.
+    let _ = exit_tx.send(Some(VmmExit {
+        status: status_string,
+        requested,
+        reason,
+    }));
+
+    Ok(())
+}
+
+// Report the exit of a CH process which is not a child of the shim, such as
+// a recovered one, by polling its /proc entry: its exit status cannot be
+// known. CH is killed once the shutdown is requested.
+async fn cloud_hypervisor_monitor_pid(
+    pid: u32,
+    mut shutdown: Receiver<bool>,
+    exit_tx: Sender<Option<VmmExit>>,
+    stop_requested: Arc<AtomicBool>,
+    log_path: Option<String>,
+) -> Result<()> {
+    let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+
+    let mut killed = false;
+
+    while is_process_running(&proc_dir) {
+        tokio::select! {
+            _ = shutdown.changed(), if !killed => {
+                info!(sl!(), "got shutdown request");
+
+                if let Err(e) = kill(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+                    warn!(sl!(), "failed to kill {}: {:?}", CH_NAME, e);
+                }
+
+                killed = true;
+            },
+            _ = tokio::time::sleep(Duration::from_millis(CH_POLL_TIME_MS)) => (),
+        };
+    }
+
+    let requested = killed || stop_requested.load(Ordering::SeqCst);
+
+    let mut lines: Vec<String> = Vec::new();
+
+    if !requested {
+        if let Some(path) = &log_path {
+            match get_file_tail(path, CH_LOG_TAIL_LINES) {
+                Ok(tail) => lines = tail,
+                Err(e) => warn!(sl!(), "failed to read {} log: {:?}", CH_NAME, e),
+            }
+        }
+
+        error!(
+            sl!(),
+            "{} exited unexpectedly, last output:\n{}",
+            CH_NAME,
+            lines.join("\n")
+        );
+    }
+
+    let error = std::io::Error::new(std::io::ErrorKind::Other, "not a child of the shim");
+
+    let status_string = format!("unknown status: {}", error);
+
+    let reason = get_vm_exit_reason(&Err(error), requested, &lines);
+
+    // Nobody may be waiting for the exit any more.
+    let _ = exit_tx.send(Some(VmmExit {
+        status: status_string,
+        requested,
+        reason,
+    }));
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_get_balloon_config() {
+        let mut memory_info = MemoryInfo::default();
+
+        assert_eq!(get_balloon_config(&memory_info), None);
+
+        memory_info.enable_balloon = true;
+        memory_info.balloon_size_mb = 128;
+
+        assert_eq!(
+            get_balloon_config(&memory_info),
+            Some(BalloonConfig {
+                size: 128 * 1024 * 1024,
+                deflate_on_oom: false,
+                free_page_reporting: false,
+            })
+        );
+
+        memory_info.balloon_deflate_on_oom = true;
+        memory_info.balloon_free_page_reporting = true;
+
+        let cfg = get_balloon_config(&memory_info).unwrap();
+        assert!(cfg.deflate_on_oom);
+        assert!(cfg.free_page_reporting);
+    }
+
+    #[test]
+    fn test_get_pci_segments() {
+        // Defaults
+        assert_eq!(get_pci_segments(0, &[], &[]).unwrap(), (1, None));
+        assert_eq!(get_pci_segments(0, &[], &[(0, true)]).unwrap(), (1, None));
+
+        // Derived from the devices
+        assert_eq!(
+            get_pci_segments(0, &[], &[(0, false), (2, true), (1, false), (2, true)]).unwrap(),
+            (3, Some(vec![2]))
+        );
+
+        // Configured
+        assert_eq!(
+            get_pci_segments(4, &[3, 1], &[(2, true)]).unwrap(),
+            (4, Some(vec![1, 3]))
+        );
+
+        // Invalid segment references
+        assert!(get_pci_segments(2, &[], &[(2, false)]).is_err());
+        assert!(get_pci_segments(2, &[2], &[]).is_err());
+        assert!(get_pci_segments(0, &[], &[(MAX_NUM_PCI_SEGMENTS, false)]).is_err());
+    }
+
+    #[test]
+    fn test_get_address_space_warning() {
+        // 64 GiB of address space.
+        assert_eq!(get_address_space_warning(16 * 1024, 8 << 30, 36), None);
+
+        let warning = get_address_space_warning(50 * 1024, 8 << 30, 36).unwrap();
+
+        assert!(warning.contains("51200 MiB of memory"));
+        assert!(warning.contains("8192 MiB of VFIO BARs"));
+        assert!(warning.contains("out of 65536 MiB (36 bits)"));
+
+        // Partial MiB of BARs are counted.
+        assert_eq!(get_address_space_warning(57957, 1, 36), None);
+        assert!(get_address_space_warning(57958, 1, 36).is_some());
+
+        assert!(get_address_space_warning(u64::MAX, 0, 63).is_some());
+        assert_eq!(get_address_space_warning(u64::MAX, 0, 64), None);
+    }
+
+    #[test]
+    fn test_get_vcpus() {
+        assert_eq!(get_vcpus(2, 4), (2, 4));
+
+        // The host CPUs are not a limit, the configuration was adjusted.
+        assert_eq!(get_vcpus(16, 32), (16, 32));
+
+        // Larger than the CH limit.
+        assert_eq!(get_vcpus(300, 1000), (CH_MAX_VCPUS, CH_MAX_VCPUS));
+
+        // Boot vCPUs above the maximum vCPUs.
+        assert_eq!(get_vcpus(6, 4), (4, 4));
+
+        // Unset.
+        assert_eq!(get_vcpus(0, 0), (1, 1));
+    }
+
+    #[test]
+    fn test_get_vcpu_resize() {
+        assert_eq!(get_vcpu_resize(4, 1, 12), 4);
+
+        // Clamped to the maximum vCPUs.
+        assert_eq!(get_vcpu_resize(16, 1, 12), 12);
+
+        // Not shrunk below the boot vCPUs, nor to no vCPU.
+        assert_eq!(get_vcpu_resize(1, 2, 12), 2);
+        assert_eq!(get_vcpu_resize(0, 0, 12), 1);
+
+        // Boot vCPUs above the maximum, which CH would have rejected.
+        assert_eq!(get_vcpu_resize(2, 4, 2), 2);
+        assert_eq!(get_vcpu_resize(2, 0, 0), 1);
+    }
+
+    #[test]
+    fn test_get_vcpu_shrink_plan() {
+        let plan = get_vcpu_shrink_plan(4, 2);
+
+        assert_eq!(plan.from, 4);
+        assert_eq!(plan.to, 2);
+        assert_eq!(plan.offline_vcpus, vec![3, 2]);
+
+        // vCPU 0 is kept.
+        assert_eq!(get_vcpu_shrink_plan(3, 0).offline_vcpus, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_check_vcpu_shrink() {
+        let planned = VcpuShrink::Planned(get_vcpu_shrink_plan(4, 2));
+        let requested = VcpuShrink::Requested(get_vcpu_shrink_plan(4, 2));
+
+        // A shrink must be planned.
+        assert!(check_vcpu_shrink(None, 4, 2).is_err());
+        assert!(check_vcpu_shrink(None, 4, 6).is_ok());
+
+        assert!(check_vcpu_shrink(Some(&planned), 4, 2).is_ok());
+        assert!(check_vcpu_shrink(Some(&planned), 4, 3).is_err());
+
+        // A planned shrink does not prevent growing.
+        assert!(check_vcpu_shrink(Some(&planned), 4, 6).is_ok());
+
+        // Once requested, only the same shrink, or growing back to the
+        // vCPUs present when it was planned, which cancels it.
+        assert!(check_vcpu_shrink(Some(&requested), 3, 2).is_ok());
+        assert!(check_vcpu_shrink(Some(&requested), 3, 3).is_err());
+        assert!(check_vcpu_shrink(Some(&requested), 3, 4).is_ok());
+        assert!(check_vcpu_shrink(Some(&requested), 3, 6).is_ok());
+    }
+
+    #[test]
+    fn test_get_vcpu_shrink_retry_delay_ms() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut config = HypervisorConfig::default();
+        ch.set_hypervisor_config(config.clone());
+
+        assert_eq!(ch.get_vcpu_shrink_retry_delay_ms(), None);
+
+        ch.vcpu_shrink = Some(VcpuShrink::Planned(get_vcpu_shrink_plan(4, 2)));
+        assert_eq!(ch.get_vcpu_shrink_retry_delay_ms(), None);
+
+        ch.vcpu_shrink = Some(VcpuShrink::Requested(get_vcpu_shrink_plan(4, 2)));
+        assert_eq!(
+            ch.get_vcpu_shrink_retry_delay_ms(),
+            Some(CH_VCPU_SHRINK_RETRY_DELAY_MS)
+        );
+
+        config.cpu_info.vcpu_shrink_retry_delay_ms = 200;
+        ch.set_hypervisor_config(config);
+        assert_eq!(ch.get_vcpu_shrink_retry_delay_ms(), Some(200));
+    }
+
+    #[test]
+    fn test_get_memory_resize() {
+        assert_eq!(get_memory_resize(2048, 1024, 4096).unwrap(), 2048);
+
+        // Rounded up to the hotplug granularity.
+        assert_eq!(get_memory_resize(1100, 1024, 4096).unwrap(), 1152);
+        assert_eq!(get_memory_resize(4000, 1024, 4096).unwrap(), 4096);
+
+        // The boot memory is kept.
+        assert_eq!(get_memory_resize(512, 1024, 4096).unwrap(), 1024);
+
+        let err = get_memory_resize(8192, 1024, 4096).unwrap_err();
+        assert!(err.to_string().contains("maximum memory is 4096 MiB"));
+
+        // No hotplug size.
+        assert!(get_memory_resize(1024, 1024, 1024).is_err());
+        assert!(get_memory_resize(2048, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_check_memory_unplug() {
+        assert!(check_memory_unplug(HotplugMethod::VirtioMem, true).is_ok());
+        assert!(check_memory_unplug(HotplugMethod::VirtioMem, false).is_err());
+        assert!(check_memory_unplug(HotplugMethod::Acpi, true).is_err());
+    }
+
+    #[test]
+    fn test_get_balloon_reclaim() {
+        // Inflate by 512 MiB, with up to 50% of 2048 MiB free.
+        assert_eq!(
+            get_balloon_reclaim(1536, 2048, 0, Some(2048), 50).unwrap(),
+            512
+        );
+
+        // Deflating does not need the guest free memory.
+        assert_eq!(get_balloon_reclaim(1792, 2048, 512, None, 50).unwrap(), 256);
+        assert_eq!(get_balloon_reclaim(4096, 2048, 512, None, 50).unwrap(), 0);
+
+        // Only the growth of the balloon counts against the free memory.
+        assert_eq!(
+            get_balloon_reclaim(1024, 2048, 512, Some(1024), 50).unwrap(),
+            1024
+        );
+
+        let err = get_balloon_reclaim(1024, 2048, 0, Some(1024), 50)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("reclaiming 1024 MiB exceeds 50% of the 1024 MiB free"));
+        assert!(get_balloon_reclaim(1536, 2048, 0, None, 50).is_err());
+    }
+
+    #[test]
+    fn test_check_hotplug_quota() {
+        assert!(check_hotplug_quota("vCPU", 4, 4).is_ok());
+        assert!(check_hotplug_quota("vCPU", 0, 4).is_ok());
+
+        // No quota.
+        assert!(check_hotplug_quota("memory", 65536, 0).is_ok());
+
+        let err = check_hotplug_quota("memory", 2048, 1024).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ChError>(),
+            Some(ChError::QuotaExceeded {
+                requested: 2048,
+                quota: 1024,
+                ..
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "memory hotplug quota exceeded: 2048 requested, quota is 1024"
+        );
+    }
+
+    #[test]
+    fn test_check_not_confidential() {
+        let mut config = HypervisorConfig::default();
+
+        assert!(check_not_confidential(Some(&config), "VM snapshot").is_ok());
+
+        config.security_info.confidential_guest = true;
+
+        assert!(matches!(
+            check_not_confidential(Some(&config), "VM snapshot")
+                .unwrap_err()
+                .downcast_ref::<ChError>(),
+            Some(ChError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_set_confidential_config() {
+        let mut boot_info = BootInfo {
+            image: "/opt/kata/kata-containers.img".to_string(),
+            rootfs_verity_params: "cc_rootfs_verity.scheme=dm-verity cc_rootfs_verity.hash=0123"
+                .to_string(),
+            ..Default::default()
+        };
+
+        let pmem_cmdline =
+            "root=/dev/pmem0p1 rootflags=dax,data=ordered,errors=remount-ro ro rootfstype=ext4 panic=1";
+
+        let mut cfg = VmConfig {
+            disks: Some(vec![DiskConfig {
+                path: Some(PathBuf::from("/dev/sdb")),
+                ..Default::default()
+            }]),
+            payload: Some(ch_config::PayloadConfig {
+                cmdline: Some(pmem_cmdline.to_string()),
+                ..Default::default()
+            }),
+            balloon: Some(BalloonConfig {
+                free_page_reporting: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        cfg.memory.shared = true;
+
+        set_confidential_config(&mut cfg, &boot_info).unwrap();
+
+        assert!(!cfg.memory.shared);
+
+        // The boot image is the first disk, so /dev/vda.
+        let disks = cfg.disks.as_ref().unwrap();
+        assert_eq!(disks.len(), 2);
+        assert_eq!(
+            disks[0].path.as_deref(),
+            Some(Path::new("/opt/kata/kata-containers.img"))
+        );
+        assert!(disks[0].readonly);
+
+        assert_eq!(
+            cfg.payload.unwrap().cmdline.unwrap(),
+            "panic=1 root=/dev/vda1 rootflags=data=ordered,errors=remount-ro ro rootfstype=ext4 cc_rootfs_verity.scheme=dm-verity cc_rootfs_verity.hash=0123"
+        );
+        assert!(!cfg.balloon.unwrap().free_page_reporting);
+
+        // The initrd is booted from alone, without a root filesystem.
+        boot_info.initrd = "/opt/kata/kata-containers-initrd.img".to_string();
+        boot_info.rootfs_verity_params.clear();
+
+        let mut cfg = VmConfig {
+            payload: Some(ch_config::PayloadConfig {
+                cmdline: Some(pmem_cmdline.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        set_confidential_config(&mut cfg, &boot_info).unwrap();
+
+        assert!(cfg.disks.is_none());
+
+        let payload = cfg.payload.unwrap();
+        assert_eq!(
+            payload.initramfs.as_deref(),
+            Some(Path::new("/opt/kata/kata-containers-initrd.img"))
+        );
+        assert_eq!(payload.cmdline.unwrap(), "panic=1");
+
+        // pmem and virtio-fs cannot be used.
+        let mut cfg = VmConfig {
+            pmem: Some(vec![ch_config::PmemConfig {
+                file: PathBuf::from("/opt/kata/kata-containers.img"),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        assert!(set_confidential_config(&mut cfg, &boot_info).is_err());
+
+        let mut cfg = VmConfig {
+            fs: Some(vec![ch_config::FsConfig {
+                tag: "kataShared".to_string(),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        assert!(set_confidential_config(&mut cfg, &boot_info).is_err());
+    }
+
+    #[test]
+    fn test_get_snp_failure() {
+        assert_eq!(
+            get_snp_failure("Error booting VM: SNP_LAUNCH_START failed: invalid guest policy"),
+            Some("SEV-SNP guest policy rejected by the host")
+        );
+        assert_eq!(
+            get_snp_failure("Failed to open /dev/sev: No such file or directory"),
+            Some("host not SEV-SNP capable")
+        );
+        assert_eq!(
+            get_snp_failure("SEV-SNP is not supported by the hypervisor"),
+            Some("host not SEV-SNP capable")
+        );
+        assert_eq!(get_snp_failure("Error booting VM: kernel not found"), None);
+    }
+
+    #[test]
+    fn test_check_resizable() {
+        let mut config = HypervisorConfig::default();
+
+        assert!(check_resizable(None, "vCPU resize").is_ok());
+        assert!(check_resizable(Some(&config), "vCPU resize").is_ok());
+
+        config.static_sandbox_resource_mgmt = true;
+
+        let err = check_resizable(Some(&config), "memory resize").unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ChError>(),
+            Some(ChError::Unsupported { .. })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "memory resize not supported: the VM is sized statically"
+        );
+    }
+
+    #[test]
+    fn test_check_min_version() {
+        assert!(check_min_version("cloud-hypervisor v30.0", "aio", (30, 0)).is_ok());
+        assert!(check_min_version("v36.0-12-gabcdef", "aio", (30, 0)).is_ok());
+
+        let err = check_min_version("v29.0", "aio", (30, 0)).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ChError>(),
+            Some(ChError::Unsupported { .. })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "aio not supported: requires cloud-hypervisor v30.0 or newer, version is \"v29.0\""
+        );
+
+        // Unknown versions are refused.
+        assert!(check_min_version("", "aio", (30, 0)).is_err());
+    }
+
+    #[test]
+    fn test_get_disk_io_backend() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut config = HypervisorConfig::default();
+        ch.set_hypervisor_config(config.clone());
+        assert_eq!(ch.get_disk_io_backend().unwrap(), (false, false));
+
+        config.blockdev_info.block_device_aio = CH_BLOCK_DEVICE_AIO_AIO.to_string();
+        ch.set_hypervisor_config(config);
+
+        ch.ch_version = Some("v30.0".to_string());
+        assert_eq!(ch.get_disk_io_backend().unwrap(), (true, false));
+
+        ch.ch_version = Some("v29.0".to_string());
+        assert!(ch.get_disk_io_backend().is_err());
+    }
+
+    #[test]
+    fn test_check_migration_url() {
+        assert!(check_migration_url("unix:/run/vc/vm/migration.sock").is_ok());
+        assert!(check_migration_url("tcp:192.168.0.2:6000").is_ok());
+
+        // A scheme without an address.
+        assert!(check_migration_url("unix:").is_err());
+        assert!(check_migration_url("tcp:").is_err());
+
+        assert!(check_migration_url("").is_err());
+        assert!(check_migration_url("vsock:3:1024").is_err());
+        assert!(check_migration_url("/run/vc/vm/migration.sock").is_err());
+    }
+
+    #[test]
+    fn test_get_resource_capacity() {
+        let mut cfg = VmConfig::default();
+        cfg.cpus.boot_vcpus = 2;
+        cfg.cpus.max_vcpus = 8;
+        cfg.memory.size = 1024 * MIB;
+        cfg.memory.hotplug_size = Some(3072 * MIB);
+        cfg.memory.hotplugged_size = Some(512 * MIB);
+
+        assert_eq!(
+            get_resource_capacity(&cfg, 4, 2048),
+            ResourceCapacity {
+                vcpus: 2,
+                max_vcpus: 4,
+                memory_mb: 1536,
+                max_memory_mb: 2048,
+                balloon_mb: None,
+            }
+        );
+
+        // Maximum sizes not cached.
+        cfg.balloon = Some(BalloonConfig {
+            size: 256 * MIB,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            get_resource_capacity(&cfg, 0, 0),
+            ResourceCapacity {
+                vcpus: 2,
+                max_vcpus: 8,
+                memory_mb: 1536,
+                max_memory_mb: 4096,
+                balloon_mb: Some(256),
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_resized_memory() {
+        let mut cfg = VmConfig::default();
+        cfg.memory.size = 1024 * MIB;
+
+        // ACPI hot plugging grows the boot memory.
+        assert_eq!(get_plugged_memory_size(&cfg) / MIB, 1024);
+
+        // virtio-mem records the hot plugged memory separately.
+        cfg.memory.hotplugged_size = Some(512 * MIB);
+        assert_eq!(get_plugged_memory_size(&cfg) / MIB, 1536);
+
+        // A request which is not aligned is rounded by CH.
+        assert!(check_resized_memory(1000, 1024).is_ok());
+        assert!(check_resized_memory(1100, 1024).is_ok());
+        assert!(check_resized_memory(1536, 1536).is_ok());
+
+        assert!(check_resized_memory(2048, 1024).is_err());
+        assert!(check_resized_memory(1024, 1152).is_err());
+    }
+
+    #[test]
+    fn test_get_balloon_resize() {
+        let resize = get_balloon_resize(256);
+
+        assert_eq!(resize.desired_vcpus, None);
+        assert_eq!(resize.desired_ram, None);
+        assert_eq!(resize.desired_balloon, Some(256 * 1024 * 1024));
+
+        // Only the balloon must be resized.
+        assert_eq!(
+            serde_json::to_string(&resize).unwrap(),
+            r#"{"desired_balloon":268435456}"#
+        );
+    }
+
+    #[test]
+    fn test_get_hugepages_config() {
+        let mut memory_info = MemoryInfo::default();
+
+        let requests = vec![
+            HugePagesRequest {
+                page_size: 2 * MIB,
+                size: 64 * MIB,
+            },
+            HugePagesRequest {
+                page_size: 1024 * MIB,
+                size: 0,
+            },
+        ];
+
+        assert_eq!(
+            get_hugepages_config(&memory_info, false, &[]),
+            (false, None)
+        );
+
+        // The configured size is only used with huge pages.
+        memory_info.hugepage_size_mb = 1024;
+
+        assert_eq!(
+            get_hugepages_config(&memory_info, false, &[]),
+            (false, None)
+        );
+        assert_eq!(
+            get_hugepages_config(&memory_info, true, &[]),
+            (true, Some(1024 * MIB))
+        );
+
+        // The pod request wins, and empty requests are ignored.
+        assert_eq!(
+            get_hugepages_config(&memory_info, true, &requests),
+            (true, Some(2 * MIB))
+        );
+        assert_eq!(
+            get_hugepages_config(&memory_info, false, &requests),
+            (true, Some(2 * MIB))
+        );
+
+        assert_eq!(
+            get_guest_hugepage_params(&requests),
+            vec!["hugepagesz=2M", "hugepages=32"]
+        );
+
+        let requests = vec![
+            HugePagesRequest {
+                page_size: 1024 * MIB,
+                size: 2048 * MIB,
+            },
+            HugePagesRequest {
+                page_size: 2 * MIB,
+                size: 3 * MIB,
+            },
+        ];
+
+        assert_eq!(
+            get_hugepages_config(&memory_info, false, &requests),
+            (true, Some(1024 * MIB))
+        );
+        assert_eq!(
+            get_guest_hugepage_params(&requests),
+            vec![
+                "hugepagesz=2M",
+                "hugepages=2",
+                "hugepagesz=1G",
+                "hugepages=2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_memory_backing() {
+        let mut memory_info = MemoryInfo::default();
+        assert_eq!(get_memory_backing(&memory_info), (false, false));
+
+        memory_info.enable_thp = true;
+        assert_eq!(get_memory_backing(&memory_info), (false, true));
+
+        memory_info.enable_hugepages = true;
+        assert_eq!(get_memory_backing(&memory_info), (true, false));
+
+        memory_info.enable_thp = false;
+        assert_eq!(get_memory_backing(&memory_info), (true, false));
+    }
+
+    #[test]
+    fn test_get_firmware() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let firmware = std::env::temp_dir().join(format!("ch-firmware-{}", std::process::id()));
+        std::fs::write(&firmware, "").unwrap();
+
+        let mut config = HypervisorConfig::default();
+        ch.set_hypervisor_config(config.clone());
+        assert_eq!(ch.get_firmware().unwrap(), None);
+
+        config.boot_info.firmware = firmware.display().to_string();
+        ch.set_hypervisor_config(config.clone());
+        assert_eq!(ch.get_firmware().unwrap(), Some(firmware.clone()));
+
+        std::fs::remove_file(&firmware).unwrap();
+        assert!(ch.get_firmware().is_err());
+
+        config.boot_info.missing_firmware_policy = MISSING_FIRMWARE_KERNEL.to_string();
+        ch.set_hypervisor_config(config.clone());
+        assert_eq!(ch.get_firmware().unwrap(), None);
+
+        // The firmware is mandatory for confidential guests, TDX ones
+        // included, even with the policy falling back to the kernel.
+        config.security_info.confidential_guest = true;
+        ch.set_hypervisor_config(config.clone());
+        assert!(ch.get_firmware().is_err());
+
+        config.boot_info.firmware.clear();
+        ch.set_hypervisor_config(config.clone());
+        let err = ch.get_firmware().unwrap_err().to_string();
+        assert!(err.contains("require a firmware"), "{}", err);
+
+        config.security_info.sev_snp_guest = true;
+        ch.set_hypervisor_config(config);
+        assert!(ch.get_firmware().is_err());
+    }
+
+    #[test]
+    fn test_get_kernel_cmdline() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut config = HypervisorConfig::default();
+        config.boot_info.kernel_params = "panic=0 systemd.mask=c foo=\"a b\"".to_string();
+        ch.set_hypervisor_config(config);
+
+        // The user parameters are appended as is, replacing the generated
+        // ones, except the ones with several values.
+        assert_eq!(
+            ch.get_kernel_cmdline(
+                "root=/dev/pmem0p1 panic=1 console=hvc0 systemd.mask=a systemd.mask=b"
+            ),
+            "root=/dev/pmem0p1 systemd.mask=a systemd.mask=b console=ttyS0,115200n8 panic=0 systemd.mask=c foo=\"a b\""
+        );
+    }
+
+    #[test]
+    fn test_set_entropy_config() {
+        let mut ch = CloudHypervisorInner::new();
+        let mut cfg = VmConfig::default();
+
+        let mut config = HypervisorConfig::default();
+        config.machine_info.guest_entropy_seed_file = "/opt/seed".to_string();
+        ch.set_hypervisor_config(config.clone());
+
+        // The seed file is only used for deterministic entropy.
+        ch.set_entropy_config(&mut cfg).unwrap();
+        assert_eq!(cfg.rng.src, PathBuf::new());
+        assert!(ch.get_entropy_kernel_params().is_empty());
+
+        config.machine_info.deterministic_guest_entropy = true;
+        ch.set_hypervisor_config(config);
+
+        ch.set_entropy_config(&mut cfg).unwrap();
+        assert_eq!(cfg.rng.src, PathBuf::from("/opt/seed"));
+        assert_eq!(
+            ch.get_entropy_kernel_params(),
+            vec!["random.trust_cpu=off", "random.trust_bootloader=off"]
+        );
+    }
+
+    #[test]
+    fn test_set_iommu_config() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut cfg = VmConfig {
+            disks: Some(vec![ch_config::DiskConfig::default()]),
+            net: Some(vec![ch_config::NetConfig::default()]),
+            pmem: Some(vec![ch_config::PmemConfig::default()]),
+            ..Default::default()
+        };
+
+        let mut config = HypervisorConfig::default();
+        config.device_info.iommu_devices = vec!["block".to_string(), "pmem".to_string()];
+        ch.set_hypervisor_config(config.clone());
+
+        // The device types are ignored unless the IOMMU is enabled.
+        ch.set_iommu_config(&mut cfg);
+        assert!(!cfg.iommu);
+        assert!(cfg.device_pci_segments().iter().all(|(_, iommu)| !iommu));
+
+        config.device_info.enable_iommu = true;
+        ch.set_hypervisor_config(config);
+
+        ch.set_iommu_config(&mut cfg);
+        assert!(cfg.iommu);
+        assert!(cfg.disks.as_ref().unwrap()[0].iommu);
+        assert!(!cfg.net.as_ref().unwrap()[0].iommu);
+        assert!(cfg.pmem.as_ref().unwrap()[0].iommu);
+    }
+
+    #[test]
+    fn test_get_memory_shrink_floor() {
+        // Without stats, only the boot memory and the reserve are kept.
+        assert_eq!(get_memory_shrink_floor(1024, 0, 4096, None), 1024);
+        assert_eq!(get_memory_shrink_floor(1024, 256, 4096, None), 1280);
+
+        // 4096 MiB plugged, 1024 MiB free: 3072 MiB used.
+        assert_eq!(get_memory_shrink_floor(1024, 0, 4096, Some(1024)), 3072);
+        assert_eq!(get_memory_shrink_floor(1024, 256, 4096, Some(1024)), 3328);
+
+        // The floor is aligned as the hot plugged memory.
+        assert_eq!(get_memory_shrink_floor(1024, 100, 4096, None), 1152);
+        assert_eq!(get_memory_shrink_floor(1000, 0, 4096, Some(1000)), 3176);
+
+        // More free memory than plugged.
+        assert_eq!(get_memory_shrink_floor(1024, 0, 2048, Some(4096)), 1024);
+    }
+
+    #[test]
+    fn test_get_memory_shrink_steps() {
+        assert_eq!(
+            get_memory_shrink_steps(4096, 1024, 1024),
+            vec![3072, 2048, 1024]
+        );
+        assert_eq!(get_memory_shrink_steps(4096, 2560, 1024), vec![3072, 2560]);
+        assert_eq!(get_memory_shrink_steps(4096, 3968, 1024), vec![3968]);
+        assert_eq!(get_memory_shrink_steps(4096, 4096, 1024), Vec::<u64>::new());
+        assert_eq!(get_memory_shrink_steps(2048, 4096, 1024), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_get_hint_inflation() {
+        // Up to 50% of 2048 MiB free.
+        assert_eq!(get_hint_inflation(512, Some(2048), 50), 512);
+        assert_eq!(get_hint_inflation(1536, Some(2048), 50), 1024);
+        assert_eq!(get_hint_inflation(512, Some(2048), 100), 512);
+        assert_eq!(get_hint_inflation(512, Some(2048), 0), 0);
+
+        // Nothing is reclaimed without stats.
+        assert_eq!(get_hint_inflation(512, None, 50), 0);
+    }
+
+    #[test]
+    fn test_get_hint_deflation() {
+        // 2048 MiB plugged, 512 MiB inflated on hints: 1536 MiB available.
+        assert_eq!(get_hint_deflation(1536, 2048, 512, 512), 0);
+        assert_eq!(get_hint_deflation(1024, 2048, 512, 512), 0);
+        assert_eq!(get_hint_deflation(1792, 2048, 512, 512), 256);
+
+        // Memory hot added beyond the plugged memory does not need the
+        // balloon deflated further.
+        assert_eq!(get_hint_deflation(4096, 2048, 512, 512), 512);
+
+        // The part of the balloon inflated to shrink the memory is left.
+        assert_eq!(get_hint_deflation(2048, 2048, 768, 256), 256);
+        assert_eq!(get_hint_deflation(0, 2048, 0, 256), 0);
+    }
+
+    #[test]
+    fn test_shrink_memory_hint() {
+        let mut ch = CloudHypervisorInner::new();
+
+        // The VM is not running.
+        assert!(block_on(ch.shrink_memory_hint(512 * MIB)).is_err());
+
+        // Hints are ignored unless enabled.
+        ch.state = VmmState::VmRunning;
+        ch.set_hypervisor_config(HypervisorConfig::default());
+        ch.set_guest_free_memory(2048);
+
+        assert_eq!(block_on(ch.shrink_memory_hint(512 * MIB)).unwrap(), 0);
+
+        // Nothing is reclaimed without the guest free memory.
+        let mut config = HypervisorConfig::default();
+        config.memory_info.enable_balloon = true;
+        config.memory_info.balloon_hint_free_percent = 50;
+        ch.set_hypervisor_config(config);
+        ch.guest_free_mb = None;
+
+        assert_eq!(block_on(ch.shrink_memory_hint(512 * MIB)).unwrap(), 0);
+
+        // Reclaiming needs CH.
+        ch.set_guest_free_memory(2048);
+
+        assert!(block_on(ch.shrink_memory_hint(512 * MIB)).is_err());
+        assert_eq!(ch.hinted_balloon_mb, 0);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut ch = CloudHypervisorInner::new();
+        ch.ch_version = Some("v32.0".to_string());
+
+        let mut config = HypervisorConfig::default();
+        config.memory_info.default_memory = 1024;
+        config.memory_info.default_maxmemory = 1024;
+        config.shared_fs.shared_fs = Some("virtio-fs".to_string());
+        ch.set_hypervisor_config(config.clone());
+
+        let caps = block_on(ch.capabilities()).unwrap();
+        assert!(caps.is_block_device_hotplug_supported());
+        assert!(caps.is_fs_sharing_supported());
+        assert!(caps.is_virtio_fs_supported());
+        assert!(!caps.is_virtio_9p_supported());
+        assert!(caps.is_hybrid_vsock_supported());
+        assert_eq!(caps.max_vsock_devices(), CH_MAX_VSOCK_DEVICES);
+        assert!(caps.is_vfio_hotplug_supported());
+        assert!(!caps.is_memory_hotplug_supported());
+
+        // Memory can only be hot plugged up to the maximum memory.
+        config.memory_info.default_maxmemory = 2048;
+        ch.set_hypervisor_config(config.clone());
+
+        let caps = block_on(ch.capabilities()).unwrap();
+        assert!(caps.is_memory_hotplug_supported());
+
+        // Confidential guests reject virtio-fs and VFIO devices.
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut config = config.clone();
+            config.security_info.confidential_guest = true;
+            ch.set_hypervisor_config(config);
+
+            let caps = block_on(ch.capabilities()).unwrap();
+            assert!(!caps.is_fs_sharing_supported());
+            assert!(!caps.is_virtio_fs_supported());
+            assert!(!caps.is_vfio_hotplug_supported());
+            assert!(caps.is_block_device_hotplug_supported());
+        }
+
+        config.shared_fs.shared_fs = Some("virtio-9p".to_string());
+        ch.set_hypervisor_config(config);
+
+        let caps = block_on(ch.capabilities()).unwrap();
+        assert!(!caps.is_fs_sharing_supported());
+        assert!(!caps.is_virtio_fs_supported());
+        assert!(!caps.is_virtio_9p_supported());
+
+        ch.ch_version = Some("v29.0".to_string());
+
+        let caps = block_on(ch.capabilities()).unwrap();
+        assert!(!caps.is_vfio_hotplug_supported());
+        assert!(caps.is_memory_hotplug_supported());
+    }
+
+    #[test]
+    fn test_set_memory_file_backend() {
+        let mut memory = MemoryConfig {
+            size: 2048 * MIB,
+            shared: true,
+            hotplug_size: Some(1024 * MIB),
+            ..Default::default()
+        };
+
+        set_memory_file_backend(&mut memory, PathBuf::from("/dev/shm"));
+
+        assert_eq!(memory.size, 0);
+        assert_eq!(memory.hotplug_size, None);
+        assert_eq!(
+            memory.zones,
+            Some(vec![MemoryZoneConfig {
+                id: CH_MEMORY_ZONE_ID.to_string(),
+                size: 2048 * MIB,
+                file: Some(PathBuf::from("/dev/shm")),
+                shared: true,
+                ..Default::default()
+            }])
+        );
+    }
+
+    #[test]
+    fn test_set_numa_memory_zone() {
+        let mut memory = MemoryConfig {
+            size: 2048 * MIB,
+            hotplug_size: Some(1024 * MIB),
+            ..Default::default()
+        };
+
+        set_numa_memory_zone(&mut memory, 1, HotplugMethod::VirtioMem);
+
+        assert_eq!(memory.size, 0);
+        assert_eq!(memory.hotplug_size, None);
+        assert_eq!(
+            memory.zones,
+            Some(vec![MemoryZoneConfig {
+                id: CH_MEMORY_ZONE_ID.to_string(),
+                size: 2048 * MIB,
+                host_numa_node: Some(1),
+                hotplug_size: Some(1024 * MIB),
+                ..Default::default()
+            }])
+        );
+
+        let mut cfg = VmConfig {
+            memory: memory.clone(),
+            ..Default::default()
+        };
+
+        assert_eq!(get_memory_hotplug_size(&cfg), 1024 * MIB);
+
+        cfg.memory.zones.as_mut().unwrap()[0].hotplugged_size = Some(512 * MIB);
+
+        assert_eq!(get_plugged_memory_size(&cfg), 2560 * MIB);
+
+        // An existing zone is placed on the node.
+        set_numa_memory_zone(&mut memory, 0, HotplugMethod::VirtioMem);
+
+        assert_eq!(memory.zones.as_ref().unwrap()[0].host_numa_node, Some(0));
+
+        // ACPI hotplug is not supported with zones.
+        let mut memory = MemoryConfig {
+            size: 2048 * MIB,
+            hotplug_size: Some(1024 * MIB),
+            ..Default::default()
+        };
+
+        set_numa_memory_zone(&mut memory, 1, HotplugMethod::Acpi);
+
+        assert_eq!(memory.zones.as_ref().unwrap()[0].hotplug_size, None);
+    }
+
+    #[test]
+    fn test_get_vcpu_affinity() {
+        let affinity = get_vcpu_affinity(2, &[4, 5, 6]).unwrap();
+
+        assert_eq!(
+            affinity,
+            vec![
+                CpuAffinity {
+                    vcpu: 0,
+                    host_cpus: vec![4, 5, 6],
+                },
+                CpuAffinity {
+                    vcpu: 1,
+                    host_cpus: vec![4, 5, 6],
+                },
+            ]
+        );
+
+        assert!(get_vcpu_affinity(2, &[]).is_err());
+        assert!(get_vcpu_affinity(2, &[255, 256]).is_err());
+    }
+
+    #[test]
+    fn test_check_confidential_memory_hotplug() {
+        assert!(check_confidential_memory_hotplug(false, false).is_ok());
+
+        let err = check_confidential_memory_hotplug(true, false).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ChError>(),
+            Some(ChError::Unsupported { .. })
+        ));
+        assert!(err
+            .to_string()
+            .contains("set enable_confidential_memory_hotplug"));
+
+        assert!(check_confidential_memory_hotplug(true, true).is_ok());
+    }
+
+    #[test]
+    fn test_get_memory_hotplug_config() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut config = kata_types::config::hypervisor::Hypervisor::default();
+        config.memory_info.default_maxmemory = 4096;
+        ch.set_hypervisor_config(config.clone());
+
+        // Not a confidential guest.
+        assert_eq!(
+            ch.get_memory_hotplug_config(2048 * MIB).unwrap(),
+            (HotplugMethod::Acpi, Some(2048 * MIB))
+        );
+
+        // A confidential guest is sized statically by default, confidential
+        // guests being only available on x86_64.
+        config.security_info.confidential_guest = true;
+        ch.set_hypervisor_config(config.clone());
+
+        let hotplug_size = ch.get_memory_hotplug_config(2048 * MIB).unwrap().1;
+
+        if cfg!(target_arch = "x86_64") {
+            assert_eq!(hotplug_size, None);
+        } else {
+            assert_eq!(hotplug_size, Some(2048 * MIB));
+        }
+
+        // Unless hot adding memory is allowed.
+        config.memory_info.enable_confidential_memory_hotplug = true;
+        ch.set_hypervisor_config(config);
+
+        assert_eq!(
+            ch.get_memory_hotplug_config(2048 * MIB).unwrap(),
+            (HotplugMethod::Acpi, Some(2048 * MIB))
+        );
+    }
+
+    #[test]
+    fn test_get_memory_hotplug() {
+        let mut memory_info = MemoryInfo::default();
+
+        assert_eq!(
+            get_memory_hotplug(&memory_info, 8192, 2048).unwrap(),
+            (HotplugMethod::Acpi, Some(6144 * MIB))
+        );
+
+        // Aligned down to the CH requirement.
+        assert_eq!(
+            get_memory_hotplug(&memory_info, 2048 + 200, 2048).unwrap(),
+            (HotplugMethod::Acpi, Some(128 * MIB))
+        );
+
+        assert_eq!(
+            get_memory_hotplug(&memory_info, 2048 + 100, 2048).unwrap(),
+            (HotplugMethod::Acpi, None)
+        );
+
+        memory_info.enable_virtio_mem = true;
+
+        assert_eq!(
+            get_memory_hotplug(&memory_info, 4096, 2048).unwrap(),
+            (HotplugMethod::VirtioMem, Some(2048 * MIB))
+        );
+
+        assert!(get_memory_hotplug(&memory_info, 1024, 2048).is_err());
+    }
+
+    #[test]
+    fn test_cloud_hypervisor_landlock_rules() {
+        use crate::ch::inner_device::{DeviceCriticality, DevicePlugMode, PendingDevice};
+        use crate::device::{BlockConfig, DeviceLabels, ShareFsDeviceConfig};
+
+        let mut ch = CloudHypervisorInner::new();
+        ch.vm_path = "/run/vc/vm/sandbox".to_string();
+
+        let mut config = kata_types::config::hypervisor::Hypervisor::default();
+        config.boot_info.kernel = "/opt/kata/vmlinux".to_string();
+        ch.set_hypervisor_config(config);
+
+        let queue = |device| PendingDevice {
+            device,
+            criticality: DeviceCriticality::Required,
+            plug_mode: DevicePlugMode::ColdPlug,
+        };
+
+        ch.pending_devices = Some(vec![
+            queue(Device::ShareFsDevice(ShareFsDeviceConfig {
+                fs_type: "virtio-fs".to_string(),
+                sock_path: "/run/virtiofsd/sandbox.sock".to_string(),
+                mount_tag: "kataShared".to_string(),
+                host_path: "/run/kata-containers/shared/sandbox".to_string(),
+                queue_size: 0,
+                queue_num: 0,
+                coalesce: false,
+                guest_mount_point: String::default(),
+                labels: DeviceLabels::new(),
+            })),
+            queue(Device::Block(BlockConfig {
+                id: "blk0".to_string(),
+                path_on_host: "/dev/dm-1".to_string(),
+                is_readonly: true,
+                no_drop: false,
+                index: 0,
+                labels: DeviceLabels::new(),
+            })),
+        ]);
+
+        assert_eq!(
+            ch.cloud_hypervisor_landlock_rules(),
+            vec![
+                "path=/dev/dm-1,access=r",
+                "path=/opt/kata/vmlinux,access=r",
+                "path=/run/kata-containers/shared/sandbox,access=rw",
+                "path=/run/vc/vm/sandbox,access=rw",
+                "path=/run/virtiofsd,access=rw",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_console_socket_config() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut config = kata_types::config::hypervisor::Hypervisor::default();
+        config.debug_info.console_socket = true;
+        ch.set_hypervisor_config(config);
+
+        let dir = std::env::temp_dir().join(format!("ch-console-test-{}", std::process::id()));
+        let vsock_socket = dir.join("ch-vm.sock");
+
+        assert!(ch
+            .get_console_config(ConsoleConfig::default(), None)
+            .is_err());
+
+        let (serial, console) = ch
+            .get_console_config(ConsoleConfig::default(), Some(&vsock_socket))
+            .unwrap();
+
+        let socket = dir.join(CH_CONSOLE_SOCKET_NAME);
+
+        assert_eq!(serial.mode, ConsoleOutputMode::Socket);
+        assert_eq!(serial.socket, Some(socket.clone()));
+        assert_eq!(console.mode, ConsoleOutputMode::Off);
+        assert_eq!(
+            ch.get_console_socket_path(),
+            Some(socket.display().to_string())
+        );
+
+        ch.config.as_mut().unwrap().debug_info.console_type = CH_CONSOLE_TYPE_VIRTIO.to_string();
+
+        assert!(ch
+            .get_console_config(ConsoleConfig::default(), Some(&vsock_socket))
+            .is_err());
+    }
+
+    #[test]
+    fn test_exit_status_string() {
+        // Raw wait statuses: exit code in the second byte, signal in the
+        // low bits.
+        assert_eq!(exit_status_string(&ExitStatus::from_raw(0)), "status 0");
+        assert_eq!(
+            exit_status_string(&ExitStatus::from_raw(1 << 8)),
+            "status 1"
+        );
+        assert_eq!(exit_status_string(&ExitStatus::from_raw(9)), "signal 9");
+    }
+
+    #[test]
+    fn test_wait_vmm_exit() {
+        let (tx, rx) = channel(None);
+
+        tx.send(Some(VmmExit {
+            status: "signal 9".to_string(),
+            requested: false,
+            reason: VmExitReason::Signaled(9),
+        }))
+        .unwrap();
+
+        assert_eq!(
+            block_on(wait_vm_exit_reason(rx.clone())).unwrap(),
+            VmExitReason::Signaled(9)
+        );
+
+        assert_eq!(
+            block_on(wait_vmm_exit(rx.clone())).unwrap(),
+            Some("VMM exited (signal 9)".to_string())
+        );
+
+        tx.send(Some(VmmExit {
+            status: "status 0".to_string(),
+            requested: true,
+            reason: VmExitReason::Stopped,
+        }))
+        .unwrap();
+
+        assert_eq!(block_on(wait_vmm_exit(rx)).unwrap(), None);
+
+        // The monitor went away without reporting an exit.
+        let (tx, rx) = channel::<Option<VmmExit>>(None);
+        drop(tx);
+
+        assert!(block_on(wait_vmm_exit(rx)).is_err());
+    }
+
+    #[test]
+    fn test_get_vm_exit_reason() {
+        let exited = |raw: i32| Ok(ExitStatus::from_raw(raw));
+
+        assert_eq!(
+            get_vm_exit_reason(&exited(0), false, &[]),
+            VmExitReason::GuestShutdown
+        );
+        assert_eq!(
+            get_vm_exit_reason(&exited(1 << 8), false, &[]),
+            VmExitReason::Exited(1)
+        );
+        assert_eq!(
+            get_vm_exit_reason(&exited(9), false, &[]),
+            VmExitReason::Signaled(9)
+        );
+
+        // Killed on request.
+        assert_eq!(
+            get_vm_exit_reason(&exited(9), true, &[]),
+            VmExitReason::Stopped
+        );
+
+        let output = vec!["cloud-hypervisor: 10.5s: <watchdog> ERROR:virtio-devices/src/watchdog.rs:123 -- Watchdog timer expired. Initiating reboot.".to_string()];
+
+        assert_eq!(
+            get_vm_exit_reason(&exited(1 << 8), false, &output),
+            VmExitReason::Watchdog
+        );
+
+        let err = Err(std::io::Error::from(std::io::ErrorKind::Other));
+
+        assert_eq!(get_vm_exit_reason(&err, false, &[]), VmExitReason::Unknown);
+        assert_eq!(VmExitReason::Exited(1).to_string(), "exited with status 1");
+    }
+
+    #[test]
+    fn test_vmm_exited() {
+        let mut ch = CloudHypervisorInner::new();
+
+        // Without monitor task, as when restored, the pid is checked.
+        ch.pid = Some(std::process::id());
+        assert!(!ch.vmm_exited());
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        ch.pid = Some(child.id());
+        child.wait().unwrap();
+        assert!(ch.vmm_exited());
+
+        // The monitor task reports the exit.
+        let (tx, rx) = channel(None);
+        ch.exit_rx = Some(rx);
+        ch.pid = Some(std::process::id());
+        assert!(!ch.vmm_exited());
+
+        tx.send(Some(VmmExit::default())).unwrap();
+        assert!(ch.vmm_exited());
+
+        // Nothing tells whether CH exited.
+        let ch = CloudHypervisorInner::new();
+        assert!(!ch.vmm_exited());
+        assert!(block_on(ch.cleanup()).is_err());
+    }
+
+    #[test]
+    fn test_get_ns_path() {
+        let mut ch = CloudHypervisorInner::new();
+
+        assert!(block_on(ch.get_ns_path()).is_err());
+
+        // Use a pid which exists.
+        let pid = std::process::id();
+        ch.pid = Some(pid);
+
+        assert_eq!(
+            block_on(ch.get_ns_path()).unwrap(),
+            format!("/proc/{}/ns/net", pid)
+        );
+
+        ch.netns = Some("/var/run/netns/cni-1234".to_string());
+
+        assert_eq!(
+            block_on(ch.get_ns_path()).unwrap(),
+            "/var/run/netns/cni-1234"
+        );
+    }
+
+    #[test]
+    fn test_adopt_export_api_socket() {
+        let mut ch = CloudHypervisorInner::new();
+
+        assert!(block_on(ch.adopt_api_socket_fd(-1)).is_err());
+        assert!(ch.export_api_socket_fd().is_err());
+
+        let (socket, mut peer) = UnixStream::pair().unwrap();
+
+        ch.api_socket = Some(socket);
+        ch.state = VmmState::VmRunning;
+
+        // Only one API socket can be adopted.
+        let (other, _) = UnixStream::pair().unwrap();
+        assert!(block_on(ch.adopt_api_socket(other)).is_err());
+
+        let fd = ch.export_api_socket_fd().unwrap();
+
+        assert!(ch.api_socket.is_none());
+        assert_eq!(ch.state, VmmState::NotReady);
+        assert!(ch.export_api_socket_fd().is_err());
+
+        // The exported fd is still connected to the peer.
+        // SAFETY: the fd was just exported, nothing else owns it.
+        let mut socket = unsafe { UnixStream::from_raw_fd(fd) };
+
+        socket.write_all(b"ping").unwrap();
+
+        let mut buf = [0u8; 4];
+        peer.read_exact(&mut buf).unwrap();
+
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test]
+    fn test_register_helper() {
+        let mut ch = CloudHypervisorInner::new();
+
+        let mut config = HypervisorConfig::default();
+        ch.set_hypervisor_config(config.clone());
+
+        let mut helper = std::process::Command::new("sleep")
+            .arg("60")
+            .spawn()
+            .unwrap();
+        let pid = helper.id();
+
+        ch.register_helper(pid).unwrap();
+        ch.register_helper(pid).unwrap();
+
+        assert_eq!(ch.helper_pids, vec![pid]);
+
+        let metrics = ch.hypervisor_metrics();
+
+        assert_eq!(metrics.helpers.len(), 1);
+        assert_eq!(metrics.helpers[0].pid, pid);
+
+        // The cgroup of CH must exist.
+        config.security_info.vmm_cgroup_path = "kata-missing-cgroup".to_string();
+        ch.set_hypervisor_config(config);
+
+        assert!(ch.register_helper(pid).is_err());
+
+        helper.kill().unwrap();
+        helper.wait().unwrap();
+
+        // Helpers which exited are left out of the metrics.
+        assert!(ch.hypervisor_metrics().helpers.is_empty());
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Confinement of the CH process to a directory of the sandbox, the "jail".
+//
+// The host paths CH needs are bind mounted in the jail root at the same
+// paths, so the paths passed to CH are the host paths, as long as they are
+// available in the jail. The jail root is a shared mount and CH runs in its
+// own mount namespace where the mounts are slaves: the paths bind mounted
+// by the driver once CH runs, such as the sockets of hot plugged devices,
+// propagate to CH, while the mounts of CH do not propagate to the host.
+//
+// CH is chrooted into the jail root and its capabilities are dropped,
+// except CAP_NET_ADMIN which it needs to open the tap devices. CH is not
+// placed in a new pid namespace: unshare() only applies to the children of
+// the caller, and CH must remain the process spawned by the driver, whose
+// pid is used to manage it. The jail has no /proc, so CH cannot see the
+// other processes of the host.
+
+use anyhow::{anyhow, Context, Result};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+const CAP_LAST_CAP_PATH: &str = "/proc/sys/kernel/cap_last_cap";
+
+// Last capability of recent kernels, if the one of the host is unknown.
+const DEFAULT_CAP_LAST_CAP: u32 = 40;
+
+const CAP_NET_ADMIN: u32 = 12;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[derive(Debug)]
+pub struct Jail {
+    root: PathBuf,
+
+    // Host paths bind mounted in the jail.
+    mounts: Mutex<Vec<PathBuf>>,
+}
+
+impl Jail {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Jail {
+            root: root.into(),
+            mounts: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Return the jail of a running CH instance, as when restored from the
+    // persisted state, based on the current mounts.
+    pub fn restore<P: Into<PathBuf>>(root: P) -> Result<Self> {
+        let jail = Jail::new(root);
+
+        let mountinfo = std::fs::read_to_string(MOUNTINFO_PATH)?;
+
+        let mounts = get_mount_points_below(&mountinfo, &jail.root)
+            .iter()
+            .filter_map(|p| p.strip_prefix(&jail.root).ok())
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(|p| Path::new("/").join(p))
+            .collect();
+
+        *jail.mounts.lock().unwrap_or_else(|e| e.into_inner()) = mounts;
+
+        Ok(jail)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Make the jail root a shared mount point, so that the mounts below it
+    // propagate to CH.
+    pub fn setup(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.root)
+            .context(format!("failed to create jail root {:?}", self.root))?;
+
+        mount(
+            Some(&self.root),
+            &self.root,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .context(format!("failed to bind mount jail root {:?}", self.root))?;
+
+        mount(
+            None::<&str>,
+            &self.root,
+            None::<&str>,
+            MsFlags::MS_SHARED,
+            None::<&str>,
+        )
+        .context(format!("failed to make jail root {:?} shared", self.root))?;
+
+        Ok(())
+    }
+
+    // Return the path in the jail of a host path, failing if it is not
+    // available in the jail.
+    pub fn get_path(&self, host_path: &Path) -> Result<PathBuf> {
+        let mounts = self.mounts.lock().unwrap_or_else(|e| e.into_inner());
+
+        if !mounts.iter().any(|m| host_path.starts_with(m)) {
+            return Err(anyhow!("{:?} is not available in the jail", host_path));
+        }
+
+        Ok(host_path.to_path_buf())
+    }
+
+    // Make a host path available in the jail, if not already, and return
+    // its path in the jail.
+    pub fn bind(&self, host_path: &Path, read_only: bool) -> Result<PathBuf> {
+        if !host_path.is_absolute() {
+            return Err(anyhow!(
+                "cannot bind relative path {:?} in the jail",
+                host_path
+            ));
+        }
+
+        if let Ok(path) = self.get_path(host_path) {
+            return Ok(path);
+        }
+
+        let target = self.root.join(host_path.strip_prefix("/")?);
+
+        let metadata = std::fs::metadata(host_path)
+            .context(format!("failed to bind {:?} in the jail", host_path))?;
+
+        if metadata.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(&target)
+                .context(format!("failed to create mount point {:?}", target))?;
+        }
+
+        mount(
+            Some(host_path),
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .context(format!("failed to bind {:?} in the jail", host_path))?;
+
+        if read_only {
+            mount(
+                None::<&str>,
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                None::<&str>,
+            )
+            .context(format!(
+                "failed to make {:?} read only in the jail",
+                host_path
+            ))?;
+        }
+
+        self.mounts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(host_path.to_path_buf());
+
+        Ok(host_path.to_path_buf())
+    }
+
+    // Make the directory of a socket available in the jail, as the socket
+    // may be created again by its server, and return the path of the socket
+    // in the jail.
+    pub fn bind_socket(&self, host_path: &Path) -> Result<PathBuf> {
+        let (dir, name) = match (host_path.parent(), host_path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return Err(anyhow!("invalid socket path {:?}", host_path)),
+        };
+
+        Ok(self.bind(dir, false)?.join(name))
+    }
+
+    // Unmount everything below the jail root, then remove it. The jail root
+    // is only removed once no mount is left, as its content would
+    // otherwise include host paths.
+    pub fn teardown(&self) -> Result<()> {
+        if !self.root.exists() {
+            return Ok(());
+        }
+
+        let mountinfo = std::fs::read_to_string(MOUNTINFO_PATH)?;
+
+        for mount_point in get_mount_points_below(&mountinfo, &self.root) {
+            umount2(&mount_point, MntFlags::MNT_DETACH)
+                .context(format!("failed to unmount {:?}", mount_point))?;
+        }
+
+        let mountinfo = std::fs::read_to_string(MOUNTINFO_PATH)?;
+
+        if !get_mount_points_below(&mountinfo, &self.root).is_empty() {
+            return Err(anyhow!("jail {:?} still has mounts", self.root));
+        }
+
+        self.mounts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+
+        std::fs::remove_dir_all(&self.root)
+            .context(format!("failed to remove jail {:?}", self.root))
+    }
+
+    // Return what the CH process needs to enter the jail. This allocates,
+    // so must be called before CH is spawned.
+    pub fn entry(&self, user: Option<(u32, u32)>) -> Result<JailEntry> {
+        let cap_last_cap = std::fs::read_to_string(CAP_LAST_CAP_PATH)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(DEFAULT_CAP_LAST_CAP);
+
+        Ok(JailEntry {
+            root: CString::new(self.root.as_os_str().as_bytes())?,
+            user,
+            cap_last_cap,
+        })
+    }
+}
+
+// Settings used by the CH process to enter the jail, between fork and
+// exec.
+pub struct JailEntry {
+    root: CString,
+    user: Option<(u32, u32)>,
+    cap_last_cap: u32,
+}
+
+impl JailEntry {
+    // Enter the jail. Called in the CH process before exec, so it only
+    // makes system calls and must not allocate. The user is switched last,
+    // as mounting and dropping capabilities require privileges.
+    pub fn enter(&self) -> io::Result<()> {
+        let check = |ret: libc::c_int| {
+            if ret < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+
+        let slash = b"/\0".as_ptr() as *const libc::c_char;
+
+        // The unused prctl() arguments must be zero.
+        let zero: libc::c_ulong = 0;
+
+        // SAFETY: the arguments are valid nul terminated strings, null
+        // pointers where allowed, or plain values.
+        unsafe {
+            check(libc::unshare(libc::CLONE_NEWNS))?;
+
+            check(libc::mount(
+                std::ptr::null(),
+                slash,
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_SLAVE,
+                std::ptr::null(),
+            ))?;
+
+            check(libc::chroot(self.root.as_ptr()))?;
+            check(libc::chdir(slash))?;
+
+            for cap in 0..=self.cap_last_cap {
+                if cap != CAP_NET_ADMIN {
+                    check(libc::prctl(
+                        libc::PR_CAPBSET_DROP,
+                        cap as libc::c_ulong,
+                        zero,
+                        zero,
+                        zero,
+                    ))?;
+                }
+            }
+
+            // Not supported before Linux 4.3, where there is no ambient
+            // capability to clear.
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong,
+                zero,
+                zero,
+                zero,
+            );
+
+            // Switching to another user clears the capabilities.
+            let kept = match self.user {
+                Some((uid, gid)) => {
+                    check(libc::setgroups(0, std::ptr::null()))?;
+                    check(libc::setgid(gid))?;
+                    check(libc::setuid(uid))?;
+
+                    0
+                }
+                None => 1 << CAP_NET_ADMIN,
+            };
+
+            let header = CapUserHeader {
+                version: LINUX_CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+
+            let data = [
+                CapUserData {
+                    effective: kept,
+                    permitted: kept,
+                    inheritable: 0,
+                },
+                CapUserData::default(),
+            ];
+
+            check(libc::syscall(libc::SYS_capset, &header, data.as_ptr()) as libc::c_int)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Return the mount points at or below root from the content of
+// /proc/self/mountinfo, the most nested first so that they can be
+// unmounted in order.
+fn get_mount_points_below(mountinfo: &str, root: &Path) -> Vec<PathBuf> {
+    let mut mount_points: Vec<PathBuf> = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|p| PathBuf::from(unescape_mount_point(p)))
+        .filter(|p| p.starts_with(root))
+        .collect();
+
+    mount_points.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+    mount_points.dedup();
+
+    mount_points
+}
+
+// Decode the octal escapes of the spaces, tabs, newlines and backslashes
+// of a mount point in /proc/self/mountinfo.
+fn unescape_mount_point(path: &str) -> String {
+    path.replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_mount_points_below() {
+        let mountinfo = "\
+22 1 0:21 / /run rw,nosuid,nodev shared:5 - tmpfs tmpfs rw
+40 22 0:21 /kata/sb/root /run/kata/sb/root rw shared:20 - tmpfs tmpfs rw
+41 40 0:5 /kvm /run/kata/sb/root/dev/kvm rw - devtmpfs udev rw
+42 40 8:1 /usr/share/kata /run/kata/sb/root/usr/share/kata ro - ext4 /dev/sda1 rw
+43 40 8:1 /my\\040dir /run/kata/sb/root/my\\040dir rw - ext4 /dev/sda1 rw
+44 22 0:21 /kata/other /run/kata/sb/rootfs rw - tmpfs tmpfs rw
+";
+
+        let mount_points = get_mount_points_below(mountinfo, Path::new("/run/kata/sb/root"));
+
+        assert_eq!(mount_points.len(), 4);
+        assert_eq!(mount_points[3], PathBuf::from("/run/kata/sb/root"));
+        assert!(mount_points.contains(&PathBuf::from("/run/kata/sb/root/dev/kvm")));
+        assert!(mount_points.contains(&PathBuf::from("/run/kata/sb/root/my dir")));
+    }
+
+    #[test]
+    fn test_jail_get_path() {
+        let jail = Jail::new("/run/kata/sb/root");
+
+        jail.mounts
+            .lock()
+            .unwrap()
+            .push(PathBuf::from("/run/kata/sb"));
+
+        assert_eq!(
+            jail.get_path(Path::new("/run/kata/sb/ch-api.sock"))
+                .unwrap(),
+            PathBuf::from("/run/kata/sb/ch-api.sock")
+        );
+        assert!(jail.get_path(Path::new("/run/kata/other.sock")).is_err());
+        assert!(jail.get_path(Path::new("/run/kata/sbx")).is_err());
+        assert!(jail.bind(Path::new("relative"), true).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Digests of the files a VM boots from, recorded for attestation and
+// checked against the expected digests, if configured, and the data the
+// host binds into the launch measurement of confidential guests.
+
+use anyhow::{anyhow, Context, Result};
+use kata_types::config::hypervisor::SecurityInfo;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+/// SHA-256 digests, in lowercase hexadecimal, of the files the VM booted
+/// from. The files the VM did not boot from are None.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BootMeasurements {
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    pub image: Option<String>,
+    pub firmware: Option<String>,
+}
+
+/// Data bound into the launch measurement of a confidential guest, in
+/// lowercase hexadecimal, by name of its configuration field.
+pub type LaunchData = BTreeMap<String, String>;
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Return the SHA-256 digest of a file, in lowercase hexadecimal.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).context(format!("failed to open {:?}", path))?;
+
+    let mut hasher = Sha256::new();
+
+    std::io::copy(&mut file, &mut hasher).context(format!("failed to read {:?}", path))?;
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+// Return the launch data configured for the guest. Fails, naming the field,
+// if the data is invalid or not supported by the kind of guest.
+pub fn get_launch_data(security_info: &SecurityInfo) -> Result<LaunchData> {
+    let launch_data = security_info
+        .get_launch_data()
+        .context("invalid launch data")?;
+
+    Ok(launch_data
+        .iter()
+        .map(|(name, data)| (name.to_string(), to_hex(data)))
+        .collect())
+}
+
+// Return the digest of a boot file, or None if the VM does not boot from
+// such a file. Fails if the digest does not match the expected one, unless
+// none is expected, and if a digest is expected for a file the VM does not
+// boot from, as the measurements would not be what the user attests.
+pub fn measure_boot_file(
+    kind: &str,
+    path: Option<&Path>,
+    expected: &str,
+) -> Result<Option<String>> {
+    let path = match path {
+        Some(path) => path,
+        None if expected.is_empty() => return Ok(None),
+        None => {
+            return Err(anyhow!(
+                "SHA-256 digest expected for the {}, which the VM does not boot from",
+                kind
+            ))
+        }
+    };
+
+    let digest = sha256_file(path).context(format!("failed to measure the {}", kind))?;
+
+    if !expected.is_empty() && !digest.eq_ignore_ascii_case(expected) {
+        return Err(anyhow!(
+            "{} {:?} has SHA-256 digest {}, expected {}",
+            kind,
+            path,
+            digest,
+            expected
+        ));
+    }
+
+    Ok(Some(digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SHA-256 digest of "abc".
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn test_measure_boot_file() {
+        let path = std::env::temp_dir().join(format!("ch-measure-{}", std::process::id()));
+
+        std::fs::write(&path, "abc").unwrap();
+
+        assert_eq!(sha256_file(&path).unwrap(), ABC_SHA256);
+
+        assert_eq!(
+            measure_boot_file("kernel", Some(&path), "").unwrap(),
+            Some(ABC_SHA256.to_string())
+        );
+        assert_eq!(
+            measure_boot_file("kernel", Some(&path), &ABC_SHA256.to_uppercase()).unwrap(),
+            Some(ABC_SHA256.to_string())
+        );
+
+        let err = measure_boot_file("kernel", Some(&path), &"0".repeat(64))
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains(&format!("has SHA-256 digest {}", ABC_SHA256)));
+
+        // Not booted from.
+        assert_eq!(measure_boot_file("initrd", None, "").unwrap(), None);
+        assert!(measure_boot_file("initrd", None, ABC_SHA256).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(measure_boot_file("kernel", Some(&path), "").is_err());
+    }
+
+    #[test]
+    fn test_get_launch_data() {
+        let mut security_info = SecurityInfo {
+            confidential_guest: true,
+            // "abc" repeated to 48 bytes, in base64.
+            tdx_mrowner: "YWJj".repeat(16),
+            ..Default::default()
+        };
+
+        let launch_data = get_launch_data(&security_info).unwrap();
+
+        assert_eq!(launch_data.len(), 1);
+        assert_eq!(launch_data["tdx_mrowner"], "616263".repeat(16));
+
+        // Not an SEV-SNP guest.
+        security_info.snp_host_data = "00".repeat(32);
+
+        let err = format!("{:#}", get_launch_data(&security_info).unwrap_err());
+
+        assert!(err.contains("snp_host_data"), "{}", err);
+
+        // Not a confidential guest.
+        security_info.confidential_guest = false;
+        security_info.snp_host_data.clear();
+
+        assert!(get_launch_data(&security_info).is_err());
+
+        security_info.tdx_mrowner.clear();
+
+        assert!(get_launch_data(&security_info).unwrap().is_empty());
+    }
+}
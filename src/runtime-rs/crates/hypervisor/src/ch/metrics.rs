@@ -0,0 +1,497 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use ch_config::{VmCounters, VmInfo};
+use nix::unistd::{sysconf, SysconfVar};
+use std::path::Path;
+
+// Clock ticks per second used if it cannot be queried.
+const DEFAULT_CLK_TCK: u64 = 100;
+
+// Prefix of the Prometheus metric names. The names and labels are used by
+// the dashboards and alerts of the operators, so must not change.
+const METRICS_PREFIX: &str = "kata_ch";
+
+/// Resource usage of a process of the VM.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProcessMetrics {
+    pub pid: u32,
+
+    /// Time spent in user mode, in seconds.
+    pub user_cpu_secs: f64,
+
+    /// Time spent in kernel mode, in seconds.
+    pub system_cpu_secs: f64,
+
+    /// Resident set size in bytes.
+    pub rss_bytes: u64,
+
+    /// Number of open file descriptors.
+    pub open_fds: u64,
+
+    /// Number of threads.
+    pub threads: u64,
+}
+
+/// Resource usage of the VMM and of its helper processes, such as
+/// virtiofsd.
+///
+/// A process which is not running, or which exited while being sampled, is
+/// absent.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HypervisorMetrics {
+    pub vmm: Option<ProcessMetrics>,
+    pub helpers: Vec<ProcessMetrics>,
+}
+
+// Sample the resource usage of a process from its /proc directory. Returns
+// None if the process is gone.
+pub fn sample_process(proc_dir: &Path, pid: u32) -> Option<ProcessMetrics> {
+    let stat = std::fs::read_to_string(proc_dir.join("stat")).ok()?;
+    let status = std::fs::read_to_string(proc_dir.join("status")).ok()?;
+    let open_fds = proc_dir.join("fd").read_dir().ok()?.count() as u64;
+
+    let (utime, stime, threads) = parse_stat(&stat)?;
+
+    let clk_tck = match sysconf(SysconfVar::CLK_TCK) {
+        Ok(Some(v)) if v > 0 => v as u64,
+        _ => DEFAULT_CLK_TCK,
+    };
+
+    Some(ProcessMetrics {
+        pid,
+        user_cpu_secs: utime as f64 / clk_tck as f64,
+        system_cpu_secs: stime as f64 / clk_tck as f64,
+        rss_bytes: parse_status_rss(&status).unwrap_or_default(),
+        open_fds,
+        threads,
+    })
+}
+
+// Return the user and system times in clock ticks, and the number of
+// threads, from the content of /proc/<pid>/stat.
+fn parse_stat(stat: &str) -> Option<(u64, u64, u64)> {
+    // The command name may contain spaces and parentheses, so the fields
+    // are counted from the end of the name. The first field after it is
+    // the 3rd one of the file.
+    let fields: Vec<&str> = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .collect();
+
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+
+    Some((field(14)?, field(15)?, field(20)?))
+}
+
+// Return the resident set size in bytes from the content of
+// /proc/<pid>/status. Kernel threads have no VmRSS.
+fn parse_status_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+
+    Some(kb * 1024)
+}
+
+// Return the counters minus their baselines, that is the counts since they
+// were last reset. A counter below its baseline was restarted by CH, such as
+// for a device removed and added again with the same id, so is returned as
+// is.
+pub fn subtract_counter_baselines(counters: &VmCounters, baselines: &VmCounters) -> VmCounters {
+    counters
+        .iter()
+        .map(|(device, device_counters)| {
+            let baseline = baselines.get(device);
+
+            let device_counters = device_counters
+                .iter()
+                .map(|(counter, value)| {
+                    let base = baseline
+                        .and_then(|b| b.get(counter))
+                        .copied()
+                        .filter(|base| base <= value)
+                        .unwrap_or_default();
+
+                    (counter.clone(), value - base)
+                })
+                .collect();
+
+            (device.clone(), device_counters)
+        })
+        .collect()
+}
+
+// Render the metrics of a VM in the Prometheus text exposition format, with
+// a sandbox_id label on every series. The helper processes are added up,
+// as their pids would make new series every time one is restarted.
+pub fn render_prometheus(
+    sandbox_id: &str,
+    metrics: &HypervisorMetrics,
+    info: Option<&VmInfo>,
+    counters: &VmCounters,
+) -> String {
+    let mut out = String::new();
+
+    let up = if metrics.vmm.is_some() { "1" } else { "0" };
+
+    write_family(
+        &mut out,
+        "vmm_up",
+        "gauge",
+        "Whether the VMM process is running.",
+        &[(labels(&[("sandbox_id", sandbox_id)]), up.to_string())],
+    );
+
+    if let Some(info) = info {
+        write_vm_info(&mut out, sandbox_id, info);
+    }
+
+    let helpers = (!metrics.helpers.is_empty()).then(|| sum_processes(&metrics.helpers));
+
+    let processes: Vec<(&str, &ProcessMetrics)> = metrics
+        .vmm
+        .iter()
+        .map(|p| ("vmm", p))
+        .chain(helpers.iter().map(|p| ("helper", p)))
+        .collect();
+
+    let mut cpu = Vec::new();
+    let mut rss = Vec::new();
+    let mut fds = Vec::new();
+    let mut threads = Vec::new();
+
+    for (process, p) in &processes {
+        let base = labels(&[("sandbox_id", sandbox_id), ("process", process)]);
+
+        cpu.push((
+            format!("{},mode=\"user\"", base),
+            p.user_cpu_secs.to_string(),
+        ));
+        cpu.push((
+            format!("{},mode=\"system\"", base),
+            p.system_cpu_secs.to_string(),
+        ));
+        rss.push((base.clone(), p.rss_bytes.to_string()));
+        fds.push((base.clone(), p.open_fds.to_string()));
+        threads.push((base, p.threads.to_string()));
+    }
+
+    write_family(
+        &mut out,
+        "process_cpu_seconds_total",
+        "counter",
+        "CPU time consumed by the processes of the VM.",
+        &cpu,
+    );
+    write_family(
+        &mut out,
+        "process_resident_memory_bytes",
+        "gauge",
+        "Resident memory of the processes of the VM.",
+        &rss,
+    );
+    write_family(
+        &mut out,
+        "process_open_fds",
+        "gauge",
+        "Open file descriptors of the processes of the VM.",
+        &fds,
+    );
+    write_family(
+        &mut out,
+        "process_threads",
+        "gauge",
+        "Threads of the processes of the VM.",
+        &threads,
+    );
+
+    let devices: Vec<(String, String)> = counters
+        .iter()
+        .flat_map(|(device, device_counters)| {
+            device_counters.iter().map(move |(counter, value)| {
+                (
+                    labels(&[
+                        ("sandbox_id", sandbox_id),
+                        ("device", device),
+                        ("counter", counter),
+                    ]),
+                    value.to_string(),
+                )
+            })
+        })
+        .collect();
+
+    write_family(
+        &mut out,
+        "device_counter_total",
+        "counter",
+        "Counters reported by CH for the devices of the VM.",
+        &devices,
+    );
+
+    out
+}
+
+// Append the state and the size of the VM, as reported by vm.info.
+fn write_vm_info(out: &mut String, sandbox_id: &str, info: &VmInfo) {
+    let base = labels(&[("sandbox_id", sandbox_id)]);
+
+    write_family(
+        out,
+        "vm_state",
+        "gauge",
+        "State of the VM reported by CH.",
+        &[(
+            labels(&[("sandbox_id", sandbox_id), ("state", &info.state)]),
+            "1".to_string(),
+        )],
+    );
+
+    if let Some(config) = &info.config {
+        write_family(
+            out,
+            "vm_vcpus",
+            "gauge",
+            "vCPUs of the VM.",
+            &[(base.clone(), config.cpus.boot_vcpus.to_string())],
+        );
+        write_family(
+            out,
+            "vm_max_vcpus",
+            "gauge",
+            "vCPUs the VM can be resized to.",
+            &[(base.clone(), config.cpus.max_vcpus.to_string())],
+        );
+    }
+
+    if let Some(size) = info.memory_actual_size {
+        write_family(
+            out,
+            "vm_memory_bytes",
+            "gauge",
+            "Memory of the VM, hot plugged memory included and balloon excluded.",
+            &[(base, size.to_string())],
+        );
+    }
+}
+
+// Add up the resource usage of processes.
+fn sum_processes(processes: &[ProcessMetrics]) -> ProcessMetrics {
+    processes
+        .iter()
+        .fold(ProcessMetrics::default(), |sum, p| ProcessMetrics {
+            pid: 0,
+            user_cpu_secs: sum.user_cpu_secs + p.user_cpu_secs,
+            system_cpu_secs: sum.system_cpu_secs + p.system_cpu_secs,
+            rss_bytes: sum.rss_bytes + p.rss_bytes,
+            open_fds: sum.open_fds + p.open_fds,
+            threads: sum.threads + p.threads,
+        })
+}
+
+// Append a metric family, unless it has no samples.
+fn write_family(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: &[(String, String)],
+) {
+    if samples.is_empty() {
+        return;
+    }
+
+    let name = format!("{}_{}", METRICS_PREFIX, name);
+
+    out.push_str(&format!("# HELP {} {}\n", name, help));
+    out.push_str(&format!("# TYPE {} {}\n", name, kind));
+
+    for (labels, value) in samples {
+        out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+    }
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ch_config::VmConfig;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "1234 (cloud (hyper) v) S 1 1234 1234 0 -1 4194560 2000 0 0 0 \
+                    350 120 0 0 20 0 9 0 5000 1073741824 25000 18446744073709551615";
+
+        assert_eq!(parse_stat(stat), Some((350, 120, 9)));
+        assert_eq!(parse_stat("1234 (ch) S 1"), None);
+        assert_eq!(parse_stat(""), None);
+    }
+
+    #[test]
+    fn test_parse_status_rss() {
+        let status = "Name:\tcloud-hypervisor\nVmPeak:\t  300000 kB\nVmRSS:\t   51200 kB\n";
+
+        assert_eq!(parse_status_rss(status), Some(51200 * 1024));
+        assert_eq!(parse_status_rss("Name:\tkthreadd\n"), None);
+    }
+
+    #[test]
+    fn test_sample_process() {
+        let pid = std::process::id();
+
+        let metrics = sample_process(Path::new(&format!("/proc/{}", pid)), pid).unwrap();
+
+        assert_eq!(metrics.pid, pid);
+        assert!(metrics.rss_bytes > 0);
+        assert!(metrics.open_fds > 0);
+        assert!(metrics.threads > 0);
+
+        assert!(sample_process(Path::new("/proc/not-a-pid"), 0).is_none());
+    }
+
+    #[test]
+    fn test_subtract_counter_baselines() {
+        let counters_of = |values: &[(&str, &str, u64)]| {
+            let mut counters = VmCounters::new();
+
+            for (device, counter, value) in values {
+                counters
+                    .entry(device.to_string())
+                    .or_default()
+                    .insert(counter.to_string(), *value);
+            }
+
+            counters
+        };
+
+        let counters = counters_of(&[
+            ("_net0", "rx_bytes", 1024),
+            ("_net0", "tx_bytes", 10),
+            ("_disk0", "read_bytes", 4096),
+        ]);
+
+        // The counters of _net0 were reset, and tx_bytes restarted since.
+        let baselines = counters_of(&[("_net0", "rx_bytes", 1000), ("_net0", "tx_bytes", 20)]);
+
+        assert_eq!(
+            subtract_counter_baselines(&counters, &baselines),
+            counters_of(&[
+                ("_net0", "rx_bytes", 24),
+                ("_net0", "tx_bytes", 10),
+                ("_disk0", "read_bytes", 4096),
+            ])
+        );
+
+        assert_eq!(
+            subtract_counter_baselines(&counters, &VmCounters::new()),
+            counters
+        );
+    }
+
+    #[test]
+    fn test_render_prometheus() {
+        let metrics = HypervisorMetrics {
+            vmm: Some(ProcessMetrics {
+                pid: 100,
+                user_cpu_secs: 1.5,
+                system_cpu_secs: 0.25,
+                rss_bytes: 4096,
+                open_fds: 12,
+                threads: 5,
+            }),
+            helpers: vec![
+                ProcessMetrics {
+                    pid: 101,
+                    rss_bytes: 1024,
+                    threads: 2,
+                    ..Default::default()
+                },
+                ProcessMetrics {
+                    pid: 102,
+                    rss_bytes: 2048,
+                    threads: 3,
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let mut counters = VmCounters::new();
+        counters
+            .entry("_net0".to_string())
+            .or_default()
+            .insert("rx_bytes".to_string(), 1024);
+
+        let mut config = VmConfig::default();
+        config.cpus.boot_vcpus = 2;
+        config.cpus.max_vcpus = 4;
+
+        let info = VmInfo {
+            state: "Running".to_string(),
+            memory_actual_size: Some(1 << 30),
+            config: Some(config),
+        };
+
+        let out = render_prometheus("sb\"1", &metrics, Some(&info), &counters);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "# HELP kata_ch_vmm_up Whether the VMM process is running."
+        );
+        assert_eq!(lines[1], "# TYPE kata_ch_vmm_up gauge");
+        assert_eq!(lines[2], "kata_ch_vmm_up{sandbox_id=\"sb\\\"1\"} 1");
+        assert!(lines.contains(&"kata_ch_vm_state{sandbox_id=\"sb\\\"1\",state=\"Running\"} 1"));
+        assert!(lines.contains(&"kata_ch_vm_vcpus{sandbox_id=\"sb\\\"1\"} 2"));
+        assert!(lines.contains(&"kata_ch_vm_max_vcpus{sandbox_id=\"sb\\\"1\"} 4"));
+        assert!(lines.contains(&"kata_ch_vm_memory_bytes{sandbox_id=\"sb\\\"1\"} 1073741824"));
+        assert!(lines.contains(
+            &"kata_ch_process_cpu_seconds_total{sandbox_id=\"sb\\\"1\",process=\"vmm\",mode=\"user\"} 1.5"
+        ));
+        assert!(lines.contains(
+            &"kata_ch_process_resident_memory_bytes{sandbox_id=\"sb\\\"1\",process=\"vmm\"} 4096"
+        ));
+
+        // The helpers are added up.
+        assert!(lines.contains(
+            &"kata_ch_process_resident_memory_bytes{sandbox_id=\"sb\\\"1\",process=\"helper\"} 3072"
+        ));
+        assert!(
+            lines.contains(&"kata_ch_process_threads{sandbox_id=\"sb\\\"1\",process=\"helper\"} 5")
+        );
+        assert!(!out.contains("pid="));
+        assert!(lines.contains(
+            &"kata_ch_device_counter_total{sandbox_id=\"sb\\\"1\",device=\"_net0\",counter=\"rx_bytes\"} 1024"
+        ));
+
+        // The VMM exited.
+        let out = render_prometheus(
+            "sb",
+            &HypervisorMetrics::default(),
+            None,
+            &VmCounters::new(),
+        );
+
+        assert_eq!(
+            out,
+            "# HELP kata_ch_vmm_up Whether the VMM process is running.\n\
+             # TYPE kata_ch_vmm_up gauge\n\
+             kata_ch_vmm_up{sandbox_id=\"sb\"} 0\n"
+        );
+    }
+}
@@ -4,14 +4,23 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::HypervisorState;
-use crate::{device::Device, Hypervisor, VcpuThreadIds};
+use crate::{
+    device::{AddedDevice, Device},
+    Hypervisor, ResourceCapacity, VcpuThreadIds,
+};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
+use ch_config::VmCounters;
 use kata_types::capabilities::Capabilities;
 use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
 use persist::sandbox_persist::Persist;
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
+use tokio::io::{BufReader, Lines};
+use tokio::net::UnixStream;
 use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
 
 // Convenience macro to obtain the scope logger
 #[macro_export]
@@ -21,12 +30,36 @@ macro_rules! sl {
       };
   }
 
+mod args;
+mod artifacts;
+mod boot_progress;
+mod confidential;
+mod console_buffer;
+mod errors;
+mod events;
 mod inner;
 mod inner_device;
 mod inner_hypervisor;
+mod jail;
+mod measurements;
+mod metrics;
+mod pci_segments;
+mod recovery;
+mod snapshot;
 mod utils;
 
+pub use errors::ChError;
+pub use events::DeviceEvent;
 use inner::CloudHypervisorInner;
+pub use inner::VmExitReason;
+use inner_device::DEVICE_READY_POLL_MS;
+pub use inner_device::{DeviceCriticality, DeviceGroupResult, DevicePlugMode};
+use inner_hypervisor::{wait_vm_exit_reason, wait_vmm_exit, CH_VCPU_SHRINK_ATTEMPTS};
+pub use inner_hypervisor::{HugePagesRequest, ResizedMemory, VcpuShrinkPlan};
+pub use measurements::{BootMeasurements, LaunchData};
+pub use metrics::{HypervisorMetrics, ProcessMetrics};
+pub use pci_segments::DeviceCounts;
+pub use snapshot::SnapshotMode;
 
 #[derive(Debug, Default, Clone)]
 pub struct CloudHypervisor {
@@ -43,10 +76,462 @@ impl CloudHypervisor {
         }
     }
 
+    /// Create an instance controlling an existing CH process through its
+    /// already open API socket. The ownership of the fd is transferred.
+    pub async fn from_api_socket_fd(fd: RawFd) -> Result<Self> {
+        let ch = Self::new();
+
+        ch.inner.write().await.adopt_api_socket_fd(fd).await?;
+
+        Ok(ch)
+    }
+
+    /// Create an instance controlling an existing CH process through its
+    /// API socket path.
+    pub async fn from_api_socket_path(path: &str) -> Result<Self> {
+        let ch = Self::new();
+
+        ch.inner.write().await.adopt_api_socket_path(path).await?;
+
+        Ok(ch)
+    }
+
+    /// Take the control back of the VM of a sandbox after a shim restart,
+    /// from the state saved when the VM started. Fails with
+    /// ChError::VmmLost if CH is gone or does not answer, in which case the
+    /// sandbox is dead.
+    pub async fn recover(id: &str) -> Result<Self> {
+        let inner = CloudHypervisorInner::recover(id).await?;
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(inner)),
+        })
+    }
+
+    /// Use the already open API socket of an existing CH process. The
+    /// ownership of the fd is transferred.
+    pub async fn adopt_api_socket_fd(&self, fd: RawFd) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.adopt_api_socket_fd(fd).await
+    }
+
+    /// Detach the API socket, handing its fd over to the caller.
+    pub async fn export_api_socket_fd(&self) -> Result<RawFd> {
+        let mut inner = self.inner.write().await;
+        inner.export_api_socket_fd()
+    }
+
     pub async fn set_hypervisor_config(&mut self, config: HypervisorConfig) {
         let mut inner = self.inner.write().await;
         inner.set_hypervisor_config(config)
     }
+
+    /// Set the vsock port the guest agent listens on, as configured for the
+    /// agent, to check it is reachable while the VM boots.
+    pub async fn set_agent_vsock_port(&self, port: u32) {
+        let mut inner = self.inner.write().await;
+        inner.set_agent_vsock_port(port)
+    }
+
+    /// Validate and record the requested guest clock offset in seconds.
+    pub async fn set_guest_clock_offset(&self, offset_secs: i64) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.set_guest_clock_offset(offset_secs)
+    }
+
+    /// Return the requested guest clock offset in seconds.
+    pub async fn get_guest_clock_offset(&self) -> i64 {
+        let inner = self.inner.read().await;
+        inner.get_guest_clock_offset()
+    }
+
+    /// Set the host NUMA node to place the VM on when it is started, such as
+    /// the node selected by the Topology Manager, overriding the configured
+    /// one. None uses the configured node, if any.
+    pub async fn set_host_numa_node(&self, node: Option<u32>) {
+        let mut inner = self.inner.write().await;
+        inner.set_host_numa_node(node)
+    }
+
+    /// Set the huge pages requested by the pod, used when the VM is
+    /// started: the guest memory is backed by huge pages of the largest
+    /// requested size, and the requested huge pages are reserved in the
+    /// guest.
+    pub async fn set_hugepage_requests(&self, requests: Vec<HugePagesRequest>) {
+        let mut inner = self.inner.write().await;
+        inner.set_hugepage_requests(requests)
+    }
+
+    /// Set the tap fds, by net device id, to restore the net devices of a
+    /// snapshot with, such as when it was taken on another host. Every net
+    /// device of the snapshot must then have fds. The fds must stay open
+    /// until the VM is started.
+    pub async fn set_restore_net_fds(&self, net_fds: HashMap<String, Vec<RawFd>>) {
+        let mut inner = self.inner.write().await;
+        inner.set_restore_net_fds(net_fds)
+    }
+
+    /// Live migrate the running VM to the CH instance listening on dest_url
+    /// ("unix:<path>" or "tcp:<host>:<port>").
+    pub async fn migrate_send(&self, dest_url: &str) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.migrate_send(dest_url).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Receive a live migrated VM on listen_url ("unix:<path>" or
+    /// "tcp:<host>:<port>").
+    pub async fn migrate_receive(&self, listen_url: &str) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.migrate_receive(listen_url).await
+    }
+
+    /// Check the host hypervisor device used to run the VM is available
+    /// and accessible.
+    pub async fn check_host(&self) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.check_host()
+    }
+
+    /// Record the free memory in MiB of the guest, from its most recent
+    /// stats, which bounds the memory reclaimed on hints.
+    pub async fn set_guest_free_memory(&self, free_mb: u64) {
+        let mut inner = self.inner.write().await;
+        inner.set_guest_free_memory(free_mb)
+    }
+
+    /// Hint that the guest no longer needs the specified amount of memory in
+    /// bytes, such as when a container exits. If enabled in the
+    /// configuration, the balloon is inflated by it, within a share of the
+    /// guest free memory, and deflated again when a later memory resize
+    /// needs the room. Returns the size in MiB the balloon was inflated by.
+    pub async fn shrink_memory_hint(&self, bytes: u64) -> Result<u64> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.shrink_memory_hint(bytes).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Set the size in MiB of the balloon device.
+    pub async fn set_balloon(&self, size_mb: u32) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.set_balloon(size_mb).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Sample the resource usage of the CH process and of its helper
+    /// processes. Cheap enough to be called on every metrics scrape.
+    pub async fn hypervisor_metrics(&self) -> HypervisorMetrics {
+        let inner = self.inner.read().await;
+        inner.hypervisor_metrics()
+    }
+
+    /// Return the counters CH reports for the devices of the running VM,
+    /// by device id and counter name, counted since they were last reset.
+    pub async fn get_counters(&self) -> Result<VmCounters> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.get_counters().await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Reset the counters of a device of the running VM, such as on every
+    /// billing period. CH cannot reset them, so the driver records their
+    /// current values and subtracts them from the values read afterwards.
+    /// The Prometheus metrics are not affected.
+    pub async fn reset_counters(&self, device_id: &str) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.reset_counters(device_id).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Render the resource usage, the state and size and the device counters
+    /// of the VM in the Prometheus text exposition format, labelled with the
+    /// sandbox id. Never fails, so that a scrape succeeds even if the VM
+    /// just exited.
+    pub async fn prometheus_metrics(&self) -> String {
+        let inner = self.inner.read().await;
+        inner.prometheus_metrics().await
+    }
+
+    /// Snapshot the VM to the specified directory, which must be empty or
+    /// not exist. Returns the path of the snapshot directory. Only full
+    /// snapshots are supported.
+    pub async fn save_vm_to(&self, path: &str, mode: SnapshotMode) -> Result<String> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.save_vm(Some(path), mode).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Resize the number of vCPUs, returning the number of vCPUs before and
+    /// after the resize. The request is clamped between the vCPUs the VM
+    /// booted with and its maximum vCPUs, so the returned number must be used
+    /// to online the vCPUs in the guest. Removing vCPUs requires a shrink
+    /// planned with plan_vcpu_shrink(), and is retried while the guest does
+    /// not release them; a shrink which did not complete prevents other
+    /// resizes until it does. Fails with ChError::Unsupported if the VM is
+    /// sized statically.
+    pub async fn resize_vcpu(&self, old_vcpus: u32, new_vcpus: u32) -> Result<(u32, u32)> {
+        let mut attempt = 1;
+
+        loop {
+            let mut inner = self.inner.write().await;
+            inner.check_vmm_exited()?;
+            let result = inner.resize_vcpu(old_vcpus, new_vcpus).await;
+
+            // The lock is released while waiting for the guest to release
+            // the vCPUs of a shrink.
+            let delay_ms = match (&result, inner.get_vcpu_shrink_retry_delay_ms()) {
+                (Err(e), Some(delay_ms)) if attempt < CH_VCPU_SHRINK_ATTEMPTS => {
+                    warn!(
+                        sl!(),
+                        "vCPU resize to {} attempt {} failed, retrying in {} ms: {:#}",
+                        new_vcpus,
+                        attempt,
+                        delay_ms,
+                        e
+                    );
+
+                    delay_ms
+                }
+                (Err(_), Some(_)) => {
+                    let result = result.context(format!(
+                        "vCPU shrink still in progress after {} attempts",
+                        attempt
+                    ));
+
+                    return inner.check_vmm_result(result).await;
+                }
+                _ => return inner.check_vmm_result(result).await,
+            };
+
+            drop(inner);
+
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+
+            attempt += 1;
+        }
+    }
+
+    /// Plan the removal of vCPUs down to new_vcpus, clamped like
+    /// resize_vcpu(). The agent must offline the vCPUs of the plan, then
+    /// resize_vcpu() to the planned number removes them. vCPU 0 and the
+    /// vCPUs the VM booted with are never removed.
+    pub async fn plan_vcpu_shrink(&self, new_vcpus: u32) -> Result<VcpuShrinkPlan> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.plan_vcpu_shrink(new_vcpus).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Resize the guest memory to the specified size in MiB, which cannot
+    /// exceed the maximum memory of the VM. Hot added memory is rounded up
+    /// to the CH hotplug granularity, and can only be removed with
+    /// virtio-mem when enabled in the configuration: it is removed in steps,
+    /// and never below the boot memory and the memory used by the guest
+    /// plus the configured reserve. Otherwise, the memory is shrunk by
+    /// inflating the balloon, if allowed by the configuration, within a
+    /// share of the guest free memory in MiB, which must then be provided.
+    /// Returns the size of the memory actually plugged, whether the agent
+    /// must online it, and whether the balloon reclaimed memory. Fails with
+    /// ChError::Unsupported if the VM is sized statically.
+    pub async fn resize_memory(
+        &self,
+        new_mem_mb: u64,
+        guest_free_mb: Option<u64>,
+    ) -> Result<ResizedMemory> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.resize_memory(new_mem_mb, guest_free_mb).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Wait for the VMM to terminate, returning why it did. The VM may be
+    /// stopped while waiting.
+    pub async fn wait_vm_exit(&self) -> Result<VmExitReason> {
+        let exit_rx = self.inner.read().await.get_exit_receiver()?;
+
+        wait_vm_exit_reason(exit_rx).await
+    }
+
+    /// Return the features the CH binary was built with, such as "io_uring"
+    /// or "mshv", or None if it does not report them. The features are only
+    /// queried once per binary.
+    pub async fn get_features(&self) -> Result<Option<Vec<String>>> {
+        let inner = self.inner.read().await;
+        inner.get_ch_features()
+    }
+
+    /// Return the devices added to the VM, with their labels, sorted by id.
+    pub async fn list_devices(&self) -> Vec<AddedDevice> {
+        let inner = self.inner.read().await;
+        inner.list_devices()
+    }
+
+    /// Return the number of devices of the VM on each PCI segment, and the
+    /// limits CH places on them, to tell how many more devices
+    /// add_device() can add. Adding a device beyond the limits fails with
+    /// ChError::DeviceLimitReached.
+    pub async fn get_device_counts(&self) -> DeviceCounts {
+        let inner = self.inner.read().await;
+        inner.get_device_counts()
+    }
+
+    /// Return the SHA-256 digests of the files the VM booted from, or None if
+    /// the boot files are not measured.
+    pub async fn get_boot_measurements(&self) -> Option<BootMeasurements> {
+        let inner = self.inner.read().await;
+        inner.get_boot_measurements()
+    }
+
+    /// Return the data bound into the launch measurement of a confidential
+    /// guest, in hexadecimal, by name of its configuration field.
+    pub async fn get_launch_data(&self) -> LaunchData {
+        let inner = self.inner.read().await;
+        inner.get_launch_data()
+    }
+
+    /// Return the path of the file capturing the guest console output, or
+    /// None if console logging is not enabled.
+    pub async fn get_console_log_path(&self) -> Option<String> {
+        let inner = self.inner.read().await;
+        inner.get_console_log_path()
+    }
+
+    /// Return the path of the socket streaming the guest console, or None
+    /// if the console socket is not enabled.
+    pub async fn get_console_socket_path(&self) -> Option<String> {
+        let inner = self.inner.read().await;
+        inner.get_console_socket_path()
+    }
+
+    /// Return the end of the guest console, at most size bytes, retained
+    /// in memory if console_buffer_size_kb is set. The output is kept once
+    /// the VM stopped, which tells how far a guest which crashed, or never
+    /// reached the agent, went.
+    pub async fn get_console_tail(&self, size: usize) -> Result<String> {
+        let inner = self.inner.read().await;
+        inner.get_console_tail(size)
+    }
+
+    /// Connect to the socket streaming the guest console, returning a
+    /// reader of the console lines. The VM must have been started, and the
+    /// console must not be buffered.
+    pub async fn console_reader(&self) -> Result<Lines<BufReader<UnixStream>>> {
+        let inner = self.inner.read().await;
+        inner.console_reader().await
+    }
+
+    /// Add a device, only failing if the device is required. Optional
+    /// devices which cannot be added are dropped.
+    pub async fn add_device_with_criticality(
+        &self,
+        device: Device,
+        criticality: DeviceCriticality,
+    ) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.add_device_with_criticality(device, criticality).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Add a device, choosing how it is plugged if the VM is not running
+    /// yet. Returns what the guest needs to use the device, such as the
+    /// mount tag and mount point of a share fs device, or None if the device
+    /// is queued until the VM runs or was dropped.
+    pub async fn add_device_with_options(
+        &self,
+        device: Device,
+        criticality: DeviceCriticality,
+        plug_mode: DevicePlugMode,
+    ) -> Result<Option<AddedDevice>> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner
+            .add_device_with_options(device, criticality, plug_mode)
+            .await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Remove a device, tearing down its host side state if the guest does
+    /// not release it within the timeout. Returns true if the removal was
+    /// forced, in which case the guest may be in a degraded state.
+    pub async fn remove_device_force(&self, device: Device, timeout: Duration) -> Result<bool> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.remove_device_force(device, timeout).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Add a group of devices, such as the network interfaces of a pod, as a
+    /// unit: if a device fails to be added, the devices of the group already
+    /// added are removed and the remaining ones are not attempted. Returns
+    /// the outcome of each device, in the order of the group.
+    pub async fn add_devices(&self, devices: Vec<Device>) -> Result<Vec<DeviceGroupResult>> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        Ok(inner.add_devices(devices).await)
+    }
+
+    /// Point a block device at a new backing file, which must have the size
+    /// of the current one, such as a rotated snapshot of a volume. CH cannot
+    /// swap the file of a disk, so a disk of the VM is removed and added
+    /// again with the same id, or added back with its original file on
+    /// failure: the guest must not use the disk meanwhile. A device queued
+    /// before the VM was started, or while it was paused, is just updated.
+    pub async fn replace_block_backing(&self, kata_id: &str, new_path: &str) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.replace_block_backing(kata_id, new_path).await;
+        inner.check_vmm_result(result).await
+    }
+
+    /// Wait until the guest can use a device added with add_device(), and
+    /// return it along with its guest PCI address, if known. A virtio device
+    /// is ready once the guest driver activated it, which is only known with
+    /// the event monitor enabled; otherwise, and for passed through devices,
+    /// the device is ready once CH holds it. Fails with
+    /// ChError::DeviceNotReady if the device is not ready within the timeout.
+    pub async fn wait_device_ready(&self, kata_id: &str, timeout: Duration) -> Result<AddedDevice> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            // The lock is only held while checking, not while waiting.
+            {
+                let mut inner = self.inner.write().await;
+                inner.check_vmm_exited()?;
+                let result = inner.get_ready_device(kata_id).await;
+
+                if let Some(added) = inner.check_vmm_result(result).await? {
+                    return Ok(added);
+                }
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Err(ChError::DeviceNotReady {
+                    device: kata_id.to_string(),
+                    timeout,
+                }
+                .into());
+            }
+
+            tokio::time::sleep(Duration::from_millis(DEVICE_READY_POLL_MS).min(deadline - now))
+                .await;
+        }
+    }
+
+    /// Withdraw a device queued before the VM was started, or while it was
+    /// paused. Returns true if the device was found.
+    pub async fn remove_pending_device(&self, kata_id: &str) -> bool {
+        let mut inner = self.inner.write().await;
+        inner.remove_pending_device(kata_id)
+    }
 }
 
 #[async_trait]
@@ -58,37 +543,51 @@ impl Hypervisor for CloudHypervisor {
 
     async fn start_vm(&self, timeout: i32) -> Result<()> {
         let mut inner = self.inner.write().await;
-        inner.start_vm(timeout).await
+        inner.check_vmm_exited()?;
+        let result = inner.start_vm(timeout).await;
+        inner.check_vmm_result(result).await
     }
 
     async fn stop_vm(&self) -> Result<()> {
         let mut inner = self.inner.write().await;
-        inner.stop_vm()
+        inner.stop_vm().await
     }
 
     async fn pause_vm(&self) -> Result<()> {
-        let inner = self.inner.write().await;
-        inner.pause_vm()
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.pause_vm().await;
+        inner.check_vmm_result(result).await
     }
 
     async fn resume_vm(&self) -> Result<()> {
-        let inner = self.inner.write().await;
-        inner.resume_vm()
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.resume_vm().await;
+        inner.check_vmm_result(result).await
     }
 
     async fn save_vm(&self) -> Result<()> {
-        let inner = self.inner.write().await;
-        inner.save_vm().await
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.save_vm(None, SnapshotMode::Full).await;
+        inner.check_vmm_result(result).await?;
+
+        Ok(())
     }
 
     async fn add_device(&self, device: Device) -> Result<()> {
         let mut inner = self.inner.write().await;
-        inner.add_device(device).await
+        inner.check_vmm_exited()?;
+        let result = inner.add_device(device).await;
+        inner.check_vmm_result(result).await
     }
 
     async fn remove_device(&self, device: Device) -> Result<()> {
         let mut inner = self.inner.write().await;
-        inner.remove_device(device).await
+        inner.check_vmm_exited()?;
+        let result = inner.remove_device(device).await;
+        inner.check_vmm_result(result).await
     }
 
     async fn get_agent_socket(&self) -> Result<String> {
@@ -121,11 +620,28 @@ impl Hypervisor for CloudHypervisor {
         inner.get_pids().await
     }
 
-    async fn check(&self) -> Result<()> {
+    async fn get_vmm_master_tid(&self) -> Result<u32> {
         let inner = self.inner.read().await;
+        inner.get_vmm_master_tid().await
+    }
+
+    async fn get_ns_path(&self) -> Result<String> {
+        let inner = self.inner.read().await;
+        inner.get_ns_path().await
+    }
+
+    async fn check(&self) -> Result<()> {
+        let mut inner = self.inner.write().await;
         inner.check().await
     }
 
+    async fn wait_vm(&self) -> Result<Option<String>> {
+        // Do not hold the lock while waiting, so that the VM can be stopped.
+        let exit_rx = self.inner.read().await.get_exit_receiver()?;
+
+        wait_vmm_exit(exit_rx).await
+    }
+
     async fn get_jailer_root(&self) -> Result<String> {
         let inner = self.inner.read().await;
         inner.get_jailer_root().await
@@ -139,6 +655,18 @@ impl Hypervisor for CloudHypervisor {
         let inner = self.inner.read().await;
         inner.capabilities().await
     }
+
+    async fn get_resource_capacity(&self) -> Result<ResourceCapacity> {
+        let mut inner = self.inner.write().await;
+        inner.check_vmm_exited()?;
+        let result = inner.get_resource_capacity().await;
+        inner.check_vmm_result(result).await
+    }
+
+    async fn register_helper(&self, pid: u32) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        inner.register_helper(pid)
+    }
 }
 
 #[async_trait]
@@ -155,7 +683,16 @@ impl Persist for CloudHypervisor {
         hypervisor_args: Self::ConstructorArgs,
         hypervisor_state: Self::State,
     ) -> Result<Self> {
-        let inner = CloudHypervisorInner::restore(hypervisor_args, hypervisor_state).await?;
+        // Take the control back of a VM still running, otherwise only the
+        // state needed to clean up the sandbox is restored.
+        let inner = match CloudHypervisorInner::recover(&hypervisor_state.id).await {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!(sl!(), "VM not recovered: {:#}", e);
+
+                CloudHypervisorInner::restore(hypervisor_args, hypervisor_state).await?
+            }
+        };
         Ok(Self {
             inner: Arc::new(RwLock::new(inner)),
         })
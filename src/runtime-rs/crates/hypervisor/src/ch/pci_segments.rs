@@ -0,0 +1,219 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Allocator of the PCI segments of the hot plugged devices.
+//
+// Devices are spread over the PCI segments of the VM, each device being
+// placed on the segment holding the fewest devices. CH refuses to add a
+// device to a full segment, so the limit is checked beforehand to report
+// it clearly.
+
+use super::errors::ChError;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// Devices a PCI segment holds: CH gives each segment a bus of 32 slots, the
+// first one being used by the host bridge.
+pub const MAX_DEVICES_PER_PCI_SEGMENT: usize = 31;
+
+/// Number of devices of the VM and the limits CH places on it, so that
+/// callers can tell how many devices can still be added.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceCounts {
+    /// Devices on each PCI segment, including the ones CH adds itself.
+    pub pci_segments: Vec<usize>,
+
+    /// Devices a PCI segment can hold.
+    pub per_segment_limit: usize,
+
+    /// Devices on all the PCI segments.
+    pub total: usize,
+
+    /// Devices all the PCI segments can hold.
+    pub limit: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PciSegmentAllocator {
+    // Number of devices on each segment.
+    devices: Vec<usize>,
+}
+
+impl PciSegmentAllocator {
+    pub fn new(num_segments: u16) -> Self {
+        PciSegmentAllocator {
+            devices: vec![0; num_segments.max(1) as usize],
+        }
+    }
+
+    // Record a device placed on the specified segment, such as a device
+    // in the VM config used to boot the VM.
+    pub fn reserve(&mut self, segment: u16) {
+        if let Some(count) = self.devices.get_mut(segment as usize) {
+            *count += 1;
+        }
+    }
+
+    // Record a device to be hot plugged on the specified segment, failing if
+    // the segment is full.
+    pub fn try_reserve(&mut self, segment: u16, device: &str) -> Result<()> {
+        if let Some(count) = self.devices.get_mut(segment as usize) {
+            if *count >= MAX_DEVICES_PER_PCI_SEGMENT {
+                return Err(ChError::DeviceLimitReached {
+                    device: device.to_string(),
+                    scope: format!("on PCI segment {}", segment),
+                    limit: MAX_DEVICES_PER_PCI_SEGMENT,
+                }
+                .into());
+            }
+
+            *count += 1;
+        }
+
+        Ok(())
+    }
+
+    // Place a device to be hot plugged, failing if all the segments are
+    // full.
+    pub fn allocate(&mut self, device: &str) -> Result<u16> {
+        if self.devices.is_empty() {
+            self.devices.push(0);
+        }
+
+        // min_by_key() returns the first minimum, the lowest segment.
+        let (segment, count) = self
+            .devices
+            .iter_mut()
+            .enumerate()
+            .min_by_key(|(_, count)| **count)
+            .unwrap_or_else(|| unreachable!());
+
+        if *count >= MAX_DEVICES_PER_PCI_SEGMENT {
+            let num_segments = self.devices.len();
+
+            return Err(ChError::DeviceLimitReached {
+                device: device.to_string(),
+                scope: format!("on the {} PCI segment(s)", num_segments),
+                limit: num_segments * MAX_DEVICES_PER_PCI_SEGMENT,
+            }
+            .into());
+        }
+
+        *count += 1;
+
+        Ok(segment as u16)
+    }
+
+    // Check no segment holds more devices than it can, such as after the
+    // devices of the VM config were reserved.
+    pub fn check_limits(&self, device: &str) -> Result<()> {
+        match self
+            .devices
+            .iter()
+            .position(|count| *count > MAX_DEVICES_PER_PCI_SEGMENT)
+        {
+            Some(segment) => Err(ChError::DeviceLimitReached {
+                device: device.to_string(),
+                scope: format!("on PCI segment {}", segment),
+                limit: MAX_DEVICES_PER_PCI_SEGMENT,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn counts(&self) -> DeviceCounts {
+        DeviceCounts {
+            pci_segments: self.devices.clone(),
+            per_segment_limit: MAX_DEVICES_PER_PCI_SEGMENT,
+            total: self.devices.iter().sum(),
+            limit: self.devices.len() * MAX_DEVICES_PER_PCI_SEGMENT,
+        }
+    }
+
+    pub fn release(&mut self, segment: u16) {
+        if let Some(count) = self.devices.get_mut(segment as usize) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pci_segment_allocator() {
+        let mut allocator = PciSegmentAllocator::default();
+        assert_eq!(allocator.allocate("dev").unwrap(), 0);
+        assert_eq!(allocator.allocate("dev").unwrap(), 0);
+
+        let mut allocator = PciSegmentAllocator::new(3);
+        allocator.reserve(0);
+        allocator.reserve(0);
+        allocator.reserve(2);
+
+        assert_eq!(allocator.allocate("dev").unwrap(), 1);
+        assert_eq!(allocator.allocate("dev").unwrap(), 1);
+        assert_eq!(allocator.allocate("dev").unwrap(), 2);
+
+        allocator.release(1);
+        assert_eq!(allocator.allocate("dev").unwrap(), 1);
+
+        // Out of range segments are ignored.
+        allocator.reserve(5);
+        allocator.release(5);
+        allocator.try_reserve(5, "dev").unwrap();
+    }
+
+    #[test]
+    fn test_pci_segment_limits() {
+        let mut allocator = PciSegmentAllocator::new(2);
+
+        for _ in 0..MAX_DEVICES_PER_PCI_SEGMENT {
+            allocator.try_reserve(0, "dev").unwrap();
+        }
+
+        let err = allocator.try_reserve(0, "disk1").unwrap_err();
+
+        match err.downcast_ref::<ChError>() {
+            Some(ChError::DeviceLimitReached {
+                device,
+                scope,
+                limit,
+            }) => {
+                assert_eq!(device, "disk1");
+                assert_eq!(scope, "on PCI segment 0");
+                assert_eq!(*limit, MAX_DEVICES_PER_PCI_SEGMENT);
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+
+        // The allocator moves on to the segment with room left.
+        for _ in 0..MAX_DEVICES_PER_PCI_SEGMENT {
+            assert_eq!(allocator.allocate("dev").unwrap(), 1);
+        }
+
+        let err = allocator.allocate("pmem0").unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ChError>(),
+            Some(ChError::DeviceLimitReached { limit, .. })
+                if *limit == 2 * MAX_DEVICES_PER_PCI_SEGMENT
+        ));
+
+        let counts = allocator.counts();
+
+        assert_eq!(counts.pci_segments, vec![31, 31]);
+        assert_eq!(counts.total, counts.limit);
+
+        allocator.check_limits("boot devices").unwrap();
+        allocator.reserve(1);
+        assert!(allocator.check_limits("boot devices").is_err());
+
+        allocator.release(1);
+        allocator.release(0);
+        assert_eq!(allocator.allocate("dev").unwrap(), 0);
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// State of a running VM saved in the sandbox directory, so that a shim
+// restarted while CH kept running can take the control of the VM back.
+
+use super::inner_hypervisor::HugePagesRequest;
+use super::measurements::LaunchData;
+use super::pci_segments::PciSegmentAllocator;
+use crate::device::AddedDevice;
+use anyhow::{anyhow, Context, Result};
+use ch_config::HotplugMethod;
+use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+// Version of the format of the state file, changed on incompatible changes.
+const RECOVERY_STATE_VERSION: u32 = 1;
+
+// Maximum length of the command name of a process, which the kernel
+// truncates (TASK_COMM_LEN without the terminating NUL).
+const TASK_COMM_LEN: usize = 15;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RecoveryState {
+    pub version: u32,
+    pub id: String,
+
+    // The CH process and the socket it is controlled through.
+    pub pid: u32,
+    pub api_socket_path: String,
+
+    pub vm_path: String,
+    pub run_dir: String,
+    pub netns: Option<String>,
+    pub jailed: bool,
+    pub jailer_root: String,
+    pub config: HypervisorConfig,
+
+    // Devices placed on each PCI segment.
+    pub pci_segments: PciSegmentAllocator,
+
+    // Devices added to the VM, by kata id.
+    #[serde(default)]
+    pub added_devices: HashMap<String, AddedDevice>,
+
+    pub boot_memory_mb: u64,
+    pub max_memory_mb: u64,
+    pub memory_hotplug_method: HotplugMethod,
+    pub boot_vcpus: u32,
+    #[serde(default)]
+    pub max_vcpus: u32,
+    #[serde(default)]
+    pub memory_shared: bool,
+    #[serde(default)]
+    pub hugepage_requests: Vec<HugePagesRequest>,
+    #[serde(default)]
+    pub guest_clock_offset: i64,
+
+    // Helper processes of the VM, such as virtiofsd.
+    #[serde(default)]
+    pub helper_pids: Vec<u32>,
+
+    pub console_log_path: Option<String>,
+    pub console_socket_path: Option<String>,
+    pub vmm_log_path: Option<String>,
+
+    #[serde(default)]
+    pub launch_data: LaunchData,
+
+    // Files and directories created for the VM, as (path, kind) pairs.
+    #[serde(default)]
+    pub artifacts: Vec<(String, String)>,
+}
+
+impl RecoveryState {
+    // Write the state to a temporary file renamed over the state file, so
+    // that a shim stopped while writing it leaves the previous state.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let state = RecoveryState {
+            version: RECOVERY_STATE_VERSION,
+            ..self.clone()
+        };
+
+        let data = serde_json::to_vec(&state).context("failed to serialize recovery state")?;
+
+        let tmp_path = path.with_extension("tmp");
+
+        std::fs::write(&tmp_path, data)
+            .context(format!("failed to write recovery state {:?}", tmp_path))?;
+
+        std::fs::rename(&tmp_path, path)
+            .context(format!("failed to rename recovery state to {:?}", path))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data =
+            std::fs::read(path).context(format!("failed to read recovery state {:?}", path))?;
+
+        let state: RecoveryState =
+            serde_json::from_slice(&data).context(format!("invalid recovery state {:?}", path))?;
+
+        if state.version != RECOVERY_STATE_VERSION {
+            return Err(anyhow!(
+                "unsupported recovery state version {} in {:?}",
+                state.version,
+                path
+            ));
+        }
+
+        Ok(state)
+    }
+}
+
+// Return true if the process runs the binary, according to its command
+// name. The path of its executable is not used, as it is relative to the
+// jail when CH is confined.
+pub fn is_vmm_process(proc_dir: &Path, binary: &Path) -> bool {
+    let name = match binary.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+
+    let comm = match std::fs::read_to_string(proc_dir.join("comm")) {
+        Ok(comm) => comm,
+        Err(_) => return false,
+    };
+
+    let expected: String = name.chars().take(TASK_COMM_LEN).collect();
+
+    comm.trim_end_matches('\n') == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovery_state() {
+        let dir = std::env::temp_dir().join(format!("ch-recovery-{}", std::process::id()));
+        let path = dir.join("ch-state.json");
+
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut pci_segments = PciSegmentAllocator::new(2);
+        pci_segments.reserve(1);
+
+        let added_devices = HashMap::from([(
+            "blk0".to_string(),
+            AddedDevice {
+                id: "_disk0".to_string(),
+                guest_pci_address: Some("02/00".to_string()),
+                ..Default::default()
+            },
+        )]);
+
+        let state = RecoveryState {
+            id: "sandbox".to_string(),
+            pid: 1234,
+            api_socket_path: "/run/vc/vm/sandbox/ch-api.sock".to_string(),
+            pci_segments,
+            added_devices,
+            boot_vcpus: 2,
+            memory_hotplug_method: HotplugMethod::VirtioMem,
+            memory_shared: true,
+            hugepage_requests: vec![HugePagesRequest {
+                page_size: 2 << 20,
+                size: 64 << 20,
+            }],
+            guest_clock_offset: -60,
+            helper_pids: vec![1235],
+            artifacts: vec![(
+                "/run/vc/vm/sandbox/ch-api.sock".to_string(),
+                "file".to_string(),
+            )],
+            ..Default::default()
+        };
+
+        state.save(&path).unwrap();
+
+        let mut loaded = RecoveryState::load(&path).unwrap();
+
+        assert_eq!(loaded.version, RECOVERY_STATE_VERSION);
+        assert_eq!(loaded.pid, 1234);
+        assert_eq!(loaded.api_socket_path, state.api_socket_path);
+        assert_eq!(loaded.boot_vcpus, 2);
+        assert_eq!(loaded.memory_hotplug_method, HotplugMethod::VirtioMem);
+        assert_eq!(loaded.pci_segments.allocate("dev").unwrap(), 0);
+        assert_eq!(loaded.added_devices, state.added_devices);
+        assert!(loaded.memory_shared);
+        assert_eq!(loaded.hugepage_requests, state.hugepage_requests);
+        assert_eq!(loaded.guest_clock_offset, -60);
+        assert_eq!(loaded.helper_pids, vec![1235]);
+        assert_eq!(loaded.artifacts, state.artifacts);
+        assert!(!dir.join("ch-state.tmp").exists());
+
+        std::fs::write(&path, "{\"version\": 0}").unwrap();
+
+        assert!(RecoveryState::load(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(RecoveryState::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_is_vmm_process() {
+        let proc_dir = Path::new("/proc/self");
+        let binary = std::env::current_exe().unwrap();
+
+        // The name of the test binary is longer than the command name.
+        assert!(is_vmm_process(proc_dir, &binary));
+        assert!(!is_vmm_process(
+            proc_dir,
+            Path::new("/usr/bin/cloud-hypervisor")
+        ));
+        assert!(!is_vmm_process(Path::new("/proc/not-a-pid"), &binary));
+    }
+}
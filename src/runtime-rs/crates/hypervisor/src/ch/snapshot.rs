@@ -0,0 +1,523 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Context, Result};
+use ch_config::{RestoredNetConfig, VmConfig};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+// Name of the file describing a snapshot, stored alongside the files
+// written by CH.
+pub const SNAPSHOT_METADATA_FILE: &str = "kata-snapshot.json";
+
+// Name of the snapshot directory below vm_path, if no directory is
+// specified.
+pub const SNAPSHOT_DIR_NAME: &str = "snapshot";
+
+// Name of the directory below vm_path holding a snapshot of another sandbox
+// prepared to be restored as a clone.
+pub const CLONE_SNAPSHOT_DIR_NAME: &str = "clone-snapshot";
+
+// Name of the file CH stores the VM config in, in a snapshot.
+const SNAPSHOT_CONFIG_FILE: &str = "config.json";
+
+// Devices of the VM config using host resources specific to a sandbox.
+const CLONE_SINGLE_DEVICES: [&str; 3] = ["vsock", "console", "serial"];
+const CLONE_DEVICE_LISTS: [&str; 4] = ["disks", "net", "fs", "pmem"];
+
+/// What a snapshot saves.
+///
+/// CH always saves the state of the devices along with the guest memory,
+/// and needs both to restore a VM, so only full snapshots are supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotMode {
+    /// Guest memory and device state.
+    Full,
+    /// Guest memory only, not supported by CH.
+    MemoryOnly,
+}
+
+pub fn check_snapshot_mode(mode: SnapshotMode) -> Result<()> {
+    match mode {
+        SnapshotMode::Full => Ok(()),
+        SnapshotMode::MemoryOnly => Err(anyhow!(
+            "memory only snapshots are not supported: CH requires the device state to restore a VM"
+        )),
+    }
+}
+
+// Details of the VM saved in a snapshot.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotMetadata {
+    // Version of the CH instance which created the snapshot.
+    pub ch_version: String,
+
+    // Devices of the VM, as "<type>:<id>".
+    pub devices: Vec<String>,
+
+    // Size in bytes of the boot memory of the guest.
+    pub memory_size: u64,
+
+    // Id of the sandbox the snapshot was taken from. Restoring the
+    // snapshot in another sandbox clones the VM.
+    #[serde(default)]
+    pub sandbox_id: String,
+}
+
+impl SnapshotMetadata {
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(SNAPSHOT_METADATA_FILE);
+
+        let data = serde_json::to_string_pretty(self)?;
+
+        std::fs::write(&path, data).context(format!("failed to write {:?}", path))
+    }
+
+    pub fn read(dir: &Path) -> Result<Self> {
+        let path = dir.join(SNAPSHOT_METADATA_FILE);
+
+        let data = std::fs::read_to_string(&path).context(format!("failed to read {:?}", path))?;
+
+        serde_json::from_str(&data).context(format!("invalid snapshot metadata {:?}", path))
+    }
+}
+
+// Return the devices of the VM config, as "<type>:<id>".
+pub fn get_snapshot_devices(cfg: &VmConfig) -> Vec<String> {
+    let mut devices = Vec::new();
+
+    let mut add = |kind: &str, id: &Option<String>| {
+        devices.push(format!("{}:{}", kind, id.as_deref().unwrap_or_default()));
+    };
+
+    for disk in cfg.disks.iter().flatten() {
+        add("disk", &disk.id);
+    }
+    for net in cfg.net.iter().flatten() {
+        add("net", &net.id);
+    }
+    for fs in cfg.fs.iter().flatten() {
+        add("fs", &fs.id);
+    }
+    for pmem in cfg.pmem.iter().flatten() {
+        add("pmem", &pmem.id);
+    }
+    for device in cfg.devices.iter().flatten() {
+        add("vfio", &device.id);
+    }
+    if let Some(vsock) = &cfg.vsock {
+        add("vsock", &vsock.id);
+    }
+
+    devices
+}
+
+// Return the size in bytes of the boot memory of the VM, excluding any hot
+// added memory.
+pub fn get_boot_memory_size(cfg: &VmConfig) -> u64 {
+    match cfg.memory.zones.as_ref() {
+        Some(zones) => zones.iter().map(|z| z.size).sum(),
+        None => cfg.memory.size,
+    }
+}
+
+// CH cannot save the state of VFIO devices.
+pub fn check_snapshot_devices(cfg: &VmConfig) -> Result<()> {
+    let vfio: Vec<String> = cfg
+        .devices
+        .iter()
+        .flatten()
+        .map(|d| d.id.clone().unwrap_or_else(|| d.path.display().to_string()))
+        .collect();
+
+    if !vfio.is_empty() {
+        return Err(anyhow!(
+            "cannot snapshot a VM with VFIO devices: {:?}",
+            vfio
+        ));
+    }
+
+    Ok(())
+}
+
+// Check that a snapshot can be restored by the running CH version, with the
+// specified VM config, reporting all the differences.
+pub fn check_snapshot_compatibility(
+    metadata: &SnapshotMetadata,
+    ch_version: &str,
+    cfg: &VmConfig,
+) -> Result<()> {
+    let mut mismatches = Vec::<String>::new();
+
+    if metadata.ch_version != ch_version {
+        mismatches.push(format!(
+            "CH version: snapshot {:?}, current {:?}",
+            metadata.ch_version, ch_version
+        ));
+    }
+
+    let devices = get_snapshot_devices(cfg);
+
+    let missing: Vec<&String> = metadata
+        .devices
+        .iter()
+        .filter(|d| !devices.contains(d))
+        .collect();
+
+    if !missing.is_empty() {
+        mismatches.push(format!("devices missing from the config: {:?}", missing));
+    }
+
+    let unexpected: Vec<&String> = devices
+        .iter()
+        .filter(|d| !metadata.devices.contains(d))
+        .collect();
+
+    if !unexpected.is_empty() {
+        mismatches.push(format!("devices not in the snapshot: {:?}", unexpected));
+    }
+
+    let memory_size = get_boot_memory_size(cfg);
+
+    if metadata.memory_size != memory_size {
+        mismatches.push(format!(
+            "memory size: snapshot {}, current {}",
+            metadata.memory_size, memory_size
+        ));
+    }
+
+    if !mismatches.is_empty() {
+        return Err(anyhow!("snapshot mismatch: {}", mismatches.join("; ")));
+    }
+
+    Ok(())
+}
+
+// Return the tap fds to restore the net devices of a snapshot with, from
+// the fds supplied by net device id. Every net device of the snapshot must
+// have fds, as a device left with the taps of the host the snapshot was
+// taken on would not be connected.
+pub fn get_restored_net_configs(
+    metadata: &SnapshotMetadata,
+    net_fds: &HashMap<String, Vec<RawFd>>,
+) -> Result<Vec<RestoredNetConfig>> {
+    let net_ids: Vec<&str> = metadata
+        .devices
+        .iter()
+        .filter_map(|d| d.strip_prefix("net:"))
+        .collect();
+
+    let missing: Vec<&str> = net_ids
+        .iter()
+        .copied()
+        .filter(|id| net_fds.get(*id).map_or(true, |fds| fds.is_empty()))
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "no tap fds supplied for the net devices {:?} of the snapshot",
+            missing
+        ));
+    }
+
+    let mut unexpected: Vec<&String> = net_fds
+        .keys()
+        .filter(|id| !net_ids.contains(&id.as_str()))
+        .collect();
+
+    if !unexpected.is_empty() {
+        unexpected.sort();
+
+        return Err(anyhow!(
+            "tap fds supplied for net devices not in the snapshot: {:?}",
+            unexpected
+        ));
+    }
+
+    Ok(net_ids
+        .iter()
+        .map(|id| {
+            let fds = net_fds[*id].clone();
+
+            RestoredNetConfig {
+                id: id.to_string(),
+                num_fds: fds.len(),
+                fds,
+            }
+        })
+        .collect())
+}
+
+// Replace the host side settings of the VM config saved in a snapshot, such
+// as the socket paths, by the ones of the sandbox the snapshot is restored
+// in, so that a clone does not use the resources of the VM it was taken
+// from. The devices keep their ids, which CH uses to restore their state
+// and which are only unique within a VM.
+pub fn rewrite_snapshot_config(snapshot_cfg: &mut Value, cfg: &VmConfig) -> Result<()> {
+    let cfg = serde_json::to_value(cfg)?;
+
+    let snapshot_cfg = snapshot_cfg
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("invalid snapshot VM config"))?;
+
+    for key in CLONE_SINGLE_DEVICES {
+        match cfg.get(key) {
+            Some(device) if !device.is_null() => {
+                snapshot_cfg.insert(key.to_string(), device.clone());
+            }
+            _ => (),
+        }
+    }
+
+    for key in CLONE_DEVICE_LISTS {
+        let devices = match cfg.get(key).and_then(|v| v.as_array()) {
+            Some(devices) => devices,
+            None => continue,
+        };
+
+        let snapshot_devices = snapshot_cfg.get_mut(key).and_then(|v| v.as_array_mut());
+
+        for device in snapshot_devices.into_iter().flatten() {
+            let id = device.get("id").cloned();
+
+            if let Some(clone_device) = devices.iter().find(|d| d.get("id") == id.as_ref()) {
+                *device = clone_device.clone();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Prepare in dest a copy of the snapshot in src to be restored with the
+// specified VM config. The files saved by CH are linked rather than copied,
+// as they can be large and are only read on restore.
+pub fn prepare_clone_snapshot(src: &Path, dest: &Path, cfg: &VmConfig) -> Result<()> {
+    if dest.exists() {
+        std::fs::remove_dir_all(dest).context(format!("failed to remove {:?}", dest))?;
+    }
+
+    std::fs::create_dir_all(dest).context(format!("failed to create {:?}", dest))?;
+
+    for entry in src
+        .read_dir()
+        .context(format!("failed to read {:?}", src))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+
+        if name == SNAPSHOT_CONFIG_FILE {
+            continue;
+        }
+
+        std::os::unix::fs::symlink(entry.path(), dest.join(&name))
+            .context(format!("failed to link {:?}", entry.path()))?;
+    }
+
+    let config_path = src.join(SNAPSHOT_CONFIG_FILE);
+
+    let data = std::fs::read_to_string(&config_path)
+        .context(format!("failed to read {:?}", config_path))?;
+
+    let mut snapshot_cfg: Value = serde_json::from_str(&data)
+        .context(format!("invalid snapshot VM config {:?}", config_path))?;
+
+    rewrite_snapshot_config(&mut snapshot_cfg, cfg)?;
+
+    let config_path = dest.join(SNAPSHOT_CONFIG_FILE);
+
+    std::fs::write(&config_path, serde_json::to_string(&snapshot_cfg)?)
+        .context(format!("failed to write {:?}", config_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ch_config::{DeviceConfig, FsConfig, VsockConfig};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_snapshot_devices() {
+        let mut cfg = VmConfig {
+            fs: Some(vec![FsConfig {
+                id: Some("fs0".to_string()),
+                ..Default::default()
+            }]),
+            vsock: Some(VsockConfig {
+                id: Some("vsock0".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(get_snapshot_devices(&cfg), vec!["fs:fs0", "vsock:vsock0"]);
+        assert!(check_snapshot_devices(&cfg).is_ok());
+
+        cfg.devices = Some(vec![DeviceConfig {
+            path: PathBuf::from("/sys/bus/pci/devices/0000:01:00.0"),
+            ..Default::default()
+        }]);
+
+        assert_eq!(
+            get_snapshot_devices(&cfg),
+            vec!["fs:fs0", "vfio:", "vsock:vsock0"]
+        );
+
+        let err = check_snapshot_devices(&cfg).unwrap_err();
+        assert!(err.to_string().contains("0000:01:00.0"));
+    }
+
+    #[test]
+    fn test_check_snapshot_compatibility() {
+        let mut cfg = VmConfig::default();
+        cfg.memory.size = 1 << 30;
+        cfg.fs = Some(vec![FsConfig {
+            id: Some("fs0".to_string()),
+            ..Default::default()
+        }]);
+
+        let metadata = SnapshotMetadata {
+            ch_version: "v32.0".to_string(),
+            devices: vec!["fs:fs0".to_string()],
+            memory_size: 1 << 30,
+            ..Default::default()
+        };
+
+        assert!(check_snapshot_compatibility(&metadata, "v32.0", &cfg).is_ok());
+
+        cfg.memory.size = 2 << 30;
+        cfg.vsock = Some(VsockConfig {
+            id: Some("vsock0".to_string()),
+            ..Default::default()
+        });
+        cfg.fs = None;
+
+        let err = check_snapshot_compatibility(&metadata, "v33.0", &cfg)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("CH version"));
+        assert!(err.contains("devices missing from the config: [\"fs:fs0\"]"));
+        assert!(err.contains("devices not in the snapshot: [\"vsock:vsock0\"]"));
+        assert!(err.contains("memory size"));
+    }
+
+    #[test]
+    fn test_get_restored_net_configs() {
+        let metadata = SnapshotMetadata {
+            devices: vec![
+                "disk:_disk0".to_string(),
+                "net:_net0".to_string(),
+                "net:_net1".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let mut net_fds = HashMap::new();
+        net_fds.insert("_net0".to_string(), vec![10, 11]);
+        net_fds.insert("_net1".to_string(), vec![]);
+
+        let err = get_restored_net_configs(&metadata, &net_fds)
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(
+            err,
+            "no tap fds supplied for the net devices [\"_net1\"] of the snapshot"
+        );
+
+        net_fds.insert("_net1".to_string(), vec![12]);
+
+        let configs = get_restored_net_configs(&metadata, &net_fds).unwrap();
+
+        assert_eq!(
+            configs,
+            vec![
+                RestoredNetConfig {
+                    id: "_net0".to_string(),
+                    num_fds: 2,
+                    fds: vec![10, 11],
+                },
+                RestoredNetConfig {
+                    id: "_net1".to_string(),
+                    num_fds: 1,
+                    fds: vec![12],
+                },
+            ]
+        );
+
+        net_fds.insert("_net2".to_string(), vec![13]);
+
+        let err = get_restored_net_configs(&metadata, &net_fds)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("not in the snapshot: [\"_net2\"]"));
+    }
+
+    #[test]
+    fn test_check_snapshot_mode() {
+        assert!(check_snapshot_mode(SnapshotMode::Full).is_ok());
+        assert!(check_snapshot_mode(SnapshotMode::MemoryOnly).is_err());
+    }
+
+    #[test]
+    fn test_prepare_clone_snapshot() {
+        let root = std::env::temp_dir().join(format!("ch-clone-{}", std::process::id()));
+        let src = root.join("snapshot");
+        let dest = root.join("clone");
+
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("state.json"), "{}").unwrap();
+        std::fs::write(
+            src.join(SNAPSHOT_CONFIG_FILE),
+            serde_json::json!({
+                "memory": {"size": 1024},
+                "vsock": {"cid": 3, "socket": "/run/parent/ch-vm.sock", "id": "vsock0"},
+                "fs": [
+                    {"tag": "kataShared", "socket": "/run/parent/vhost-fs.sock", "id": "fs0"},
+                    {"tag": "other", "socket": "/run/other.sock", "id": "fs1"}
+                ]
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let cfg = VmConfig {
+            fs: Some(vec![FsConfig {
+                tag: "kataShared".to_string(),
+                socket: PathBuf::from("/run/clone/vhost-fs.sock"),
+                id: Some("fs0".to_string()),
+                ..Default::default()
+            }]),
+            vsock: Some(VsockConfig {
+                cid: 3,
+                socket: PathBuf::from("/run/clone/ch-vm.sock"),
+                id: Some("vsock0".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        prepare_clone_snapshot(&src, &dest, &cfg).unwrap();
+
+        assert_eq!(
+            std::fs::read_link(dest.join("state.json")).unwrap(),
+            src.join("state.json")
+        );
+
+        let data = std::fs::read_to_string(dest.join(SNAPSHOT_CONFIG_FILE)).unwrap();
+        let snapshot_cfg: Value = serde_json::from_str(&data).unwrap();
+
+        assert_eq!(snapshot_cfg["memory"]["size"], 1024);
+        assert_eq!(snapshot_cfg["vsock"]["socket"], "/run/clone/ch-vm.sock");
+        assert_eq!(snapshot_cfg["fs"][0]["socket"], "/run/clone/vhost-fs.sock");
+        assert_eq!(snapshot_cfg["fs"][1]["socket"], "/run/other.sock");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
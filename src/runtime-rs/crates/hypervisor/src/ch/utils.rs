@@ -2,8 +2,21 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use kata_types::config::hypervisor::{CH_HYPERVISOR_DEVICE_KVM, CH_HYPERVISOR_DEVICE_MSHV};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::unistd::{chown, Gid, Uid};
+use safe_path::scoped_join;
 use shim_interface::KATA_PATH;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
 // The socket used to connect to CH. This is used for CH API communications.
 const CH_API_SOCKET_NAME: &str = "ch-api.sock";
@@ -14,6 +27,81 @@ const CH_VM_SOCKET_NAME: &str = "ch-vm.sock";
 
 const CH_JAILER_DIR: &str = "root";
 
+// The FIFO CH writes its events to.
+const CH_EVENT_MONITOR_NAME: &str = "ch-events.fifo";
+
+// The file the state needed to recover the VM after a shim restart is
+// saved to.
+const CH_RECOVERY_STATE_NAME: &str = "ch-state.json";
+
+// The file the guest console output is written to.
+const CH_CONSOLE_LOG_NAME: &str = "console.log";
+
+// The file CH writes its own log to.
+const CH_VMM_LOG_NAME: &str = "vmm.log";
+
+// Names of all the log files the driver creates in the sandbox directory.
+pub const CH_LOG_FILE_NAMES: [&str; 2] = [CH_CONSOLE_LOG_NAME, CH_VMM_LOG_NAME];
+
+// List of the Linux Security Modules enabled in the host kernel.
+const LSM_LIST_PATH: &str = "/sys/kernel/security/lsm";
+
+const LANDLOCK_LSM_NAME: &str = "landlock";
+
+const KERNEL_RELEASE_PATH: &str = "/proc/sys/kernel/osrelease";
+
+// Set to a non-zero value by the administrator to restrict io_uring.
+const IO_URING_DISABLED_PATH: &str = "/proc/sys/kernel/io_uring_disabled";
+
+// Oldest kernel providing all the io_uring operations used by CH.
+const IO_URING_MIN_KERNEL_VERSION: (u32, u32) = (5, 6);
+
+// Device nodes of the host hypervisors CH can run on.
+const KVM_DEVICE: &str = "/dev/kvm";
+const MSHV_DEVICE: &str = "/dev/mshv";
+
+const CPUINFO_PATH: &str = "/proc/cpuinfo";
+
+// CH names the thread of each vCPU "vcpu<index>".
+const CH_VCPU_THREAD_PREFIX: &str = "vcpu";
+
+const MEMINFO_PATH: &str = "/proc/meminfo";
+
+const NUMA_NODES_PATH: &str = "/sys/devices/system/node";
+
+const PCI_DEVICES_PATH: &str = "/sys/bus/pci/devices";
+
+// The resource file of a PCI device lists the standard BARs first, then the
+// expansion ROM and the bridge windows, which are not mapped in the guest.
+const PCI_STD_NUM_BARS: usize = 6;
+
+// Flag of the memory resources, the only ones mapped in the guest physical
+// address space.
+const IORESOURCE_MEM: u64 = 0x200;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Only present at the root of a cgroup v2 (unified) hierarchy.
+const CGROUP_V2_CONTROLLERS: &str = "cgroup.controllers";
+
+const CGROUP_PROCS: &str = "cgroup.procs";
+
+// Device used by the hypervisor to allocate virtual SGX EPC memory.
+#[cfg(target_arch = "x86_64")]
+const SGX_VEPC_DEVICE: &str = "/dev/sgx_vepc";
+
+// Parameter of the KVM module, enabled when KVM can run TDX guests.
+#[cfg(target_arch = "x86_64")]
+const KVM_INTEL_TDX_PARAM: &str = "/sys/module/kvm_intel/parameters/tdx";
+
+// Device of the AMD secure processor, present once its driver initialized
+// it, and the parameter of the KVM module enabled once SEV-SNP is
+// initialized.
+#[cfg(target_arch = "x86_64")]
+const SEV_DEVICE: &str = "/dev/sev";
+#[cfg(target_arch = "x86_64")]
+const KVM_AMD_SEV_SNP_PARAM: &str = "/sys/module/kvm_amd/parameters/sev_snp";
+
 // Return the path for a _hypothetical_ sandbox: the path does *not* exist
 // yet, and for this reason safe-path cannot be used.
 pub fn get_sandbox_path(id: &str) -> Result<String> {
@@ -44,6 +132,22 @@ pub fn get_vsock_path(id: &str) -> Result<String> {
     Ok(path)
 }
 
+pub fn get_event_monitor_path(id: &str) -> Result<String> {
+    let sandbox_path = get_sandbox_path(id)?;
+
+    let path = [&sandbox_path, CH_EVENT_MONITOR_NAME].join("/");
+
+    Ok(path)
+}
+
+pub fn get_recovery_state_path(id: &str) -> Result<String> {
+    let sandbox_path = get_sandbox_path(id)?;
+
+    let path = [&sandbox_path, CH_RECOVERY_STATE_NAME].join("/");
+
+    Ok(path)
+}
+
 pub fn get_jailer_root(id: &str) -> Result<String> {
     let sandbox_path = get_sandbox_path(id)?;
 
@@ -51,3 +155,1458 @@ pub fn get_jailer_root(id: &str) -> Result<String> {
 
     Ok(path)
 }
+
+// Return true if the host kernel has the Landlock LSM enabled.
+pub fn host_supports_landlock() -> bool {
+    std::fs::read_to_string(LSM_LIST_PATH)
+        .map(|lsms| lsms.trim().split(',').any(|lsm| lsm == LANDLOCK_LSM_NAME))
+        .unwrap_or(false)
+}
+
+// Check the host can run CH on the specified hypervisor device ("kvm" or
+// "mshv"), or on any device if none is specified.
+//
+// CH has no option to choose the device: it always uses KVM when
+// available, so MSHV can only be selected on hosts without KVM.
+pub fn check_host_hypervisor(device: &str) -> Result<()> {
+    let kvm_present = Path::new(KVM_DEVICE).exists();
+
+    let path = match device {
+        CH_HYPERVISOR_DEVICE_KVM => KVM_DEVICE,
+        CH_HYPERVISOR_DEVICE_MSHV => {
+            if kvm_present {
+                return Err(anyhow!(
+                    "{} selected but {} is present, and is always used by CH",
+                    MSHV_DEVICE,
+                    KVM_DEVICE
+                ));
+            }
+
+            MSHV_DEVICE
+        }
+        "" => get_hypervisor_device_path(device),
+        _ => return Err(anyhow!("invalid hypervisor device {:?}", device)),
+    };
+
+    check_hypervisor_device_node(path)
+}
+
+// Return the path of the hypervisor device CH uses, for the specified device
+// ("kvm" or "mshv") or for the device available if none is specified.
+pub fn get_hypervisor_device_path(device: &str) -> &'static str {
+    match device {
+        CH_HYPERVISOR_DEVICE_MSHV => MSHV_DEVICE,
+        CH_HYPERVISOR_DEVICE_KVM => KVM_DEVICE,
+        _ => {
+            if Path::new(KVM_DEVICE).exists() || !Path::new(MSHV_DEVICE).exists() {
+                KVM_DEVICE
+            } else {
+                MSHV_DEVICE
+            }
+        }
+    }
+}
+
+// Return the CH feature required to use the hypervisor device CH uses for
+// the specified device, which is named after the device.
+pub fn get_hypervisor_feature(device: &str) -> &'static str {
+    if get_hypervisor_device_path(device) == MSHV_DEVICE {
+        CH_HYPERVISOR_DEVICE_MSHV
+    } else {
+        CH_HYPERVISOR_DEVICE_KVM
+    }
+}
+
+// Check the hypervisor device node exists and can be opened.
+fn check_hypervisor_device_node(path: &str) -> Result<()> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let hint = if path == KVM_DEVICE {
+                let cpuinfo = std::fs::read_to_string(CPUINFO_PATH).unwrap_or_default();
+
+                get_missing_kvm_hint(&cpuinfo)
+            } else {
+                "check the mshv module is loaded"
+            };
+
+            return Err(anyhow!("{} not found: {}", path, hint));
+        }
+        Err(e) => return Err(anyhow!(e).context(format!("failed to stat {}", path))),
+    };
+
+    if !metadata.file_type().is_char_device() {
+        return Err(anyhow!("{} is not a character device", path));
+    }
+
+    if let Err(e) = OpenOptions::new().read(true).write(true).open(path) {
+        if e.kind() == ErrorKind::PermissionDenied {
+            return Err(anyhow!(
+                "no permission to open {} (mode {:o}, owner {}:{})",
+                path,
+                metadata.permissions().mode() & 0o777,
+                metadata.uid(),
+                metadata.gid()
+            ));
+        }
+
+        return Err(anyhow!(e).context(format!("failed to open {}", path)));
+    }
+
+    Ok(())
+}
+
+// Return the most likely reason for the absence of the KVM device, based
+// on the host CPU flags.
+fn get_missing_kvm_hint(cpuinfo: &str) -> &'static str {
+    if !cfg!(target_arch = "x86_64") {
+        return "check the kvm module is loaded";
+    }
+
+    let flags: Vec<&str> = cpuinfo
+        .lines()
+        .find(|l| l.starts_with("flags"))
+        .and_then(|l| l.split(':').nth(1))
+        .map(|f| f.split_whitespace().collect())
+        .unwrap_or_default();
+
+    let has_virt = flags.iter().any(|f| *f == "vmx" || *f == "svm");
+
+    if has_virt {
+        "check the kvm and kvm_intel or kvm_amd modules are loaded"
+    } else if flags.contains(&"hypervisor") {
+        "the host is a VM, enable nested virtualization"
+    } else {
+        "CPU virtualization extensions are not available, enable VT-x or AMD-V in the firmware"
+    }
+}
+
+// Fail if the host kernel cannot provide io_uring to CH.
+pub fn check_host_io_uring() -> Result<()> {
+    let release = std::fs::read_to_string(KERNEL_RELEASE_PATH)
+        .context(format!("failed to read {}", KERNEL_RELEASE_PATH))?;
+
+    let version = parse_version(release.trim())
+        .ok_or_else(|| anyhow!("invalid kernel release {:?}", release.trim()))?;
+
+    if version < IO_URING_MIN_KERNEL_VERSION {
+        return Err(anyhow!(
+            "io_uring requires kernel {}.{} or newer, host kernel is {}",
+            IO_URING_MIN_KERNEL_VERSION.0,
+            IO_URING_MIN_KERNEL_VERSION.1,
+            release.trim()
+        ));
+    }
+
+    // Older kernels do not have this knob, in which case io_uring is
+    // always available.
+    if let Ok(value) = std::fs::read_to_string(IO_URING_DISABLED_PATH) {
+        if value.trim() != "0" {
+            return Err(anyhow!(
+                "io_uring is disabled by {}",
+                IO_URING_DISABLED_PATH
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Return the major and minor numbers of a version string such as
+// "5.15.0-91-generic".
+fn parse_version(release: &str) -> Option<(u32, u32)> {
+    let mut numbers = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>());
+
+    let major = numbers.next()?.ok()?;
+    let minor = numbers.next()?.ok()?;
+
+    Some((major, minor))
+}
+
+// Return the major and minor numbers of a CH version string, as reported by
+// "cloud-hypervisor --version" ("cloud-hypervisor v32.0") or vmm.ping
+// ("v32.0-12-gabcdef").
+pub fn parse_ch_version(version: &str) -> Option<(u32, u32)> {
+    let version = version.split_whitespace().last()?;
+
+    parse_version(version.strip_prefix('v')?)
+}
+
+// Return the version of the specified CH binary.
+pub fn get_ch_version(path: &Path) -> Result<String> {
+    let output = std::process::Command::new(path)
+        .arg("--version")
+        .output()
+        .context(format!("failed to run {:?}", path))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{:?} --version failed: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Features of the CH binaries, by path. None if a binary does not report
+// its features.
+static CH_FEATURES: Mutex<Option<HashMap<PathBuf, Option<Vec<String>>>>> = Mutex::new(None);
+
+// Return the features the specified CH binary was built with, as reported
+// by "cloud-hypervisor -v --version", or None if it does not report them,
+// as older CH builds do. The result is cached per binary path, so that CH
+// is only run once.
+pub fn get_ch_features(path: &Path) -> Result<Option<Vec<String>>> {
+    let mut cache = CH_FEATURES
+        .lock()
+        .map_err(|e| anyhow!("failed to lock CH features cache: {}", e))?;
+
+    let cache = cache.get_or_insert_with(HashMap::new);
+
+    if let Some(features) = cache.get(path) {
+        return Ok(features.clone());
+    }
+
+    let output = std::process::Command::new(path)
+        .args(["-v", "--version"])
+        .output()
+        .context(format!("failed to run {:?}", path))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{:?} --version failed: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let features = parse_ch_features(&String::from_utf8_lossy(&output.stdout));
+
+    cache.insert(path.to_path_buf(), features.clone());
+
+    Ok(features)
+}
+
+// Return the features of the "Enabled features: [...]" line of the verbose
+// version output of CH, which lists them as quoted strings.
+fn parse_ch_features(output: &str) -> Option<Vec<String>> {
+    let list = output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Enabled features:"))?;
+
+    let list = list.trim().strip_prefix('[')?.strip_suffix(']')?;
+
+    Some(
+        list.split(',')
+            .map(|f| f.trim().trim_matches('"').to_string())
+            .filter(|f| !f.is_empty())
+            .collect(),
+    )
+}
+
+// Return the host thread ids of the vCPUs of a process, by vCPU index, from
+// its task directory ("/proc/<pid>/task"). Threads exiting while the
+// directory is read are skipped.
+pub fn get_vcpu_thread_ids(task_dir: &Path) -> Result<HashMap<u32, u32>> {
+    let mut vcpus = HashMap::new();
+
+    let entries =
+        std::fs::read_dir(task_dir).context(format!("failed to read {}", task_dir.display()))?;
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).context(format!("failed to read {}", task_dir.display())),
+        };
+
+        let tid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u32>().ok())
+        {
+            Some(tid) => tid,
+            None => continue,
+        };
+
+        let comm_path = entry.path().join("comm");
+
+        let comm = match std::fs::read_to_string(&comm_path) {
+            Ok(comm) => comm,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).context(format!("failed to read {}", comm_path.display())),
+        };
+
+        let index = comm
+            .trim()
+            .strip_prefix(CH_VCPU_THREAD_PREFIX)
+            .and_then(|i| i.parse::<u32>().ok());
+
+        if let Some(index) = index {
+            vcpus.insert(index, tid);
+        }
+    }
+
+    Ok(vcpus)
+}
+
+// Return true if the host can provide SGX EPC sections to guests.
+#[cfg(target_arch = "x86_64")]
+pub fn host_supports_sgx() -> bool {
+    Path::new(SGX_VEPC_DEVICE).exists()
+}
+
+// Fail unless KVM can run TDX guests, which requires TDX to be enabled by
+// the firmware and by the kvm_intel module.
+#[cfg(target_arch = "x86_64")]
+pub fn check_host_tdx() -> Result<()> {
+    let value = std::fs::read_to_string(KVM_INTEL_TDX_PARAM).context(format!(
+        "KVM does not support TDX: cannot read {}",
+        KVM_INTEL_TDX_PARAM
+    ))?;
+
+    if !is_kvm_param_enabled(&value) {
+        return Err(anyhow!(
+            "KVM does not support TDX: {} is {:?}, load kvm_intel with tdx=1",
+            KVM_INTEL_TDX_PARAM,
+            value.trim()
+        ));
+    }
+
+    Ok(())
+}
+
+// Fail unless the host can run SEV-SNP guests, which requires the AMD secure
+// processor and KVM to have initialized SEV-SNP.
+#[cfg(target_arch = "x86_64")]
+pub fn check_host_sev_snp() -> Result<()> {
+    check_sev_snp(Path::new(SEV_DEVICE), Path::new(KVM_AMD_SEV_SNP_PARAM))
+}
+
+#[cfg(target_arch = "x86_64")]
+fn check_sev_snp(sev_device: &Path, snp_param: &Path) -> Result<()> {
+    if !sev_device.exists() {
+        return Err(anyhow!(
+            "host not SEV-SNP capable: {:?} not found, the AMD secure processor is unavailable",
+            sev_device
+        ));
+    }
+
+    let value = std::fs::read_to_string(snp_param).context(format!(
+        "host not SEV-SNP capable: cannot read {:?}, kvm_amd not loaded",
+        snp_param
+    ))?;
+
+    if !is_kvm_param_enabled(&value) {
+        return Err(anyhow!(
+            "host not SEV-SNP capable: {:?} is {:?}, SEV-SNP not initialized by the firmware or kvm_amd",
+            snp_param,
+            value.trim()
+        ));
+    }
+
+    Ok(())
+}
+
+// Return true if the value of a boolean module parameter is set.
+#[cfg(target_arch = "x86_64")]
+fn is_kvm_param_enabled(value: &str) -> bool {
+    matches!(value.trim(), "Y" | "y" | "1")
+}
+
+// Move the specified process into the cgroup, whose path is relative to the
+// root of the cgroup filesystem.
+pub fn move_to_cgroup(cgroup_path: &str, pid: u32) -> Result<()> {
+    let procs_paths = get_cgroup_file_paths(
+        Path::new(CGROUP_ROOT),
+        cgroup_path,
+        CGROUP_PROCS,
+        CGROUP_PROCS,
+    )?;
+
+    for procs in procs_paths {
+        write_cgroup_file(&procs, "process", pid)?;
+    }
+
+    Ok(())
+}
+
+// Write a process or thread id to a cgroup file, reporting the cgroup and
+// the errno on failure.
+fn write_cgroup_file(path: &Path, kind: &str, id: u32) -> Result<()> {
+    std::fs::write(path, id.to_string()).map_err(|e| {
+        let cgroup = path.parent().unwrap_or(path);
+        let errno = e.raw_os_error().unwrap_or_default();
+
+        match e.kind() {
+            ErrorKind::PermissionDenied => anyhow!(
+                "permission denied moving {} {} into cgroup {:?} (errno {})",
+                kind,
+                id,
+                cgroup,
+                errno
+            ),
+            _ => anyhow!(e).context(format!(
+                "failed to move {} {} into cgroup {:?} (errno {})",
+                kind, id, cgroup, errno
+            )),
+        }
+    })
+}
+
+// Return the paths of the specified file of the cgroup, such as
+// cgroup.procs: a single one with cgroup v2, one per hierarchy where the
+// cgroup exists with cgroup v1.
+fn get_cgroup_file_paths(
+    root: &Path,
+    cgroup_path: &str,
+    v1_file: &str,
+    v2_file: &str,
+) -> Result<Vec<PathBuf>> {
+    let relative_path = cgroup_path.trim_start_matches('/');
+
+    if relative_path.is_empty() {
+        return Err(anyhow!("invalid cgroup path {:?}", cgroup_path));
+    }
+
+    let v2 = root.join(CGROUP_V2_CONTROLLERS).exists();
+
+    let hierarchies = if v2 {
+        vec![root.to_path_buf()]
+    } else {
+        // Controllers mounted together, such as "cpu,cpuacct", are also
+        // exposed through symlinks which are skipped.
+        let mut hierarchies = Vec::new();
+
+        for entry in std::fs::read_dir(root).context(format!("failed to read {:?}", root))? {
+            let entry = entry?;
+
+            if entry.file_type()?.is_dir() {
+                hierarchies.push(entry.path());
+            }
+        }
+
+        hierarchies.sort();
+
+        hierarchies
+    };
+
+    let mut paths = Vec::new();
+
+    for hierarchy in hierarchies {
+        let dir = scoped_join(&hierarchy, relative_path)
+            .context(format!("invalid cgroup path {:?}", cgroup_path))?;
+
+        if dir.is_dir() {
+            paths.push(dir.join(if v2 { v2_file } else { v1_file }));
+        }
+    }
+
+    if paths.is_empty() {
+        return Err(anyhow!(
+            "cgroup {:?} does not exist below {:?}",
+            cgroup_path,
+            root
+        ));
+    }
+
+    Ok(paths)
+}
+
+// Return the amount of physical RAM of the host in MiB.
+pub fn get_host_memory_mb() -> Result<u64> {
+    let meminfo = std::fs::read_to_string(MEMINFO_PATH)
+        .context(format!("failed to read {:?}", MEMINFO_PATH))?;
+
+    parse_meminfo_total_mb(&meminfo)
+        .ok_or_else(|| anyhow!("failed to find total memory in {:?}", MEMINFO_PATH))
+}
+
+fn parse_meminfo_total_mb(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|l| l.starts_with("MemTotal:"))?;
+
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some(kb / 1024)
+}
+
+// Return the number of physical address bits of the host CPUs, which
+// bounds the guest physical address space, if reported.
+pub fn get_host_phys_bits() -> Option<u32> {
+    let cpuinfo = std::fs::read_to_string(CPUINFO_PATH).ok()?;
+
+    parse_cpuinfo_phys_bits(&cpuinfo)
+}
+
+// Parse the "address sizes : 46 bits physical, 48 bits virtual" line.
+fn parse_cpuinfo_phys_bits(cpuinfo: &str) -> Option<u32> {
+    let line = cpuinfo.lines().find(|l| l.starts_with("address sizes"))?;
+
+    let (_, sizes) = line.split_once(':')?;
+
+    sizes
+        .trim()
+        .strip_suffix("bits virtual")?
+        .split(" bits physical")
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// Return the CPUs of a host NUMA node, failing if the node does not exist.
+pub fn get_numa_node_cpus(node: u32) -> Result<Vec<u32>> {
+    let path = Path::new(NUMA_NODES_PATH)
+        .join(format!("node{}", node))
+        .join("cpulist");
+
+    let cpulist =
+        std::fs::read_to_string(&path).context(format!("host NUMA node {} not found", node))?;
+
+    parse_cpu_list(cpulist.trim()).ok_or_else(|| anyhow!("invalid CPU list in {:?}", path))
+}
+
+// Parse a CPU list, such as "0-3,8,10-11".
+fn parse_cpu_list(cpulist: &str) -> Option<Vec<u32>> {
+    let mut cpus = vec![];
+
+    for range in cpulist.split(',').filter(|r| !r.is_empty()) {
+        let (first, last): (u32, u32) = match range.split_once('-') {
+            Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+            None => {
+                let cpu = range.parse().ok()?;
+                (cpu, cpu)
+            }
+        };
+
+        if first > last {
+            return None;
+        }
+
+        cpus.extend(first..=last);
+    }
+
+    Some(cpus)
+}
+
+// Return the free memory in MiB of a host NUMA node. With huge pages, this
+// is the size of its free huge pages of the specified size, or of the
+// default size of the host.
+pub fn get_numa_node_free_mb(
+    node: u32,
+    hugepages: bool,
+    hugepage_size: Option<u64>,
+) -> Result<u64> {
+    let node_path = Path::new(NUMA_NODES_PATH).join(format!("node{}", node));
+
+    if !hugepages {
+        let path = node_path.join("meminfo");
+
+        let meminfo =
+            std::fs::read_to_string(&path).context(format!("failed to read {:?}", path))?;
+
+        let kb = parse_meminfo_kb(&meminfo, "MemFree:")
+            .ok_or_else(|| anyhow!("failed to find free memory in {:?}", path))?;
+
+        return Ok(kb / 1024);
+    }
+
+    let size_kb = match hugepage_size {
+        Some(size) => size / 1024,
+        None => {
+            let meminfo = std::fs::read_to_string(MEMINFO_PATH)
+                .context(format!("failed to read {:?}", MEMINFO_PATH))?;
+
+            parse_meminfo_kb(&meminfo, "Hugepagesize:")
+                .ok_or_else(|| anyhow!("failed to find the huge page size in {:?}", MEMINFO_PATH))?
+        }
+    };
+
+    let path = node_path
+        .join("hugepages")
+        .join(format!("hugepages-{}kB", size_kb))
+        .join("free_hugepages");
+
+    let pages: u64 = std::fs::read_to_string(&path)
+        .context(format!("failed to read {:?}", path))?
+        .trim()
+        .parse()
+        .context(format!("invalid number of free huge pages in {:?}", path))?;
+
+    Ok(pages * size_kb / 1024)
+}
+
+// Return the value in kB of a meminfo field, such as "MemFree:", in the
+// format of /proc/meminfo or of the meminfo of a NUMA node, whose lines
+// start with "Node <id>".
+fn parse_meminfo_kb(meminfo: &str, field: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let mut tokens = line.split_whitespace().skip_while(|t| *t != field);
+
+        tokens.next()?;
+        tokens.next()?.parse().ok()
+    })
+}
+
+// Return the sysfs directory of a PCI device, whose address may omit the
+// domain.
+pub fn get_pci_device_path(bdf: &str) -> PathBuf {
+    if bdf.matches(':').count() == 1 {
+        Path::new(PCI_DEVICES_PATH).join(format!("0000:{}", bdf))
+    } else {
+        Path::new(PCI_DEVICES_PATH).join(bdf)
+    }
+}
+
+// Return the size in bytes of the file or block device backing a disk.
+pub fn get_backing_size(path: &Path) -> Result<u64> {
+    let mut file = File::open(path).context(format!("failed to open {:?}", path))?;
+
+    let metadata = file
+        .metadata()
+        .context(format!("failed to stat {:?}", path))?;
+
+    if !metadata.is_file() && !metadata.file_type().is_block_device() {
+        return Err(anyhow!("{:?} is not a file or a block device", path));
+    }
+
+    // The size of a block device is only known by seeking to its end.
+    file.seek(SeekFrom::End(0))
+        .context(format!("failed to get the size of {:?}", path))
+}
+
+// Return the size in bytes of the memory BARs of the PCI device of the
+// sysfs directory.
+pub fn get_pci_bars_size(device_path: &Path) -> Result<u64> {
+    let path = device_path.join("resource");
+
+    let resource = std::fs::read_to_string(&path).context(format!("failed to read {:?}", path))?;
+
+    parse_pci_bars_size(&resource).ok_or_else(|| anyhow!("invalid PCI resources in {:?}", path))
+}
+
+fn parse_pci_bars_size(resource: &str) -> Option<u64> {
+    let parse = |field: &str| u64::from_str_radix(field.trim_start_matches("0x"), 16).ok();
+
+    let mut size: u64 = 0;
+
+    for line in resource.lines().take(PCI_STD_NUM_BARS) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        if fields.len() != 3 {
+            return None;
+        }
+
+        let (start, end, flags) = (parse(fields[0])?, parse(fields[1])?, parse(fields[2])?);
+
+        if flags & IORESOURCE_MEM == 0 || end <= start {
+            continue;
+        }
+
+        size = size.checked_add(end - start + 1)?;
+    }
+
+    Some(size)
+}
+
+// Return the canonical path of the specified binary, failing if it is not
+// an executable regular file.
+pub fn get_executable_path(path: &str) -> Result<PathBuf> {
+    let path = Path::new(path)
+        .canonicalize()
+        .context(format!("invalid binary path {:?}", path))?;
+
+    let metadata = path
+        .metadata()
+        .context(format!("failed to stat binary {:?}", path))?;
+
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return Err(anyhow!("binary {:?} is not an executable file", path));
+    }
+
+    Ok(path)
+}
+
+// Return the path for a _hypothetical_ guest console log file:
+// the path does *not* exist yet, and for this reason safe-path cannot be
+// used.
+pub fn get_console_log_path(id: &str) -> Result<String> {
+    let sandbox_path = get_sandbox_path(id)?;
+
+    let path = [&sandbox_path, CH_CONSOLE_LOG_NAME].join("/");
+
+    Ok(path)
+}
+
+// Return the path for a _hypothetical_ CH log file:
+// the path does *not* exist yet, and for this reason safe-path cannot be
+// used.
+pub fn get_vmm_log_path(id: &str) -> Result<String> {
+    let sandbox_path = get_sandbox_path(id)?;
+
+    let path = [&sandbox_path, CH_VMM_LOG_NAME].join("/");
+
+    Ok(path)
+}
+
+// Create (or truncate) a log file which is only readable by its owner and
+// group.
+pub fn create_log_file(path: &str) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o640)
+        .open(path)
+        .context(format!("failed to create log file {}", path))?;
+
+    Ok(())
+}
+
+// Return the last lines of the specified file.
+pub fn get_file_tail(path: &str, lines: usize) -> Result<Vec<String>> {
+    let file = File::open(path).context(format!("failed to open {}", path))?;
+
+    let all: Vec<String> = BufReader::new(file).lines().collect::<Result<_, _>>()?;
+
+    let start = all.len().saturating_sub(lines);
+
+    Ok(all[start..].to_vec())
+}
+
+// Move an existing log file to "<path>.1", only retaining the last
+// max_bytes of it. Any previously rotated file is replaced.
+pub fn rotate_log_file(path: &str, max_bytes: u64) -> Result<()> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+
+    let rotated_path = format!("{}.1", path);
+
+    let mut file = File::open(path).context(format!("failed to open {}", path))?;
+
+    let len = file.metadata()?.len();
+
+    if len > max_bytes {
+        file.seek(SeekFrom::Start(len - max_bytes))?;
+    }
+
+    let mut rotated =
+        File::create(&rotated_path).context(format!("failed to create {}", rotated_path))?;
+
+    std::io::copy(&mut file, &mut rotated)?;
+
+    std::fs::remove_file(path).context(format!("failed to remove {}", path))?;
+
+    Ok(())
+}
+
+// Only allow the owner of a unix socket, and the members of its group if
+// one is specified, to connect to it, since connecting requires write
+// permission on the socket file. The owner is left unchanged if no uid is
+// specified.
+pub fn restrict_socket_access(path: &str, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    chown(path, uid.map(Uid::from_raw), gid.map(Gid::from_raw))
+        .context(format!("failed to change owner of {}", path))?;
+
+    let mode = if gid.is_some() { 0o660 } else { 0o600 };
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .context(format!("failed to set permissions of {}", path))?;
+
+    Ok(())
+}
+
+// Check a user can read and write a file, such as the hypervisor device,
+// based on the file mode. The user is not a member of any supplementary
+// group once CH is spawned as that user.
+pub fn check_user_access(path: &str, uid: u32, gid: u32) -> Result<()> {
+    let metadata = std::fs::metadata(path).context(format!("failed to stat {}", path))?;
+
+    if uid == 0 {
+        return Ok(());
+    }
+
+    let mode = metadata.permissions().mode();
+
+    let access = if metadata.uid() == uid {
+        mode >> 6
+    } else if metadata.gid() == gid {
+        mode >> 3
+    } else {
+        mode
+    };
+
+    if access & 0o6 != 0o6 {
+        return Err(anyhow!(
+            "user {}:{} cannot open {} (mode {:o}, owner {}:{})",
+            uid,
+            gid,
+            path,
+            mode & 0o777,
+            metadata.uid(),
+            metadata.gid()
+        ));
+    }
+
+    Ok(())
+}
+
+// Return the pid and uid of the process at the other end of a connected
+// unix socket (SO_PEERCRED).
+pub fn get_peer_credentials(socket: &UnixStream) -> Result<(i32, u32)> {
+    let creds = getsockopt(socket.as_raw_fd(), PeerCredentials)
+        .context("failed to get socket peer credentials")?;
+
+    Ok((creds.pid(), creds.uid()))
+}
+
+// Return true if a unix socket is bound to the path and listening.
+//
+// Listening is checked in /proc/net/unix rather than by connecting, as
+// vhost-user daemons such as virtiofsd serve a single connection and exit
+// once it is closed. Only the sockets of the network namespace of the caller
+// are listed there, so a socket which is not listed is assumed listening.
+pub fn is_unix_socket_listening(path: &Path) -> bool {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => (),
+        _ => return false,
+    }
+
+    std::fs::read_to_string("/proc/net/unix")
+        .ok()
+        .and_then(|table| get_unix_socket_listening(&table, path))
+        .unwrap_or(true)
+}
+
+// Return whether a socket bound to the path is listening, according to the
+// content of /proc/net/unix, or None if no socket is bound to it. The
+// __SO_ACCEPTCON flag is set on listening sockets.
+fn get_unix_socket_listening(table: &str, path: &Path) -> Option<bool> {
+    const SO_ACCEPTCON: u32 = 0x10000;
+
+    let flags: Vec<u32> = table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            match fields.get(7) {
+                Some(p) if Path::new(p) == path => u32::from_str_radix(fields[3], 16).ok(),
+                _ => None,
+            }
+        })
+        .collect();
+
+    if flags.is_empty() {
+        return None;
+    }
+
+    Some(flags.iter().any(|f| f & SO_ACCEPTCON != 0))
+}
+
+// Set the OOM score adjustment of the process of the /proc directory.
+pub fn set_oom_score_adj(proc_dir: &Path, adj: i32) -> Result<()> {
+    let path = proc_dir.join("oom_score_adj");
+
+    std::fs::write(&path, adj.to_string()).context(format!("failed to write {:?}", path))
+}
+
+// Return true if the process of the /proc directory is running. A zombie,
+// which exited but was not reaped by its parent yet, is not.
+pub fn is_process_running(proc_dir: &Path) -> bool {
+    let stat = match std::fs::read_to_string(proc_dir.join("stat")) {
+        Ok(stat) => stat,
+        Err(_) => return false,
+    };
+
+    // The state follows the command name, which may contain spaces.
+    let state = stat
+        .rfind(')')
+        .and_then(|i| stat[i + 1..].split_whitespace().next());
+
+    !matches!(state, None | Some("Z") | Some("X"))
+}
+
+// Return true if the command line of the process of the /proc directory
+// has the path as an argument, alone or as the value of an option such as
+// "--socket-path=<path>".
+pub fn is_process_using_path(proc_dir: &Path, path: &Path) -> bool {
+    let cmdline = match std::fs::read(proc_dir.join("cmdline")) {
+        Ok(cmdline) => cmdline,
+        Err(_) => return false,
+    };
+
+    let path = path.display().to_string();
+    let option_suffix = format!("={}", path);
+
+    cmdline
+        .split(|b| *b == 0)
+        .map(String::from_utf8_lossy)
+        .any(|arg| arg == path || arg.ends_with(&option_suffix))
+}
+
+// Check a guest process accepts connections on a vsock port, through the
+// hybrid vsock socket of CH: CH replies "OK <host port>" once the guest
+// accepted the connection, and closes the connection otherwise.
+pub fn check_hybrid_vsock_port(path: &str, port: u32, timeout: Duration) -> bool {
+    let connect = || -> std::io::Result<bool> {
+        let mut socket = UnixStream::connect(path)?;
+
+        socket.set_read_timeout(Some(timeout))?;
+        socket.set_write_timeout(Some(timeout))?;
+
+        socket.write_all(format!("CONNECT {}\n", port).as_bytes())?;
+
+        let mut reply = String::new();
+        BufReader::new(socket).read_line(&mut reply)?;
+
+        Ok(reply.starts_with("OK "))
+    };
+
+    connect().unwrap_or_default()
+}
+
+// Return the path of the socket the hybrid vsock of CH connects the guest to
+// when it connects to a port of the host.
+#[cfg(target_arch = "x86_64")]
+pub fn get_hybrid_vsock_host_path(vsock_path: &str, port: u32) -> String {
+    format!("{}_{}", vsock_path, port)
+}
+
+// Return the path of the TDX quote generation socket, configured as
+// "unix:<path>" or "<path>". The guest reaches the host through the hybrid
+// vsock of CH, which only connects to unix sockets, so a service listening on
+// the host vsock is not supported.
+pub fn parse_qgs_socket(socket: &str) -> Result<PathBuf> {
+    if socket.starts_with("vsock:") {
+        return Err(anyhow!(
+            "TDX quote generation socket {} not supported: the hybrid vsock only connects the guest to unix sockets",
+            socket
+        ));
+    }
+
+    let path = Path::new(socket.strip_prefix("unix:").unwrap_or(socket));
+
+    if !path.is_absolute() {
+        return Err(anyhow!(
+            "TDX quote generation socket {} is not an absolute path",
+            socket
+        ));
+    }
+
+    Ok(path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_get_missing_kvm_hint() {
+        let cpuinfo = "processor\t: 0\nflags\t\t: fpu vme vmx sse\n";
+        assert!(get_missing_kvm_hint(cpuinfo).contains("modules"));
+
+        let cpuinfo = "processor\t: 0\nflags\t\t: fpu vme hypervisor sse\n";
+        assert!(get_missing_kvm_hint(cpuinfo).contains("nested"));
+
+        let cpuinfo = "processor\t: 0\nflags\t\t: fpu vme sse\n";
+        assert!(get_missing_kvm_hint(cpuinfo).contains("firmware"));
+    }
+
+    #[test]
+    fn test_get_cgroup_file_paths() {
+        let root = std::env::temp_dir().join(format!("ch-cgroup-test-{}", std::process::id()));
+
+        // cgroup v1: the cgroup only exists in some hierarchies.
+        let v1 = root.join("v1");
+        std::fs::create_dir_all(v1.join("cpu/kata/vm")).unwrap();
+        std::fs::create_dir_all(v1.join("memory/kata/vm")).unwrap();
+        std::fs::create_dir_all(v1.join("pids")).unwrap();
+
+        assert_eq!(
+            get_cgroup_file_paths(&v1, "/kata/vm", CGROUP_PROCS, CGROUP_PROCS).unwrap(),
+            vec![
+                v1.join("cpu/kata/vm/cgroup.procs"),
+                v1.join("memory/kata/vm/cgroup.procs"),
+            ]
+        );
+        assert_eq!(
+            get_cgroup_file_paths(&v1, "kata/vm", "tasks", "cgroup.threads").unwrap(),
+            vec![
+                v1.join("cpu/kata/vm/tasks"),
+                v1.join("memory/kata/vm/tasks")
+            ]
+        );
+
+        assert!(get_cgroup_file_paths(&v1, "kata/missing", CGROUP_PROCS, CGROUP_PROCS).is_err());
+        assert!(get_cgroup_file_paths(&v1, "/", CGROUP_PROCS, CGROUP_PROCS).is_err());
+
+        // cgroup v2
+        let v2 = root.join("v2");
+        std::fs::create_dir_all(v2.join("kata/vm")).unwrap();
+        std::fs::write(v2.join(CGROUP_V2_CONTROLLERS), "cpu memory").unwrap();
+
+        assert_eq!(
+            get_cgroup_file_paths(&v2, "kata/vm", CGROUP_PROCS, CGROUP_PROCS).unwrap(),
+            vec![v2.join("kata/vm/cgroup.procs")]
+        );
+        assert_eq!(
+            get_cgroup_file_paths(&v2, "kata/vm", "tasks", "cgroup.threads").unwrap(),
+            vec![v2.join("kata/vm/cgroup.threads")]
+        );
+
+        assert!(get_cgroup_file_paths(&v2, "kata/missing", CGROUP_PROCS, CGROUP_PROCS).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_meminfo_total_mb() {
+        let meminfo = "MemTotal:       16303428 kB\nMemFree:         1030208 kB\n";
+        assert_eq!(parse_meminfo_total_mb(meminfo), Some(15921));
+
+        assert_eq!(parse_meminfo_total_mb("MemFree: 1030208 kB\n"), None);
+        assert_eq!(parse_meminfo_total_mb("MemTotal: foo kB\n"), None);
+    }
+
+    #[test]
+    fn test_parse_cpuinfo_phys_bits() {
+        let cpuinfo = "processor\t: 0\naddress sizes\t: 46 bits physical, 48 bits virtual\n";
+        assert_eq!(parse_cpuinfo_phys_bits(cpuinfo), Some(46));
+
+        assert_eq!(parse_cpuinfo_phys_bits("processor\t: 0\n"), None);
+        assert_eq!(
+            parse_cpuinfo_phys_bits("address sizes\t: foo bits physical, 48 bits virtual\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_hypervisor_device_selection() {
+        assert_eq!(get_hypervisor_device_path("kvm"), KVM_DEVICE);
+        assert_eq!(get_hypervisor_device_path("mshv"), MSHV_DEVICE);
+        assert_eq!(get_hypervisor_feature("kvm"), CH_HYPERVISOR_DEVICE_KVM);
+        assert_eq!(get_hypervisor_feature("mshv"), CH_HYPERVISOR_DEVICE_MSHV);
+
+        let err = check_host_hypervisor("xen").unwrap_err().to_string();
+        assert!(err.contains("invalid hypervisor device"));
+
+        // A missing device node is reported with its path.
+        let err = check_hypervisor_device_node("/dev/not-a-hypervisor")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("/dev/not-a-hypervisor not found"));
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+        assert_eq!(parse_cpu_list(""), Some(vec![]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a-b"), None);
+    }
+
+    #[test]
+    fn test_parse_meminfo_kb() {
+        let node_meminfo =
+            "Node 1 MemTotal:       32768000 kB\nNode 1 MemFree:        1024000 kB\n";
+        assert_eq!(parse_meminfo_kb(node_meminfo, "MemFree:"), Some(1024000));
+
+        let meminfo = "MemTotal:       16303428 kB\nHugepagesize:       2048 kB\n";
+        assert_eq!(parse_meminfo_kb(meminfo, "Hugepagesize:"), Some(2048));
+        assert_eq!(parse_meminfo_kb(meminfo, "MemFree:"), None);
+    }
+
+    #[test]
+    fn test_parse_pci_bars_size() {
+        // A 16 MiB and a 64-bit 256 MiB memory BAR, an I/O BAR, and an
+        // expansion ROM, which is not counted.
+        let resource = "\
+0x00000000fd000000 0x00000000fdffffff 0x0000000000040200
+0x0000000000000000 0x0000000000000000 0x0000000000000000
+0x00000000e0000000 0x00000000efffffff 0x000000000014220c
+0x0000000000000000 0x0000000000000000 0x0000000000000000
+0x000000000000e000 0x000000000000e07f 0x0000000000040101
+0x0000000000000000 0x0000000000000000 0x0000000000000000
+0x00000000fe000000 0x00000000fe07ffff 0x0000000000046200
+";
+
+        assert_eq!(parse_pci_bars_size(resource), Some(272 << 20));
+        assert_eq!(parse_pci_bars_size(""), Some(0));
+        assert_eq!(parse_pci_bars_size("0x0 0x0\n"), None);
+        assert_eq!(parse_pci_bars_size("0x0 foo 0x200\n"), None);
+
+        assert_eq!(
+            get_pci_device_path("01:00.0"),
+            Path::new("/sys/bus/pci/devices/0000:01:00.0")
+        );
+        assert_eq!(
+            get_pci_device_path("0001:01:00.0"),
+            Path::new("/sys/bus/pci/devices/0001:01:00.0")
+        );
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("5.15.0-91-generic"), Some((5, 15)));
+        assert_eq!(parse_version("6.1.55"), Some((6, 1)));
+        assert_eq!(parse_version("4.19"), Some((4, 19)));
+        assert_eq!(parse_version("6"), None);
+        assert_eq!(parse_version("foo"), None);
+    }
+
+    #[test]
+    fn test_get_backing_size() {
+        let dir = std::env::temp_dir().join(format!("ch-backing-{}", std::process::id()));
+        let path = dir.join("disk.img");
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, vec![0u8; 4096]).unwrap();
+
+        assert_eq!(get_backing_size(&path).unwrap(), 4096);
+        assert!(get_backing_size(&dir).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(get_backing_size(&path).is_err());
+    }
+
+    #[test]
+    fn test_get_vcpu_thread_ids() {
+        let root = std::env::temp_dir().join(format!("ch-task-{}", std::process::id()));
+
+        for (tid, comm) in [
+            ("100", "cloud-hypervisor"),
+            ("101", "vcpu0"),
+            ("102", "vcpu1"),
+            ("103", "virtio-blk0"),
+            ("104", "vcpu12"),
+        ] {
+            std::fs::create_dir_all(root.join(tid)).unwrap();
+            std::fs::write(root.join(tid).join("comm"), format!("{}\n", comm)).unwrap();
+        }
+
+        // A thread which exited while the directory was read.
+        std::fs::create_dir_all(root.join("105")).unwrap();
+
+        let vcpus = get_vcpu_thread_ids(&root).unwrap();
+
+        assert_eq!(vcpus, HashMap::from([(0, 101), (1, 102), (12, 104)]));
+
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(get_vcpu_thread_ids(&root).is_err());
+    }
+
+    #[test]
+    fn test_parse_ch_version() {
+        assert_eq!(parse_ch_version("cloud-hypervisor v32.0"), Some((32, 0)));
+        assert_eq!(parse_ch_version("v36.0-12-gabcdef"), Some((36, 0)));
+        assert_eq!(parse_ch_version("cloud-hypervisor 32.0"), None);
+        assert_eq!(parse_ch_version(""), None);
+    }
+
+    #[test]
+    fn test_socket_credentials() {
+        let (socket, _peer) = UnixStream::pair().unwrap();
+
+        let (pid, uid) = get_peer_credentials(&socket).unwrap();
+
+        assert_eq!(pid, std::process::id() as i32);
+        assert_eq!(uid, nix::unistd::geteuid().as_raw());
+
+        let path = std::env::temp_dir().join(format!("ch-socket-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, "").unwrap();
+
+        restrict_socket_access(path, Some(uid), None).unwrap();
+
+        let metadata = std::fs::metadata(path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(metadata.uid(), uid);
+
+        restrict_socket_access(path, None, Some(metadata.gid())).unwrap();
+
+        let metadata = std::fs::metadata(path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_check_hybrid_vsock_port() {
+        let path = std::env::temp_dir().join(format!("ch-hvsock-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let _ = std::fs::remove_file(path);
+
+        let listener = std::os::unix::net::UnixListener::bind(path).unwrap();
+
+        // Accept the connection to port 1024 only, like CH with a guest
+        // listening on that port.
+        let server = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (socket, _) = listener.accept().unwrap();
+
+                let mut request = String::new();
+                BufReader::new(&socket).read_line(&mut request).unwrap();
+
+                if request == "CONNECT 1024\n" {
+                    (&socket).write_all(b"OK 1073741824\n").unwrap();
+                }
+            }
+        });
+
+        assert!(check_hybrid_vsock_port(path, 1024, Duration::from_secs(5)));
+        assert!(!check_hybrid_vsock_port(path, 1025, Duration::from_secs(5)));
+
+        server.join().unwrap();
+
+        std::fs::remove_file(path).unwrap();
+
+        assert!(!check_hybrid_vsock_port(path, 1024, Duration::from_secs(5)));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_is_kvm_param_enabled() {
+        assert!(is_kvm_param_enabled("Y\n"));
+        assert!(is_kvm_param_enabled("1"));
+        assert!(!is_kvm_param_enabled("N\n"));
+        assert!(!is_kvm_param_enabled(""));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_check_sev_snp() {
+        let dir = std::env::temp_dir().join(format!("ch-sev-snp-{}", std::process::id()));
+        let device = dir.join("sev");
+        let param = dir.join("sev_snp");
+
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let err = check_sev_snp(&device, &param).unwrap_err().to_string();
+        assert!(err.contains("AMD secure processor is unavailable"));
+
+        std::fs::write(&device, "").unwrap();
+
+        assert!(check_sev_snp(&device, &param).is_err());
+
+        std::fs::write(&param, "N\n").unwrap();
+
+        let err = check_sev_snp(&device, &param).unwrap_err().to_string();
+        assert!(err.starts_with("host not SEV-SNP capable"));
+        assert!(err.contains("not initialized"));
+
+        std::fs::write(&param, "Y\n").unwrap();
+
+        assert!(check_sev_snp(&device, &param).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_qgs_socket() {
+        assert_eq!(
+            parse_qgs_socket("unix:/run/qgs.sock").unwrap(),
+            PathBuf::from("/run/qgs.sock")
+        );
+        assert_eq!(
+            parse_qgs_socket("/run/qgs.sock").unwrap(),
+            PathBuf::from("/run/qgs.sock")
+        );
+        assert!(parse_qgs_socket("unix:qgs.sock").is_err());
+        assert!(parse_qgs_socket("vsock:4050").is_err());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_get_hybrid_vsock_host_path() {
+        assert_eq!(
+            get_hybrid_vsock_host_path("/run/kata/sandbox/ch-vm.sock", 4050),
+            "/run/kata/sandbox/ch-vm.sock_4050"
+        );
+    }
+
+    #[test]
+    fn test_get_unix_socket_listening() {
+        let table = "Num       RefCount Protocol Flags    Type St Inode Path\n\
+                     0000000000000000: 00000002 00000000 00010000 0001 01 2001 /run/vfsd.sock\n\
+                     0000000000000000: 00000002 00000000 00000000 0001 01 2002 /run/bound.sock\n\
+                     0000000000000000: 00000003 00000000 00000000 0001 03 2003\n";
+
+        assert_eq!(
+            get_unix_socket_listening(table, Path::new("/run/vfsd.sock")),
+            Some(true)
+        );
+        assert_eq!(
+            get_unix_socket_listening(table, Path::new("/run/bound.sock")),
+            Some(false)
+        );
+        assert_eq!(
+            get_unix_socket_listening(table, Path::new("/run/other.sock")),
+            None
+        );
+
+        let path = std::env::temp_dir().join(format!("ch-listening-{}", std::process::id()));
+
+        let _ = std::fs::remove_file(&path);
+
+        assert!(!is_unix_socket_listening(&path));
+
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        assert!(is_unix_socket_listening(&path));
+
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_ch_features() {
+        let output = "cloud-hypervisor v36.0\nEnabled features: [\"kvm\", \"io_uring\"]\n";
+
+        assert_eq!(
+            parse_ch_features(output),
+            Some(vec!["kvm".to_string(), "io_uring".to_string()])
+        );
+        assert_eq!(
+            parse_ch_features("cloud-hypervisor v36.0\nEnabled features: []\n"),
+            Some(vec![])
+        );
+
+        // Not reported.
+        assert_eq!(parse_ch_features("cloud-hypervisor v32.0\n"), None);
+    }
+
+    #[test]
+    fn test_get_ch_features() {
+        let path = std::env::temp_dir().join(format!("ch-features-{}", std::process::id()));
+
+        std::fs::write(
+            &path,
+            "#!/bin/sh\necho cloud-hypervisor v36.0\necho 'Enabled features: [\"kvm\"]'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(
+            get_ch_features(&path).unwrap(),
+            Some(vec!["kvm".to_string()])
+        );
+
+        // The features are cached.
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            get_ch_features(&path).unwrap(),
+            Some(vec!["kvm".to_string()])
+        );
+        assert!(get_ch_features(Path::new("/not/a/binary")).is_err());
+    }
+
+    #[test]
+    fn test_set_oom_score_adj() {
+        let proc_dir = std::env::temp_dir().join(format!("ch-oom-{}", std::process::id()));
+
+        std::fs::create_dir_all(&proc_dir).unwrap();
+
+        set_oom_score_adj(&proc_dir, -500).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(proc_dir.join("oom_score_adj")).unwrap(),
+            "-500"
+        );
+
+        std::fs::remove_dir_all(&proc_dir).unwrap();
+
+        assert!(set_oom_score_adj(&proc_dir, 0).is_err());
+    }
+
+    #[test]
+    fn test_is_process_running() {
+        assert!(is_process_running(Path::new("/proc/self")));
+        assert!(!is_process_running(Path::new("/proc/not-a-pid")));
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let proc_dir = PathBuf::from(format!("/proc/{}", child.id()));
+
+        // The child is a zombie until it is waited for.
+        for _ in 0..100 {
+            if !is_process_running(&proc_dir) {
+                break;
+            }
+
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(proc_dir.exists());
+        assert!(!is_process_running(&proc_dir));
+
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_is_process_using_path() {
+        let socket = Path::new("/run/kata/vm/foo/virtiofsd.sock");
+
+        // The extra argument is $0 of the shell.
+        let mut child = std::process::Command::new("sh")
+            .args([
+                "-c",
+                "sleep 10; true",
+                "--socket-path=/run/kata/vm/foo/virtiofsd.sock",
+            ])
+            .spawn()
+            .unwrap();
+        let proc_dir = PathBuf::from(format!("/proc/{}", child.id()));
+
+        assert!(is_process_using_path(&proc_dir, socket));
+        assert!(!is_process_using_path(
+            &proc_dir,
+            Path::new("/run/kata/vm/foo/virtiofsd")
+        ));
+        assert!(!is_process_using_path(Path::new("/proc/not-a-pid"), socket));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_check_user_access() {
+        let path = std::env::temp_dir().join(format!("ch-access-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, "").unwrap();
+
+        let metadata = std::fs::metadata(path).unwrap();
+        let (uid, gid) = (metadata.uid(), metadata.gid());
+        let (other_uid, other_gid) = (uid + 1000, gid + 1000);
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660)).unwrap();
+
+        assert!(check_user_access(path, 0, 0).is_ok());
+        assert!(check_user_access(path, other_uid, gid).is_ok());
+        assert!(check_user_access(path, other_uid, other_gid).is_err());
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o606)).unwrap();
+
+        assert!(check_user_access(path, other_uid, gid).is_err());
+        assert!(check_user_access(path, other_uid, other_gid).is_ok());
+
+        std::fs::remove_file(path).unwrap();
+
+        assert!(check_user_access(path, other_uid, other_gid).is_err());
+    }
+}
@@ -4,6 +4,8 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use super::DeviceLabels;
+
 #[derive(Debug)]
 pub struct BlockConfig {
     /// Unique identifier of the drive.
@@ -21,4 +23,7 @@ pub struct BlockConfig {
 
     /// device index
     pub index: u64,
+
+    /// labels: labels of the device, see DeviceLabels.
+    pub labels: DeviceLabels,
 }
@@ -9,16 +9,31 @@ pub use block::BlockConfig;
 mod network;
 pub use network::{Address, NetworkConfig};
 mod share_fs_device;
-pub use share_fs_device::ShareFsDeviceConfig;
+pub use share_fs_device::{
+    ShareFsDeviceConfig, ShareFsDeviceConfigBuilder, SHARE_FS_TYPE_INLINE_VIRTIO_FS,
+    SHARE_FS_TYPE_VIRTIO_FS,
+};
 mod vfio;
 pub use vfio::{bind_device_to_host, bind_device_to_vfio, VfioBusMode, VfioConfig};
 mod share_fs_mount;
 pub use share_fs_mount::{ShareFsMountConfig, ShareFsMountType, ShareFsOperation};
 mod vsock;
 pub use vsock::{HybridVsockConfig, VsockConfig};
+mod vhost_user_net;
+pub use vhost_user_net::VhostUserNetConfig;
+mod pmem;
+pub use pmem::PmemDeviceConfig;
 
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
 
+/// DeviceLabels: arbitrary key/value pairs attached to a device, such as
+/// the volume it originates from. The hypervisor logs them along with the
+/// events of the device and returns them with the devices added to the VM.
+/// They do not change how the device is added to the VM.
+pub type DeviceLabels = BTreeMap<String, String>;
+
 #[derive(Debug)]
 pub enum Device {
     Block(BlockConfig),
@@ -28,6 +43,47 @@ pub enum Device {
     ShareFsMount(ShareFsMountConfig),
     Vsock(VsockConfig),
     HybridVsock(HybridVsockConfig),
+    VhostUserNet(VhostUserNetConfig),
+    Pmem(PmemDeviceConfig),
+}
+
+/// AddedDevice: what the guest needs to use a device added to the VM.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AddedDevice {
+    /// id: id of the device in the VM, empty if it has none.
+    pub id: String,
+
+    /// guest_pci_address: PCI address of the device in the guest, if known.
+    pub guest_pci_address: Option<String>,
+
+    /// mount_tag: tag the guest mounts a share fs device by.
+    pub mount_tag: Option<String>,
+
+    /// guest_mount_point: where the guest is expected to mount a share fs
+    /// device.
+    pub guest_mount_point: Option<String>,
+
+    /// labels: labels of the device, see DeviceLabels.
+    pub labels: DeviceLabels,
+}
+
+impl Device {
+    /// Return the labels of the device, if it has any.
+    pub fn labels(&self) -> Option<&DeviceLabels> {
+        let labels = match self {
+            Device::Block(cfg) => &cfg.labels,
+            Device::Network(cfg) => &cfg.labels,
+            Device::ShareFsDevice(cfg) => &cfg.labels,
+            Device::Vfio(cfg) => &cfg.labels,
+            Device::Vsock(cfg) => &cfg.labels,
+            Device::HybridVsock(cfg) => &cfg.labels,
+            Device::VhostUserNet(cfg) => &cfg.labels,
+            Device::Pmem(cfg) => &cfg.labels,
+            Device::ShareFsMount(_) => return None,
+        };
+
+        Some(labels).filter(|labels| !labels.is_empty())
+    }
 }
 
 impl fmt::Display for Device {
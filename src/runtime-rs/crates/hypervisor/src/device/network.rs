@@ -4,6 +4,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use super::DeviceLabels;
 use std::fmt;
 
 pub struct Address(pub [u8; 6]);
@@ -29,4 +30,7 @@ pub struct NetworkConfig {
 
     /// Guest MAC address.
     pub guest_mac: Option<Address>,
+
+    /// labels: labels of the device, see DeviceLabels.
+    pub labels: DeviceLabels,
 }
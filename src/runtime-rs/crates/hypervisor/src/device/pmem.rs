@@ -0,0 +1,28 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use super::DeviceLabels;
+
+/// PmemDeviceConfig: persistent memory device config, backed by a host file.
+#[derive(Debug)]
+pub struct PmemDeviceConfig {
+    /// Unique identifier of the device
+    pub id: String,
+
+    /// path_on_host: path of the backing file on the host.
+    pub path_on_host: String,
+
+    /// size: size in bytes of the device, the size of the backing file if None.
+    pub size: Option<u64>,
+
+    /// iommu: place the device behind the virtual IOMMU.
+    pub iommu: bool,
+
+    /// discard_writes: do not write the guest changes back to the backing file.
+    pub discard_writes: bool,
+
+    /// labels: labels of the device, see DeviceLabels.
+    pub labels: DeviceLabels,
+}
@@ -4,6 +4,20 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use super::DeviceLabels;
+
+/// Share fs backed by an external vhost-user virtiofs daemon.
+pub const SHARE_FS_TYPE_VIRTIO_FS: &str = "virtio-fs";
+/// Share fs backed by the virtiofs daemon built into the VMM.
+pub const SHARE_FS_TYPE_INLINE_VIRTIO_FS: &str = "inline-virtio-fs";
+
+// Size of the tag field in the virtio-fs device configuration space.
+const VIRTIO_FS_MAX_TAG_LEN: usize = 36;
+
 /// ShareFsDeviceConfig: share fs device config
 #[derive(Debug)]
 pub struct ShareFsDeviceConfig {
@@ -24,4 +38,223 @@ pub struct ShareFsDeviceConfig {
 
     /// queue_num: queue number
     pub queue_num: u64,
+
+    /// coalesce: set if the socket is deliberately shared with other share
+    /// fs devices.
+    pub coalesce: bool,
+
+    /// guest_mount_point: where the guest is expected to mount the volume,
+    /// empty if left to the guest.
+    pub guest_mount_point: String,
+
+    /// labels: labels of the device, see DeviceLabels.
+    pub labels: DeviceLabels,
+}
+
+/// ShareFsDeviceConfigBuilder: builds a validated ShareFsDeviceConfig.
+///
+/// The queue number and queue size must either both be set, or both be
+/// left unset to use the hypervisor defaults.
+#[derive(Debug, Default, Clone)]
+pub struct ShareFsDeviceConfigBuilder {
+    fs_type: String,
+    sock_path: String,
+    mount_tag: String,
+    host_path: String,
+    queue_size: u64,
+    queue_num: u64,
+    coalesce: bool,
+    guest_mount_point: String,
+    labels: DeviceLabels,
+}
+
+impl ShareFsDeviceConfigBuilder {
+    pub fn new(fs_type: &str) -> Self {
+        ShareFsDeviceConfigBuilder {
+            fs_type: fs_type.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn sock_path(mut self, path: &str) -> Self {
+        self.sock_path = path.to_string();
+        self
+    }
+
+    pub fn mount_tag(mut self, tag: &str) -> Self {
+        self.mount_tag = tag.to_string();
+        self
+    }
+
+    pub fn host_path(mut self, path: &str) -> Self {
+        self.host_path = path.to_string();
+        self
+    }
+
+    pub fn queues(mut self, queue_num: u64, queue_size: u64) -> Self {
+        self.queue_num = queue_num;
+        self.queue_size = queue_size;
+        self
+    }
+
+    pub fn coalesce(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    pub fn guest_mount_point(mut self, path: &str) -> Self {
+        self.guest_mount_point = path.to_string();
+        self
+    }
+
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn build(&self) -> Result<ShareFsDeviceConfig> {
+        match self.fs_type.as_str() {
+            SHARE_FS_TYPE_VIRTIO_FS => {
+                // The socket of an external daemon is looked up relative
+                // to the VMM working directory otherwise.
+                if !Path::new(&self.sock_path).is_absolute() {
+                    return Err(anyhow!(
+                        "share fs socket path must be absolute: {:?}",
+                        self.sock_path
+                    ));
+                }
+            }
+            SHARE_FS_TYPE_INLINE_VIRTIO_FS => (),
+            _ => return Err(anyhow!("invalid share fs type: {:?}", self.fs_type)),
+        }
+
+        if self.mount_tag.is_empty() {
+            return Err(anyhow!("share fs mount tag must not be empty"));
+        }
+
+        if self.mount_tag.len() > VIRTIO_FS_MAX_TAG_LEN {
+            return Err(anyhow!(
+                "share fs mount tag {:?} longer than {} bytes",
+                self.mount_tag,
+                VIRTIO_FS_MAX_TAG_LEN
+            ));
+        }
+
+        if !self.host_path.is_empty() && !Path::new(&self.host_path).is_absolute() {
+            return Err(anyhow!(
+                "share fs host path must be absolute: {:?}",
+                self.host_path
+            ));
+        }
+
+        if !self.guest_mount_point.is_empty() && !Path::new(&self.guest_mount_point).is_absolute() {
+            return Err(anyhow!(
+                "share fs guest mount point must be absolute: {:?}",
+                self.guest_mount_point
+            ));
+        }
+
+        match (self.queue_num, self.queue_size) {
+            (0, 0) => (),
+            (0, _) | (_, 0) => {
+                return Err(anyhow!(
+                    "share fs queue number ({}) and queue size ({}) must be set together",
+                    self.queue_num,
+                    self.queue_size
+                ))
+            }
+            (_, size) => {
+                if size > u16::MAX as u64 || !size.is_power_of_two() {
+                    return Err(anyhow!(
+                        "share fs queue size must be a power of two up to {}: {}",
+                        u16::MAX,
+                        size
+                    ));
+                }
+            }
+        }
+
+        Ok(ShareFsDeviceConfig {
+            fs_type: self.fs_type.clone(),
+            sock_path: self.sock_path.clone(),
+            mount_tag: self.mount_tag.clone(),
+            host_path: self.host_path.clone(),
+            queue_size: self.queue_size,
+            queue_num: self.queue_num,
+            coalesce: self.coalesce,
+            guest_mount_point: self.guest_mount_point.clone(),
+            labels: self.labels.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_fs_device_config_builder() {
+        let builder = ShareFsDeviceConfigBuilder::new(SHARE_FS_TYPE_VIRTIO_FS)
+            .sock_path("/run/virtiofsd.sock")
+            .mount_tag("kataShared")
+            .host_path("/run/shared");
+
+        let cfg = builder.build().unwrap();
+        assert_eq!(cfg.fs_type, SHARE_FS_TYPE_VIRTIO_FS);
+        assert_eq!(cfg.sock_path, "/run/virtiofsd.sock");
+        assert_eq!(cfg.mount_tag, "kataShared");
+        assert_eq!(cfg.host_path, "/run/shared");
+        assert_eq!((cfg.queue_num, cfg.queue_size), (0, 0));
+        assert!(cfg.labels.is_empty());
+
+        let cfg = builder
+            .clone()
+            .label("volume", "data")
+            .label("tenant", "a")
+            .build()
+            .unwrap();
+        assert_eq!(cfg.labels.get("volume").map(String::as_str), Some("data"));
+        assert_eq!(cfg.labels.len(), 2);
+
+        let cfg = builder.clone().queues(1, 1024).build().unwrap();
+        assert_eq!((cfg.queue_num, cfg.queue_size), (1, 1024));
+
+        let cfg = builder
+            .clone()
+            .guest_mount_point("/run/kata-containers/shared/containers")
+            .build()
+            .unwrap();
+        assert_eq!(
+            cfg.guest_mount_point,
+            "/run/kata-containers/shared/containers"
+        );
+
+        // The inline daemon does not use a socket.
+        assert!(
+            ShareFsDeviceConfigBuilder::new(SHARE_FS_TYPE_INLINE_VIRTIO_FS)
+                .mount_tag("kataShared")
+                .build()
+                .is_ok()
+        );
+
+        let invalid = vec![
+            ShareFsDeviceConfigBuilder::new("virtiofs").mount_tag("kataShared"),
+            builder.clone().sock_path(""),
+            builder.clone().sock_path("virtiofsd.sock"),
+            builder.clone().mount_tag(""),
+            builder
+                .clone()
+                .mount_tag(&"a".repeat(VIRTIO_FS_MAX_TAG_LEN + 1)),
+            builder.clone().host_path("shared"),
+            builder.clone().guest_mount_point("shared"),
+            builder.clone().queues(1, 0),
+            builder.clone().queues(0, 1024),
+            builder.clone().queues(1, 1000),
+            builder.clone().queues(1, 1 << 16),
+        ];
+
+        for b in invalid {
+            assert!(b.build().is_err(), "{:?}", b);
+        }
+    }
 }
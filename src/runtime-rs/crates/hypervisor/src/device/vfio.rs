@@ -6,6 +6,8 @@
 
 use std::{fs, path::Path, process::Command};
 
+use super::DeviceLabels;
+
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use anyhow::anyhow;
 use anyhow::{Context, Result};
@@ -53,6 +55,9 @@ pub struct VfioConfig {
 
     /// Bus Mode, PCI or MMIO
     pub mode: VfioBusMode,
+
+    /// labels: labels of the device, see DeviceLabels.
+    pub labels: DeviceLabels,
 }
 
 /// binds the device to vfio driver after unbinding from host.
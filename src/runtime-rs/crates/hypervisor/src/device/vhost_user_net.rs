@@ -0,0 +1,29 @@
+// Copyright (c) 2026 Kata Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use super::{Address, DeviceLabels};
+
+/// VhostUserNetConfig: vhost-user-net device config, for network backends
+/// such as DPDK or OVS-DPDK running on the host.
+#[derive(Debug)]
+pub struct VhostUserNetConfig {
+    /// Unique identifier of the device
+    pub id: String,
+
+    /// socket_path: vhost-user socket of the backend.
+    pub socket_path: String,
+
+    /// num_queues: number of queues, which must match the backend.
+    pub num_queues: usize,
+
+    /// queue_size: size of each queue, 0 to use the hypervisor default.
+    pub queue_size: u16,
+
+    /// Guest MAC address.
+    pub guest_mac: Option<Address>,
+
+    /// labels: labels of the device, see DeviceLabels.
+    pub labels: DeviceLabels,
+}
@@ -4,6 +4,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use super::DeviceLabels;
 use anyhow::{Context, Result};
 use rand::Rng;
 use std::os::unix::prelude::AsRawFd;
@@ -19,6 +20,9 @@ pub struct HybridVsockConfig {
 
     /// unix domain socket path
     pub uds_path: String,
+
+    /// labels: labels of the device, see DeviceLabels.
+    pub labels: DeviceLabels,
 }
 
 #[derive(Debug)]
@@ -31,6 +35,9 @@ pub struct VsockConfig {
 
     /// Vhost vsock fd. Hold to ensure CID is not used by other VM.
     pub vhost_fd: File,
+
+    /// labels: labels of the device, see DeviceLabels.
+    pub labels: DeviceLabels,
 }
 
 const VHOST_VSOCK_DEVICE: &str = "/dev/vhost-vsock";
@@ -76,6 +83,7 @@ impl VsockConfig {
                         id,
                         guest_cid: rand_cid,
                         vhost_fd,
+                        labels: DeviceLabels::new(),
                     });
                 }
                 Err(nix::Error::EADDRINUSE) => {
@@ -66,6 +66,9 @@ impl DragonballInner {
             Device::Vsock(_) => {
                 todo!()
             }
+            Device::VhostUserNet(_) | Device::Pmem(_) => {
+                Err(anyhow!("unsupported device {:?}", device))
+            }
         }
     }
 
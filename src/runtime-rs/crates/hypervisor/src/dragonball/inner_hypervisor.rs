@@ -9,11 +9,11 @@ use std::{
     iter::FromIterator,
 };
 
-use anyhow::{Context, Ok, Result};
+use anyhow::{anyhow, Context, Ok, Result};
 use kata_types::capabilities::Capabilities;
 
 use super::inner::DragonballInner;
-use crate::{utils, VcpuThreadIds, VmmState};
+use crate::{utils, ResourceCapacity, VcpuThreadIds, VmmState};
 use shim_interface::KATA_PATH;
 const DEFAULT_HYBRID_VSOCK_NAME: &str = "kata.hvsock";
 
@@ -36,6 +36,7 @@ impl DragonballInner {
             id: format!("vsock-{}", &self.id),
             guest_cid: 3,
             uds_path,
+            labels: Default::default(),
         });
 
         self.add_device(d).await.context("add device")?;
@@ -127,10 +128,24 @@ impl DragonballInner {
         Ok(Vec::from_iter(pids.into_iter()))
     }
 
+    pub(crate) async fn get_vmm_master_tid(&self) -> Result<u32> {
+        Err(anyhow!("getting the VMM master thread is not supported"))
+    }
+
+    pub(crate) async fn get_ns_path(&self) -> Result<String> {
+        self.netns
+            .clone()
+            .ok_or_else(|| anyhow!("no network namespace set"))
+    }
+
     pub(crate) async fn check(&self) -> Result<()> {
         Ok(())
     }
 
+    pub(crate) async fn wait_vm(&self) -> Result<Option<String>> {
+        Err(anyhow!("waiting for the VMM to exit is not supported"))
+    }
+
     pub(crate) async fn get_jailer_root(&self) -> Result<String> {
         Ok(self.jailer_root.clone())
     }
@@ -138,4 +153,13 @@ impl DragonballInner {
     pub(crate) async fn capabilities(&self) -> Result<Capabilities> {
         Ok(self.capabilities.clone())
     }
+
+    pub(crate) async fn get_resource_capacity(&self) -> Result<ResourceCapacity> {
+        Err(anyhow!("resource capacity not supported by dragonball"))
+    }
+
+    // The helper processes are not managed by dragonball.
+    pub(crate) async fn register_helper(&self, _pid: u32) -> Result<()> {
+        Ok(())
+    }
 }
@@ -20,7 +20,7 @@ use kata_types::capabilities::Capabilities;
 use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
 use tokio::sync::RwLock;
 
-use crate::{device::Device, Hypervisor, VcpuThreadIds};
+use crate::{device::Device, Hypervisor, ResourceCapacity, VcpuThreadIds};
 
 pub struct Dragonball {
     inner: Arc<RwLock<DragonballInner>>,
@@ -117,11 +117,26 @@ impl Hypervisor for Dragonball {
         inner.get_pids().await
     }
 
+    async fn get_vmm_master_tid(&self) -> Result<u32> {
+        let inner = self.inner.read().await;
+        inner.get_vmm_master_tid().await
+    }
+
+    async fn get_ns_path(&self) -> Result<String> {
+        let inner = self.inner.read().await;
+        inner.get_ns_path().await
+    }
+
     async fn check(&self) -> Result<()> {
         let inner = self.inner.read().await;
         inner.check().await
     }
 
+    async fn wait_vm(&self) -> Result<Option<String>> {
+        let inner = self.inner.read().await;
+        inner.wait_vm().await
+    }
+
     async fn get_jailer_root(&self) -> Result<String> {
         let inner = self.inner.read().await;
         inner.get_jailer_root().await
@@ -135,6 +150,16 @@ impl Hypervisor for Dragonball {
         let inner = self.inner.read().await;
         inner.capabilities().await
     }
+
+    async fn get_resource_capacity(&self) -> Result<ResourceCapacity> {
+        let inner = self.inner.read().await;
+        inner.get_resource_capacity().await
+    }
+
+    async fn register_helper(&self, pid: u32) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.register_helper(pid).await
+    }
 }
 
 #[async_trait]
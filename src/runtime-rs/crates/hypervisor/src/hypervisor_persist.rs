@@ -6,7 +6,7 @@
 
 use crate::HypervisorConfig;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct HypervisorState {
@@ -33,4 +33,12 @@ pub struct HypervisorState {
     /// cached block device
     pub cached_block_devices: HashSet<String>,
     pub virtiofs_daemon_pid: i32,
+    /// data bound into the launch measurement of a confidential guest, in
+    /// hexadecimal, by configuration field
+    #[serde(default)]
+    pub launch_data: BTreeMap<String, String>,
+    /// files and directories created for the VM, as (path, kind) pairs in
+    /// creation order
+    #[serde(default)]
+    pub artifacts: Vec<(String, String)>,
 }
@@ -59,6 +59,9 @@ pub(crate) enum VmmState {
     NotReady,
     VmmServerReady,
     VmRunning,
+    VmPaused,
+    VmMigrating,
+    VmStopped,
 }
 
 // vcpu mapping from vcpu number to thread number
@@ -67,6 +70,18 @@ pub struct VcpuThreadIds {
     pub vcpus: HashMap<u32, u32>,
 }
 
+// Resources of a VM, and how far they can grow with hotplug.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceCapacity {
+    pub vcpus: u32,
+    pub max_vcpus: u32,
+    // Guest memory plugged, in MiB.
+    pub memory_mb: u64,
+    pub max_memory_mb: u64,
+    // Size in MiB of the balloon, None without balloon device.
+    pub balloon_mb: Option<u64>,
+}
+
 #[async_trait]
 pub trait Hypervisor: Send + Sync {
     // vm manager
@@ -87,9 +102,18 @@ pub trait Hypervisor: Send + Sync {
     async fn hypervisor_config(&self) -> HypervisorConfig;
     async fn get_thread_ids(&self) -> Result<VcpuThreadIds>;
     async fn get_pids(&self) -> Result<Vec<u32>>;
+    async fn get_vmm_master_tid(&self) -> Result<u32>;
+    async fn get_ns_path(&self) -> Result<String>;
     async fn cleanup(&self) -> Result<()>;
     async fn check(&self) -> Result<()>;
+    // Wait for the VMM to exit. Returns None if it was stopped with
+    // stop_vm(), or the reason it exited otherwise.
+    async fn wait_vm(&self) -> Result<Option<String>>;
     async fn get_jailer_root(&self) -> Result<String>;
     async fn save_state(&self) -> Result<HypervisorState>;
     async fn capabilities(&self) -> Result<Capabilities>;
+    async fn get_resource_capacity(&self) -> Result<ResourceCapacity>;
+    // Register a helper process of the VM started by the runtime, such as
+    // virtiofsd, for the hypervisor to manage along with the VMM.
+    async fn register_helper(&self, pid: u32) -> Result<()>;
 }
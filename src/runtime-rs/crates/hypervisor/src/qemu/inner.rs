@@ -3,9 +3,9 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use crate::{HypervisorConfig, VcpuThreadIds};
+use crate::{HypervisorConfig, ResourceCapacity, VcpuThreadIds};
 use kata_types::capabilities::{Capabilities, CapabilityBits};
 
 const VSOCK_SCHEME: &str = "vsock";
@@ -102,10 +102,24 @@ impl QemuInner {
         todo!()
     }
 
+    pub(crate) async fn get_vmm_master_tid(&self) -> Result<u32> {
+        info!(sl!(), "QemuInner::get_vmm_master_tid()");
+        todo!()
+    }
+
+    pub(crate) async fn get_ns_path(&self) -> Result<String> {
+        info!(sl!(), "QemuInner::get_ns_path()");
+        todo!()
+    }
+
     pub(crate) async fn check(&self) -> Result<()> {
         todo!()
     }
 
+    pub(crate) async fn wait_vm(&self) -> Result<Option<String>> {
+        todo!()
+    }
+
     pub(crate) async fn get_jailer_root(&self) -> Result<String> {
         todo!()
     }
@@ -116,6 +130,15 @@ impl QemuInner {
         Ok(caps)
     }
 
+    pub(crate) async fn get_resource_capacity(&self) -> Result<ResourceCapacity> {
+        Err(anyhow!("resource capacity not supported by qemu"))
+    }
+
+    pub(crate) async fn register_helper(&self, pid: u32) -> Result<()> {
+        info!(sl!(), "QemuInner::register_helper() {}", pid);
+        Ok(())
+    }
+
     pub fn set_hypervisor_config(&mut self, config: HypervisorConfig) {
         self.config = config;
     }
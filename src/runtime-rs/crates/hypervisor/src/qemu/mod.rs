@@ -8,7 +8,7 @@ mod inner;
 use crate::device::Device;
 use crate::hypervisor_persist::HypervisorState;
 use crate::Hypervisor;
-use crate::{HypervisorConfig, VcpuThreadIds};
+use crate::{HypervisorConfig, ResourceCapacity, VcpuThreadIds};
 use inner::QemuInner;
 use kata_types::capabilities::Capabilities;
 
@@ -113,11 +113,26 @@ impl Hypervisor for Qemu {
         inner.get_pids().await
     }
 
+    async fn get_vmm_master_tid(&self) -> Result<u32> {
+        let inner = self.inner.read().await;
+        inner.get_vmm_master_tid().await
+    }
+
+    async fn get_ns_path(&self) -> Result<String> {
+        let inner = self.inner.read().await;
+        inner.get_ns_path().await
+    }
+
     async fn check(&self) -> Result<()> {
         let inner = self.inner.read().await;
         inner.check().await
     }
 
+    async fn wait_vm(&self) -> Result<Option<String>> {
+        let inner = self.inner.read().await;
+        inner.wait_vm().await
+    }
+
     async fn get_jailer_root(&self) -> Result<String> {
         let inner = self.inner.read().await;
         inner.get_jailer_root().await
@@ -131,4 +146,14 @@ impl Hypervisor for Qemu {
         let inner = self.inner.read().await;
         inner.capabilities().await
     }
+
+    async fn get_resource_capacity(&self) -> Result<ResourceCapacity> {
+        let inner = self.inner.read().await;
+        inner.get_resource_capacity().await
+    }
+
+    async fn register_helper(&self, pid: u32) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.register_helper(pid).await
+    }
 }
@@ -44,6 +44,7 @@ impl MacVlanEndpoint {
             id: self.net_pair.virt_iface.name.clone(),
             host_dev_name: iface.name.clone(),
             guest_mac: Some(guest_mac),
+            labels: Default::default(),
         })
     }
 }
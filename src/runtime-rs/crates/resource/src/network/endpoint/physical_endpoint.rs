@@ -114,6 +114,7 @@ impl Endpoint for PhysicalEndpoint {
             bus_slot_func: self.bdf.clone(),
             mode: device::VfioBusMode::new(mode)
                 .with_context(|| format!("new vfio bus mode {:?}", mode))?,
+            labels: Default::default(),
         });
         hypervisor.add_device(d).await.context("add device")?;
         Ok(())
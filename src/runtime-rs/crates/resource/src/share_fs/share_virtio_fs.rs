@@ -9,11 +9,11 @@ use std::path::Path;
 use anyhow::{Context, Result};
 use hypervisor::{
     device::{Device as HypervisorDevice, ShareFsMountConfig, ShareFsMountType, ShareFsOperation},
-    Hypervisor, ShareFsDeviceConfig,
+    Hypervisor, ShareFsDeviceConfigBuilder,
 };
 use kata_sys_util::mount;
 
-use super::{utils, PASSTHROUGH_FS_DIR};
+use super::{utils, KATA_GUEST_SHARE_DIR, PASSTHROUGH_FS_DIR};
 
 pub(crate) const MOUNT_GUEST_TAG: &str = "kataShared";
 
@@ -42,14 +42,15 @@ pub(crate) async fn prepare_virtiofs(
     mount::bind_mount_unchecked(&host_rw_dest, &host_ro_dest, true)
         .context("bind mount shared_fs directory")?;
 
-    let share_fs_device = HypervisorDevice::ShareFsDevice(ShareFsDeviceConfig {
-        sock_path: generate_sock_path(root),
-        mount_tag: String::from(MOUNT_GUEST_TAG),
-        host_path: String::from(host_ro_dest.to_str().unwrap()),
-        fs_type: fs_type.to_string(),
-        queue_size: 0,
-        queue_num: 0,
-    });
+    let share_fs_config = ShareFsDeviceConfigBuilder::new(fs_type)
+        .sock_path(&generate_sock_path(root))
+        .mount_tag(MOUNT_GUEST_TAG)
+        .host_path(host_ro_dest.to_str().unwrap())
+        .guest_mount_point(KATA_GUEST_SHARE_DIR)
+        .build()
+        .context("share fs device config")?;
+
+    let share_fs_device = HypervisorDevice::ShareFsDevice(share_fs_config);
     h.add_device(share_fs_device).await.context("add device")?;
     Ok(())
 }
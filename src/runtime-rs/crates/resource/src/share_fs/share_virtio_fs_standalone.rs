@@ -4,7 +4,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::{collections::HashMap, process::Stdio, sync::Arc};
+use std::{collections::HashMap, os::unix::fs::FileTypeExt, process::Stdio, sync::Arc};
 
 use crate::share_fs::share_virtio_fs::{
     prepare_virtiofs, FS_TYPE_VIRTIO_FS, KATA_VIRTIO_FS_DEV_TYPE, MOUNT_GUEST_TAG,
@@ -29,6 +29,17 @@ use super::{
     virtio_fs_share_mount::VirtiofsShareMount, MountedInfo, ShareFs, ShareFsMount,
 };
 
+const VIRTIOFSD_THREAD_POOL_SIZE_ARG: &str = "--thread-pool-size";
+
+// Sandbox modes of virtiofsd, from the most to the least secure.
+const VIRTIOFSD_SANDBOX_NAMESPACE: &str = "namespace";
+const VIRTIOFSD_SANDBOX_CHROOT: &str = "chroot";
+const VIRTIOFSD_SANDBOX_NONE: &str = "none";
+
+// First virtiofsd version with the --sandbox option. Older versions always
+// use the namespace sandbox.
+const VIRTIOFSD_SANDBOX_MIN_VERSION: (u32, u32, u32) = (1, 2, 0);
+
 #[derive(Debug, Clone)]
 pub struct ShareVirtioFsStandaloneConfig {
     id: String,
@@ -39,6 +50,11 @@ pub struct ShareVirtioFsStandaloneConfig {
     pub virtio_fs_cache: String,
     // virtio_fs_extra_args passes options to virtiofsd daemon
     pub virtio_fs_extra_args: Vec<String>,
+    // virtio_fs_thread_pool_size size of the virtiofsd thread pool
+    pub virtio_fs_thread_pool_size: Option<u32>,
+    // virtio_fs_sandbox sandbox mode of virtiofsd, empty for the most secure
+    // supported mode
+    pub virtio_fs_sandbox: String,
 }
 
 #[derive(Default, Debug)]
@@ -62,6 +78,8 @@ impl ShareVirtioFsStandalone {
                 virtio_fs_daemon: config.virtio_fs_daemon.clone(),
                 virtio_fs_cache: config.virtio_fs_cache.clone(),
                 virtio_fs_extra_args: config.virtio_fs_extra_args.clone(),
+                virtio_fs_thread_pool_size: config.virtio_fs_thread_pool_size,
+                virtio_fs_sandbox: config.virtio_fs_sandbox.clone(),
             },
             share_fs_mount: Arc::new(VirtiofsShareMount::new(id)),
             mounted_info_set: Arc::new(Mutex::new(HashMap::new())),
@@ -75,6 +93,18 @@ impl ShareVirtioFsStandalone {
             .to_str()
             .ok_or_else(|| anyhow!("convert source path {:?} to str failed", source_path))?;
 
+        let version = get_virtiofsd_version(&self.config.virtio_fs_daemon);
+        let sandbox = get_sandbox_mode(
+            &self.config.virtio_fs_sandbox,
+            version,
+            nix::unistd::geteuid().is_root(),
+        )?;
+
+        info!(
+            sl!(),
+            "virtiofsd version {:?}, sandbox mode {:?}", version, sandbox
+        );
+
         let mut args: Vec<String> = vec![
             String::from("--socket-path"),
             String::from(sock_path),
@@ -82,12 +112,28 @@ impl ShareVirtioFsStandalone {
             String::from(shared_dir),
             String::from("--cache"),
             self.config.virtio_fs_cache.clone(),
-            String::from("--sandbox"),
-            String::from("none"),
-            String::from("--seccomp"),
-            String::from("none"),
         ];
 
+        if let Some(sandbox) = sandbox {
+            args.push(String::from("--sandbox"));
+            args.push(String::from(sandbox));
+        }
+
+        args.push(String::from("--seccomp"));
+        args.push(String::from("none"));
+
+        if let Some(size) = self.config.virtio_fs_thread_pool_size {
+            if has_thread_pool_size_arg(&self.config.virtio_fs_extra_args) {
+                warn!(
+                    sl!(),
+                    "thread pool size set in virtiofsd extra args, ignoring virtio_fs_thread_pool_size {}",
+                    size
+                );
+            } else {
+                args.push(format!("{}={}", VIRTIOFSD_THREAD_POOL_SIZE_ARG, size));
+            }
+        }
+
         if !self.config.virtio_fs_extra_args.is_empty() {
             let mut extra_args: Vec<String> = self.config.virtio_fs_extra_args.clone();
             args.append(&mut extra_args);
@@ -96,23 +142,43 @@ impl ShareVirtioFsStandalone {
         Ok(args)
     }
 
+    // Start virtiofsd. Setting up virtiofsd again after a failure starts
+    // from a clean state: the socket left by a previous virtiofsd would make
+    // it fail with "address already in use", so it is removed both before
+    // spawning virtiofsd and when it fails to start.
     async fn setup_virtiofsd(&self, h: &dyn Hypervisor) -> Result<()> {
         let sock_path = generate_sock_path(&h.get_jailer_root().await?);
         let args = self.virtiofsd_args(&sock_path).context("virtiofsd args")?;
 
+        remove_stale_socket(&sock_path).context("remove stale virtiofsd socket")?;
+
         let mut cmd = Command::new(&self.config.virtio_fs_daemon);
         let child_cmd = cmd.args(&args).stderr(Stdio::piped());
         let child = child_cmd.spawn().context("spawn virtiofsd")?;
 
+        let pid = child.id();
+
         // update virtiofsd pid{
         {
             let mut inner = self.inner.write().await;
-            inner.pid = child.id();
+            inner.pid = pid;
         }
 
         let (tx, mut rx): (Sender<Result<()>>, Receiver<Result<()>>) = channel(100);
         tokio::spawn(run_virtiofsd(child, tx));
 
+        // Let the hypervisor manage virtiofsd along with the VMM, before
+        // the VM starts using it.
+        if let Some(pid) = pid {
+            if let Err(e) = h.register_helper(pid).await {
+                self.shutdown_virtiofsd()
+                    .await
+                    .context("shutdown_virtiofsd")?;
+                remove_stale_socket(&sock_path).context("remove virtiofsd socket")?;
+                return Err(e.context("register virtiofsd with the hypervisor"));
+            }
+        }
+
         // TODO: support timeout
         match rx.recv().await.unwrap() {
             Ok(_) => {
@@ -124,6 +190,7 @@ impl ShareVirtioFsStandalone {
                 self.shutdown_virtiofsd()
                     .await
                     .context("shutdown_virtiofsd")?;
+                remove_stale_socket(&sock_path).context("remove virtiofsd socket")?;
                 Err(anyhow!("failed to start virtiofsd"))
             }
         }
@@ -147,6 +214,104 @@ impl ShareVirtioFsStandalone {
     }
 }
 
+// Remove the socket at the specified path, if any. Other types of files are
+// left untouched.
+fn remove_stale_socket(path: &str) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path).context(format!("remove {}", path))
+        }
+        Ok(_) => Err(anyhow!("{} exists and is not a socket", path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(anyhow!(e).context(format!("stat {}", path))),
+    }
+}
+
+// Return true if the extra arguments set the size of the thread pool, either
+// as "--thread-pool-size=N" or "--thread-pool-size N".
+fn has_thread_pool_size_arg(args: &[String]) -> bool {
+    args.iter().any(|arg| {
+        arg == VIRTIOFSD_THREAD_POOL_SIZE_ARG
+            || arg.starts_with(&format!("{}=", VIRTIOFSD_THREAD_POOL_SIZE_ARG))
+    })
+}
+
+// Return the version of virtiofsd, from "virtiofsd --version", or None if it
+// cannot be determined.
+fn get_virtiofsd_version(path: &str) -> Option<(u32, u32, u32)> {
+    let output = match std::process::Command::new(path).arg("--version").output() {
+        Ok(output) => output,
+        Err(e) => {
+            warn!(sl!(), "failed to get virtiofsd version: {}", e);
+            return None;
+        }
+    };
+
+    parse_virtiofsd_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+// Parse the output of "virtiofsd --version", such as "virtiofsd 1.6.1".
+fn parse_virtiofsd_version(output: &str) -> Option<(u32, u32, u32)> {
+    let version = output.split_whitespace().nth(1)?;
+    let mut numbers = version
+        .trim_start_matches('v')
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>());
+
+    let major = numbers.next()?.ok()?;
+    let minor = numbers.next()?.ok()?;
+    let patch = numbers.next().and_then(|n| n.ok()).unwrap_or_default();
+
+    Some((major, minor, patch))
+}
+
+// Return the sandbox mode to pass to virtiofsd, or None to not pass any,
+// from the configured mode, the version of virtiofsd, if known, and whether
+// it is started as root. Without a configured mode, the most secure mode
+// supported is used: the namespace sandbox needs root, and chroot
+// CAP_SYS_CHROOT, which only root has. A virtiofsd without the --sandbox
+// option only supports the namespace sandbox.
+fn get_sandbox_mode(
+    configured: &str,
+    version: Option<(u32, u32, u32)>,
+    is_root: bool,
+) -> Result<Option<&str>> {
+    let has_sandbox_arg = version
+        .map(|v| v >= VIRTIOFSD_SANDBOX_MIN_VERSION)
+        .unwrap_or(true);
+
+    let mode = match configured {
+        "" if is_root => VIRTIOFSD_SANDBOX_NAMESPACE,
+        "" => VIRTIOFSD_SANDBOX_NONE,
+        VIRTIOFSD_SANDBOX_NAMESPACE | VIRTIOFSD_SANDBOX_CHROOT | VIRTIOFSD_SANDBOX_NONE => {
+            configured
+        }
+        _ => return Err(anyhow!("invalid virtiofsd sandbox mode {}", configured)),
+    };
+
+    if mode != VIRTIOFSD_SANDBOX_NONE && !is_root {
+        return Err(anyhow!(
+            "virtiofsd sandbox mode {} requires the runtime to run as root",
+            mode
+        ));
+    }
+
+    if has_sandbox_arg {
+        return Ok(Some(mode));
+    }
+
+    if mode != VIRTIOFSD_SANDBOX_NAMESPACE {
+        return Err(anyhow!(
+            "virtiofsd {:?} does not support sandbox mode {}, only {}",
+            version,
+            mode,
+            VIRTIOFSD_SANDBOX_NAMESPACE
+        ));
+    }
+
+    Ok(None)
+}
+
 async fn run_virtiofsd(mut child: Child, tx: Sender<Result<()>>) -> Result<()> {
     let stderr = child.stderr.as_mut().unwrap();
     let stderr_reader = BufReader::new(stderr);
@@ -205,3 +370,54 @@ impl ShareFs for ShareVirtioFsStandalone {
         self.mounted_info_set.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_virtiofsd_version() {
+        assert_eq!(
+            parse_virtiofsd_version("virtiofsd 1.6.1\n"),
+            Some((1, 6, 1))
+        );
+        assert_eq!(parse_virtiofsd_version("virtiofsd v1.10"), Some((1, 10, 0)));
+        assert_eq!(
+            parse_virtiofsd_version("virtiofsd 1.8.0-dev"),
+            Some((1, 8, 0))
+        );
+        assert_eq!(parse_virtiofsd_version("virtiofsd"), None);
+        assert_eq!(parse_virtiofsd_version(""), None);
+    }
+
+    #[test]
+    fn test_get_sandbox_mode() {
+        let recent = Some((1, 6, 1));
+        let old = Some((1, 1, 0));
+
+        // The most secure mode supported is the default.
+        assert_eq!(
+            get_sandbox_mode("", recent, true).unwrap(),
+            Some("namespace")
+        );
+        assert_eq!(get_sandbox_mode("", recent, false).unwrap(), Some("none"));
+        assert_eq!(get_sandbox_mode("", None, true).unwrap(), Some("namespace"));
+
+        assert_eq!(
+            get_sandbox_mode("chroot", recent, true).unwrap(),
+            Some("chroot")
+        );
+        assert_eq!(
+            get_sandbox_mode("none", recent, true).unwrap(),
+            Some("none")
+        );
+        assert!(get_sandbox_mode("chroot", recent, false).is_err());
+        assert!(get_sandbox_mode("jail", recent, true).is_err());
+
+        // Without the --sandbox option, only the namespace sandbox is used.
+        assert_eq!(get_sandbox_mode("", old, true).unwrap(), None);
+        assert_eq!(get_sandbox_mode("namespace", old, true).unwrap(), None);
+        assert!(get_sandbox_mode("none", old, true).is_err());
+        assert!(get_sandbox_mode("", old, false).is_err());
+    }
+}
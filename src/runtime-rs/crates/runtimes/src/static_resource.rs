@@ -102,6 +102,8 @@ impl StaticResourceManager {
             .context("failed to get hypervisor config")?;
         hv.cpu_info.default_vcpus += self.resource.vcpu as i32;
         hv.memory_info.default_memory += self.resource.mem_mb;
+        // the VM is sized once for all, so the hypervisor should not allow hotplug
+        hv.static_sandbox_resource_mgmt = true;
         Ok(())
     }
 }
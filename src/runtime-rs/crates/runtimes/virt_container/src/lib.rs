@@ -139,6 +139,12 @@ async fn new_hypervisor(toml_config: &TomlConfig) -> Result<Arc<dyn Hypervisor>>
                 .set_hypervisor_config(hypervisor_config.clone())
                 .await;
 
+            if let Some(agent_config) = toml_config.agent.get(&toml_config.runtime.agent_name) {
+                hypervisor
+                    .set_agent_vsock_port(agent_config.server_port)
+                    .await;
+            }
+
             Ok(Arc::new(hypervisor))
         }
         _ => Err(anyhow!("Unsupported hypervisor {}", &hypervisor_name)),
@@ -16,7 +16,11 @@ use common::{
     Sandbox,
 };
 use containerd_shim_protos::events::task::TaskOOM;
+#[cfg(feature = "cloud-hypervisor")]
+use hypervisor::ch::CloudHypervisor;
 use hypervisor::{dragonball::Dragonball, Hypervisor, HYPERVISOR_DRAGONBALL};
+#[cfg(feature = "cloud-hypervisor")]
+use kata_types::config::hypervisor::HYPERVISOR_NAME_CH;
 use kata_types::config::{
     default::{DEFAULT_AGENT_LOG_PORT, DEFAULT_AGENT_VSOCK_PORT},
     TomlConfig,
@@ -117,6 +121,31 @@ impl VirtSandbox {
 
         Ok(resource_configs)
     }
+
+    // Shut down if the VMM exits without being stopped, so that the
+    // containers of the sandbox are reported as failed rather than left
+    // running until the next health check.
+    fn start_vmm_exit_watcher(&self) {
+        let id = self.sid.clone();
+        let hypervisor = self.hypervisor.clone();
+        let sender = self.msg_sender.clone();
+
+        let _ = tokio::spawn(async move {
+            match hypervisor.wait_vm().await {
+                Ok(None) => info!(sl!(), "sandbox {} vmm stopped", id),
+                Ok(Some(reason)) => {
+                    error!(sl!(), "sandbox {} vmm failed: {}", id, reason);
+
+                    let msg = Message::new(Action::Shutdown);
+                    let sender = sender.lock().await;
+                    if let Err(err) = sender.send(msg).await.context("send shutdown msg") {
+                        error!(sl!(), "failed to shut down sandbox {}: {:?}", id, err);
+                    }
+                }
+                Err(err) => info!(sl!(), "not watching sandbox {} vmm: {:?}", id, err),
+            }
+        });
+    }
 }
 
 #[async_trait]
@@ -225,6 +254,7 @@ impl Sandbox for VirtSandbox {
             }
         });
         self.monitor.start(id, self.agent.clone());
+        self.start_vmm_exit_watcher();
         self.save().await.context("save state")?;
         Ok(())
     }
@@ -324,11 +354,13 @@ impl Persist for VirtSandbox {
         let config = sandbox_args.toml_config;
         let r = sandbox_state.resource.unwrap_or_default();
         let h = sandbox_state.hypervisor.unwrap_or_default();
-        let hypervisor = match h.hypervisor_type.as_str() {
+        let hypervisor: Arc<dyn Hypervisor> = match h.hypervisor_type.as_str() {
             // TODO support other hypervisors
-            HYPERVISOR_DRAGONBALL => Ok(Arc::new(Dragonball::restore((), h).await?)),
-            _ => Err(anyhow!("Unsupported hypervisor {}", &h.hypervisor_type)),
-        }?;
+            HYPERVISOR_DRAGONBALL => Arc::new(Dragonball::restore((), h).await?),
+            #[cfg(feature = "cloud-hypervisor")]
+            HYPERVISOR_NAME_CH => Arc::new(CloudHypervisor::restore((), h).await?),
+            _ => return Err(anyhow!("Unsupported hypervisor {}", &h.hypervisor_type)),
+        };
         let agent = Arc::new(KataAgent::new(kata_types::config::Agent {
             debug: true,
             enable_tracing: false,